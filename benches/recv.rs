@@ -0,0 +1,130 @@
+//! Cost of receiving small messages on an encrypted channel, allocating a new
+//! buffer per message with recv or reusing one with recv_into
+//! Allocations per message are counted and printed before the timings
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use pomegranate::comm::{
+    channel::{ChannelReceiver, ChannelSender},
+    crypto::{AES256GCMInitializer, AES256GCMMsgReceiver, AES256GCMMsgSender},
+    encaps::{AsyncMsgRecv, AsyncMsgSend, LenU64EncapsMsgReceiver, LenU64EncapsMsgSender},
+    seq::{SeqMsgReceiver, SeqMsgSender},
+};
+use tokio::{
+    io::{duplex, DuplexStream},
+    runtime::{self, Runtime},
+};
+
+const MSG_LEN: usize = 256;
+const MSGS: u64 = 1000;
+
+/// Allocator counting the allocations made through it
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+type Receiver = ChannelReceiver<DuplexStream>;
+
+/// Returns an encrypted channel with MSGS messages waiting to be received
+/// The layers are the ones set up by the handshake, without performing one
+async fn channel() -> Receiver {
+    let init = AES256GCMInitializer::new_rand();
+    let (a, b) = duplex(2 * MSGS as usize * (MSG_LEN + 64));
+    let mut sender: ChannelSender<DuplexStream> =
+        AES256GCMMsgSender::new(SeqMsgSender::new(LenU64EncapsMsgSender::new(a)), &init);
+    let receiver =
+        AES256GCMMsgReceiver::new(SeqMsgReceiver::new(LenU64EncapsMsgReceiver::new(b)), &init);
+
+    let msg = [0x5a; MSG_LEN];
+    for _ in 0..MSGS {
+        sender.send(&msg).await.unwrap();
+    }
+    receiver
+}
+
+async fn recv_all(mut receiver: Receiver) {
+    for _ in 0..MSGS {
+        let msg = receiver.recv().await.unwrap();
+        assert_eq!(msg.len(), MSG_LEN);
+    }
+}
+
+async fn recv_into_all(mut receiver: Receiver) {
+    let mut buf = BytesMut::new();
+    for _ in 0..MSGS {
+        receiver.recv_into(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), MSG_LEN);
+    }
+}
+
+/// Prints the allocations made per message while receiving
+fn report_allocations<F: Future>(rt: &Runtime, name: &str, f: impl Fn(Receiver) -> F) {
+    let receiver = rt.block_on(channel());
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    rt.block_on(f(receiver));
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "{}: {:.2} allocations per message",
+        name,
+        allocations as f64 / MSGS as f64
+    );
+}
+
+/// Measures receiving MSGS messages, filling the channel outside of the measurement
+async fn measure<F: Future>(iters: u64, f: impl Fn(Receiver) -> F) -> Duration {
+    let mut total = Duration::ZERO;
+    for _ in 0..iters {
+        let receiver = channel().await;
+        let start = Instant::now();
+        f(receiver).await;
+        total += start.elapsed();
+    }
+    total
+}
+
+fn recv_buffers(c: &mut Criterion) {
+    let rt = runtime::Builder::new_current_thread().build().unwrap();
+    report_allocations(&rt, "recv", recv_all);
+    report_allocations(&rt, "recv_into", recv_into_all);
+
+    let mut group = c.benchmark_group("recv_buffers");
+    group.throughput(Throughput::Elements(MSGS));
+    group.bench_function("recv", |b| {
+        b.to_async(&rt)
+            .iter_custom(|iters| measure(iters, recv_all))
+    });
+    group.bench_function("recv_into", |b| {
+        b.to_async(&rt)
+            .iter_custom(|iters| measure(iters, recv_into_all))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, recv_buffers);
+criterion_main!(benches);
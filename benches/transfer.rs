@@ -0,0 +1,54 @@
+//! Throughput of stream transfers over an encrypted channel by chunk length
+//! Larger chunks mean fewer frames to encrypt, authenticate and frame, at the
+//! cost of buffering more of the payload per message
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use pomegranate::comm::{
+    crypto::{AES256GCMInitializer, AES256GCMMsgReceiver, AES256GCMMsgSender},
+    encaps::{LenU64EncapsMsgReceiver, LenU64EncapsMsgSender},
+    transfer::{recv_stream, send_stream, DEFAULT_CHUNK_LEN},
+};
+use tokio::{
+    io::{duplex, AsyncReadExt},
+    runtime,
+};
+
+const PAYLOAD_LEN: usize = 16 * 1024 * 1024;
+
+async fn transfer(payload: &[u8], chunk_len: usize) {
+    let init = AES256GCMInitializer::new_rand();
+    let (a, b) = duplex(1024 * 1024);
+    let mut sender = AES256GCMMsgSender::new(LenU64EncapsMsgSender::new(a), &init);
+    let mut receiver = AES256GCMMsgReceiver::new(LenU64EncapsMsgReceiver::new(b), &init);
+
+    let send = send_stream(&mut sender, 1, payload, payload.len() as u64, chunk_len);
+    let recv = async {
+        let mut reader = recv_stream(&mut receiver, None).await.unwrap();
+        let mut out = Vec::with_capacity(PAYLOAD_LEN);
+        reader.read_to_end(&mut out).await.unwrap();
+        out
+    };
+    let (sent, received) = tokio::join!(send, recv);
+    sent.unwrap();
+    assert_eq!(received.len(), payload.len());
+}
+
+fn stream_chunk_len(c: &mut Criterion) {
+    let rt = runtime::Builder::new_current_thread().build().unwrap();
+    let payload = vec![0x5a; PAYLOAD_LEN];
+
+    let mut group = c.benchmark_group("stream_chunk_len");
+    group.throughput(Throughput::Bytes(PAYLOAD_LEN as u64));
+    group.sample_size(10);
+    for chunk_len in [4 * 1024, 64 * 1024, DEFAULT_CHUNK_LEN, 4 * 1024 * 1024] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(chunk_len),
+            &chunk_len,
+            |b, &chunk_len| b.to_async(&rt).iter(|| transfer(&payload, chunk_len)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, stream_chunk_len);
+criterion_main!(benches);
@@ -0,0 +1,34 @@
+use std::{env, time::Duration};
+
+use pomegranate::comm::trace::{Direction, TraceReader};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let path = env::args().nth(1).unwrap_or_else(|| {
+        println!("Usage: dump_trace <trace file>");
+        std::process::exit(1);
+    });
+
+    let mut reader = TraceReader::open(&path).await.unwrap_or_else(|err| {
+        println!("Unable to open trace: {}", err);
+        std::process::exit(1);
+    });
+
+    // Print every record with its time offset from the start of the trace
+    let mut start: Option<Duration> = None;
+    while let Some(record) = reader.next_record().await.unwrap() {
+        let start = *start.get_or_insert(record.timestamp);
+        let arrow = match record.direction {
+            Direction::Sent => "->",
+            Direction::Received => "<-",
+        };
+
+        println!(
+            "[{:>10.3}s] {} {} bytes: {}",
+            (record.timestamp - start).as_secs_f64(),
+            arrow,
+            record.msg.len(),
+            String::from_utf8_lossy(&record.msg)
+        );
+    }
+}
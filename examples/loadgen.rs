@@ -0,0 +1,220 @@
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::LevelFilter;
+use pomegranate::{
+    client::ClusterClient,
+    comm::crypto::RsaKeyPair,
+    config::{ClusterClientConfig, ClusterCoordinatorConfig},
+    coordinator::{jobs::JobSpec, ClusterCoordinator},
+    logging::{self, LogFormat},
+};
+use tokio::{
+    task::{JoinSet, LocalSet},
+    time::{self, Instant},
+};
+
+const USAGE: &str = "Usage: pomegranate-loadgen [options]
+  --workers N      simulated workers (default 8)
+  --slots N        task slots per worker (default 4)
+  --jobs N         jobs to submit (default 100)
+  --concurrency N  jobs in flight at once (default 16)
+  --mix MIX        job mix, comma separated tasks:payload_len:task_ms entries,
+                   submitted in turn (default 10:64:5,2:4096:50)";
+
+/// Kind of the tasks run by the simulated workers
+const KIND: &str = "loadgen";
+
+/// Shape of the jobs of one entry of the mix
+#[derive(Debug, Clone, Copy)]
+struct JobShape {
+    tasks: usize,
+    payload_len: usize,
+    task_ms: u32, // Time each task keeps its worker slot busy
+}
+
+impl JobShape {
+    fn spec(&self) -> JobSpec {
+        // The task duration leads the payload, the rest is padding
+        let mut payload = self.task_ms.to_be_bytes().to_vec();
+        payload.resize(self.payload_len.max(4), 0);
+        (0..self.tasks).fold(JobSpec::new(KIND), |spec, _| spec.task(payload.clone()))
+    }
+}
+
+struct Options {
+    workers: usize,
+    slots: usize,
+    jobs: usize,
+    concurrency: usize,
+    mix: Vec<JobShape>,
+}
+
+impl Options {
+    fn from_args() -> Result<Self, String> {
+        let mut options = Options {
+            workers: 8,
+            slots: 4,
+            jobs: 100,
+            concurrency: 16,
+            mix: parse_mix("10:64:5,2:4096:50")?,
+        };
+
+        let mut args = env::args().skip(1);
+        while let Some(flag) = args.next() {
+            let val = args
+                .next()
+                .ok_or_else(|| format!("missing value for {}", flag))?;
+            let number = || {
+                val.parse::<usize>()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("invalid value for {}: {}", flag, val))
+            };
+            match flag.as_str() {
+                "--workers" => options.workers = number()?,
+                "--slots" => options.slots = number()?,
+                "--jobs" => options.jobs = number()?,
+                "--concurrency" => options.concurrency = number()?,
+                "--mix" => options.mix = parse_mix(&val)?,
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn parse_mix(mix: &str) -> Result<Vec<JobShape>, String> {
+    mix.split(',')
+        .map(|entry| {
+            let fields: Vec<_> = entry.split(':').map(str::parse::<usize>).collect();
+            match fields[..] {
+                [Ok(tasks), Ok(payload_len), Ok(task_ms)] if tasks > 0 => Ok(JobShape {
+                    tasks,
+                    payload_len,
+                    task_ms: task_ms as u32,
+                }),
+                _ => Err(format!("invalid mix entry {:?}", entry)),
+            }
+        })
+        .collect()
+}
+
+/// Latencies of the submitted jobs and their tasks, from submission
+#[derive(Default)]
+struct Latencies {
+    tasks: Vec<Duration>,
+    jobs: Vec<Duration>,
+    failed: usize,
+}
+
+#[tokio::main]
+async fn main() {
+    let options = Options::from_args().unwrap_or_else(|e| {
+        println!("{}\n{}", e, USAGE);
+        std::process::exit(1);
+    });
+    logging::init(LogFormat::from_env(), LevelFilter::Warn).expect("log initialization");
+
+    let keypair = RsaKeyPair::generate().unwrap();
+    let config = ClusterCoordinatorConfig::new("127.0.0.1:0");
+    let coord = ClusterCoordinator::bind(config, keypair).await.unwrap();
+    let addr = coord.local_addr().unwrap();
+
+    // Simulated workers sleep for the duration of each task, then echo its payload
+    // Clients aren't Send, so they share the main thread
+    let local = LocalSet::new();
+    for i in 0..options.workers {
+        let config = ClusterClientConfig::new(addr)
+            .worker_id(format!("loadgen-{}", i))
+            .max_tasks(options.slots)
+            .benchmark_duration(Duration::ZERO);
+        let mut client = ClusterClient::new(config);
+        client.register_handler(KIND, |payload| async move {
+            let task_ms = u32::from_be_bytes(payload[..4].try_into().unwrap());
+            time::sleep(Duration::from_millis(task_ms as u64)).await;
+            Ok(payload)
+        });
+        local.spawn_local(async move { client.run().await });
+    }
+
+    let load = async {
+        let start = Instant::now();
+        while coord.workers().len() < options.workers {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        println!(
+            "{} workers x {} slots connected in {:.3}s",
+            options.workers,
+            options.slots,
+            start.elapsed().as_secs_f64()
+        );
+
+        let latencies = Arc::new(Mutex::new(Latencies::default()));
+        let mut in_flight = JoinSet::new();
+        let start = Instant::now();
+        for shape in options.mix.iter().cycle().take(options.jobs) {
+            if in_flight.len() == options.concurrency {
+                in_flight.join_next().await;
+            }
+
+            let submitted = Instant::now();
+            let mut handle = coord.submit_job(shape.spec()).unwrap();
+            let latencies = latencies.clone();
+            in_flight.spawn(async move {
+                while let Some((_, res)) = handle.next().await {
+                    let mut latencies = latencies.lock().unwrap();
+                    latencies.tasks.push(submitted.elapsed());
+                    latencies.failed += res.is_err() as usize;
+                }
+                latencies.lock().unwrap().jobs.push(submitted.elapsed());
+            });
+        }
+        while in_flight.join_next().await.is_some() {}
+        let elapsed = start.elapsed();
+
+        let mut latencies = latencies.lock().unwrap();
+        println!(
+            "{} jobs, {} tasks in {:.3}s: {:.1} tasks/s, {} failed",
+            latencies.jobs.len(),
+            latencies.tasks.len(),
+            elapsed.as_secs_f64(),
+            latencies.tasks.len() as f64 / elapsed.as_secs_f64(),
+            latencies.failed
+        );
+        print_percentiles("Task latency", &mut latencies.tasks);
+        print_percentiles("Job latency", &mut latencies.jobs);
+    };
+
+    local
+        .run_until(async {
+            tokio::select! {
+                _ = coord.run() => {}
+                _ = load => {}
+            }
+        })
+        .await;
+}
+
+fn print_percentiles(name: &str, latencies: &mut [Duration]) {
+    if latencies.is_empty() {
+        return;
+    }
+
+    latencies.sort();
+    let at = |p: f64| {
+        let index = ((latencies.len() - 1) as f64 * p).round() as usize;
+        latencies[index].as_secs_f64() * 1000.0
+    };
+    println!(
+        "{}: p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+        name,
+        at(0.5),
+        at(0.9),
+        at(0.99),
+        at(1.0)
+    );
+}
@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use log::LevelFilter;
+use pomegranate::{
+    client::ClusterClient,
+    comm::{crypto::RsaKeyPair, transport::Endpoint},
+    config::{ClusterClientConfig, ClusterCoordinatorConfig},
+    coordinator::{
+        jobs::{JobSpec, TaskOutput},
+        ClusterCoordinator,
+    },
+    logging::{self, LogFormat},
+};
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+
+const PORT: u16 = 1234;
+
+// Coordinator and worker in one process, for clusters of a single machine
+// The local worker connects through a Unix domain socket where available.
+// More workers can join later on the TCP port, jobs are then spread across them.
+// Each line read from stdin is submitted as a job of "echo" tasks, one per word
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    // Initialize logging to stderr, JSON if POMEGRANATE_LOG_FORMAT=json
+    logging::init(LogFormat::from_env(), LevelFilter::Info).expect("log initialization");
+
+    let keypair = RsaKeyPair::load_or_generate("coordinator_key.pem", None).unwrap();
+    let config = ClusterCoordinatorConfig::new(("0.0.0.0", PORT));
+    #[cfg(unix)]
+    let (config, local_addr) = {
+        let path = std::env::temp_dir().join(format!("pomegranate-solo-{}.sock", PORT));
+        (config.unix_socket(&path), Endpoint::Unix(path))
+    };
+    #[cfg(not(unix))]
+    let local_addr = Endpoint::Tcp(([127, 0, 0, 1], PORT).into());
+    let coord = ClusterCoordinator::bind(config, keypair).await.unwrap();
+
+    let worker_config = ClusterClientConfig::new(("127.0.0.1", PORT))
+        .coord_addr(local_addr)
+        .worker_id("solo")
+        .benchmark_duration(Duration::ZERO);
+    let mut worker = ClusterClient::new(worker_config);
+    worker.register_handler("echo", |payload| async move { Ok(payload) });
+
+    let submit = async {
+        let mut lines = BufReader::new(stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let spec = line
+                .split_whitespace()
+                .fold(JobSpec::new("echo"), |spec, word| spec.task(word));
+            let handle = match coord.submit_job(spec) {
+                Ok(handle) => handle,
+                Err(e) => {
+                    println!("Job rejected: {}", e);
+                    continue;
+                }
+            };
+
+            for result in handle.results().await {
+                match result {
+                    Ok(TaskOutput::Inline(output)) => {
+                        println!("{}", String::from_utf8_lossy(&output))
+                    }
+                    Ok(TaskOutput::Artifact(artifact)) => println!("artifact {}", artifact.name()),
+                    Err(e) => println!("{}", e),
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = coord.run() => {}
+        Err(e) = worker.run() => println!("Local worker stopped: {}", e),
+        _ = submit => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
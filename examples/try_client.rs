@@ -1,18 +1,40 @@
-use std::io::stderr;
-
-use log::Level;
-use pomegranate::{client::ClusterClient, config::ClusterClientConfig};
+use log::LevelFilter;
+use pomegranate::{
+    client::{ClusterClient, ClusterEvent},
+    config::ClusterClientConfig,
+    logging::{self, LogFormat},
+};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    // Initialize logging to stderr
-    stderrlog::new()
-        .verbosity(Level::Debug)
-        .init()
-        .expect("log initialization");
+    // Initialize logging to stderr, JSON if POMEGRANATE_LOG_FORMAT=json
+    logging::init(LogFormat::from_env(), LevelFilter::Debug).expect("log initialization");
 
     let cclient_conf = ClusterClientConfig::new("127.0.0.1:1234").bypass_pk_check(false);
-    let cclient = ClusterClient::new(cclient_conf);
+    let mut cclient = ClusterClient::new(cclient_conf);
+
+    // Run "echo" tasks by returning their payload
+    cclient.register_handler("echo", |payload| async move { Ok(payload) });
 
-    cclient.run().await;
+    // Print cluster events, stop cleanly on Ctrl-C
+    let shutdown = async {
+        let events = async {
+            loop {
+                match cclient.next_event().await {
+                    ClusterEvent::MessageReceived(msg) => {
+                        println!("Received message: {}", String::from_utf8_lossy(&msg))
+                    }
+                    ev => println!("{:?}", ev),
+                }
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = events => {}
+        }
+    };
+    if let Err(e) = cclient.run_until(shutdown).await {
+        println!("Client stopped: {}", e);
+        std::process::exit(1);
+    }
 }
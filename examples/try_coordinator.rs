@@ -0,0 +1,36 @@
+use log::LevelFilter;
+use pomegranate::{
+    comm::crypto::RsaKeyPair,
+    config::ClusterCoordinatorConfig,
+    coordinator::{ClusterCoordinator, WorkerEvent},
+    logging::{self, LogFormat},
+};
+
+const PORT: u16 = 1234;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    // Initialize logging to stderr, JSON if POMEGRANATE_LOG_FORMAT=json
+    logging::init(LogFormat::from_env(), LevelFilter::Debug).expect("log initialization");
+
+    // Load the key pair, generating it on the first run, so workers keep trusting it
+    let keypair = RsaKeyPair::load_or_generate("coordinator_key.pem", None).unwrap();
+
+    let config = ClusterCoordinatorConfig::new(("0.0.0.0", PORT));
+    let coord = ClusterCoordinator::bind(config, keypair).await.unwrap();
+
+    // Echo worker messages back to all workers
+    let echo = async {
+        loop {
+            match coord.next_event().await {
+                WorkerEvent::Message { id, msg } => {
+                    let text = format!("Worker {}: {}", id, String::from_utf8_lossy(&msg));
+                    coord.broadcast(text.as_bytes()).await;
+                }
+                ev => println!("{:?}", ev),
+            }
+        }
+    };
+
+    tokio::join!(coord.run(), echo);
+}
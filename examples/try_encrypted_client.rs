@@ -1,30 +1,22 @@
-use std::{str::from_utf8, time::Duration};
+use std::str::from_utf8;
 
 use pomegranate::comm::{
-    crypto::{client_setup_encrypted_channel, ServerPublicKeyValidator},
-    encaps::{AsyncMsgRecv, AsyncMsgSend, LenU64EncapsMsgReceiver, LenU64EncapsMsgSender},
+    channel::ChannelOptions,
+    connect_encrypted,
+    crypto::ServerPublicKeyValidator,
+    encaps::{AsyncMsgRecv, AsyncMsgSend},
 };
-use tokio::net::TcpStream;
 
 const PORT: u16 = 1234;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    // Connect to server
-    let mut socket = TcpStream::connect(("127.0.0.1", PORT)).await.unwrap();
-
-    println!("Connected!");
-    let (reader, writer) = socket.split();
-    let sender = LenU64EncapsMsgSender::new(writer);
-    let receiver = LenU64EncapsMsgReceiver::new(reader);
-
-    // Enstablish a secure channel
+    // Connect to server and enstablish a secure channel
     let mut key_validator = ServerPublicKeyValidator::new(false);
-    let (mut sender, mut receiver) = client_setup_encrypted_channel(
-        sender,
-        receiver,
-        Duration::from_millis(1000),
+    let (mut sender, mut receiver) = connect_encrypted(
+        ("127.0.0.1", PORT),
         &mut key_validator,
+        &ChannelOptions::default(),
     )
     .await
     .unwrap();
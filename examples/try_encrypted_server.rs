@@ -1,8 +1,7 @@
-use std::{str::from_utf8, time::Duration};
+use std::time::Duration;
 
 use pomegranate::comm::{
-    crypto::{server_setup_encrypted_channel, RsaKeyPair},
-    encaps::{AsyncMsgRecv, AsyncMsgSend, LenU64EncapsMsgReceiver, LenU64EncapsMsgSender},
+    accept_encrypted, channel::ChannelOptions, crypto::RsaKeyPair, encaps::AsyncMsgSend,
 };
 use tokio::{net::TcpListener, time};
 
@@ -18,23 +17,16 @@ async fn main() {
     let listener = TcpListener::bind(("0.0.0.0", PORT)).await.unwrap();
     println!("Listening on port {}", PORT);
 
-    // Listen for connection
-    let (mut socket, addr) = listener.accept().await.unwrap();
-    println!("New connection from {}", addr);
-    let (reader, writer) = socket.split();
-    let sender = LenU64EncapsMsgSender::new(writer);
-    let receiver = LenU64EncapsMsgReceiver::new(reader);
-
-    // Enstablish a secure channel
-    let (mut sender, mut receiver) =
-        server_setup_encrypted_channel(sender, receiver, &keypair, Duration::from_millis(1000))
+    // Listen for connection and enstablish a secure channel
+    let ((mut sender, _receiver), addr) =
+        accept_encrypted(&listener, &keypair, &ChannelOptions::default())
             .await
             .unwrap_or_else(|err| {
                 println!("Unable to enstablish encyprted channel: {}", err);
                 std::process::exit(1);
             });
 
-    println!("Encrypted channel enstablished!");
+    println!("Encrypted channel enstablished with {}!", addr);
 
     for i in 0..1000 {
         sender
@@ -44,11 +36,4 @@ async fn main() {
 
         time::sleep(Duration::from_millis(1000)).await;
     }
-    // sender.send("Hello from server 2".as_bytes()).await.unwrap();
-
-    // let msg = receiver.recv().await.unwrap();
-    // println!("Message from client: {}", from_utf8(&msg).unwrap());
-
-    // let msg = receiver.recv().await.unwrap();
-    // println!("Message from client: {}", from_utf8(&msg).unwrap());
 }
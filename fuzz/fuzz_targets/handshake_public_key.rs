@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pomegranate::comm::crypto::parse_public_key;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_public_key(data);
+});
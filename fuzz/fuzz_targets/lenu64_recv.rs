@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pomegranate::comm::encaps::{AsyncMsgRecv, LenU64EncapsMsgReceiver};
+use tokio::runtime;
+
+// Keep the limit small so oversized length headers get rejected rather than allocated
+const MAX_LEN: u64 = 64 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    let rt = runtime::Builder::new_current_thread().build().unwrap();
+    rt.block_on(async {
+        let mut receiver = LenU64EncapsMsgReceiver::new(data).max_len(MAX_LEN);
+
+        // Receive until the input is exhausted or rejected
+        while let Ok(msg) = receiver.recv().await {
+            assert!(msg.len() as u64 <= MAX_LEN);
+        }
+    });
+});
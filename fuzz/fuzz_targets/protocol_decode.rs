@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pomegranate::comm::protocol::{
+    decode_frame, decode_message, ClientMessage, CoordinatorMessage,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_message::<ClientMessage>(data);
+    let _ = decode_message::<CoordinatorMessage>(data);
+    let _ = decode_frame::<ClientMessage>(data);
+});
@@ -1,87 +1,986 @@
-use std::{io, ops::DivAssign, sync::mpsc::Receiver, time::Duration};
+pub mod admin;
+pub mod bench;
+pub mod breaker;
+pub mod executor;
+pub mod store;
+
+use std::{
+    fmt,
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
 use log::{debug, error, info, warn};
-use stderrlog::new;
-use tokio::{io::AsyncSeek, net::TcpStream, sync::broadcast::error, time};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{
+        self,
+        mpsc::{self, error::TrySendError},
+    },
+    task::JoinSet,
+    time,
+};
+
+use crate::{
+    client::{
+        bench::run_benchmark,
+        breaker::{BreakerState, CircuitBreaker, FailureClass},
+        executor::Executor,
+    },
+    comm::{
+        channel::{client_channel, ChannelOptions, ChannelReceiver, ChannelSender},
+        crypto::{ClientIdentity, ServerPublicKeyChanged, ServerPublicKeyValidator},
+        encaps::{AsyncMsgRecv, AsyncMsgSend},
+        heartbeat::recv_timeout,
+        hexdump::set_hexdump_len,
+        protocol::{
+            ArtifactRef, ClientMessage, CoordinatorMessage, OnboardingReject, TaskOutcome,
+            TypedMsgReceiver, TypedMsgSender, WorkerAssignment, WorkerHello, PROTOCOL_VERSION,
+        },
+        proxy::{self, ProxyError},
+        trace::{Tracer, TracingMsgReceiver, TracingMsgSender},
+        transport::Endpoint,
+    },
+    config::{ClusterClientConfig, ConfigError},
+    systemd::{self, Watchdog},
+};
 
+#[cfg(feature = "stats")]
+use crate::comm::stats::{ConnStats, StatsMsgReceiver, StatsMsgSender};
+#[cfg(feature = "tls")]
 use crate::{
     comm::{
-        crypto::{client_setup_encrypted_channel, ServerPublicKeyValidator},
-        encaps::{AsyncMsgRecv, AsyncMsgSend, LenU64EncapsMsgReceiver, LenU64EncapsMsgSender},
-        timer::DoublingTimer,
+        encaps::Either,
+        tls::{self, rustls::pki_types::ServerName, TlsChannel},
     },
-    config::ClusterClientConfig,
+    config::ChannelSecurity,
 };
+#[cfg(feature = "tls")]
+use tokio::io::Join;
+#[cfg(feature = "tls")]
+use tokio_rustls::client;
+
+/// Maximum time to wait for the TCP connection to the coordinator
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Messages queued for sending to the coordinator
+const OUT_QUEUE_LEN: usize = 64;
+
+/// Error of a failed connection attempt
+#[derive(Debug)]
+pub struct ConnectError {
+    pub class: FailureClass,
+    pub err: io::Error,
+}
+
+impl ConnectError {
+    fn new(class: FailureClass, err: io::Error) -> Self {
+        Self { class, err }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.err, self.class)
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.err)
+    }
+}
+
+/// Event concerning the connection to the cluster
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClusterEvent {
+    Connected { coordinator: Endpoint }, // Onboarded by a coordinator
+    Disconnected { reason: String },     // Enstablished connection was lost
+    MessageReceived(Vec<u8>),            // Data message from the coordinator
+    HandshakeFailed { reason: String }, // Coordinator reached, but channel setup or onboarding failed
+    RetryScheduled { delay: Duration }, // Waiting before the next connection attempt
+}
 
 /// Pomegranate Cluster Client
 pub struct ClusterClient {
     config: ClusterClientConfig,
+    breaker_state: Mutex<BreakerState>,
+    assignment: Mutex<Option<WorkerAssignment>>,
+    coordinator: Mutex<Option<Endpoint>>,
+    events_tx: mpsc::Sender<ClusterEvent>,
+    events_rx: sync::Mutex<mpsc::Receiver<ClusterEvent>>,
+    dropped_events: AtomicU64, // Events dropped because the queue was full
+    perf_score: Arc<Mutex<u32>>, // Shared with the benchmark thread
+    executor: Executor,
+    #[cfg(feature = "stats")]
+    conn_stats: Mutex<Option<Arc<ConnStats>>>,
 }
 
 impl ClusterClient {
     /// Creates new ClusterClient
+    /// Panics if the configuration doesn't validate, see try_new
     pub fn new(config: ClusterClientConfig) -> Self {
-        Self { config }
+        Self::try_new(config).unwrap()
+    }
+
+    /// Creates new ClusterClient, failing if the configuration doesn't validate
+    pub fn try_new(config: ClusterClientConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        if let Some(len) = config.hexdump_len {
+            set_hexdump_len(len);
+        }
+        let (events_tx, events_rx) = mpsc::channel(config.event_queue_len);
+        let executor = Executor::new(config.max_tasks, config.task_timeout);
+
+        Ok(Self {
+            events_tx,
+            events_rx: sync::Mutex::new(events_rx),
+            dropped_events: AtomicU64::new(0),
+            config,
+            breaker_state: Mutex::new(BreakerState::Closed),
+            assignment: Mutex::new(None),
+            coordinator: Mutex::new(None),
+            perf_score: Arc::new(Mutex::new(0)),
+            executor,
+            #[cfg(feature = "stats")]
+            conn_stats: Mutex::new(None),
+        })
+    }
+
+    /// Registers the handler of a kind of task
+    /// Tasks of kinds without a handler are failed, so the coordinator retries them elsewhere
+    pub fn register_handler<F, Fut>(&mut self, kind: impl Into<String>, handler: F)
+    where
+        F: Fn(Vec<u8>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Vec<u8>, String>> + Send + 'static,
+    {
+        self.executor.register(kind, handler);
+    }
+
+    /// Returns the message statistics of the current (or last) connection
+    #[cfg(feature = "stats")]
+    pub fn conn_stats(&self) -> Option<Arc<ConnStats>> {
+        self.conn_stats.lock().unwrap().clone()
+    }
+
+    /// Returns the assignment received from the coordinator on the current (or last) connection
+    pub fn assignment(&self) -> Option<WorkerAssignment> {
+        self.assignment.lock().unwrap().clone()
+    }
+
+    /// Returns the score of the last benchmark, 0 if it didn't run
+    pub fn perf_score(&self) -> u32 {
+        *self.perf_score.lock().unwrap()
+    }
+
+    /// Waits for the next cluster event
+    /// Events are dropped if they aren't read, the connection never waits for the reader
+    pub async fn next_event(&self) -> ClusterEvent {
+        self.events_rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .expect("event sender is owned by the client")
+    }
+
+    /// Returns the number of cluster events dropped because nobody read them
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+
+    /// Returns the coordinator the client is currently connected to
+    pub fn coordinator(&self) -> Option<Endpoint> {
+        self.coordinator.lock().unwrap().clone()
+    }
+
+    /// Returns the state of the reconnection circuit breaker
+    pub fn breaker_state(&self) -> BreakerState {
+        *self.breaker_state.lock().unwrap()
+    }
+
+    /// Runs the client until the shutdown future resolves
+    /// The connection is closed and pending retries are cancelled on shutdown
+    pub async fn run_until(&self, shutdown: impl Future<Output = ()>) -> Result<(), ConnectError> {
+        tokio::select! {
+            res = self.run() => res,
+            _ = shutdown => {
+                info!("Shutting down");
+                systemd::notify_status("Shutting down");
+                Ok(())
+            }
+        }
     }
 
     /// Run Client
-    pub async fn run(&self) {
-        let mut key_validator = ServerPublicKeyValidator::new(self.config.bypass_pk_check);
-        let mut retry_timer =
-            DoublingTimer::new(5, Duration::from_secs(1), Duration::from_secs(30));
+    /// Only returns on fatal errors, which can't be fixed by reconnecting
+    /// Coordinators are tried in order, the client waits to retry only after all of them failed
+    pub async fn run(&self) -> Result<(), ConnectError> {
+        let addrs: Vec<&Endpoint> = self.config.coord_addrs().collect();
+        // Each coordinator has its own trusted key
+        let mut key_validators = addrs
+            .iter()
+            .map(|addr| self.key_validator(addr))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut options = ChannelOptions::default();
+        if let Some(metrics) = &self.config.metrics {
+            options = options.metrics(metrics.clone());
+        }
+        if let Some(path) = &self.config.identity {
+            let identity = ClientIdentity::load(path).map_err(|e| {
+                error!("Unable to read identity {}: {}", path.display(), e);
+                ConnectError::new(FailureClass::Fatal, e)
+            })?;
+            if let Ok(fingerprint) = identity.fingerprint() {
+                info!("Using identity {}", fingerprint);
+            }
+            options = options.identity(Arc::new(identity));
+        }
+        let mut breaker = CircuitBreaker::new(
+            &self.config.reconnect_timer,
+            self.config.breaker_threshold,
+            self.config.breaker_cooldown,
+        );
+        let watchdog = Watchdog::from_env();
+        let mut ready = false;
 
-        loop {
-            debug!("Attempting connection to {}", self.config.coord_addr);
-            match self.connect_to_cluster(&mut key_validator).await {
+        // Measure performance once, so the coordinator learns it during onboarding
+        if !self.config.benchmark_duration.is_zero() {
+            let score = self.benchmark().await;
+            info!("Benchmark score: {}", score);
+        }
+
+        // Open trace file if requested
+        let tracer = match &self.config.trace_path {
+            Some(path) => match Tracer::create(path).await {
+                Ok(tracer) => Some(tracer),
                 Err(e) => {
-                    let delay = retry_timer.next();
-                    error!(
-                        "Error connecting to cluster: {}. Retrying in {}s",
-                        e,
-                        delay.as_secs()
+                    error!("Unable to create trace file {}: {}", path.display(), e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        // Index of the coordinator to try next, and of the first one tried in this round
+        let mut current = 0;
+        let mut round_start = 0;
+        loop {
+            let addr = addrs[current];
+            debug!("Attempting connection to {}", addr);
+            match watchdog
+                .guard(self.connect_to_cluster(
+                    addr,
+                    &mut key_validators[current],
+                    &options,
+                    tracer.clone(),
+                ))
+                .await
+            {
+                Err(e) if e.class == FailureClass::Fatal => {
+                    error!("Fatal error connecting to cluster: {}", e);
+                    systemd::notify_status(&format!("Failed: {}", e));
+                    self.report_failure(&e);
+                    return Err(e);
+                }
+                Err(e) if (current + 1) % addrs.len() != round_start => {
+                    current = (current + 1) % addrs.len();
+                    warn!(
+                        "Error connecting to {}: {}. Trying {}",
+                        addr, e, addrs[current]
                     );
-                    time::sleep(delay).await;
+                    self.report_failure(&e);
                 }
-                Ok((sender, mut receiver)) => {
-                    info!("Connected!");
-                    retry_timer.reset();
-                    loop {
-                        let msg = match receiver.recv().await {
-                            Ok(msg) => msg,
-                            Err(e) => {
-                                error!("Connection terminated: {}", e);
-                                break;
-                            }
-                        };
-
-                        println!("Received message: {}", String::from_utf8_lossy(&msg));
+                Err(e) => {
+                    // Every coordinator failed, start the next round from the first one
+                    current = 0;
+                    round_start = 0;
+                    let delay = breaker.on_failure(e.class);
+                    self.set_breaker_state(breaker.state());
+                    match breaker.state() {
+                        BreakerState::Open { .. } => warn!(
+                            "Error connecting to cluster: {}. Too many failures, pausing for {}s",
+                            e,
+                            delay.as_secs()
+                        ),
+                        _ => error!(
+                            "Error connecting to cluster: {}. Retrying in {}s",
+                            e,
+                            delay.as_secs()
+                        ),
                     }
-                    // Do clustery stuff
+                    systemd::notify_status(&format!("Disconnected: {}", e));
+                    self.report_failure(&e);
+                    self.emit(ClusterEvent::RetryScheduled { delay });
+                    watchdog.guard(time::sleep(delay)).await;
+
+                    breaker.on_retry();
+                    self.set_breaker_state(breaker.state());
+                }
+                Ok((sender, receiver)) => {
+                    info!("Connected to {}", addr);
+                    breaker.on_success();
+                    self.set_breaker_state(breaker.state());
+                    *self.coordinator.lock().unwrap() = Some(addr.clone());
+
+                    // Notify systemd once the first connection is enstablished
+                    systemd::notify_status(&format!("Connected to {}", addr));
+                    self.emit(ClusterEvent::Connected {
+                        coordinator: addr.clone(),
+                    });
+                    if let Some(metrics) = &self.config.metrics {
+                        metrics.connected();
+                    }
+                    if !ready {
+                        systemd::notify_ready();
+                        ready = true;
+                    }
+
+                    let e = self.serve(sender, receiver, &watchdog).await;
+                    error!("Connection to {} terminated: {}", addr, e);
+                    *self.coordinator.lock().unwrap() = None;
+                    self.emit(ClusterEvent::Disconnected {
+                        reason: e.to_string(),
+                    });
+                    if let Some(metrics) = &self.config.metrics {
+                        metrics.disconnected();
+                    }
+
+                    // Try to reconnect to the same coordinator first
+                    round_start = current;
                 }
             }
         }
     }
 
+    /// Handle an enstablished connection until it fails
+    /// The watchdog is pinged as messages are handled, the coordinator's heartbeats
+    /// keep an idle connection handling messages
+    async fn serve(
+        &self,
+        mut sender: TypedMsgSender<ClientMessage, impl AsyncMsgSend>,
+        mut receiver: TypedMsgReceiver<CoordinatorMessage, impl AsyncMsgRecv>,
+        watchdog: &Watchdog,
+    ) -> io::Error {
+        let heartbeat = self.config.heartbeat;
+        let (out_tx, mut out_rx) = mpsc::channel(OUT_QUEUE_LEN);
+
+        // Send queued messages, or a heartbeat if nothing was queued for an interval
+        let send_loop = async {
+            loop {
+                let msg = match time::timeout(heartbeat.interval, out_rx.recv()).await {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => unreachable!("outgoing queue sender is owned by serve"),
+                    Err(_) => ClientMessage::Heartbeat,
+                };
+
+                if let Err(e) = sender.send(&msg).await {
+                    return e;
+                }
+            }
+        };
+
+        let recv_loop = async {
+            // Dropped with the connection, which aborts the running tasks
+            let mut running = JoinSet::new();
+            loop {
+                while running.try_join_next().is_some() {}
+
+                match recv_timeout(heartbeat.timeout, receiver.recv()).await {
+                    Ok(CoordinatorMessage::Heartbeat) => {}
+                    Ok(CoordinatorMessage::Data(data)) => {
+                        self.emit(ClusterEvent::MessageReceived(data));
+                    }
+                    Ok(CoordinatorMessage::Task(task)) => {
+                        let task_id = task.task_id;
+                        if !self.executor.has_handler(&task.kind) {
+                            warn!(task = task_id; "No handler for task {} of kind {}", task_id, task.kind);
+                        }
+
+                        let run = self.executor.run(task);
+                        let out_tx = out_tx.clone();
+                        let max_result_len = self.config.max_result_len.max(1);
+                        running.spawn(async move {
+                            let outcome = match run.await {
+                                // Too large to send inline, the coordinator stores it as an artifact
+                                TaskOutcome::Success(result) if result.len() > max_result_len => {
+                                    for data in result.chunks(max_result_len) {
+                                        let chunk = ClientMessage::ResultChunk {
+                                            task_id,
+                                            data: data.to_vec(),
+                                        };
+                                        if out_tx.send(chunk).await.is_err() {
+                                            return;
+                                        }
+                                    }
+                                    TaskOutcome::Artifact(ArtifactRef::of(&result))
+                                }
+                                outcome => outcome,
+                            };
+                            debug!(task = task_id; "Task {} done", task_id);
+                            let _ = out_tx
+                                .send(ClientMessage::TaskResult { task_id, outcome })
+                                .await;
+                        });
+                    }
+                    Ok(CoordinatorMessage::Benchmark) => {
+                        // Measured beside the loop, heartbeats keep flowing meanwhile
+                        let benchmark = self.benchmark();
+                        let out_tx = out_tx.clone();
+                        running.spawn(async move {
+                            let score = benchmark.await;
+                            debug!("Benchmark requested, score: {}", score);
+                            let _ = out_tx.send(ClientMessage::BenchmarkResult { score }).await;
+                        });
+                    }
+                    Ok(msg) => warn!("Ignoring unexpected message {:?}", msg),
+                    Err(e) => return e,
+                }
+                watchdog.progress();
+            }
+        };
+
+        tokio::select! {
+            e = send_loop => e,
+            e = recv_loop => e,
+        }
+    }
+
+    /// Returns the validator of a coordinator's public key
+    fn key_validator(&self, addr: &Endpoint) -> Result<ServerPublicKeyValidator, ConnectError> {
+        match &self.config.known_hosts {
+            Some(path) => ServerPublicKeyValidator::from_file(
+                path,
+                addr.to_string(),
+                self.config.bypass_pk_check,
+            )
+            .map_err(|e| {
+                error!("Unable to read known hosts file {}: {}", path.display(), e);
+                ConnectError::new(FailureClass::Fatal, e)
+            }),
+            None => Ok(ServerPublicKeyValidator::new(self.config.bypass_pk_check)),
+        }
+    }
+
     /// Connect to Cluster Controller and do Onboarding
     async fn connect_to_cluster(
         &self,
+        addr: &Endpoint,
         key_validator: &mut ServerPublicKeyValidator,
-    ) -> io::Result<(impl AsyncMsgSend, impl AsyncMsgRecv)> {
-        // Connect to server
-        let socket = TcpStream::connect(self.config.coord_addr).await?;
-        let (reader, writer) = socket.into_split();
-        let sender = LenU64EncapsMsgSender::new(writer);
-        let receiver = LenU64EncapsMsgReceiver::new(reader);
+        options: &ChannelOptions,
+        tracer: Option<Tracer>,
+    ) -> Result<
+        (
+            TypedMsgSender<ClientMessage, impl AsyncMsgSend>,
+            TypedMsgReceiver<CoordinatorMessage, impl AsyncMsgRecv>,
+        ),
+        ConnectError,
+    > {
+        // Connect to server, through the proxy if configured
+        let connect = proxy::connect(self.config.proxy.as_ref(), addr);
+        let (reader, writer) = time::timeout(CONNECT_TIMEOUT, connect)
+            .await
+            .map_err(|_| {
+                ConnectError::new(
+                    FailureClass::Unreachable,
+                    io::Error::new(io::ErrorKind::TimedOut, "connection timed out"),
+                )
+            })?
+            .map_err(|e| match (ProxyError::from_io(&e), e.kind()) {
+                (Some(proxy_err), _) if proxy_err.is_auth() => {
+                    error!("Proxy rejected the connection: {}", proxy_err);
+                    ConnectError::new(FailureClass::Fatal, e)
+                }
+                (_, io::ErrorKind::ConnectionRefused) => {
+                    ConnectError::new(FailureClass::Refused, e)
+                }
+                _ => ConnectError::new(FailureClass::Unreachable, e),
+            })?;
 
         // Setup encrypted channel
-        let (sender, receiver) = client_setup_encrypted_channel(
-            sender,
-            receiver,
-            Duration::from_millis(1000),
-            key_validator,
-        )
-        .await?;
+        #[cfg(not(feature = "tls"))]
+        let (sender, receiver) = custom_channel(reader, writer, key_validator, options).await?;
+        #[cfg(feature = "tls")]
+        let (sender, receiver) = match &self.config.security {
+            ChannelSecurity::Custom => {
+                let (sender, receiver) =
+                    custom_channel(reader, writer, key_validator, options).await?;
+                (Either::Left(sender), Either::Left(receiver))
+            }
+            ChannelSecurity::Tls { .. } => {
+                let (sender, receiver) = self.tls_channel(addr, reader, writer, options).await?;
+                (Either::Right(sender), Either::Right(receiver))
+            }
+        };
+
+        // Record decrypted traffic if tracing is enabled
+        let sender = TracingMsgSender::new(sender, tracer.clone());
+        let receiver = TracingMsgReceiver::new(receiver, tracer);
+
+        // Record message statistics for this connection
+        #[cfg(feature = "stats")]
+        let (sender, receiver) = {
+            let stats = Arc::new(ConnStats::default());
+            *self.conn_stats.lock().unwrap() = Some(stats.clone());
+            (
+                StatsMsgSender::new(sender, stats.clone()),
+                StatsMsgReceiver::new(receiver, stats),
+            )
+        };
+
+        let mut sender = TypedMsgSender::new(sender);
+        let mut receiver = TypedMsgReceiver::new(receiver).offload(options.offload.clone());
+
+        // Introduce ourselves and wait for the coordinator's decision
+        let assignment = self
+            .onboard(&mut sender, &mut receiver, options.handshake_timeout)
+            .await
+            .map_err(|e| match OnboardingReject::from_io(&e) {
+                Some(
+                    OnboardingReject::VersionMismatch { .. }
+                    | OnboardingReject::Unauthorized
+                    | OnboardingReject::ClusterMismatch,
+                ) => ConnectError::new(FailureClass::Fatal, e),
+                Some(OnboardingReject::PendingApproval) => {
+                    info!("Waiting for an operator to approve this worker");
+                    ConnectError::new(FailureClass::Handshake, e)
+                }
+                _ => ConnectError::new(FailureClass::Handshake, e),
+            })?;
+        debug!("Onboarded with assignment {:?}", assignment);
+        *self.assignment.lock().unwrap() = Some(assignment);
 
         Ok((sender, receiver))
     }
+
+    /// Sends the worker introduction and waits for the coordinator to accept it
+    async fn onboard(
+        &self,
+        sender: &mut TypedMsgSender<ClientMessage, impl AsyncMsgSend>,
+        receiver: &mut TypedMsgReceiver<CoordinatorMessage, impl AsyncMsgRecv>,
+        timeout: Duration,
+    ) -> io::Result<WorkerAssignment> {
+        sender.send_version(PROTOCOL_VERSION).await?;
+        sender.send(&ClientMessage::Hello(self.hello())).await?;
+
+        match time::timeout(timeout, receiver.recv()).await?? {
+            // Don't rely on the coordinator to check the cluster name
+            CoordinatorMessage::Welcome(assignment)
+                if self.config.cluster.is_some() && assignment.cluster != self.config.cluster =>
+            {
+                Err(OnboardingReject::ClusterMismatch.into())
+            }
+            CoordinatorMessage::Welcome(assignment) => Ok(assignment),
+            CoordinatorMessage::Rejected(reason) => Err(reason.into()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected onboarding reply",
+            )),
+        }
+    }
+
+    /// Builds the introduction sent to the coordinator
+    fn hello(&self) -> WorkerHello {
+        let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+
+        WorkerHello {
+            worker_id: self.config.worker_id.clone().unwrap_or(hostname.clone()),
+            hostname,
+            cpus: thread::available_parallelism().map_or(1, |n| n.get() as u32),
+            tags: self.config.tags.clone(),
+            perf_score: self.perf_score(),
+            cluster: self.config.cluster.clone(),
+            zone: self.config.zone.clone(),
+        }
+    }
+
+    /// Starts the benchmark on a blocking thread, which stores its score
+    /// The returned future doesn't borrow the client, so it can run beside the connection
+    fn benchmark(&self) -> impl Future<Output = u32> + Send + 'static {
+        let duration = self.config.benchmark_duration;
+        let perf_score = self.perf_score.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            let score = run_benchmark(duration);
+            *perf_score.lock().unwrap() = score;
+            score
+        });
+        async move { handle.await.expect("benchmark panicked") }
+    }
+
+    /// Sets up a TLS channel to the coordinator
+    #[cfg(feature = "tls")]
+    async fn tls_channel<R, W>(
+        &self,
+        addr: &Endpoint,
+        reader: R,
+        writer: W,
+        options: &ChannelOptions,
+    ) -> Result<TlsChannel<client::TlsStream<Join<R, W>>>, ConnectError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let ChannelSecurity::Tls {
+            root_store,
+            client_cert,
+            server_name,
+        } = &self.config.security
+        else {
+            unreachable!("TLS channel requested without TLS settings");
+        };
+
+        // Configuration problems can't be fixed by reconnecting
+        let fatal = |e| ConnectError::new(FailureClass::Fatal, e);
+        let config = tls::client_config(root_store.clone(), client_cert.clone()).map_err(fatal)?;
+        let server_name = match (server_name, addr) {
+            (Some(name), _) => ServerName::try_from(name.clone())
+                .map_err(|e| fatal(io::Error::new(io::ErrorKind::InvalidInput, e)))?,
+            (None, Endpoint::Tcp(addr)) => tls::ip_server_name(addr.ip()),
+            #[cfg(unix)]
+            (None, Endpoint::Unix(_)) => {
+                return Err(fatal(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "TLS over a Unix socket requires a server name",
+                )))
+            }
+        };
+
+        tls::client_tls_channel(reader, writer, config, server_name, options)
+            .await
+            .map_err(|e| ConnectError::new(FailureClass::Handshake, e))
+    }
+
+    /// Queues an event, dropping it if nobody reads the events
+    fn emit(&self, event: ClusterEvent) {
+        if let Err(TrySendError::Full(event)) = self.events_tx.try_send(event) {
+            self.dropped_events.fetch_add(1, Ordering::Relaxed);
+            debug!("Event queue full, dropping {:?}", event);
+        }
+    }
+
+    /// Reports a failed connection attempt to the event reader and metrics
+    fn report_failure(&self, e: &ConnectError) {
+        if let Some(metrics) = &self.config.metrics {
+            metrics.connect_failed(e.class);
+        }
+        if e.class == FailureClass::Handshake {
+            self.emit(ClusterEvent::HandshakeFailed {
+                reason: e.err.to_string(),
+            });
+        }
+    }
+
+    fn set_breaker_state(&self, state: BreakerState) {
+        *self.breaker_state.lock().unwrap() = state;
+    }
+}
+
+/// Sets up the custom encrypted channel to the coordinator
+async fn custom_channel<R, W>(
+    reader: R,
+    writer: W,
+    key_validator: &mut ServerPublicKeyValidator,
+    options: &ChannelOptions,
+) -> Result<(ChannelSender<W>, ChannelReceiver<R>), ConnectError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    client_channel(reader, writer, key_validator, options)
+        .await
+        .map_err(|e| match ServerPublicKeyChanged::from_io(&e) {
+            Some(_) => ConnectError::new(FailureClass::Fatal, e),
+            None => ConnectError::new(FailureClass::Handshake, e),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        comm::testutil::test_keypair,
+        config::ClusterCoordinatorConfig,
+        coordinator::{
+            jobs::{JobSpec, TaskOutput},
+            ClusterCoordinator,
+        },
+    };
+
+    #[tokio::test]
+    async fn client_run_until() {
+        // Nothing listens there, so the client waits to retry when shut down
+        let config = ClusterClientConfig::new("127.0.0.1:1").benchmark_duration(Duration::ZERO);
+        let client = ClusterClient::new(config);
+
+        let res = time::timeout(
+            Duration::from_secs(5),
+            client.run_until(time::sleep(Duration::from_millis(100))),
+        )
+        .await
+        .expect("client didn't stop");
+        res.unwrap();
+        assert!(matches!(
+            client.next_event().await,
+            ClusterEvent::RetryScheduled { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn client_failover() {
+        let coord =
+            ClusterCoordinator::bind(ClusterCoordinatorConfig::new("127.0.0.1:0"), test_keypair())
+                .await
+                .unwrap();
+        let coord_addr = coord.local_addr().unwrap();
+
+        // The first coordinator is down, the client moves on without waiting
+        let config = ClusterClientConfig::new("127.0.0.1:1")
+            .fallback_addr(coord_addr)
+            .benchmark_duration(Duration::ZERO);
+        let client = ClusterClient::new(config);
+
+        let connected = async {
+            assert_eq!(
+                client.next_event().await,
+                ClusterEvent::Connected {
+                    coordinator: Endpoint::Tcp(coord_addr)
+                }
+            );
+            coord.broadcast(b"hello").await;
+            assert_eq!(
+                client.next_event().await,
+                ClusterEvent::MessageReceived(b"hello".to_vec())
+            );
+        };
+        let res = time::timeout(
+            Duration::from_secs(5),
+            client.run_until(async {
+                tokio::select! {
+                    _ = coord.run() => {}
+                    _ = connected => {}
+                }
+            }),
+        )
+        .await
+        .expect("client didn't fail over");
+        res.unwrap();
+        assert_eq!(client.coordinator(), Some(Endpoint::Tcp(coord_addr)));
+    }
+
+    #[tokio::test]
+    async fn client_executes_tasks() {
+        let coord =
+            ClusterCoordinator::bind(ClusterCoordinatorConfig::new("127.0.0.1:0"), test_keypair())
+                .await
+                .unwrap();
+        let config = ClusterClientConfig::new(coord.local_addr().unwrap())
+            .benchmark_duration(Duration::ZERO);
+        let mut client = ClusterClient::new(config);
+        client.register_handler("upper", |payload: Vec<u8>| async move {
+            match String::from_utf8(payload) {
+                Ok(text) => Ok(text.to_uppercase().into_bytes()),
+                Err(_) => Err("not UTF-8".to_string()),
+            }
+        });
+
+        let job = coord
+            .submit_job(
+                JobSpec::new("upper")
+                    .task(b"abc".to_vec())
+                    .task(vec![0xff])
+                    .max_attempts(1),
+            )
+            .unwrap();
+        let results = async {
+            tokio::select! {
+                _ = coord.run() => unreachable!(),
+                results = job.results() => results,
+            }
+        };
+        let results = tokio::select! {
+            res = client.run() => panic!("client stopped: {:?}", res.err()),
+            res = time::timeout(Duration::from_secs(5), results) => res.expect("tasks didn't run"),
+        };
+
+        assert_eq!(results[0], Ok(TaskOutput::Inline(b"ABC".to_vec())));
+        assert_eq!(results[1].as_ref().unwrap_err().error, "not UTF-8");
+    }
+
+    #[tokio::test]
+    async fn client_unread_events() {
+        let coord =
+            ClusterCoordinator::bind(ClusterCoordinatorConfig::new("127.0.0.1:0"), test_keypair())
+                .await
+                .unwrap();
+        let config = ClusterClientConfig::new(coord.local_addr().unwrap())
+            .event_queue_len(1)
+            .benchmark_duration(Duration::ZERO);
+        let mut client = ClusterClient::new(config);
+        client.register_handler("echo", |payload| async move { Ok(payload) });
+
+        // Nobody reads the client's events, tasks still run
+        let results = async {
+            while coord.workers().is_empty() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            for _ in 0..4 {
+                coord.broadcast(b"hello").await;
+            }
+            let job = coord
+                .submit_job(JobSpec::new("echo").task(*b"job"))
+                .unwrap();
+            job.results().await
+        };
+        let results = async {
+            tokio::select! {
+                _ = coord.run() => unreachable!(),
+                results = results => results,
+            }
+        };
+        let results = tokio::select! {
+            res = client.run() => panic!("client stopped: {:?}", res.err()),
+            res = time::timeout(Duration::from_secs(5), results) => res.expect("task didn't run"),
+        };
+
+        assert_eq!(results, [Ok(TaskOutput::Inline(b"job".to_vec()))]);
+        assert_eq!(client.dropped_events(), 4);
+    }
+
+    #[tokio::test]
+    async fn client_benchmark_beside_tasks() {
+        let coord =
+            ClusterCoordinator::bind(ClusterCoordinatorConfig::new("127.0.0.1:0"), test_keypair())
+                .await
+                .unwrap();
+        let bench = Duration::from_secs(2);
+        let config =
+            ClusterClientConfig::new(coord.local_addr().unwrap()).benchmark_duration(bench);
+        let mut client = ClusterClient::new(config);
+        client.register_handler("echo", |payload| async move { Ok(payload) });
+
+        // A task sent during a requested benchmark completes before the benchmark does
+        let elapsed = async {
+            while coord.workers().is_empty() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            let start = time::Instant::now();
+            coord
+                .request_benchmark(coord.workers()[0].id)
+                .await
+                .unwrap();
+            let job = coord
+                .submit_job(JobSpec::new("echo").task(*b"job"))
+                .unwrap();
+            assert_eq!(
+                job.results().await,
+                [Ok(TaskOutput::Inline(b"job".to_vec()))]
+            );
+            start.elapsed()
+        };
+        let elapsed = async {
+            tokio::select! {
+                _ = coord.run() => unreachable!(),
+                elapsed = elapsed => elapsed,
+            }
+        };
+        let elapsed = tokio::select! {
+            res = client.run() => panic!("client stopped: {:?}", res.err()),
+            res = time::timeout(Duration::from_secs(10), elapsed) => res.expect("task didn't run"),
+        };
+
+        assert!(
+            elapsed < bench / 2,
+            "task waited for the benchmark: {:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn client_result_artifacts() {
+        let dir = std::env::temp_dir().join(format!("pomegranate-results-{}", std::process::id()));
+        let coord_config = ClusterCoordinatorConfig::new("127.0.0.1:0").artifact_dir(&dir);
+        let coord = ClusterCoordinator::bind(coord_config, test_keypair())
+            .await
+            .unwrap();
+        let config = ClusterClientConfig::new(coord.local_addr().unwrap())
+            .benchmark_duration(Duration::ZERO)
+            .max_result_len(4);
+        let mut client = ClusterClient::new(config);
+        client.register_handler(
+            "repeat",
+            |payload: Vec<u8>| async move { Ok(payload.repeat(3)) },
+        );
+
+        let job = coord
+            .submit_job(JobSpec::new("repeat").task(*b"a").task(*b"abc"))
+            .unwrap();
+        let results = async {
+            tokio::select! {
+                _ = coord.run() => unreachable!(),
+                results = job.results() => results,
+            }
+        };
+        let results = tokio::select! {
+            res = client.run() => panic!("client stopped: {:?}", res.err()),
+            res = time::timeout(Duration::from_secs(5), results) => res.expect("tasks didn't run"),
+        };
+
+        // Results over the limit are sent in parts and stored by the coordinator
+        assert_eq!(results[0], Ok(TaskOutput::Inline(b"aaa".to_vec())));
+        let artifact = match &results[1] {
+            Ok(TaskOutput::Artifact(artifact)) => artifact,
+            res => panic!("unexpected result {:?}", res),
+        };
+        let store = coord.artifacts().unwrap();
+        assert_eq!(std::fs::read(store.path(artifact)).unwrap(), b"abcabcabc");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_cluster_name() {
+        let coord_config = ClusterCoordinatorConfig::new("127.0.0.1:0").cluster("prod");
+        let coord = ClusterCoordinator::bind(coord_config, test_keypair())
+            .await
+            .unwrap();
+        let addr = coord.local_addr().unwrap();
+
+        // Workers of another cluster give up instead of retrying
+        let config = ClusterClientConfig::new(addr)
+            .benchmark_duration(Duration::ZERO)
+            .cluster("staging");
+        let client = ClusterClient::new(config);
+        let err = tokio::select! {
+            _ = coord.run() => unreachable!(),
+            res = client.run() => res.unwrap_err(),
+        };
+        assert_eq!(err.class, FailureClass::Fatal);
+        assert_eq!(
+            OnboardingReject::from_io(&err.err),
+            Some(&OnboardingReject::ClusterMismatch)
+        );
+
+        let config = ClusterClientConfig::new(addr)
+            .benchmark_duration(Duration::ZERO)
+            .cluster("prod");
+        let client = ClusterClient::new(config);
+        tokio::select! {
+            _ = coord.run() => unreachable!(),
+            res = client.run() => panic!("client stopped: {:?}", res.err()),
+            _ = async {
+                while client.assignment().is_none() {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            } => {}
+        }
+        assert_eq!(
+            client.assignment().unwrap().cluster.as_deref(),
+            Some("prod")
+        );
+    }
 }
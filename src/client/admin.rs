@@ -0,0 +1,61 @@
+use tokio::{io, net::ToSocketAddrs, time};
+
+use crate::comm::{
+    channel::ChannelOptions,
+    connect_encrypted,
+    crypto::ServerPublicKeyValidator,
+    protocol::{
+        ClientMessage, ClusterStatus, CoordinatorMessage, TypedMsgReceiver, TypedMsgSender,
+        PROTOCOL_VERSION,
+    },
+};
+
+/// Asks a coordinator for the cluster status, without joining as a worker
+/// Requires an identity the coordinator authorizes, the coordinator closes the
+/// connection after replying
+pub async fn cluster_status(
+    addr: impl ToSocketAddrs,
+    key_validator: &mut ServerPublicKeyValidator,
+    options: &ChannelOptions,
+) -> io::Result<ClusterStatus> {
+    admin_request(addr, key_validator, options, ClientMessage::StatusRequest).await
+}
+
+/// Approves or rejects a worker waiting for approval, by its identity fingerprint
+/// Requires an identity the coordinator authorizes, returns the updated cluster status
+pub async fn decide_approval(
+    addr: impl ToSocketAddrs,
+    key_validator: &mut ServerPublicKeyValidator,
+    options: &ChannelOptions,
+    fingerprint: &str,
+    approve: bool,
+) -> io::Result<ClusterStatus> {
+    let request = ClientMessage::Approval {
+        fingerprint: fingerprint.to_string(),
+        approve,
+    };
+    admin_request(addr, key_validator, options, request).await
+}
+
+/// Sends a request to a coordinator and waits for the cluster status it replies with
+async fn admin_request(
+    addr: impl ToSocketAddrs,
+    key_validator: &mut ServerPublicKeyValidator,
+    options: &ChannelOptions,
+    request: ClientMessage,
+) -> io::Result<ClusterStatus> {
+    let (sender, receiver) = connect_encrypted(addr, key_validator, options).await?;
+    let mut sender = TypedMsgSender::new(sender);
+    let mut receiver = TypedMsgReceiver::new(receiver);
+
+    sender.send_version(PROTOCOL_VERSION).await?;
+    sender.send(&request).await?;
+    match time::timeout(options.handshake_timeout, receiver.recv()).await?? {
+        CoordinatorMessage::Status(status) => Ok(status),
+        CoordinatorMessage::Rejected(reason) => Err(reason.into()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected status reply",
+        )),
+    }
+}
@@ -0,0 +1,41 @@
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+/// Size of the blocks hashed by the benchmark
+const BLOCK_LEN: usize = 64 * 1024;
+
+/// Measures single core performance by hashing for the given duration
+/// The score is the SHA-256 throughput in MiB/s, comparable across workers
+/// Blocks the calling thread, returns 0 for a zero duration
+pub fn run_benchmark(duration: Duration) -> u32 {
+    let block = [0x5A; BLOCK_LEN];
+    let mut hasher = Sha256::new();
+    let mut hashed = 0u64;
+
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        hasher.update(black_box(&block));
+        hashed += BLOCK_LEN as u64;
+    }
+    black_box(hasher.finalize());
+
+    if hashed == 0 {
+        return 0;
+    }
+    (hashed as f64 / start.elapsed().as_secs_f64() / (1024.0 * 1024.0)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn benchmark_score() {
+        assert_eq!(run_benchmark(Duration::ZERO), 0);
+        assert!(run_benchmark(Duration::from_millis(20)) > 0);
+    }
+}
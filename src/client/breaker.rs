@@ -0,0 +1,202 @@
+use std::{fmt, time::Duration};
+
+use crate::comm::timer::{DoublingTimer, DoublingTimerBuilder};
+
+/// Class of failure of a connection attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureClass {
+    Refused,     // Coordinator host reachable but not accepting connections
+    Unreachable, // Network errors and timeouts while connecting
+    Handshake,   // Connection enstablished but encrypted channel setup failed
+    Fatal,       // Failure which requires operator action, never retried
+}
+
+impl fmt::Display for FailureClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureClass::Refused => write!(f, "connection refused"),
+            FailureClass::Unreachable => write!(f, "coordinator unreachable"),
+            FailureClass::Handshake => write!(f, "handshake failure"),
+            FailureClass::Fatal => write!(f, "fatal error"),
+        }
+    }
+}
+
+/// State of the reconnection circuit breaker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,                            // Retrying normally
+    Open { last_class: FailureClass }, // Retry budget exhausted, cooling down
+    HalfOpen,                          // Probing after cool down
+}
+
+/// Circuit breaker around connection attempts
+/// Each failure class backs off independently, and after too many consecutive
+/// failures the breaker opens, pausing attempts for a cool down period
+pub struct CircuitBreaker {
+    threshold: u32, // Consecutive failures before opening, 0 = never open
+    cooldown: Duration,
+    failures: u32,
+    state: BreakerState,
+    refused_timer: DoublingTimer,
+    unreachable_timer: DoublingTimer,
+    handshake_timer: DoublingTimer,
+}
+
+impl CircuitBreaker {
+    /// Constructs a new CircuitBreaker in the closed state
+    pub fn new(timer: &DoublingTimerBuilder, threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            failures: 0,
+            state: BreakerState::Closed,
+            refused_timer: timer.clone().build(),
+            unreachable_timer: timer.clone().build(),
+            // Handshake failures are rarely transient, so back off on every attempt
+            handshake_timer: timer.clone().flat(1).build(),
+        }
+    }
+
+    /// Returns the current breaker state
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    /// Records a failed attempt and returns the delay before the next one
+    pub fn on_failure(&mut self, class: FailureClass) -> Duration {
+        self.failures += 1;
+
+        let delay = match class {
+            FailureClass::Refused => self.refused_timer.try_next(),
+            FailureClass::Unreachable => self.unreachable_timer.try_next(),
+            FailureClass::Handshake | FailureClass::Fatal => self.handshake_timer.try_next(),
+        };
+
+        // Open breaker when the retry budget or the timer's deadline is exhausted,
+        // or the probe failed
+        let exhausted = delay.is_none() || (self.threshold != 0 && self.failures >= self.threshold);
+        let delay = delay.unwrap_or_default();
+        if exhausted || self.state == BreakerState::HalfOpen {
+            self.state = BreakerState::Open { last_class: class };
+            return self.cooldown.max(delay);
+        }
+
+        delay
+    }
+
+    /// Records that the delay returned by on_failure has elapsed
+    pub fn on_retry(&mut self) {
+        if let BreakerState::Open { .. } = self.state {
+            self.state = BreakerState::HalfOpen;
+        }
+    }
+
+    /// Records a successful attempt, resetting the breaker
+    pub fn on_success(&mut self) {
+        self.failures = 0;
+        self.state = BreakerState::Closed;
+        self.refused_timer.reset();
+        self.unreachable_timer.reset();
+        self.handshake_timer.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer() -> DoublingTimerBuilder {
+        DoublingTimerBuilder::default()
+            .flat(2)
+            .init_dur(Duration::from_secs(1))
+            .max_dur(Duration::from_secs(8))
+    }
+
+    #[test]
+    fn breaker_per_class_backoff() {
+        let mut breaker = CircuitBreaker::new(&timer(), 0, Duration::from_secs(60));
+
+        assert_eq!(
+            breaker.on_failure(FailureClass::Refused),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            breaker.on_failure(FailureClass::Handshake),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            breaker.on_failure(FailureClass::Handshake),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            breaker.on_failure(FailureClass::Refused),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            breaker.on_failure(FailureClass::Refused),
+            Duration::from_secs(2)
+        );
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn breaker_open_half_open() {
+        let mut breaker = CircuitBreaker::new(&timer(), 3, Duration::from_secs(60));
+
+        breaker.on_failure(FailureClass::Unreachable);
+        breaker.on_failure(FailureClass::Unreachable);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        // Budget exhausted
+        assert_eq!(
+            breaker.on_failure(FailureClass::Refused),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            breaker.state(),
+            BreakerState::Open {
+                last_class: FailureClass::Refused
+            }
+        );
+
+        // Failed probe opens the breaker again
+        breaker.on_retry();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert_eq!(
+            breaker.on_failure(FailureClass::Refused),
+            Duration::from_secs(60)
+        );
+
+        // Successful probe closes it
+        breaker.on_retry();
+        breaker.on_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(
+            breaker.on_failure(FailureClass::Refused),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn breaker_timer_deadline() {
+        let timer = timer().deadline(Duration::ZERO);
+        let mut breaker = CircuitBreaker::new(&timer, 0, Duration::from_secs(60));
+
+        // The first attempt starts the deadline, later ones find it passed
+        assert_eq!(
+            breaker.on_failure(FailureClass::Unreachable),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            breaker.on_failure(FailureClass::Unreachable),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            breaker.state(),
+            BreakerState::Open {
+                last_class: FailureClass::Unreachable
+            }
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use std::{collections::HashMap, fmt, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::Semaphore,
+    task::{self, JoinHandle},
+    time,
+};
+
+use crate::comm::protocol::{TaskAssignment, TaskOutcome};
+
+/// Future returned by a task handler, resolving to the result or an error description
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>, String>> + Send>>;
+
+/// Handler of one kind of task, called with the task payload
+type Handler = Arc<dyn Fn(Vec<u8>) -> HandlerFuture + Send + Sync>;
+
+/// Runs the tasks assigned to a worker with the handler registered for their kind
+/// Each task runs on its own tokio task, so a panicking handler only fails its task
+pub struct Executor {
+    handlers: HashMap<String, Handler>,
+    permits: Arc<Semaphore>, // Tasks running at once
+    timeout: Duration,       // Maximum duration of a task, zero = unlimited
+}
+
+impl Executor {
+    /// Runs up to max_tasks tasks at once, failing those taking longer than timeout
+    pub fn new(max_tasks: usize, timeout: Duration) -> Self {
+        Self {
+            handlers: HashMap::new(),
+            permits: Arc::new(Semaphore::new(max_tasks.max(1))),
+            timeout,
+        }
+    }
+
+    /// Registers the handler of a kind of task, replacing any previous one
+    pub fn register<F, Fut>(&mut self, kind: impl Into<String>, handler: F)
+    where
+        F: Fn(Vec<u8>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Vec<u8>, String>> + Send + 'static,
+    {
+        self.handlers.insert(
+            kind.into(),
+            Arc::new(move |payload| Box::pin(handler(payload))),
+        );
+    }
+
+    /// Returns true if a handler is registered for a kind of task
+    pub fn has_handler(&self, kind: &str) -> bool {
+        self.handlers.contains_key(kind)
+    }
+
+    /// Returns a future running a task to completion
+    /// Tasks wait for a free slot if max_tasks are already running. Tasks of an
+    /// unknown kind fail, so the coordinator can retry them on another worker
+    pub fn run(&self, task: TaskAssignment) -> impl Future<Output = TaskOutcome> + Send + 'static {
+        let handler = self.handlers.get(&task.kind).cloned();
+        let permits = self.permits.clone();
+        let timeout = self.timeout;
+
+        async move {
+            let Some(handler) = handler else {
+                return TaskOutcome::Failure(format!("no handler for task kind {:?}", task.kind));
+            };
+
+            let _permit = permits
+                .acquire()
+                .await
+                .expect("executor semaphore is never closed");
+            let mut handle = AbortOnDrop(task::spawn(handler(task.payload)));
+
+            let res = if timeout.is_zero() {
+                Ok((&mut handle.0).await)
+            } else {
+                time::timeout(timeout, &mut handle.0).await
+            };
+            match res {
+                Ok(Ok(Ok(result))) => TaskOutcome::Success(result),
+                Ok(Ok(Err(e))) => TaskOutcome::Failure(e),
+                Ok(Err(e)) if e.is_panic() => TaskOutcome::Failure("task panicked".to_string()),
+                Ok(Err(_)) => TaskOutcome::Failure("task cancelled".to_string()),
+                Err(_) => {
+                    TaskOutcome::Failure(format!("task timed out after {}ms", timeout.as_millis()))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Executor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Executor")
+            .field("kinds", &self.handlers.keys().collect::<Vec<_>>())
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+/// Aborts a spawned handler when its task is dropped, e.g. timed out or disconnected
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::task::JoinSet;
+
+    use super::*;
+
+    fn task(kind: &str, payload: &[u8]) -> TaskAssignment {
+        TaskAssignment {
+            task_id: 1,
+            kind: kind.to_string(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn executor_outcomes() {
+        let mut executor = Executor::new(4, Duration::from_secs(1));
+        executor.register("reverse", |mut payload: Vec<u8>| async move {
+            payload.reverse();
+            Ok(payload)
+        });
+        executor.register("fail", |_| async { Err("bad input".to_string()) });
+        executor.register("panic", |_| async { panic!("handler bug") });
+        executor.register("hang", |_| async {
+            time::sleep(Duration::from_secs(60)).await;
+            Ok(Vec::new())
+        });
+
+        assert_eq!(
+            executor.run(task("reverse", b"abc")).await,
+            TaskOutcome::Success(b"cba".to_vec())
+        );
+        assert_eq!(
+            executor.run(task("fail", b"")).await,
+            TaskOutcome::Failure("bad input".to_string())
+        );
+        assert_eq!(
+            executor.run(task("panic", b"")).await,
+            TaskOutcome::Failure("task panicked".to_string())
+        );
+        assert_eq!(
+            executor.run(task("hang", b"")).await,
+            TaskOutcome::Failure("task timed out after 1000ms".to_string())
+        );
+        assert!(!executor.has_handler("render"));
+        assert!(matches!(
+            executor.run(task("render", b"")).await,
+            TaskOutcome::Failure(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn executor_concurrency() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let mut executor = Executor::new(2, Duration::ZERO);
+        let (r, p) = (running.clone(), peak.clone());
+        executor.register("work", move |_| {
+            let (running, peak) = (r.clone(), p.clone());
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                time::sleep(Duration::from_millis(20)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(Vec::new())
+            }
+        });
+
+        let mut tasks = JoinSet::new();
+        for _ in 0..6 {
+            tasks.spawn(executor.run(task("work", b"")));
+        }
+        while let Some(outcome) = tasks.join_next().await {
+            assert_eq!(outcome.unwrap(), TaskOutcome::Success(Vec::new()));
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+}
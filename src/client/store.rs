@@ -0,0 +1,188 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::fs;
+
+/// Maximum key length, keys are hex encoded into file names
+pub const MAX_KEY_LEN: usize = 100;
+
+/// Counter making temporary file names unique within the process
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Worker-local persistent key-value store for task state
+/// Each namespace is a directory, each entry a file, so state survives
+/// task and worker restarts
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    /// Opens the store in a directory, creating it if needed
+    pub async fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).await?;
+        Ok(Self { dir })
+    }
+
+    /// Returns a namespace of the store
+    /// Names may only contain ASCII letters, digits, '-' and '_'
+    pub fn namespace(&self, name: &str) -> io::Result<Namespace> {
+        let valid = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid namespace name {:?}", name),
+            ));
+        }
+
+        Ok(Namespace {
+            dir: self.dir.join(name),
+        })
+    }
+}
+
+/// Namespace of a StateStore
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    dir: PathBuf,
+}
+
+impl Namespace {
+    /// Returns the value of a key, if present
+    pub async fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.entry_path(key)?).await {
+            Ok(val) => Ok(Some(val)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sets the value of a key
+    /// The value is written to a temporary file and renamed over the old one,
+    /// so readers never see partial values
+    pub async fn put(&self, key: &[u8], val: &[u8]) -> io::Result<()> {
+        let path = self.entry_path(key)?;
+        fs::create_dir_all(&self.dir).await?;
+
+        let tmp = self.dir.join(format!(
+            ".tmp-{}-{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, val).await?;
+        if let Err(e) = fs::rename(&tmp, &path).await {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Removes a key, returns true if it was present
+    pub async fn remove(&self, key: &[u8]) -> io::Result<bool> {
+        match fs::remove_file(self.entry_path(key)?).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns all keys in the namespace
+    pub async fn keys(&self) -> io::Result<Vec<Vec<u8>>> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut keys = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            // Skip temporary files and anything not written by us
+            if let Some(key) = entry.file_name().to_str().and_then(decode_key) {
+                keys.push(key);
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Returns the path of the file holding a key
+    fn entry_path(&self, key: &[u8]) -> io::Result<PathBuf> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("key longer than {} bytes", MAX_KEY_LEN),
+            ));
+        }
+
+        Ok(self.dir.join(encode_key(key)))
+    }
+}
+
+/// Encodes a key into a file name
+fn encode_key(key: &[u8]) -> String {
+    let mut name = String::from("k");
+    for b in key {
+        name.push_str(&format!("{:02x}", b));
+    }
+    name
+}
+
+/// Decodes a file name produced by encode_key
+fn decode_key(name: &str) -> Option<Vec<u8>> {
+    let hex = name.strip_prefix('k')?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn store_namespaces() {
+        let dir = env::temp_dir().join(format!("pomegranate-store-{}", std::process::id()));
+        let store = StateStore::open(&dir).await.unwrap();
+        let weights = store.namespace("weights").unwrap();
+        let index = store.namespace("index").unwrap();
+
+        assert_eq!(weights.get(b"model").await.unwrap(), None);
+        weights.put(b"model", b"v1").await.unwrap();
+        weights.put(b"model", b"v2").await.unwrap();
+        weights.put(b"\x00bin", b"").await.unwrap();
+        assert_eq!(weights.get(b"model").await.unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(index.get(b"model").await.unwrap(), None);
+        assert_eq!(
+            weights.keys().await.unwrap(),
+            [b"\x00bin".to_vec(), b"model".to_vec()]
+        );
+
+        // Survives reopening
+        let store = StateStore::open(&dir).await.unwrap();
+        let weights = store.namespace("weights").unwrap();
+        assert_eq!(weights.get(b"model").await.unwrap(), Some(b"v2".to_vec()));
+        assert!(weights.remove(b"model").await.unwrap());
+        assert!(!weights.remove(b"model").await.unwrap());
+
+        assert!(store.namespace("../escape").is_err());
+        assert!(weights.put(&[0; MAX_KEY_LEN + 1], b"").await.is_err());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
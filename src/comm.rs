@@ -1,3 +1,29 @@
+pub mod channel;
 pub mod crypto;
 pub mod encaps;
+pub mod error;
+pub mod faulty;
+pub mod flow;
+pub mod heartbeat;
+pub mod hexdump;
+pub mod keyfile;
+pub mod mux;
+pub mod offload;
+pub mod protocol;
+pub mod proxy;
+pub mod seq;
+#[cfg(feature = "stats")]
+pub mod stats;
+pub mod throttle;
 pub mod timer;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trace;
+pub mod transfer;
+pub mod transport;
+
+#[cfg(test)]
+pub(crate) mod testutil;
+
+pub use channel::{accept_encrypted, connect_encrypted};
+pub use error::CommError;
@@ -0,0 +1,532 @@
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream, ToSocketAddrs,
+    },
+};
+
+use super::{
+    crypto::{
+        client_setup_encrypted_channel, server_setup_encrypted_channel, AES256GCMMsgReceiver,
+        AES256GCMMsgSender, AuthorizedClients, ClientIdentity, KeyExchange, RekeyPolicy,
+        RsaKeyPair, ServerPublicKeyValidator,
+    },
+    encaps::{LenU64EncapsMsgReceiver, LenU64EncapsMsgSender, DEFAULT_MAX_MSG_LEN},
+    offload::Offload,
+    seq::{SeqMsgReceiver, SeqMsgSender},
+    throttle::HandshakeThrottle,
+};
+use crate::metrics::Metrics;
+
+/// Sending half of an encrypted channel over a byte stream
+/// Messages are numbered below the encryption, so repeated or dropped frames
+/// are detected before decrypting them
+pub type ChannelSender<W> = AES256GCMMsgSender<SeqMsgSender<LenU64EncapsMsgSender<W>>>;
+
+/// Receiving half of an encrypted channel over a byte stream
+pub type ChannelReceiver<R> = AES256GCMMsgReceiver<SeqMsgReceiver<LenU64EncapsMsgReceiver<R>>>;
+
+/// Channel halves as set up by the handshake, before adding sequence numbers
+type HandshakeResult<W, R> = io::Result<(
+    AES256GCMMsgSender<LenU64EncapsMsgSender<W>>,
+    AES256GCMMsgReceiver<LenU64EncapsMsgReceiver<R>>,
+)>;
+
+/// Options of an encrypted channel
+#[derive(Debug, Clone)]
+pub struct ChannelOptions {
+    pub handshake_timeout: Duration, // Maximum wait for each handshake message
+    pub max_msg_len: u64,            // Maximum length of a received message
+    pub throttle: Option<Arc<HandshakeThrottle>>, // Per-IP handshake failure limits (server)
+    pub key_exchange: KeyExchange,   // Key exchange offered to the server (client)
+    pub identity: Option<Arc<ClientIdentity>>, // Identity proven to the server (client)
+    pub authorized_clients: Option<Arc<AuthorizedClients>>, // Identities allowed to connect (server)
+    pub offload: Option<Offload>, // Decrypt and decode large messages on the blocking pool
+    pub metrics: Option<Arc<dyn Metrics>>, // Receiver of traffic and handshake metrics
+    pub rekey: Option<RekeyPolicy>, // When to switch the sending key, peers must support rekeying
+}
+
+impl Default for ChannelOptions {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Duration::from_millis(1000),
+            max_msg_len: DEFAULT_MAX_MSG_LEN,
+            throttle: None,
+            key_exchange: KeyExchange::default(),
+            identity: None,
+            authorized_clients: None,
+            offload: None,
+            metrics: None,
+            rekey: None,
+        }
+    }
+}
+
+impl ChannelOptions {
+    pub fn handshake_timeout(mut self, val: Duration) -> Self {
+        self.handshake_timeout = val;
+        self
+    }
+
+    pub fn max_msg_len(mut self, val: u64) -> Self {
+        self.max_msg_len = val;
+        self
+    }
+
+    pub fn throttle(mut self, val: Arc<HandshakeThrottle>) -> Self {
+        self.throttle = Some(val);
+        self
+    }
+
+    pub fn key_exchange(mut self, val: KeyExchange) -> Self {
+        self.key_exchange = val;
+        self
+    }
+
+    pub fn identity(mut self, val: Arc<ClientIdentity>) -> Self {
+        self.identity = Some(val);
+        self
+    }
+
+    pub fn authorized_clients(mut self, val: Arc<AuthorizedClients>) -> Self {
+        self.authorized_clients = Some(val);
+        self
+    }
+
+    pub fn offload(mut self, val: Offload) -> Self {
+        self.offload = Some(val);
+        self
+    }
+
+    pub fn metrics(mut self, val: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(val);
+        self
+    }
+
+    pub fn rekey(mut self, val: RekeyPolicy) -> Self {
+        self.rekey = Some(val);
+        self
+    }
+}
+
+/// Sets up framing and encryption over a byte stream on the client side
+pub async fn client_channel<R, W>(
+    reader: R,
+    writer: W,
+    key_validator: &mut ServerPublicKeyValidator,
+    options: &ChannelOptions,
+) -> io::Result<(ChannelSender<W>, ChannelReceiver<R>)>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let (sender, receiver) = encapsulate(reader, writer, options);
+
+    let start = Instant::now();
+    let res = client_setup_encrypted_channel(
+        sender,
+        receiver,
+        options.handshake_timeout,
+        key_validator,
+        options.key_exchange,
+        options.identity.as_deref(),
+    )
+    .await;
+
+    finish_handshake(res, start, options)
+}
+
+/// Sets up framing and encryption over a byte stream on the server side
+pub async fn server_channel<R, W>(
+    reader: R,
+    writer: W,
+    keypair: &RsaKeyPair,
+    options: &ChannelOptions,
+) -> io::Result<(ChannelSender<W>, ChannelReceiver<R>)>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let (sender, receiver) = encapsulate(reader, writer, options);
+
+    let start = Instant::now();
+    let res = server_setup_encrypted_channel(
+        sender,
+        receiver,
+        keypair,
+        options.handshake_timeout,
+        options.authorized_clients.as_deref(),
+    )
+    .await;
+
+    finish_handshake(res, start, options)
+}
+
+/// Wraps a byte stream in length-and-message encapsulation
+fn encapsulate<R, W>(
+    reader: R,
+    writer: W,
+    options: &ChannelOptions,
+) -> (LenU64EncapsMsgSender<W>, LenU64EncapsMsgReceiver<R>)
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let sender = LenU64EncapsMsgSender::new(writer).metrics(options.metrics.clone());
+    let receiver = LenU64EncapsMsgReceiver::new(reader)
+        .max_len(options.max_msg_len)
+        .metrics(options.metrics.clone());
+    (sender, receiver)
+}
+
+/// Reports the outcome of a handshake and applies the options to the channel
+fn finish_handshake<R, W>(
+    res: HandshakeResult<W, R>,
+    start: Instant,
+    options: &ChannelOptions,
+) -> io::Result<(ChannelSender<W>, ChannelReceiver<R>)>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    if let Some(metrics) = &options.metrics {
+        match &res {
+            Ok(_) => metrics.handshake_completed(start.elapsed()),
+            Err(_) => metrics.handshake_failed(),
+        }
+    }
+
+    let (sender, receiver) = res?;
+    let mut sender = sender.map_inner(SeqMsgSender::new);
+    let mut receiver = receiver.map_inner(SeqMsgReceiver::new);
+    sender.set_rekey(options.rekey);
+    receiver.set_offload(options.offload.clone());
+    receiver.set_metrics(options.metrics.clone());
+    Ok((sender, receiver))
+}
+
+/// Encrypted channel over a TCP connection
+pub type TcpChannel = (
+    ChannelSender<OwnedWriteHalf>,
+    ChannelReceiver<OwnedReadHalf>,
+);
+
+/// Connects to a server and sets up an encrypted channel to it
+pub async fn connect_encrypted(
+    addr: impl ToSocketAddrs,
+    key_validator: &mut ServerPublicKeyValidator,
+    options: &ChannelOptions,
+) -> io::Result<TcpChannel> {
+    let socket = TcpStream::connect(addr).await?;
+    let (reader, writer) = socket.into_split();
+
+    client_channel(reader, writer, key_validator, options).await
+}
+
+/// Accepts a connection from a listener and sets up an encrypted channel to the client
+/// The handshake runs before returning, so servers handling many clients should
+/// accept and call server_channel from separate tasks instead
+/// If a throttle is configured, connections from banned addresses are dropped
+/// without a handshake
+pub async fn accept_encrypted(
+    listener: &TcpListener,
+    keypair: &RsaKeyPair,
+    options: &ChannelOptions,
+) -> io::Result<(TcpChannel, SocketAddr)> {
+    let (socket, addr) = loop {
+        let (socket, addr) = listener.accept().await?;
+        match &options.throttle {
+            Some(throttle) if throttle.is_banned(addr.ip()) => {
+                debug!("Dropping connection from banned address {}", addr);
+            }
+            _ => break (socket, addr),
+        }
+    };
+
+    let (reader, writer) = socket.into_split();
+    let res = server_channel(reader, writer, keypair, options).await;
+
+    // Keep track of handshake failures
+    if let Some(throttle) = &options.throttle {
+        match &res {
+            Ok(_) => throttle.record_success(addr.ip()),
+            Err(_) => {
+                if throttle.record_failure(addr.ip()) {
+                    warn!("Too many failed handshakes from {}, banning", addr.ip());
+                }
+            }
+        }
+    }
+
+    Ok((res?, addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::pkcs1::EncodeRsaPublicKey;
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::comm::{
+        crypto::{parse_handshake_status, HandshakeError, RejectReason},
+        encaps::{AsyncMsgRecv, AsyncMsgSend},
+        error::CommError,
+        seq::SequenceError,
+        testutil::test_keypair,
+    };
+    use crate::metrics::Counters;
+
+    #[tokio::test]
+    async fn channel_roundtrip() {
+        let keypair = test_keypair();
+
+        // The server accepts both key exchanges
+        for key_exchange in [KeyExchange::Rsa, KeyExchange::X25519] {
+            let (client, server) = duplex(1024);
+            let (client_reader, client_writer) = io::split(client);
+            let (server_reader, server_writer) = io::split(server);
+            // Messages are decrypted on the blocking pool
+            let options = ChannelOptions::default()
+                .key_exchange(key_exchange)
+                .offload(Offload::new(1, 1));
+
+            let mut key_validator = ServerPublicKeyValidator::new(false);
+            let (client, server) = tokio::join!(
+                client_channel(client_reader, client_writer, &mut key_validator, &options),
+                server_channel(server_reader, server_writer, &keypair, &options),
+            );
+            let (mut client_sender, mut client_receiver) = client.unwrap();
+            let (mut server_sender, mut server_receiver) = server.unwrap();
+
+            client_sender.send(b"hello").await.unwrap();
+            assert_eq!(server_receiver.recv().await.unwrap(), b"hello");
+            server_sender.send(b"world").await.unwrap();
+            assert_eq!(client_receiver.recv().await.unwrap(), b"world");
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_metrics() {
+        let keypair = test_keypair();
+        let counters = Arc::new(Counters::default());
+        let options = ChannelOptions::default().metrics(counters.clone());
+
+        let (client, server) = duplex(4096);
+        let (client_reader, client_writer) = io::split(client);
+        let (server_reader, server_writer) = io::split(server);
+        let mut key_validator = ServerPublicKeyValidator::new(false);
+        let (client, server) = tokio::join!(
+            client_channel(client_reader, client_writer, &mut key_validator, &options),
+            server_channel(server_reader, server_writer, &keypair, &options),
+        );
+        let (mut client_sender, _) = client.unwrap();
+        let (_, mut server_receiver) = server.unwrap();
+        let before = counters.snapshot();
+        assert_eq!(before.handshakes, 2);
+        assert_eq!(before.bytes_sent, before.bytes_received);
+
+        // Header, sequence number, message and AES-GCM-SIV tag
+        client_sender.send(b"hello").await.unwrap();
+        server_receiver.recv().await.unwrap();
+        let after = counters.snapshot();
+        assert_eq!(after.bytes_sent - before.bytes_sent, 8 + 8 + 5 + 16);
+        assert_eq!(after.bytes_received - before.bytes_received, 8 + 8 + 5 + 16);
+    }
+
+    #[tokio::test]
+    async fn channel_duplicate_frame() {
+        let keypair = test_keypair();
+        let (client_writer, relay_reader) = duplex(4096);
+        let (relay_writer, server_reader) = duplex(4096);
+        let (server_writer, client_reader) = duplex(4096);
+
+        // Relays the client's frames, repeating the first one after the handshake
+        let relay = tokio::spawn(async move {
+            let mut receiver = LenU64EncapsMsgReceiver::new(relay_reader);
+            let mut sender = LenU64EncapsMsgSender::new(relay_writer);
+            let hello = receiver.recv().await.unwrap();
+            sender.send(&hello).await.unwrap();
+            let frame = receiver.recv().await.unwrap();
+            sender.send(&frame).await.unwrap();
+            sender.send(&frame).await.unwrap();
+        });
+
+        let options = ChannelOptions::default();
+        let mut key_validator = ServerPublicKeyValidator::new(false);
+        let (client, server) = tokio::join!(
+            client_channel(client_reader, client_writer, &mut key_validator, &options),
+            server_channel(server_reader, server_writer, &keypair, &options),
+        );
+        let (mut client_sender, _client_receiver) = client.unwrap();
+        let (_server_sender, mut server_receiver) = server.unwrap();
+
+        client_sender.send(b"hello").await.unwrap();
+        assert_eq!(server_receiver.recv().await.unwrap(), b"hello");
+        let err = server_receiver.recv().await.unwrap_err();
+        assert!(matches!(
+            err,
+            CommError::Sequence(SequenceError::Duplicate {
+                expected: 1,
+                received: 0
+            })
+        ));
+        relay.await.unwrap();
+    }
+
+    /// Runs a handshake, returning the client and server results
+    async fn handshake(
+        keypair: &RsaKeyPair,
+        client_options: &ChannelOptions,
+        server_options: &ChannelOptions,
+    ) -> (io::Result<()>, io::Result<Option<String>>) {
+        let (client, server) = duplex(4096);
+        let (client_reader, client_writer) = io::split(client);
+        let (server_reader, server_writer) = io::split(server);
+
+        let mut key_validator = ServerPublicKeyValidator::new(false);
+        let (client, server) = tokio::join!(
+            client_channel(
+                client_reader,
+                client_writer,
+                &mut key_validator,
+                client_options
+            ),
+            server_channel(server_reader, server_writer, keypair, server_options),
+        );
+        // The server reports the identity proven by the client
+        let server = server.map(|(_, receiver)| receiver.peer_identity().map(str::to_string));
+        (client.map(|_| ()), server)
+    }
+
+    #[tokio::test]
+    async fn channel_client_identity() {
+        let keypair = test_keypair();
+        let known = Arc::new(ClientIdentity::new(test_keypair()));
+        let unknown = Arc::new(ClientIdentity::new(test_keypair()));
+
+        let mut authorized = AuthorizedClients::new();
+        authorized.insert(known.fingerprint().unwrap());
+        let server_options =
+            ChannelOptions::default().authorized_clients(Arc::new(authorized.clone()));
+
+        // Authorized identity
+        let options = ChannelOptions::default().identity(known.clone());
+        let (client, server) = handshake(&keypair, &options, &server_options).await;
+        client.unwrap();
+        assert_eq!(server.unwrap(), Some(known.fingerprint().unwrap()));
+
+        // Unknown identity, no identity, and RSA key transport which can't carry one
+        for options in [
+            ChannelOptions::default().identity(unknown.clone()),
+            ChannelOptions::default(),
+            ChannelOptions::default().key_exchange(KeyExchange::Rsa),
+        ] {
+            let (client, server) = handshake(&keypair, &options, &server_options).await;
+            assert_eq!(
+                HandshakeError::from_io(&client.unwrap_err()),
+                Some(&HandshakeError::Rejected(RejectReason::Unauthorized))
+            );
+            server.unwrap_err();
+        }
+
+        // Servers admitting unknown identities still require one
+        let server_options =
+            ChannelOptions::default().authorized_clients(Arc::new(authorized.admit_unknown(true)));
+        let options = ChannelOptions::default().identity(unknown.clone());
+        let (client, server) = handshake(&keypair, &options, &server_options).await;
+        client.unwrap();
+        assert_eq!(server.unwrap(), Some(unknown.fingerprint().unwrap()));
+        let (client, server) =
+            handshake(&keypair, &ChannelOptions::default(), &server_options).await;
+        client.unwrap_err();
+        server.unwrap_err();
+
+        // Servers without authorized clients accept identities too
+        let options = ChannelOptions::default().identity(known);
+        let (client, server) = handshake(&keypair, &options, &ChannelOptions::default()).await;
+        client.unwrap();
+        server.unwrap();
+    }
+
+    #[tokio::test]
+    async fn channel_x25519_bad_signature() {
+        let public = test_keypair().public;
+
+        let (client, server) = duplex(1024);
+        let (client_reader, client_writer) = io::split(client);
+        let (server_reader, server_writer) = io::split(server);
+        let mut server_sender = LenU64EncapsMsgSender::new(server_writer);
+        let mut server_receiver = LenU64EncapsMsgReceiver::new(server_reader);
+
+        // Impersonate the server without its private key
+        let server = tokio::spawn(async move {
+            let der = public.to_pkcs1_der().unwrap();
+            server_sender.send(der.as_bytes()).await.unwrap();
+            let hello = server_receiver.recv().await.unwrap();
+            assert_eq!(hello.len(), 33);
+
+            let mut reply = vec![0x00];
+            reply.extend_from_slice(&[0x09; 32]);
+            reply.extend_from_slice(&[0xAB; 128]);
+            server_sender.send(&reply).await.unwrap();
+        });
+
+        let mut key_validator = ServerPublicKeyValidator::new(false);
+        let err = client_channel(
+            client_reader,
+            client_writer,
+            &mut key_validator,
+            &ChannelOptions::default(),
+        )
+        .await
+        .map(|_| ())
+        .unwrap_err();
+        assert_eq!(
+            HandshakeError::from_io(&err),
+            Some(&HandshakeError::BadSignature)
+        );
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn channel_reject_malformed() {
+        let keypair = test_keypair();
+
+        let (client, server) = duplex(1024);
+        let (server_reader, server_writer) = io::split(server);
+        let (client_reader, client_writer) = io::split(client);
+        let mut client_sender = LenU64EncapsMsgSender::new(client_writer);
+        let mut client_receiver = LenU64EncapsMsgReceiver::new(client_reader);
+
+        let server = tokio::spawn(async move {
+            server_channel(
+                server_reader,
+                server_writer,
+                &keypair,
+                &ChannelOptions::default(),
+            )
+            .await
+            .map(|_| ())
+        });
+
+        // Receive public key and reply with garbage
+        client_receiver.recv().await.unwrap();
+        client_sender.send(b"garbage").await.unwrap();
+
+        let status = client_receiver.recv().await.unwrap();
+        let err = parse_handshake_status(&status).unwrap_err();
+        assert_eq!(
+            HandshakeError::from_io(&err),
+            Some(&HandshakeError::Rejected(RejectReason::Malformed))
+        );
+        server.await.unwrap().unwrap_err();
+    }
+}
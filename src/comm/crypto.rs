@@ -1,17 +1,38 @@
-use std::time::Duration;
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt, mem,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use aes_gcm_siv::{
-    aead::{generic_array::GenericArray, rand_core::RngCore, Aead, OsRng},
+    aead::{generic_array::GenericArray, rand_core::RngCore, Aead, AeadInPlace, OsRng},
     Aes256GcmSiv, KeyInit,
 };
+use bytes::BytesMut;
+use log::debug;
 use rkyv::{Archive, CheckBytes, Deserialize, Serialize};
 use rsa::{
-    pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey},
-    Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey,
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey, EncodeRsaPublicKey},
+    Pkcs1v15Encrypt, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey,
 };
+use sha2::{Digest, Sha256};
 use tokio::{io, time};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey};
 
-use super::encaps::{AsyncMsgRecv, AsyncMsgSend};
+use super::{
+    encaps::{AsyncMsgRecv, AsyncMsgSend},
+    error::CommError,
+    hexdump::{format_hexdump, hexdump_len},
+    offload::Offload,
+    protocol::spec::{
+        self, HANDSHAKE_ACCEPT, HANDSHAKE_REJECT, KEY_EXCHANGE_X25519, KEY_EXCHANGE_X25519_AUTH,
+        REJECT_MALFORMED, REJECT_TIMEOUT, REJECT_UNAUTHORIZED, TAG_LEN, X25519_HELLO_LEN,
+    },
+};
+use crate::metrics::Metrics;
 
 /// Initialization data for an AES256-GCM encrypted endpoint
 /// Contains the encryption key and initial nonce value
@@ -23,6 +44,11 @@ pub struct AES256GCMInitializer {
 }
 
 impl AES256GCMInitializer {
+    /// Constructs an initializer from a known key and initial nonce
+    pub fn new(key: [u8; 32], nonce: [u8; 12]) -> Self {
+        Self { key, nonce }
+    }
+
     /// Constructs a new encryption key and initial nonce pair from the OS RNG
     pub fn new_rand() -> Self {
         let mut key = [0u8; 32];
@@ -51,6 +77,76 @@ impl AES256GCMInitializerPair {
             stc: AES256GCMInitializer::new_rand(),
         }
     }
+
+    /// Derives both initializers from an X25519 shared secret, bound to the handshake transcript
+    fn derive(shared_secret: &[u8; 32], transcript: &[u8]) -> Self {
+        let okm = spec::channel_keys(shared_secret, transcript);
+        let initializer = |bytes: &[u8]| AES256GCMInitializer {
+            key: bytes[..32].try_into().unwrap(),
+            nonce: bytes[32..].try_into().unwrap(),
+        };
+        Self {
+            cts: initializer(&okm[..44]),
+            stc: initializer(&okm[44..]),
+        }
+    }
+}
+
+/// When the sending half of an encrypted channel switches to a fresh key
+/// Each new key is derived from the previous one, so receivers follow without
+/// any signalling. Receivers always accept rekeyed messages, but only enable
+/// rekeying once both peers support it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    pub max_messages: u64, // Rekey after this many messages, 0 = never
+    pub max_age: Duration, // Rekey after using a key for this long, zero = never
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            max_messages: 1 << 20,
+            max_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl RekeyPolicy {
+    pub fn max_messages(mut self, val: u64) -> Self {
+        self.max_messages = val;
+        self
+    }
+
+    pub fn max_age(mut self, val: Duration) -> Self {
+        self.max_age = val;
+        self
+    }
+}
+
+/// Key, cipher and nonce counter of one direction of an encrypted channel
+struct CipherState {
+    key: [u8; 32],
+    cipher: Aes256GcmSiv,
+    nonce: AESGCMNonceCounter,
+}
+
+impl CipherState {
+    fn new(init: &AES256GCMInitializer) -> Self {
+        Self {
+            key: init.key,
+            cipher: Aes256GcmSiv::new(&GenericArray::from(init.key)),
+            nonce: AESGCMNonceCounter::new(init.nonce),
+        }
+    }
+
+    /// Derives the state following a rekey, with a new key and starting nonce
+    fn next(&self) -> Self {
+        let okm = spec::rekey(&self.key);
+        Self::new(&AES256GCMInitializer {
+            key: okm[..32].try_into().unwrap(),
+            nonce: okm[32..].try_into().unwrap(),
+        })
+    }
 }
 
 /// Wrapper for an AsyncMsgSend object that provides AES256-GCM encryption
@@ -59,8 +155,11 @@ where
     S: AsyncMsgSend,
 {
     sender: S,
-    cipher: Aes256GcmSiv,
-    nonce: AESGCMNonceCounter,
+    state: CipherState,
+    seq: u64,
+    rekey: Option<RekeyPolicy>,
+    key_uses: u64,            // Messages sent with the current key
+    key_since: time::Instant, // When the current key was first used
 }
 
 impl<S> AES256GCMMsgSender<S>
@@ -71,24 +170,80 @@ where
     pub fn new(sender: S, init: &AES256GCMInitializer) -> Self {
         Self {
             sender,
-            cipher: Aes256GcmSiv::new(&GenericArray::from(init.key)),
-            nonce: AESGCMNonceCounter::new(init.nonce),
+            state: CipherState::new(init),
+            seq: 0,
+            rekey: None,
+            key_uses: 0,
+            key_since: time::Instant::now(),
         }
     }
+
+    /// Returns the underlying sender
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.sender
+    }
+
+    /// Wraps the underlying sender, keeping the encryption state
+    /// Used to add layers below the encryption once the handshake is done
+    pub fn map_inner<T: AsyncMsgSend>(self, f: impl FnOnce(S) -> T) -> AES256GCMMsgSender<T> {
+        AES256GCMMsgSender {
+            sender: f(self.sender),
+            state: self.state,
+            seq: self.seq,
+            rekey: self.rekey,
+            key_uses: self.key_uses,
+            key_since: self.key_since,
+        }
+    }
+
+    /// Switches to a fresh key according to a policy
+    pub fn set_rekey(&mut self, rekey: Option<RekeyPolicy>) {
+        self.rekey = rekey;
+    }
+
+    /// Returns true if the policy requires a fresh key for the next message
+    fn rekey_due(&self) -> bool {
+        let Some(policy) = self.rekey else {
+            return false;
+        };
+
+        (policy.max_messages > 0 && self.key_uses >= policy.max_messages)
+            || (!policy.max_age.is_zero() && self.key_since.elapsed() >= policy.max_age)
+    }
 }
 
 impl<W> AsyncMsgSend for AES256GCMMsgSender<W>
 where
     W: AsyncMsgSend,
 {
-    async fn send(&mut self, msg: &[u8]) -> io::Result<()> {
-        let nonce = self.nonce.next();
+    async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
+        if self.rekey_due() {
+            debug!("Rekeying after {} messages", self.key_uses);
+            self.state = self.state.next();
+            self.key_uses = 0;
+            self.key_since = time::Instant::now();
+        }
+        self.key_uses += 1;
+
+        let nonce = self.state.nonce.next();
+
+        // Dump plaintext if debugging
+        if let Some(max_len) = hexdump_len() {
+            debug!(
+                "send #{} ({} bytes): {}",
+                self.seq,
+                msg.len(),
+                format_hexdump(msg, max_len)
+            );
+        }
+        self.seq += 1;
 
         // Encrypt message
         let ciphertext = self
+            .state
             .cipher
             .encrypt(&GenericArray::from(nonce), msg)
-            .expect("encryption error");
+            .map_err(|_| CryptoError::Encryption)?;
 
         // Send message
         self.sender.send(&ciphertext).await
@@ -100,8 +255,11 @@ where
     R: AsyncMsgRecv,
 {
     receiver: R,
-    cipher: Aes256GcmSiv,
-    nonce: AESGCMNonceCounter,
+    state: CipherState,
+    seq: u64,
+    offload: Option<Offload>,
+    metrics: Option<Arc<dyn Metrics>>,
+    peer_identity: Option<String>, // Fingerprint of the identity proven by the client
 }
 
 impl<R> AES256GCMMsgReceiver<R>
@@ -112,26 +270,139 @@ where
     pub fn new(receiver: R, init: &AES256GCMInitializer) -> Self {
         Self {
             receiver,
-            cipher: Aes256GcmSiv::new(&GenericArray::from(init.key)),
-            nonce: AESGCMNonceCounter::new(init.nonce),
+            state: CipherState::new(init),
+            seq: 0,
+            offload: None,
+            metrics: None,
+            peer_identity: None,
         }
     }
+
+    /// Wraps the underlying receiver, keeping the encryption state
+    /// Used to add layers below the encryption once the handshake is done
+    pub fn map_inner<T: AsyncMsgRecv>(self, f: impl FnOnce(R) -> T) -> AES256GCMMsgReceiver<T> {
+        AES256GCMMsgReceiver {
+            receiver: f(self.receiver),
+            state: self.state,
+            seq: self.seq,
+            offload: self.offload,
+            metrics: self.metrics,
+            peer_identity: self.peer_identity,
+        }
+    }
+
+    /// Decrypts large messages on the blocking thread pool
+    pub fn set_offload(&mut self, offload: Option<Offload>) {
+        self.offload = offload;
+    }
+
+    /// Reports decryption failures to a Metrics implementation
+    pub fn set_metrics(&mut self, metrics: Option<Arc<dyn Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Returns the fingerprint of the identity the client proved during the
+    /// handshake, on the server side of a channel
+    pub fn peer_identity(&self) -> Option<&str> {
+        self.peer_identity.as_deref()
+    }
+}
+
+/// Decrypts a message in place with the current key, or with the next one if
+/// the sender rekeyed, leaving the plaintext in buf
+/// Returns the state to switch to in the latter case
+fn decrypt_or_rekey(
+    cipher: &Aes256GcmSiv,
+    nonce: [u8; 12],
+    key: [u8; 32],
+    buf: &mut BytesMut,
+) -> io::Result<Option<CipherState>> {
+    let Some(len) = buf.len().checked_sub(TAG_LEN) else {
+        return Err(CryptoError::Decryption.into());
+    };
+    let (data, tag) = buf.split_at_mut(len);
+    let tag = GenericArray::clone_from_slice(tag);
+
+    // A failed attempt leaves the ciphertext untouched, so the next key can be tried
+    let mut rekeyed = None;
+    if cipher
+        .decrypt_in_place_detached(&GenericArray::from(nonce), &[], data, &tag)
+        .is_err()
+    {
+        let mut next = CipherState {
+            key,
+            cipher: cipher.clone(),
+            nonce: AESGCMNonceCounter::new(nonce),
+        }
+        .next();
+        let nonce = next.nonce.next();
+        next.cipher
+            .decrypt_in_place_detached(&GenericArray::from(nonce), &[], data, &tag)
+            .map_err(|_| CryptoError::Decryption)?;
+        rekeyed = Some(next);
+    }
+
+    buf.truncate(len);
+    Ok(rekeyed)
 }
 
 impl<R> AsyncMsgRecv for AES256GCMMsgReceiver<R>
 where
     R: AsyncMsgRecv,
 {
-    async fn recv(&mut self) -> io::Result<Vec<u8>> {
+    async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+        let mut msg = BytesMut::new();
+        self.recv_into(&mut msg).await?;
+        Ok(msg.into())
+    }
+
+    /// Receives a message and decrypts it in place, without further allocations
+    async fn recv_into(&mut self, buf: &mut BytesMut) -> Result<(), CommError> {
         // Receive message from channel
-        let ciphertext = self.receiver.recv().await?;
+        self.receiver.recv_into(buf).await?;
 
-        let nonce = self.nonce.next();
+        let nonce = self.state.nonce.next();
+        let key = self.state.key;
 
         // Decrypt message
-        self.cipher
-            .decrypt(&GenericArray::from(nonce), ciphertext.as_ref())
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "decryption error"))
+        let res = match &self.offload {
+            Some(offload) => {
+                let cipher = self.state.cipher.clone();
+                let mut msg = mem::take(buf);
+                offload
+                    .run(msg.len(), move || {
+                        decrypt_or_rekey(&cipher, nonce, key, &mut msg).map(|res| (res, msg))
+                    })
+                    .await
+                    .map(|(res, msg)| {
+                        *buf = msg;
+                        res
+                    })
+            }
+            None => decrypt_or_rekey(&self.state.cipher, nonce, key, buf),
+        };
+        let rekeyed = res.inspect_err(|_| {
+            if let Some(metrics) = &self.metrics {
+                metrics.decryption_failed();
+            }
+        })?;
+        if let Some(state) = rekeyed {
+            debug!("Peer rekeyed at message #{}", self.seq);
+            self.state = state;
+        }
+
+        // Dump plaintext if debugging
+        if let Some(max_len) = hexdump_len() {
+            debug!(
+                "recv #{} ({} bytes): {}",
+                self.seq,
+                buf.len(),
+                format_hexdump(buf, max_len)
+            );
+        }
+        self.seq += 1;
+
+        Ok(())
     }
 }
 
@@ -149,7 +420,7 @@ impl AESGCMNonceCounter {
 
     /// Gets the next nonce in the counter
     fn next(&mut self) -> [u8; 12] {
-        let val = self.nonce.clone();
+        let val = self.nonce;
         inc_multibyte(&mut self.nonce);
         val
     }
@@ -176,8 +447,9 @@ pub struct RsaKeyPair {
 }
 
 impl RsaKeyPair {
-    pub fn generate() -> Result<Self, ()> {
-        let private = RsaPrivateKey::new(&mut OsRng, 2048).map_err(|_| ())?;
+    pub fn generate() -> Result<Self, CryptoError> {
+        let private =
+            RsaPrivateKey::new(&mut OsRng, 2048).map_err(|_| CryptoError::KeyGeneration)?;
         Ok(Self {
             public: RsaPublicKey::from(&private),
             private,
@@ -185,90 +457,629 @@ impl RsaKeyPair {
     }
 }
 
+/// Returns the SHA-256 fingerprint of a public key
+pub fn key_fingerprint(key: &RsaPublicKey) -> Result<String, CommError> {
+    let der = key.to_pkcs1_der().map_err(|_| CryptoError::KeyEncoding)?;
+    let digest = Sha256::digest(der.as_bytes());
+
+    let mut fingerprint = String::from("SHA256:");
+    for b in digest {
+        fingerprint.push_str(&format!("{:02x}", b));
+    }
+    Ok(fingerprint)
+}
+
+/// Error produced when the server presents a different key than the trusted one
+/// Carried inside an io::Error of kind InvalidData
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerPublicKeyChanged {
+    pub host: Option<String>, // Host the key is stored for, if persisted
+    pub stored: String,       // Fingerprint of the trusted key
+    pub received: String,     // Fingerprint of the presented key
+}
+
+impl ServerPublicKeyChanged {
+    /// Extracts a ServerPublicKeyChanged from an io::Error, if it carries one
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for ServerPublicKeyChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server public key changed")?;
+        if let Some(host) = &self.host {
+            write!(f, " for {}", host)?;
+        }
+        write!(f, ": trusted {}, received {}", self.stored, self.received)
+    }
+}
+
+impl Error for ServerPublicKeyChanged {}
+
+impl From<ServerPublicKeyChanged> for io::Error {
+    fn from(err: ServerPublicKeyChanged) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Known hosts file entry of a ServerPublicKeyValidator
+struct KnownHostsFile {
+    path: PathBuf,
+    host: String,
+}
+
 /// Storage for trusted server public keys
+/// Trusts the first key seen (TOFU), in memory or persisted to a known hosts file
 pub struct ServerPublicKeyValidator {
-    key: Option<RsaPublicKey>,
+    trusted: Option<String>, // Fingerprint of the trusted key
     bypass_check: bool,
+    file: Option<KnownHostsFile>,
 }
 
 impl ServerPublicKeyValidator {
     /// Constructs a new TrustedServerKeyStore
     pub fn new(bypass_check: bool) -> Self {
         Self {
-            key: None,
+            trusted: None,
             bypass_check,
+            file: None,
         }
     }
 
-    /// Check if key is trusted
-    pub fn validate(&mut self, key: &RsaPublicKey) -> Result<(), ()> {
-        if let Some(k) = &self.key {
-            if key == k || self.bypass_check {
+    /// Constructs a validator backed by a known hosts file
+    /// Each line holds a host and the fingerprint of its key, the file is
+    /// created when the first key is trusted
+    pub fn from_file(
+        path: impl Into<PathBuf>,
+        host: impl Into<String>,
+        bypass_check: bool,
+    ) -> io::Result<Self> {
+        let path = path.into();
+        let host = host.into();
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let trusted = parse_known_hosts(&contents)
+            .find(|(h, _)| *h == host)
+            .map(|(_, fingerprint)| fingerprint.to_string());
+
+        Ok(Self {
+            trusted,
+            bypass_check,
+            file: Some(KnownHostsFile { path, host }),
+        })
+    }
+
+    /// Check if key is trusted, trusting it if no key is known yet
+    pub fn validate(&mut self, key: &RsaPublicKey) -> io::Result<()> {
+        let fingerprint = key_fingerprint(key)?;
+
+        match &self.trusted {
+            Some(trusted) if *trusted == fingerprint || self.bypass_check => Ok(()),
+            Some(trusted) => Err(ServerPublicKeyChanged {
+                host: self.file.as_ref().map(|file| file.host.clone()),
+                stored: trusted.clone(),
+                received: fingerprint,
+            }
+            .into()),
+            None => {
+                // First connection, trust key
+                if let Some(file) = &self.file {
+                    append_known_host(&file.path, &file.host, &fingerprint)?;
+                    debug!("Trusted key {} for {}", fingerprint, file.host);
+                }
+                self.trusted = Some(fingerprint);
                 Ok(())
-            } else {
-                Err(())
             }
-        } else {
-            // First connection, trust key
-            self.key = Some(key.clone());
-            Ok(())
         }
     }
 }
 
+/// Parses the host and fingerprint pairs of a known hosts file
+fn parse_known_hosts(contents: &str) -> impl Iterator<Item = (&str, &str)> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .map(|(host, fingerprint)| (host, fingerprint.trim()))
+}
+
+/// Adds an entry to a known hosts file
+fn append_known_host(path: &Path, host: &str, fingerprint: &str) -> io::Result<()> {
+    append_line(path, &format!("{} {}", host, fingerprint))
+}
+
+/// Appends a line to a file, creating it if missing
+/// The new contents are written to a temporary file and renamed over the old
+/// one, so a crash never leaves a truncated file behind
+fn append_line(path: &Path, line: &str) -> io::Result<()> {
+    let mut contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(line);
+    contents.push('\n');
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Identity a client proves to the server during the handshake
+pub struct ClientIdentity {
+    keypair: RsaKeyPair,
+}
+
+impl ClientIdentity {
+    pub fn new(keypair: RsaKeyPair) -> Self {
+        Self { keypair }
+    }
+
+    /// Loads the identity from a PKCS#1 PEM encoded private key file
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let pem = std::fs::read_to_string(path)?;
+        let private = RsaPrivateKey::from_pkcs1_pem(&pem)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid private key"))?;
+
+        Ok(Self::new(RsaKeyPair {
+            public: RsaPublicKey::from(&private),
+            private,
+        }))
+    }
+
+    /// Returns the fingerprint the server authorizes this identity by
+    pub fn fingerprint(&self) -> Result<String, CommError> {
+        key_fingerprint(&self.keypair.public)
+    }
+}
+
+impl fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ClientIdentity")
+            .field(&self.fingerprint())
+            .finish()
+    }
+}
+
+/// Fingerprints of the client identities allowed to connect to a server
+#[derive(Debug, Clone, Default)]
+pub struct AuthorizedClients {
+    fingerprints: HashSet<String>,
+    admit_unknown: bool, // Let unknown identities complete the handshake
+}
+
+impl AuthorizedClients {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the authorized fingerprints from a file
+    /// Each line holds a fingerprint, optionally followed by a comment naming the client
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let fingerprints = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_whitespace().next())
+            .map(str::to_string)
+            .collect();
+
+        Ok(Self {
+            fingerprints,
+            admit_unknown: false,
+        })
+    }
+
+    /// Adds an identity to an authorized clients file, creating it if missing
+    pub fn append_to_file(
+        path: impl AsRef<Path>,
+        fingerprint: &str,
+        comment: &str,
+    ) -> io::Result<()> {
+        let line = match comment {
+            "" => fingerprint.to_string(),
+            comment => format!("{} {}", fingerprint, comment),
+        };
+        append_line(path.as_ref(), &line)
+    }
+
+    /// Lets clients with unknown identities complete the handshake
+    /// They must still prove an identity, which the server can then hold for approval
+    pub fn admit_unknown(mut self, val: bool) -> Self {
+        self.admit_unknown = val;
+        self
+    }
+
+    pub fn admits_unknown(&self) -> bool {
+        self.admit_unknown
+    }
+
+    /// Authorizes an identity by its fingerprint
+    pub fn insert(&mut self, fingerprint: impl Into<String>) {
+        self.fingerprints.insert(fingerprint.into());
+    }
+
+    pub fn contains(&self, fingerprint: &str) -> bool {
+        self.fingerprints.contains(fingerprint)
+    }
+}
+
+/// Parses a PKCS#1 DER encoded public key received during the handshake
+pub fn parse_public_key(bytes: &[u8]) -> io::Result<RsaPublicKey> {
+    RsaPublicKey::from_pkcs1_der(bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid public key"))
+}
+
+/// Validates and deserializes symmetric encryption initializers received during the handshake
+pub fn parse_initializer_pair(bytes: &[u8]) -> io::Result<AES256GCMInitializerPair> {
+    rkyv::from_bytes::<AES256GCMInitializerPair>(bytes).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid symmetric key initializer",
+        )
+    })
+}
+
+/// Reason for a server rejecting a client's handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    Malformed,    // Invalid or undecryptable initializers
+    Timeout,      // Client took too long to send its initializers
+    Unauthorized, // Client identity missing or not authorized
+    Other(u8),    // Reason code unknown to this version
+}
+
+impl RejectReason {
+    fn to_byte(self) -> u8 {
+        match self {
+            RejectReason::Malformed => REJECT_MALFORMED,
+            RejectReason::Timeout => REJECT_TIMEOUT,
+            RejectReason::Unauthorized => REJECT_UNAUTHORIZED,
+            RejectReason::Other(code) => code,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            REJECT_MALFORMED => RejectReason::Malformed,
+            REJECT_TIMEOUT => RejectReason::Timeout,
+            REJECT_UNAUTHORIZED => RejectReason::Unauthorized,
+            code => RejectReason::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::Malformed => write!(f, "malformed initializers"),
+            RejectReason::Timeout => write!(f, "handshake timeout"),
+            RejectReason::Unauthorized => write!(f, "client not authorized"),
+            RejectReason::Other(code) => write!(f, "reason code {}", code),
+        }
+    }
+}
+
+/// Parses the handshake status frame sent by the server
+pub fn parse_handshake_status(bytes: &[u8]) -> io::Result<()> {
+    match bytes {
+        [HANDSHAKE_ACCEPT] => Ok(()),
+        [HANDSHAKE_REJECT, reason] => {
+            Err(HandshakeError::Rejected(RejectReason::from_byte(*reason)).into())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid handshake status",
+        )),
+    }
+}
+
+/// Typed encryption failures
+/// Carried inside an io::Error of kind InvalidData
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    Encryption,    // Message couldn't be encrypted, e.g. it's too long for the cipher
+    Decryption,    // Message failed authentication, it was corrupted or forged
+    KeyGeneration, // RSA key pair couldn't be generated
+    KeyEncoding,   // RSA public key couldn't be DER encoded for fingerprinting
+}
+
+impl CryptoError {
+    /// Extracts a CryptoError from an io::Error, if it carries one
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::Encryption => write!(f, "encryption error"),
+            CryptoError::Decryption => write!(f, "decryption error"),
+            CryptoError::KeyGeneration => write!(f, "key generation error"),
+            CryptoError::KeyEncoding => write!(f, "key encoding error"),
+        }
+    }
+}
+
+impl Error for CryptoError {}
+
+impl From<CryptoError> for io::Error {
+    fn from(err: CryptoError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Typed handshake failures
+/// Carried inside an io::Error of kind InvalidData
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeError {
+    Rejected(RejectReason), // Server rejected the handshake
+    BadSignature,           // Server's key exchange isn't signed by its public key
+}
+
+impl HandshakeError {
+    /// Extracts a HandshakeError from an io::Error, if it carries one
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Rejected(reason) => write!(f, "handshake rejected: {}", reason),
+            HandshakeError::BadSignature => write!(f, "invalid key exchange signature"),
+        }
+    }
+}
+
+impl Error for HandshakeError {}
+
+impl From<HandshakeError> for io::Error {
+    fn from(err: HandshakeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Key exchange performed by the client during the handshake
+/// Servers support both, so clients can be upgraded independently
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyExchange {
+    /// Client picks the symmetric keys and encrypts them with the server's RSA key.
+    /// Recording the traffic and later stealing the server key reveals past sessions
+    Rsa,
+    /// Ephemeral X25519 key agreement, signed by the server's RSA key.
+    /// Session keys are never stored, so past sessions stay secret
+    #[default]
+    X25519,
+}
+
+/// Splits the server's X25519 accept frame into its ephemeral public key and signature
+fn parse_x25519_accept(bytes: &[u8]) -> io::Result<(X25519PublicKey, &[u8])> {
+    match bytes {
+        [HANDSHAKE_ACCEPT, rest @ ..] if rest.len() > 32 => {
+            let public: [u8; 32] = rest[..32].try_into().unwrap();
+            Ok((public.into(), &rest[32..]))
+        }
+        _ => {
+            // Rejections are reported as such, a bare accept is invalid here
+            parse_handshake_status(bytes)?;
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing server key exchange",
+            ))
+        }
+    }
+}
+
+/// Parses a client identity frame into the client's public key and signature
+fn parse_client_identity(bytes: &[u8]) -> io::Result<(RsaPublicKey, &[u8])> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid client identity");
+
+    let (len, rest) = bytes.split_first_chunk::<2>().ok_or_else(invalid)?;
+    let len = u16::from_be_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(invalid());
+    }
+    let (der, signature) = rest.split_at(len);
+
+    Ok((parse_public_key(der).map_err(|_| invalid())?, signature))
+}
+
+/// Decrypts and deserializes the symmetric encryption initializers sent by a client
+fn decrypt_initializer_pair(
+    keypair: &RsaKeyPair,
+    bytes: &[u8],
+) -> io::Result<AES256GCMInitializerPair> {
+    let bytes = keypair
+        .private
+        .decrypt(Pkcs1v15Encrypt, bytes)
+        .map_err(|_| CryptoError::Decryption)?;
+
+    parse_initializer_pair(&bytes)
+}
+
+/// Tells the client why its handshake failed. Errors are ignored, as the
+/// connection is going to be dropped anyway
+async fn reject_handshake<S: AsyncMsgSend>(sender: &mut S, reason: RejectReason) {
+    let _ = sender.send(&[HANDSHAKE_REJECT, reason.to_byte()]).await;
+}
+
 /// Encrypted channel setup result
 pub type EncChannelSetupResult<S, R> = io::Result<(AES256GCMMsgSender<S>, AES256GCMMsgReceiver<R>)>;
 
 /// Handles performing the initial key exchange phase and constructing an encrypted message channel
 /// on the client side
-/// TODO: implement first-use key trusting
+/// If an identity is given, the client proves it to the server, which requires the X25519 key exchange
 pub async fn client_setup_encrypted_channel<S, R>(
     mut sender: S,
     mut receiver: R,
     timeout: Duration,
     key_validator: &mut ServerPublicKeyValidator,
+    key_exchange: KeyExchange,
+    identity: Option<&ClientIdentity>,
 ) -> EncChannelSetupResult<S, R>
 where
     S: AsyncMsgSend,
     R: AsyncMsgRecv,
 {
-    // Generate new symmetric encryption initializers
-    let sym_init = AES256GCMInitializerPair::new_rand();
-
     // Wait for the server's public key
     let pub_key_bytes = time::timeout(timeout, receiver.recv()).await??;
-    let pub_key = RsaPublicKey::from_pkcs1_der(&pub_key_bytes)
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid public key"))?;
+    let pub_key = parse_public_key(&pub_key_bytes)?;
 
     // Check server public key
-    key_validator
-        .validate(&pub_key)
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "untrusted public key"))?;
+    key_validator.validate(&pub_key)?;
+
+    let sym_init = match (key_exchange, identity) {
+        (KeyExchange::Rsa, None) => {
+            client_rsa_key_transport(&mut sender, &mut receiver, timeout, &pub_key).await?
+        }
+        (KeyExchange::Rsa, Some(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "client identity requires the X25519 key exchange",
+            ))
+        }
+        (KeyExchange::X25519, identity) => {
+            client_x25519_key_agreement(
+                &mut sender,
+                &mut receiver,
+                timeout,
+                &pub_key,
+                &pub_key_bytes,
+                identity,
+            )
+            .await?
+        }
+    };
+
+    // We have enstablished an encrypted channel to the server
+    Ok((
+        AES256GCMMsgSender::new(sender, &sym_init.cts),
+        AES256GCMMsgReceiver::new(receiver, &sym_init.stc),
+    ))
+}
+
+/// Generates the symmetric encryption initializers and sends them encrypted with the server's key
+async fn client_rsa_key_transport(
+    sender: &mut impl AsyncMsgSend,
+    receiver: &mut impl AsyncMsgRecv,
+    timeout: Duration,
+    pub_key: &RsaPublicKey,
+) -> io::Result<AES256GCMInitializerPair> {
+    // Generate new symmetric encryption initializers
+    let sym_init = AES256GCMInitializerPair::new_rand();
 
     // Serialize, encrypt with public key and send symmetric encryption initializers
     let sym_init_bytes = rkyv::to_bytes::<_, 128>(&sym_init)
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "symmetric key serialization error"))?;
+        .map_err(|_| io::Error::other("symmetric key serialization error"))?;
     let sym_init_bytes_enc = pub_key
         .encrypt(&mut OsRng, Pkcs1v15Encrypt, &sym_init_bytes)
-        .map_err(|_| {
-            io::Error::new(io::ErrorKind::InvalidData, "symmetric key encryption error")
-        })?;
+        .map_err(|_| CryptoError::Encryption)?;
     sender.send(&sym_init_bytes_enc).await?;
 
-    // We have enstablished an encrypted channel to the server
-    Ok((
-        AES256GCMMsgSender::new(sender, &sym_init.cts),
-        AES256GCMMsgReceiver::new(receiver, &sym_init.stc),
+    // Wait for the server to accept the initializers
+    let status = time::timeout(timeout, receiver.recv()).await??;
+    parse_handshake_status(&status)?;
+
+    Ok(sym_init)
+}
+
+/// Performs an ephemeral X25519 key agreement, checking the server's signature over it
+async fn client_x25519_key_agreement(
+    sender: &mut impl AsyncMsgSend,
+    receiver: &mut impl AsyncMsgRecv,
+    timeout: Duration,
+    pub_key: &RsaPublicKey,
+    pub_key_der: &[u8],
+    identity: Option<&ClientIdentity>,
+) -> io::Result<AES256GCMInitializerPair> {
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let client_public = X25519PublicKey::from(&secret);
+
+    let marker = match identity {
+        Some(_) => KEY_EXCHANGE_X25519_AUTH,
+        None => KEY_EXCHANGE_X25519,
+    };
+    let mut hello = vec![marker];
+    hello.extend_from_slice(client_public.as_bytes());
+    sender.send(&hello).await?;
+
+    // Prove our identity by signing the server key and our ephemeral key
+    let mut identity_frame = Vec::new();
+    if let Some(identity) = identity {
+        let der = identity
+            .keypair
+            .public
+            .to_pkcs1_der()
+            .map_err(|_| io::Error::other("public key serialization error"))?;
+        let signature = identity
+            .keypair
+            .private
+            .sign(
+                Pkcs1v15Sign::new::<Sha256>(),
+                &spec::client_identity_digest(pub_key_der, client_public.as_bytes()),
+            )
+            .map_err(|_| io::Error::other("client identity signing error"))?;
+
+        identity_frame.extend_from_slice(&(der.as_bytes().len() as u16).to_be_bytes());
+        identity_frame.extend_from_slice(der.as_bytes());
+        identity_frame.extend_from_slice(&signature);
+        sender.send(&identity_frame).await?;
+    }
+
+    // Wait for the server's ephemeral key, signed together with the rest of the transcript
+    let reply = time::timeout(timeout, receiver.recv()).await??;
+    let (server_public, signature) = parse_x25519_accept(&reply)?;
+    let transcript = spec::x25519_transcript(
+        pub_key_der,
+        &[&hello, &identity_frame],
+        server_public.as_bytes(),
+    );
+    pub_key
+        .verify(
+            Pkcs1v15Sign::new::<Sha256>(),
+            &Sha256::digest(&transcript),
+            signature,
+        )
+        .map_err(|_| HandshakeError::BadSignature)?;
+
+    let shared = secret.diffie_hellman(&server_public);
+    if !shared.was_contributory() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "non-contributory key exchange",
+        ));
+    }
+
+    Ok(AES256GCMInitializerPair::derive(
+        shared.as_bytes(),
+        &transcript,
     ))
 }
 
 /// Handles performing the initial key exchange phase and constructing an encrypted message channel
 /// on the server side
+/// Both key exchanges are accepted, telling them apart from the client's first frame
+/// If authorized clients are given, clients must prove one of their identities
 pub async fn server_setup_encrypted_channel<S, R>(
     mut sender: S,
     mut receiver: R,
     keypair: &RsaKeyPair,
     timeout: Duration,
+    authorized: Option<&AuthorizedClients>,
 ) -> EncChannelSetupResult<S, R>
 where
     S: AsyncMsgSend,
@@ -278,40 +1089,256 @@ where
     let pub_key_der = keypair
         .public
         .to_pkcs1_der()
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "public key serialization error"))?;
+        .map_err(|_| io::Error::other("public key serialization error"))?;
     sender.send(pub_key_der.as_bytes()).await?;
 
-    // Wait for symmetric key from client, decrypt and deserialize
-    let sym_init_bytes = time::timeout(timeout, receiver.recv()).await??;
-    let sym_init_bytes = keypair
-        .private
-        .decrypt(Pkcs1v15Encrypt, &sym_init_bytes)
-        .map_err(|_| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                "symmetric key initializer decryption error",
+    // Wait for the client's key exchange
+    let bytes = recv_handshake_frame(&mut sender, &mut receiver, timeout).await?;
+
+    let (sym_init, fingerprint) = match bytes.as_slice() {
+        [marker @ (KEY_EXCHANGE_X25519 | KEY_EXCHANGE_X25519_AUTH), client_public @ ..]
+            if bytes.len() == X25519_HELLO_LEN =>
+        {
+            let client_public: [u8; 32] = client_public.try_into().unwrap();
+
+            // Check the client's identity, if it has one
+            let mut identity_frame = Vec::new();
+            let mut fingerprint = None;
+            if *marker == KEY_EXCHANGE_X25519_AUTH {
+                identity_frame = recv_handshake_frame(&mut sender, &mut receiver, timeout).await?;
+                let identity = parse_client_identity(&identity_frame).and_then(|(key, sig)| {
+                    key.verify(
+                        Pkcs1v15Sign::new::<Sha256>(),
+                        &spec::client_identity_digest(pub_key_der.as_bytes(), &client_public),
+                        sig,
+                    )
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "invalid client signature")
+                    })?;
+                    Ok(key)
+                });
+                let key = match identity {
+                    Ok(key) => key,
+                    Err(e) => {
+                        reject_handshake(&mut sender, RejectReason::Malformed).await;
+                        return Err(e);
+                    }
+                };
+
+                let key_fingerprint = key_fingerprint(&key)?;
+                if let Some(authorized) = authorized {
+                    authorize_client(&mut sender, authorized, &key_fingerprint).await?;
+                }
+                fingerprint = Some(key_fingerprint);
+            } else if authorized.is_some() {
+                reject_handshake(&mut sender, RejectReason::Unauthorized).await;
+                return Err(HandshakeError::Rejected(RejectReason::Unauthorized).into());
+            }
+
+            let secret = EphemeralSecret::random_from_rng(OsRng);
+            let server_public = X25519PublicKey::from(&secret);
+
+            let shared = secret.diffie_hellman(&client_public.into());
+            if !shared.was_contributory() {
+                reject_handshake(&mut sender, RejectReason::Malformed).await;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "non-contributory key exchange",
+                ));
+            }
+
+            // Prove ownership of the public key by signing the transcript
+            let transcript = spec::x25519_transcript(
+                pub_key_der.as_bytes(),
+                &[&bytes, &identity_frame],
+                server_public.as_bytes(),
+            );
+            let signature = keypair
+                .private
+                .sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(&transcript))
+                .map_err(|_| io::Error::other("key exchange signing error"))?;
+
+            let mut reply = vec![HANDSHAKE_ACCEPT];
+            reply.extend_from_slice(server_public.as_bytes());
+            reply.extend_from_slice(&signature);
+            sender.send(&reply).await?;
+
+            (
+                AES256GCMInitializerPair::derive(shared.as_bytes(), &transcript),
+                fingerprint,
             )
-        })?;
+        }
+        _ => {
+            // Clients using RSA key transport can't prove an identity
+            if authorized.is_some() {
+                reject_handshake(&mut sender, RejectReason::Unauthorized).await;
+                return Err(HandshakeError::Rejected(RejectReason::Unauthorized).into());
+            }
 
-    let sym_init = rkyv::from_bytes::<AES256GCMInitializerPair>(&sym_init_bytes).map_err(|_| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid symmetric key initializer",
-        )
-    })?;
+            // Decrypt and deserialize initializers chosen by the client
+            let sym_init = match decrypt_initializer_pair(keypair, &bytes) {
+                Ok(sym_init) => sym_init,
+                Err(e) => {
+                    reject_handshake(&mut sender, RejectReason::Malformed).await;
+                    return Err(e);
+                }
+            };
+            sender.send(&[HANDSHAKE_ACCEPT]).await?;
+            (sym_init, None)
+        }
+    };
 
     // We have enstablished an encrypted channel to the server
-    Ok((
-        AES256GCMMsgSender::new(sender, &sym_init.stc),
-        AES256GCMMsgReceiver::new(receiver, &sym_init.cts),
-    ))
+    let mut receiver = AES256GCMMsgReceiver::new(receiver, &sym_init.cts);
+    receiver.peer_identity = fingerprint;
+    Ok((AES256GCMMsgSender::new(sender, &sym_init.stc), receiver))
+}
+
+/// Receives a handshake frame from the client, rejecting the handshake on timeout
+async fn recv_handshake_frame(
+    sender: &mut impl AsyncMsgSend,
+    receiver: &mut impl AsyncMsgRecv,
+    timeout: Duration,
+) -> io::Result<Vec<u8>> {
+    match time::timeout(timeout, receiver.recv()).await {
+        Ok(res) => Ok(res?),
+        Err(e) => {
+            reject_handshake(sender, RejectReason::Timeout).await;
+            Err(e.into())
+        }
+    }
+}
+
+/// Rejects clients whose identity isn't authorized
+async fn authorize_client(
+    sender: &mut impl AsyncMsgSend,
+    authorized: &AuthorizedClients,
+    fingerprint: &str,
+) -> io::Result<()> {
+    if authorized.contains(fingerprint) {
+        debug!("Authorized client {}", fingerprint);
+        return Ok(());
+    }
+    if authorized.admits_unknown() {
+        debug!("Admitting unknown client {}", fingerprint);
+        return Ok(());
+    }
+
+    debug!("Rejecting unknown client {}", fingerprint);
+    reject_handshake(sender, RejectReason::Unauthorized).await;
+    Err(HandshakeError::Rejected(RejectReason::Unauthorized).into())
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
     use rsa::BigUint;
+    use tokio::{io::duplex, runtime};
 
     use super::*;
+    use crate::comm::{
+        encaps::{LenU64EncapsMsgReceiver, LenU64EncapsMsgSender},
+        testutil::{VecMsgReceiver, VecMsgSender},
+    };
+
+    proptest! {
+        #[test]
+        fn aes_roundtrip(msgs in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..4096), 1..8)) {
+            let rt = runtime::Builder::new_current_thread().build().unwrap();
+            rt.block_on(async {
+                let init = AES256GCMInitializer::new_rand();
+                let (a, b) = duplex(64);
+                let mut sender = AES256GCMMsgSender::new(LenU64EncapsMsgSender::new(a), &init);
+                let mut receiver = AES256GCMMsgReceiver::new(LenU64EncapsMsgReceiver::new(b), &init);
+
+                let sent = msgs.clone();
+                let send = tokio::spawn(async move {
+                    for msg in &sent {
+                        sender.send(msg).await.unwrap();
+                    }
+                });
+
+                // Both receive paths decrypt in place, recv_into reusing its buffer
+                let mut buf = BytesMut::new();
+                for (i, msg) in msgs.iter().enumerate() {
+                    if i % 2 == 0 {
+                        assert_eq!(&receiver.recv().await.unwrap(), msg);
+                    } else {
+                        receiver.recv_into(&mut buf).await.unwrap();
+                        assert_eq!(&buf[..], &msg[..]);
+                    }
+                }
+                send.await.unwrap();
+            });
+        }
+
+        #[test]
+        fn aes_rejects_bit_flip(msg in prop::collection::vec(any::<u8>(), 0..1024), bit in any::<prop::sample::Index>()) {
+            let rt = runtime::Builder::new_current_thread().build().unwrap();
+            rt.block_on(async {
+                let init = AES256GCMInitializer::new_rand();
+
+                // Encrypt message
+                let mut sender = AES256GCMMsgSender::new(VecMsgSender(Vec::new()), &init);
+                sender.send(&msg).await.unwrap();
+                let mut ciphertext = sender.sender.0.pop().unwrap();
+
+                // Flip a single bit of the ciphertext
+                let bit = bit.index(ciphertext.len() * 8);
+                ciphertext[bit / 8] ^= 1 << (bit % 8);
+
+                let mut receiver =
+                    AES256GCMMsgReceiver::new(VecMsgReceiver::new(vec![ciphertext]), &init);
+                let err = receiver.recv().await.unwrap_err();
+                assert!(matches!(err, CommError::Crypto(CryptoError::Decryption)));
+            });
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn aes_rekey() {
+        let init = AES256GCMInitializer::new_rand();
+        let policy = RekeyPolicy::default()
+            .max_messages(2)
+            .max_age(Duration::from_secs(60));
+
+        let mut sender = AES256GCMMsgSender::new(VecMsgSender(Vec::new()), &init);
+        sender.set_rekey(Some(policy));
+        for msg in [b"one", b"two", b"thr"] {
+            sender.send(msg).await.unwrap();
+        }
+        time::advance(Duration::from_secs(60)).await;
+        for msg in [b"fou", b"fiv"] {
+            sender.send(msg).await.unwrap();
+        }
+
+        // One rekey by count and one by age, followed by the receiver
+        let rekeyed = CipherState::new(&init).next().next().key;
+        assert_eq!(sender.state.key, rekeyed);
+
+        let ciphertexts = sender.sender.0;
+        let mut receiver =
+            AES256GCMMsgReceiver::new(VecMsgReceiver::new(ciphertexts.clone()), &init);
+        for msg in [b"one", b"two", b"thr", b"fou", b"fiv"] {
+            assert_eq!(receiver.recv().await.unwrap(), msg);
+        }
+        assert_eq!(receiver.state.key, sender.state.key);
+
+        // Skipping a whole key is a decryption failure
+        let mut receiver = AES256GCMMsgReceiver::new(
+            VecMsgReceiver::new(
+                ciphertexts[..1]
+                    .iter()
+                    .chain(&ciphertexts[3..])
+                    .cloned()
+                    .collect(),
+            ),
+            &init,
+        );
+        receiver.recv().await.unwrap();
+        let err = receiver.recv().await.unwrap_err();
+        assert!(matches!(err, CommError::Crypto(CryptoError::Decryption)));
+    }
 
     #[test]
     fn test_inc_multibyte() {
@@ -341,17 +1368,17 @@ mod tests {
         let mut key_validator = ServerPublicKeyValidator::new(false);
 
         let key1 = RsaPrivateKey::from_p_q(
-            BigUint::from_bytes_be(&vec![0x02]),
-            BigUint::from_bytes_be(&vec![0x03]),
-            BigUint::from_bytes_be(&vec![0x01]),
+            BigUint::from_bytes_be(&[0x02]),
+            BigUint::from_bytes_be(&[0x03]),
+            BigUint::from_bytes_be(&[0x01]),
         )
         .unwrap();
         let key1 = RsaPublicKey::from(key1);
 
         let key2 = RsaPrivateKey::from_p_q(
-            BigUint::from_bytes_be(&vec![0x05]),
-            BigUint::from_bytes_be(&vec![0x07]),
-            BigUint::from_bytes_be(&vec![0x01]),
+            BigUint::from_bytes_be(&[0x05]),
+            BigUint::from_bytes_be(&[0x07]),
+            BigUint::from_bytes_be(&[0x01]),
         )
         .unwrap();
         let key2 = RsaPublicKey::from(key2);
@@ -374,17 +1401,17 @@ mod tests {
         let mut key_validator = ServerPublicKeyValidator::new(true);
 
         let key1 = RsaPrivateKey::from_p_q(
-            BigUint::from_bytes_be(&vec![0x02]),
-            BigUint::from_bytes_be(&vec![0x03]),
-            BigUint::from_bytes_be(&vec![0x01]),
+            BigUint::from_bytes_be(&[0x02]),
+            BigUint::from_bytes_be(&[0x03]),
+            BigUint::from_bytes_be(&[0x01]),
         )
         .unwrap();
         let key1 = RsaPublicKey::from(key1);
 
         let key2 = RsaPrivateKey::from_p_q(
-            BigUint::from_bytes_be(&vec![0x05]),
-            BigUint::from_bytes_be(&vec![0x07]),
-            BigUint::from_bytes_be(&vec![0x01]),
+            BigUint::from_bytes_be(&[0x05]),
+            BigUint::from_bytes_be(&[0x07]),
+            BigUint::from_bytes_be(&[0x01]),
         )
         .unwrap();
         let key2 = RsaPublicKey::from(key2);
@@ -401,4 +1428,53 @@ mod tests {
         // Validation with incorrect key
         key_validator.validate(&key2).unwrap();
     }
+
+    #[test]
+    fn server_key_known_hosts() {
+        let path =
+            std::env::temp_dir().join(format!("pomegranate-known-hosts-{}", std::process::id()));
+        std::fs::write(&path, "# comment\nother:1234 SHA256:00\n").unwrap();
+
+        let key1 = RsaPublicKey::from(
+            RsaPrivateKey::from_p_q(
+                BigUint::from_bytes_be(&[0x02]),
+                BigUint::from_bytes_be(&[0x03]),
+                BigUint::from_bytes_be(&[0x01]),
+            )
+            .unwrap(),
+        );
+        let key2 = RsaPublicKey::from(
+            RsaPrivateKey::from_p_q(
+                BigUint::from_bytes_be(&[0x05]),
+                BigUint::from_bytes_be(&[0x07]),
+                BigUint::from_bytes_be(&[0x01]),
+            )
+            .unwrap(),
+        );
+
+        // First connection persists the key
+        let mut key_validator =
+            ServerPublicKeyValidator::from_file(&path, "coord:1234", false).unwrap();
+        key_validator.validate(&key1).unwrap();
+
+        // Trust survives restarts
+        let mut key_validator =
+            ServerPublicKeyValidator::from_file(&path, "coord:1234", false).unwrap();
+        key_validator.validate(&key1).unwrap();
+        let err = key_validator.validate(&key2).unwrap_err();
+        assert_eq!(
+            ServerPublicKeyChanged::from_io(&err),
+            Some(&ServerPublicKeyChanged {
+                host: Some("coord:1234".to_string()),
+                stored: key_fingerprint(&key1).unwrap(),
+                received: key_fingerprint(&key2).unwrap(),
+            })
+        );
+
+        // Other entries are kept
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("# comment\nother:1234 SHA256:00\n"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
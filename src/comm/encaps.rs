@@ -1,21 +1,78 @@
-use std::{future::Future, mem};
+use std::{future::Future, mem, sync::Arc};
+
+use bytes::BytesMut;
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt, BufReader};
 
+use super::{error::CommError, protocol::spec::LEN_HEADER_LEN as HEADER_LEN};
+use crate::metrics::Metrics;
+
 /// Writes encapsulated messages
 pub trait AsyncMsgSend {
     /// Sends a message
-    fn send(&mut self, msg: &[u8]) -> impl Future<Output = std::io::Result<()>>;
+    fn send(&mut self, msg: &[u8]) -> impl Future<Output = Result<(), CommError>>;
 }
 
 /// Receives encapsulated messages
 pub trait AsyncMsgRecv {
-    /// Sends a message
-    fn recv(&mut self) -> impl Future<Output = io::Result<Vec<u8>>>;
+    /// Receives a message
+    fn recv(&mut self) -> impl Future<Output = Result<Vec<u8>, CommError>>;
+
+    /// Receives a message into a buffer, replacing its contents
+    /// Reusing the buffer across calls saves an allocation per message on
+    /// receivers which override this, the default copies the received message
+    fn recv_into(&mut self, buf: &mut BytesMut) -> impl Future<Output = Result<(), CommError>> {
+        async move {
+            let msg = self.recv().await?;
+            buf.clear();
+            buf.extend_from_slice(&msg);
+            Ok(())
+        }
+    }
+}
+
+/// Either of two message senders or receivers, for choosing one at runtime
+pub enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+impl<L, R> AsyncMsgSend for Either<L, R>
+where
+    L: AsyncMsgSend,
+    R: AsyncMsgSend,
+{
+    async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
+        match self {
+            Either::Left(sender) => sender.send(msg).await,
+            Either::Right(sender) => sender.send(msg).await,
+        }
+    }
+}
+
+impl<L, R> AsyncMsgRecv for Either<L, R>
+where
+    L: AsyncMsgRecv,
+    R: AsyncMsgRecv,
+{
+    async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+        match self {
+            Either::Left(receiver) => receiver.recv().await,
+            Either::Right(receiver) => receiver.recv().await,
+        }
+    }
+
+    async fn recv_into(&mut self, buf: &mut BytesMut) -> Result<(), CommError> {
+        match self {
+            Either::Left(receiver) => receiver.recv_into(buf).await,
+            Either::Right(receiver) => receiver.recv_into(buf).await,
+        }
+    }
 }
 
 /// Wrapper for AsyncWriteExt object that provides length-and-message encapsulation
 pub struct LenU64EncapsMsgSender<W> {
     writer: W,
+    metrics: Option<Arc<dyn Metrics>>,
 }
 
 impl<W> LenU64EncapsMsgSender<W>
@@ -24,7 +81,21 @@ where
 {
     /// Creates a new EncapsulatedWriter
     pub fn new(writer: W) -> Self {
-        Self { writer }
+        Self {
+            writer,
+            metrics: None,
+        }
+    }
+
+    /// Reports the bytes sent to a Metrics implementation
+    pub fn metrics(mut self, val: Option<Arc<dyn Metrics>>) -> Self {
+        self.metrics = val;
+        self
+    }
+
+    /// Returns the underlying writer
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
     }
 }
 
@@ -33,24 +104,34 @@ where
     W: AsyncWriteExt + Unpin,
 {
     /// Sends a length-and-message encapulated message
-    async fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+    async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
         // Convert length of message to u64 type that is going to be sent
 
-        let len = u64::try_from(msg.len()).map_err(|_| {
-            io::Error::new(io::ErrorKind::Other, "message too big for encapsulation")
-        })?;
+        let len = u64::try_from(msg.len())
+            .map_err(|_| io::Error::other("message too big for encapsulation"))?;
 
         // Send length and message
         self.writer.write_all(&len.to_be_bytes()).await?;
         self.writer.write_all(msg).await?;
 
+        if let Some(metrics) = &self.metrics {
+            metrics.bytes_sent(HEADER_LEN + len);
+        }
         Ok(())
     }
 }
 
+/// Default maximum length of a received message
+pub const DEFAULT_MAX_MSG_LEN: u64 = 256 * 1024 * 1024;
+
+/// Size of the buffer initially allocated for a received message
+const RECV_INIT_CAPACITY: u64 = 64 * 1024;
+
 /// Wrapper for AsyncReadExt object that provides length-and-message encapsulation
 pub struct LenU64EncapsMsgReceiver<R> {
     reader: BufReader<R>,
+    max_len: u64,
+    metrics: Option<Arc<dyn Metrics>>,
 }
 
 impl<R> LenU64EncapsMsgReceiver<R>
@@ -61,8 +142,22 @@ where
     pub fn new(reader: R) -> Self {
         Self {
             reader: BufReader::new(reader),
+            max_len: DEFAULT_MAX_MSG_LEN,
+            metrics: None,
         }
     }
+
+    /// Sets the maximum length of a received message
+    pub fn max_len(mut self, val: u64) -> Self {
+        self.max_len = val;
+        self
+    }
+
+    /// Reports the bytes received to a Metrics implementation
+    pub fn metrics(mut self, val: Option<Arc<dyn Metrics>>) -> Self {
+        self.metrics = val;
+        self
+    }
 }
 
 impl<R> AsyncMsgRecv for LenU64EncapsMsgReceiver<R>
@@ -70,21 +165,118 @@ where
     R: AsyncReadExt + Unpin,
 {
     /// Receives a length-and-message encapsulated message
-    async fn recv(&mut self) -> io::Result<Vec<u8>> {
+    async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+        let mut msg = BytesMut::new();
+        self.recv_into(&mut msg).await?;
+        Ok(msg.into())
+    }
+
+    async fn recv_into(&mut self, buf: &mut BytesMut) -> Result<(), CommError> {
         // Read length
         let mut len = [0u8; mem::size_of::<u64>()];
         self.reader.read_exact(&mut len).await?;
         let len = u64::from_be_bytes(len);
 
-        // Convert length to system size
-        let len = usize::try_from(len).map_err(|_| {
-            io::Error::new(io::ErrorKind::Other, "message too big for encapsulation")
-        })?;
+        // Reject lengths over the limit before allocating anything
+        if len > self.max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message exceeds maximum length",
+            )
+            .into());
+        }
+
+        // Read message of length, growing the buffer as data actually arrives
+        buf.clear();
+        let mut remaining = len;
+        while remaining > 0 {
+            buf.reserve(remaining.min(RECV_INIT_CAPACITY) as usize);
+            let n = (&mut self.reader).take(remaining).read_buf(buf).await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            remaining -= n as u64;
+        }
+
+        if let Some(metrics) = &self.metrics {
+            metrics.bytes_received(HEADER_LEN + len);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use tokio::{io::duplex, runtime};
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn lenu64_roundtrip(msgs in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..4096), 1..8)) {
+            let rt = runtime::Builder::new_current_thread().build().unwrap();
+            rt.block_on(async {
+                let (a, b) = duplex(64);
+                let mut sender = LenU64EncapsMsgSender::new(a);
+                let mut receiver = LenU64EncapsMsgReceiver::new(b);
+
+                let sent = msgs.clone();
+                let send = tokio::spawn(async move {
+                    for msg in &sent {
+                        sender.send(msg).await.unwrap();
+                    }
+                });
+
+                for msg in &msgs {
+                    assert_eq!(&receiver.recv().await.unwrap(), msg);
+                }
+                send.await.unwrap();
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn lenu64_recv_into() {
+        let (a, b) = duplex(64);
+        let mut sender = LenU64EncapsMsgSender::new(a);
+        let mut receiver = LenU64EncapsMsgReceiver::new(b);
+
+        let mut buf = BytesMut::new();
+        for msg in [&b"first message"[..], b"", b"second"] {
+            sender.send(msg).await.unwrap();
+            receiver.recv_into(&mut buf).await.unwrap();
+            assert_eq!(&buf[..], msg);
+        }
+
+        // The allocation is reused once it is large enough
+        let ptr = buf.as_ptr();
+        sender.send(b"third").await.unwrap();
+        receiver.recv_into(&mut buf).await.unwrap();
+        assert_eq!((&buf[..], buf.as_ptr()), (&b"third"[..], ptr));
+    }
+
+    #[tokio::test]
+    async fn lenu64_max_len() {
+        let (mut a, b) = duplex(64);
+        let mut receiver = LenU64EncapsMsgReceiver::new(b).max_len(16);
+
+        // Only the header is sent, the receiver must not wait for the body
+        a.write_all(&u64::MAX.to_be_bytes()).await.unwrap();
+        let err = receiver.recv().await.unwrap_err();
+        assert!(matches!(err, CommError::Io(e) if e.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[tokio::test]
+    async fn lenu64_truncated() {
+        let (mut a, b) = duplex(64);
+        let mut receiver = LenU64EncapsMsgReceiver::new(b);
 
-        // Read message of length
-        let mut msg = vec![0u8; len];
-        self.reader.read_exact(&mut msg).await?;
+        a.write_all(&8u64.to_be_bytes()).await.unwrap();
+        a.write_all(b"four").await.unwrap();
+        drop(a);
 
-        Ok(msg)
+        let err = receiver.recv().await.unwrap_err();
+        assert!(matches!(err, CommError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof));
     }
 }
@@ -0,0 +1,174 @@
+use std::{error::Error, fmt};
+
+use tokio::io;
+
+use super::{
+    crypto::{CryptoError, HandshakeError, ServerPublicKeyChanged},
+    heartbeat::ConnectionLost,
+    protocol::{OnboardingReject, ProtocolError},
+    proxy::ProxyError,
+    seq::SequenceError,
+    transfer::TransferError,
+};
+
+/// Classification of the failures produced by the comm layer
+/// Returned by the message traits. The typed errors are also carried inside
+/// io::Error where channels compose with AsyncRead/AsyncWrite, converting
+/// such an io::Error into a CommError recovers them for exhaustive matching
+#[derive(Debug)]
+pub enum CommError {
+    Crypto(CryptoError),
+    Handshake(HandshakeError),
+    KeyChanged(ServerPublicKeyChanged),
+    Protocol(ProtocolError),
+    Onboarding(OnboardingReject),
+    Sequence(SequenceError),
+    Transfer(TransferError),
+    Proxy(ProxyError),
+    ConnectionLost(ConnectionLost),
+    Io(io::Error), // Transport failure or untyped error
+}
+
+impl CommError {
+    /// Returns true if the failure is local to the connection and retrying may succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            CommError::ConnectionLost(_) => true,
+            // Transport failures, not malformed data or misuse
+            CommError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+            ),
+            _ => false,
+        }
+    }
+}
+
+macro_rules! impl_from_typed {
+    ($($variant:ident($ty:ty)),*) => {
+        $(
+            impl From<$ty> for CommError {
+                fn from(err: $ty) -> Self {
+                    CommError::$variant(err)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_typed!(
+    Crypto(CryptoError),
+    Handshake(HandshakeError),
+    KeyChanged(ServerPublicKeyChanged),
+    Protocol(ProtocolError),
+    Onboarding(OnboardingReject),
+    Sequence(SequenceError),
+    Transfer(TransferError),
+    Proxy(ProxyError),
+    ConnectionLost(ConnectionLost)
+);
+
+impl From<io::Error> for CommError {
+    fn from(err: io::Error) -> Self {
+        if let Some(e) = CryptoError::from_io(&err) {
+            CommError::Crypto(*e)
+        } else if let Some(e) = HandshakeError::from_io(&err) {
+            CommError::Handshake(*e)
+        } else if let Some(e) = ServerPublicKeyChanged::from_io(&err) {
+            CommError::KeyChanged(e.clone())
+        } else if let Some(e) = ProtocolError::from_io(&err) {
+            CommError::Protocol(*e)
+        } else if let Some(e) = OnboardingReject::from_io(&err) {
+            CommError::Onboarding(*e)
+        } else if let Some(e) = SequenceError::from_io(&err) {
+            CommError::Sequence(*e)
+        } else if let Some(e) = TransferError::from_io(&err) {
+            CommError::Transfer(*e)
+        } else if let Some(e) = ProxyError::from_io(&err) {
+            CommError::Proxy(*e)
+        } else if let Some(e) = ConnectionLost::from_io(&err) {
+            CommError::ConnectionLost(*e)
+        } else {
+            CommError::Io(err)
+        }
+    }
+}
+
+impl From<CommError> for io::Error {
+    fn from(err: CommError) -> Self {
+        match err {
+            CommError::Crypto(e) => e.into(),
+            CommError::Handshake(e) => e.into(),
+            CommError::KeyChanged(e) => e.into(),
+            CommError::Protocol(e) => e.into(),
+            CommError::Onboarding(e) => e.into(),
+            CommError::Sequence(e) => e.into(),
+            CommError::Transfer(e) => e.into(),
+            CommError::Proxy(e) => e.into(),
+            CommError::ConnectionLost(e) => e.into(),
+            CommError::Io(e) => e,
+        }
+    }
+}
+
+impl fmt::Display for CommError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommError::Crypto(e) => e.fmt(f),
+            CommError::Handshake(e) => e.fmt(f),
+            CommError::KeyChanged(e) => e.fmt(f),
+            CommError::Protocol(e) => e.fmt(f),
+            CommError::Onboarding(e) => e.fmt(f),
+            CommError::Sequence(e) => e.fmt(f),
+            CommError::Transfer(e) => e.fmt(f),
+            CommError::Proxy(e) => e.fmt(f),
+            CommError::ConnectionLost(e) => e.fmt(f),
+            CommError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for CommError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CommError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comm_error_roundtrip() {
+        let err: io::Error = CryptoError::Decryption.into();
+        let comm = CommError::from(err);
+        assert!(matches!(comm, CommError::Crypto(CryptoError::Decryption)));
+        assert!(!comm.is_transient());
+
+        // Converting back keeps the typed error
+        let err = io::Error::from(comm);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(CryptoError::from_io(&err), Some(&CryptoError::Decryption));
+
+        let comm = CommError::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(matches!(&comm, CommError::Io(e) if e.kind() == io::ErrorKind::ConnectionReset));
+        assert!(comm.is_transient());
+
+        // Untyped errors are classified by kind
+        let comm = CommError::from(io::Error::new(io::ErrorKind::InvalidData, "bad length"));
+        assert!(!comm.is_transient());
+        let comm = CommError::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert!(comm.is_transient());
+    }
+}
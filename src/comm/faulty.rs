@@ -0,0 +1,283 @@
+use std::time::Duration;
+
+use tokio::{io, time};
+
+use super::{
+    encaps::{AsyncMsgRecv, AsyncMsgSend},
+    error::CommError,
+};
+
+/// Configuration of the faults injected by a faulty channel
+#[derive(Debug, Clone)]
+pub struct FaultConfig {
+    pub seed: u64,                       // Seed for fault generation
+    pub drop_rate: f64,                  // Probability of a message being dropped
+    pub truncate_rate: f64,              // Probability of a message being truncated
+    pub min_delay: Duration,             // Minimum delay added to every message
+    pub max_delay: Duration,             // Maximum delay added to every message
+    pub disconnect_after: Option<usize>, // Number of messages before disconnection
+}
+
+impl FaultConfig {
+    /// Creates a new FaultConfig which doesn't inject any faults
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            drop_rate: 0.0,
+            truncate_rate: 0.0,
+            min_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            disconnect_after: None,
+        }
+    }
+
+    pub fn drop_rate(mut self, val: f64) -> Self {
+        self.drop_rate = val;
+        self
+    }
+
+    pub fn truncate_rate(mut self, val: f64) -> Self {
+        self.truncate_rate = val;
+        self
+    }
+
+    pub fn delay(mut self, min: Duration, max: Duration) -> Self {
+        self.min_delay = min;
+        self.max_delay = max.max(min);
+        self
+    }
+
+    pub fn disconnect_after(mut self, val: usize) -> Self {
+        self.disconnect_after = Some(val);
+        self
+    }
+}
+
+/// Fault to be applied to a single message
+#[derive(Debug, PartialEq, Eq)]
+enum Fault {
+    None,
+    Drop,
+    Truncate(usize),
+    Disconnect,
+}
+
+/// Deterministic fault generator shared by the faulty sender and receiver
+struct FaultInjector {
+    config: FaultConfig,
+    rng: SplitMix64,
+    count: usize,
+    disconnected: bool,
+}
+
+impl FaultInjector {
+    fn new(config: FaultConfig) -> Self {
+        Self {
+            rng: SplitMix64::new(config.seed),
+            config,
+            count: 0,
+            disconnected: false,
+        }
+    }
+
+    /// Computes the delay to apply to the next message
+    fn delay(&mut self) -> Duration {
+        // Fields are public, so the range may be inverted: fall back to min_delay
+        let span = self.config.max_delay.saturating_sub(self.config.min_delay);
+        self.config.min_delay + span.mul_f64(self.rng.next_f64())
+    }
+
+    /// Decides what fault to apply to a message of a given length
+    fn fault(&mut self, len: usize) -> Fault {
+        if self.disconnected {
+            return Fault::Disconnect;
+        }
+
+        if let Some(max) = self.config.disconnect_after {
+            if self.count >= max {
+                self.disconnected = true;
+                return Fault::Disconnect;
+            }
+        }
+        self.count += 1;
+
+        if self.rng.next_f64() < self.config.drop_rate {
+            Fault::Drop
+        } else if self.rng.next_f64() < self.config.truncate_rate {
+            Fault::Truncate(self.rng.next_u64() as usize % (len + 1))
+        } else {
+            Fault::None
+        }
+    }
+}
+
+/// Wrapper for an AsyncMsgSend object that injects configurable faults
+pub struct FaultyMsgSender<S> {
+    sender: S,
+    injector: FaultInjector,
+}
+
+impl<S> FaultyMsgSender<S>
+where
+    S: AsyncMsgSend,
+{
+    /// Constructs a new FaultyMsgSender
+    pub fn new(sender: S, config: FaultConfig) -> Self {
+        Self {
+            sender,
+            injector: FaultInjector::new(config),
+        }
+    }
+}
+
+impl<S> AsyncMsgSend for FaultyMsgSender<S>
+where
+    S: AsyncMsgSend,
+{
+    async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
+        time::sleep(self.injector.delay()).await;
+
+        match self.injector.fault(msg.len()) {
+            Fault::None => self.sender.send(msg).await,
+            Fault::Drop => Ok(()),
+            Fault::Truncate(len) => self.sender.send(&msg[..len]).await,
+            Fault::Disconnect => Err(injected_disconnect()),
+        }
+    }
+}
+
+/// Wrapper for an AsyncMsgRecv object that injects configurable faults
+pub struct FaultyMsgReceiver<R> {
+    receiver: R,
+    injector: FaultInjector,
+}
+
+impl<R> FaultyMsgReceiver<R>
+where
+    R: AsyncMsgRecv,
+{
+    /// Constructs a new FaultyMsgReceiver
+    pub fn new(receiver: R, config: FaultConfig) -> Self {
+        Self {
+            receiver,
+            injector: FaultInjector::new(config),
+        }
+    }
+}
+
+impl<R> AsyncMsgRecv for FaultyMsgReceiver<R>
+where
+    R: AsyncMsgRecv,
+{
+    async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+        loop {
+            let mut msg = self.receiver.recv().await?;
+
+            time::sleep(self.injector.delay()).await;
+
+            match self.injector.fault(msg.len()) {
+                Fault::None => return Ok(msg),
+                Fault::Drop => continue, // Wait for next message
+                Fault::Truncate(len) => {
+                    msg.truncate(len);
+                    return Ok(msg);
+                }
+                Fault::Disconnect => return Err(injected_disconnect()),
+            }
+        }
+    }
+}
+
+fn injected_disconnect() -> CommError {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "injected disconnection").into()
+}
+
+/// Small seedable pseudo-random number generator
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Generates a number in the [0, 1) range
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::comm::encaps::{LenU64EncapsMsgReceiver, LenU64EncapsMsgSender};
+
+    #[test]
+    fn fault_generation_deterministic() {
+        let config = FaultConfig::new(42).drop_rate(0.3).truncate_rate(0.3);
+        let mut a = FaultInjector::new(config.clone());
+        let mut b = FaultInjector::new(config);
+
+        for _ in 0..100 {
+            assert_eq!(a.fault(100), b.fault(100));
+        }
+    }
+
+    #[test]
+    fn fault_delay_inverted_range() {
+        let mut config = FaultConfig::new(0);
+        config.min_delay = Duration::from_millis(20);
+        config.max_delay = Duration::from_millis(10);
+        let mut injector = FaultInjector::new(config);
+
+        for _ in 0..10 {
+            assert_eq!(injector.delay(), Duration::from_millis(20));
+        }
+    }
+
+    #[tokio::test]
+    async fn faulty_disconnect() {
+        let (a, b) = duplex(1024);
+        let mut sender = FaultyMsgSender::new(
+            LenU64EncapsMsgSender::new(a),
+            FaultConfig::new(0).disconnect_after(2),
+        );
+        let mut receiver = LenU64EncapsMsgReceiver::new(b);
+
+        sender.send(b"one").await.unwrap();
+        sender.send(b"two").await.unwrap();
+        let err = sender.send(b"three").await.unwrap_err();
+        assert!(matches!(err, CommError::Io(e) if e.kind() == io::ErrorKind::ConnectionAborted));
+        sender.send(b"four").await.unwrap_err();
+
+        assert_eq!(receiver.recv().await.unwrap(), b"one");
+        assert_eq!(receiver.recv().await.unwrap(), b"two");
+    }
+
+    #[tokio::test]
+    async fn faulty_drop_all() {
+        let (a, b) = duplex(1024);
+        let mut sender = LenU64EncapsMsgSender::new(a);
+        let mut receiver = FaultyMsgReceiver::new(
+            LenU64EncapsMsgReceiver::new(b),
+            FaultConfig::new(0).drop_rate(1.0),
+        );
+
+        sender.send(b"dropped").await.unwrap();
+        drop(sender);
+
+        // All messages are dropped, so the receiver only sees the end of the stream
+        receiver.recv().await.unwrap_err();
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io,
+    sync::{self, Notify},
+    time,
+};
+
+use super::{
+    encaps::{AsyncMsgRecv, AsyncMsgSend},
+    error::CommError,
+};
+
+/// Frame carrying an application message
+const FRAME_DATA: u8 = 0x00;
+
+/// Frame returning credit for consumed bytes, followed by their count (u64 BE)
+const FRAME_CREDIT: u8 = 0x01;
+
+/// Frame asking the peer to return credit for everything it consumed
+const FRAME_CREDIT_REQUEST: u8 = 0x02;
+
+/// Options of a flow-controlled connection
+#[derive(Debug, Clone, Copy)]
+pub struct FlowOptions {
+    pub window: u64,       // Bytes sent but not yet consumed by the peer
+    pub credit_batch: u64, // Consumed bytes to collect before returning credit
+}
+
+impl Default for FlowOptions {
+    fn default() -> Self {
+        Self {
+            window: 1024 * 1024,
+            credit_batch: 256 * 1024,
+        }
+    }
+}
+
+impl FlowOptions {
+    pub fn window(mut self, val: u64) -> Self {
+        self.window = val;
+        self
+    }
+
+    pub fn credit_batch(mut self, val: u64) -> Self {
+        self.credit_batch = val;
+        self
+    }
+}
+
+/// Send window of a connection
+#[derive(Default)]
+struct Window {
+    in_flight: u64,  // Bytes sent without credit returned
+    requested: bool, // Credit was requested since the last grant
+    closed: bool,    // Receiving half failed, no more credit will arrive
+}
+
+/// State shared by the two halves of a flow-controlled connection
+struct Shared<S> {
+    sender: sync::Mutex<S>, // Both halves send, data and credit respectively
+    window: Mutex<Window>,
+    credited: Notify,
+}
+
+impl<S: AsyncMsgSend> Shared<S> {
+    async fn send_frame(&self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(1 + payload.len());
+        frame.push(kind);
+        frame.extend_from_slice(payload);
+        Ok(self.sender.lock().await.send(&frame).await?)
+    }
+}
+
+/// Sending half of a flow-controlled connection
+/// At most a window of bytes is in flight, further messages wait until the
+/// peer consumes earlier ones. A message larger than the window is sent
+/// once nothing else is in flight
+pub struct FlowSender<S> {
+    shared: Arc<Shared<S>>,
+    window: u64,
+}
+
+impl<S: AsyncMsgSend> FlowSender<S> {
+    /// Returns the number of bytes sent but not yet consumed by the peer
+    pub fn in_flight(&self) -> u64 {
+        self.shared.window.lock().unwrap().in_flight
+    }
+
+    /// Sends a message only if the window has room for it
+    /// Fails with WouldBlock otherwise, so callers can shed load instead of waiting
+    pub async fn try_send(&mut self, msg: &[u8]) -> io::Result<()> {
+        if !self.try_reserve(msg.len() as u64).await? {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "flow control window full",
+            ));
+        }
+        self.shared.send_frame(FRAME_DATA, msg).await
+    }
+
+    /// Sends a message, failing with TimedOut if the window has no room for it in time
+    pub async fn send_timeout(&mut self, msg: &[u8], timeout: Duration) -> io::Result<()> {
+        time::timeout(timeout, self.reserve(msg.len() as u64))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "flow control window full"))??;
+        self.shared.send_frame(FRAME_DATA, msg).await
+    }
+
+    /// Waits for room in the window and reserves it for a message
+    async fn reserve(&self, len: u64) -> io::Result<()> {
+        loop {
+            // Created before checking, so no credit is missed
+            let credited = self.shared.credited.notified();
+            if self.try_reserve(len).await? {
+                return Ok(());
+            }
+            credited.await;
+        }
+    }
+
+    /// Reserves room in the window for a message, returns false if there is none
+    /// The first time the window fills up, the peer is asked for credit
+    async fn try_reserve(&self, len: u64) -> io::Result<bool> {
+        let request = {
+            let mut window = self.shared.window.lock().unwrap();
+            if window.closed {
+                return Err(flow_closed());
+            }
+            if window.in_flight == 0 || window.in_flight.saturating_add(len) <= self.window {
+                window.in_flight += len;
+                return Ok(true);
+            }
+            !mem::replace(&mut window.requested, true)
+        };
+
+        if request {
+            self.shared.send_frame(FRAME_CREDIT_REQUEST, &[]).await?;
+        }
+        Ok(false)
+    }
+}
+
+impl<S: AsyncMsgSend> AsyncMsgSend for FlowSender<S> {
+    /// Sends a message, waiting while the window is full
+    async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
+        self.reserve(msg.len() as u64).await?;
+        Ok(self.shared.send_frame(FRAME_DATA, msg).await?)
+    }
+}
+
+/// Receiving half of a flow-controlled connection
+/// Credit returned by the peer is processed while receiving, so the receiving
+/// half must be polled for the sending half to make progress
+pub struct FlowReceiver<S, R> {
+    receiver: R,
+    shared: Arc<Shared<S>>,
+    consumed: u64, // Bytes received without credit returned
+    credit_batch: u64,
+}
+
+impl<S: AsyncMsgSend, R: AsyncMsgRecv> FlowReceiver<S, R> {
+    /// Returns credit for the consumed bytes to the peer
+    async fn return_credit(&mut self) -> io::Result<()> {
+        let consumed = mem::take(&mut self.consumed);
+        self.shared
+            .send_frame(FRAME_CREDIT, &consumed.to_be_bytes())
+            .await
+    }
+
+    /// Applies credit returned by the peer to the send window
+    fn grant(&self, payload: &[u8]) -> io::Result<()> {
+        let credit = <[u8; 8]>::try_from(payload).map_err(|_| invalid_frame())?;
+        let mut window = self.shared.window.lock().unwrap();
+        window.in_flight = window.in_flight.saturating_sub(u64::from_be_bytes(credit));
+        window.requested = false;
+        self.shared.credited.notify_waiters();
+        Ok(())
+    }
+}
+
+impl<S: AsyncMsgSend, R: AsyncMsgRecv> AsyncMsgRecv for FlowReceiver<S, R> {
+    async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+        loop {
+            let mut frame = match self.receiver.recv().await {
+                Ok(frame) => frame,
+                Err(e) => {
+                    // Wake up senders waiting for credit
+                    self.shared.window.lock().unwrap().closed = true;
+                    self.shared.credited.notify_waiters();
+                    return Err(e);
+                }
+            };
+
+            match frame.first() {
+                Some(&FRAME_DATA) => {
+                    frame.remove(0);
+                    self.consumed += frame.len() as u64;
+                    if self.consumed >= self.credit_batch {
+                        self.return_credit().await?;
+                    }
+                    return Ok(frame);
+                }
+                Some(&FRAME_CREDIT) => self.grant(&frame[1..])?,
+                Some(&FRAME_CREDIT_REQUEST) => {
+                    if self.consumed > 0 {
+                        self.return_credit().await?;
+                    }
+                }
+                _ => return Err(invalid_frame()),
+            }
+        }
+    }
+}
+
+/// Error of a frame which isn't valid flow control framing
+fn invalid_frame() -> CommError {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid flow control frame").into()
+}
+
+/// Error of a sender whose receiving half failed
+fn flow_closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "flow-controlled connection closed",
+    )
+}
+
+/// Adds flow control to a message connection
+/// Both peers must use flow control, as credit is exchanged in-band
+pub fn flow_control<S, R>(
+    sender: S,
+    receiver: R,
+    options: &FlowOptions,
+) -> (FlowSender<S>, FlowReceiver<S, R>)
+where
+    S: AsyncMsgSend,
+    R: AsyncMsgRecv,
+{
+    let shared = Arc::new(Shared {
+        sender: sync::Mutex::new(sender),
+        window: Mutex::new(Window::default()),
+        credited: Notify::new(),
+    });
+
+    (
+        FlowSender {
+            shared: shared.clone(),
+            window: options.window.max(1),
+        },
+        FlowReceiver {
+            receiver,
+            shared,
+            consumed: 0,
+            credit_batch: options.credit_batch.max(1),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    struct ChanSender(mpsc::UnboundedSender<Vec<u8>>);
+
+    impl AsyncMsgSend for ChanSender {
+        async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
+            self.0
+                .send(msg.to_vec())
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe).into())
+        }
+    }
+
+    struct ChanReceiver(mpsc::UnboundedReceiver<Vec<u8>>);
+
+    impl AsyncMsgRecv for ChanReceiver {
+        async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+            self.0
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+        }
+    }
+
+    #[tokio::test]
+    async fn flow_window() {
+        let (a_tx, b_rx) = mpsc::unbounded_channel();
+        let (b_tx, a_rx) = mpsc::unbounded_channel();
+        let options = FlowOptions::default().window(250).credit_batch(1000);
+        let (mut a_sender, mut a_receiver) =
+            flow_control(ChanSender(a_tx), ChanReceiver(a_rx), &options);
+        let (_b_sender, mut b_receiver) =
+            flow_control(ChanSender(b_tx), ChanReceiver(b_rx), &options);
+
+        // The window fills up
+        let msg = [7; 100];
+        a_sender.send(&msg).await.unwrap();
+        a_sender.try_send(&msg).await.unwrap();
+        let err = a_sender.try_send(&msg).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let err = a_sender
+            .send_timeout(&msg, Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(a_sender.in_flight(), 200);
+
+        // Consuming the messages returns credit, even below the batch size
+        assert_eq!(b_receiver.recv().await.unwrap(), msg);
+        assert_eq!(b_receiver.recv().await.unwrap(), msg);
+        let (sent, received) = tokio::join!(
+            async {
+                tokio::select! {
+                    res = a_sender.send(&msg) => res,
+                    _ = a_receiver.recv() => unreachable!(),
+                }
+            },
+            b_receiver.recv()
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap(), msg);
+        assert_eq!(a_sender.in_flight(), 100);
+
+        // Messages larger than the window wait for everything in flight
+        let big = [0; 1000];
+        let (sent, received) = tokio::join!(
+            async {
+                tokio::select! {
+                    res = a_sender.send(&big) => res,
+                    _ = a_receiver.recv() => unreachable!(),
+                }
+            },
+            b_receiver.recv()
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap(), big);
+        assert_eq!(a_sender.in_flight(), 1000);
+    }
+}
@@ -0,0 +1,99 @@
+use std::{error::Error, fmt, future::Future, time::Duration};
+
+use tokio::{io, time};
+
+/// Heartbeat settings of a connection
+/// Peers send a heartbeat when idle for an interval, and consider the
+/// connection lost when nothing is received for the timeout
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration, // Idle time before sending a heartbeat
+    pub timeout: Duration,  // Silence before the peer is considered dead
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(20),
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    pub fn interval(mut self, val: Duration) -> Self {
+        self.interval = val;
+        self
+    }
+
+    pub fn timeout(mut self, val: Duration) -> Self {
+        self.timeout = val;
+        self
+    }
+}
+
+/// Error produced when nothing is received from the peer within the timeout
+/// Carried inside an io::Error of kind TimedOut
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLost {
+    pub timeout: Duration,
+}
+
+impl ConnectionLost {
+    /// Extracts a ConnectionLost from an io::Error, if it carries one
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for ConnectionLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connection lost: nothing received for {}ms",
+            self.timeout.as_millis()
+        )
+    }
+}
+
+impl Error for ConnectionLost {}
+
+impl From<ConnectionLost> for io::Error {
+    fn from(err: ConnectionLost) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, err)
+    }
+}
+
+/// Awaits a receive, failing with ConnectionLost if it takes longer than the timeout
+pub async fn recv_timeout<T>(
+    timeout: Duration,
+    recv: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    time::timeout(timeout, recv)
+        .await
+        .map_err(|_| ConnectionLost { timeout })?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn recv_timeout_lost() {
+        let timeout = Duration::from_secs(20);
+
+        let res = recv_timeout(timeout, async { Ok(42) }).await;
+        assert_eq!(res.unwrap(), 42);
+
+        let err = recv_timeout(timeout, future::pending::<io::Result<()>>())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            ConnectionLost::from_io(&err),
+            Some(&ConnectionLost { timeout })
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    env,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Once,
+    },
+};
+
+/// Environment variable which enables hexdumps. Its value is the maximum number
+/// of bytes dumped per message
+pub const HEXDUMP_ENV: &str = "POMEGRANATE_HEXDUMP";
+
+/// Number of bytes dumped when the limit is not specified
+pub const DEFAULT_HEXDUMP_LEN: usize = 64;
+
+// Maximum number of bytes dumped per message, 0 = disabled
+static HEXDUMP_LEN: AtomicUsize = AtomicUsize::new(0);
+static HEXDUMP_INIT: Once = Once::new();
+
+/// Loads the hexdump setting from the environment, only the first time it's called
+fn init_from_env() {
+    HEXDUMP_INIT.call_once(|| {
+        if let Some(val) = env::var_os(HEXDUMP_ENV) {
+            let len = val
+                .to_string_lossy()
+                .parse::<usize>()
+                .unwrap_or(DEFAULT_HEXDUMP_LEN);
+            HEXDUMP_LEN.store(len, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Enables or disables hexdumps at runtime, overriding the environment
+/// max_len = 0 disables hexdumps
+pub fn set_hexdump_len(max_len: usize) {
+    init_from_env();
+    HEXDUMP_LEN.store(max_len, Ordering::Relaxed);
+}
+
+/// Returns the maximum number of bytes to dump, or None if hexdumps are disabled
+pub fn hexdump_len() -> Option<usize> {
+    init_from_env();
+    match HEXDUMP_LEN.load(Ordering::Relaxed) {
+        0 => None,
+        len => Some(len),
+    }
+}
+
+/// Formats up to max_len bytes of data as space separated hex
+pub fn format_hexdump(data: &[u8], max_len: usize) -> String {
+    let mut res = String::with_capacity(data.len().min(max_len) * 3 + 8);
+
+    for (i, byte) in data.iter().take(max_len).enumerate() {
+        if i != 0 {
+            res.push(' ');
+        }
+        let _ = write!(res, "{:02x}", byte);
+    }
+
+    if data.len() > max_len {
+        res.push_str(" ...");
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexdump_format() {
+        assert_eq!(format_hexdump(&[], 4), "");
+        assert_eq!(format_hexdump(&[0x00, 0xAB, 0x10], 4), "00 ab 10");
+        assert_eq!(format_hexdump(&[0x00, 0xAB, 0x10], 2), "00 ab ...");
+    }
+}
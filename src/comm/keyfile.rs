@@ -0,0 +1,264 @@
+use std::{fs, path::Path};
+
+use aes_gcm_siv::{
+    aead::{generic_array::GenericArray, rand_core::RngCore, Aead, OsRng},
+    Aes256GcmSiv, KeyInit,
+};
+use hmac::{Hmac, Mac};
+use log::info;
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey,
+    pkcs8::{
+        der::pem::{self, LineEnding},
+        DecodePrivateKey, EncodePrivateKey,
+    },
+    RsaPrivateKey, RsaPublicKey,
+};
+use sha2::Sha256;
+use tokio::io;
+
+use super::crypto::{CryptoError, RsaKeyPair};
+
+/// PEM label of a private key encrypted with a passphrase
+const ENCRYPTED_LABEL: &str = "POMEGRANATE ENCRYPTED PRIVATE KEY";
+
+/// Version of the encrypted private key format
+const ENCRYPTED_VERSION: u8 = 1;
+
+/// PBKDF2-HMAC-SHA256 iterations deriving the key encryption key from a passphrase
+const KDF_ITERATIONS: u32 = 600_000;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Length of the header of an encrypted private key: version, salt, iterations and nonce
+const ENCRYPTED_HEADER_LEN: usize = 1 + SALT_LEN + 4 + NONCE_LEN;
+
+impl RsaKeyPair {
+    /// Loads a key pair from a private key file
+    /// Accepts PKCS#8 and PKCS#1 keys, PEM or DER encoded
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let private = match pem::decode_vec(&bytes) {
+            Ok((ENCRYPTED_LABEL, _)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "private key is encrypted, a passphrase is required",
+                ))
+            }
+            Ok((_, der)) => parse_private_der(&der)?,
+            Err(_) => parse_private_der(&bytes)?,
+        };
+        Ok(Self::from_private(private))
+    }
+
+    /// Loads a key pair from a private key file encrypted with save_encrypted
+    pub fn load_encrypted(path: impl AsRef<Path>, passphrase: &str) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let data = match pem::decode_vec(&bytes) {
+            Ok((ENCRYPTED_LABEL, data)) => data,
+            _ => return Err(invalid_key()),
+        };
+        if data.len() < ENCRYPTED_HEADER_LEN || data[0] != ENCRYPTED_VERSION {
+            return Err(invalid_key());
+        }
+
+        let (salt, rest) = data[1..].split_at(SALT_LEN);
+        let (iterations, rest) = rest.split_at(4);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let iterations = u32::from_be_bytes(iterations.try_into().unwrap());
+
+        let key = derive_key(passphrase, salt, iterations);
+        let der = Aes256GcmSiv::new(&GenericArray::from(key))
+            .decrypt(GenericArray::from_slice(nonce), ciphertext)
+            .map_err(|_| CryptoError::Decryption)?;
+        Ok(Self::from_private(parse_private_der(&der)?))
+    }
+
+    /// Saves the private key to a file, as PKCS#8 DER if the extension is .der,
+    /// PKCS#8 PEM otherwise
+    /// On Unix the file is only readable by its owner
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let der = self.private.to_pkcs8_der().map_err(|_| encode_error())?;
+
+        if path.extension().is_some_and(|ext| ext == "der") {
+            write_private(path, der.as_bytes())
+        } else {
+            let pem = der
+                .to_pem("PRIVATE KEY", LineEnding::LF)
+                .map_err(|_| encode_error())?;
+            write_private(path, pem.as_bytes())
+        }
+    }
+
+    /// Saves the private key to a PEM file, encrypted with a key derived from a passphrase
+    pub fn save_encrypted(&self, path: impl AsRef<Path>, passphrase: &str) -> io::Result<()> {
+        let der = self.private.to_pkcs8_der().map_err(|_| encode_error())?;
+
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let key = derive_key(passphrase, &salt, KDF_ITERATIONS);
+        let ciphertext = Aes256GcmSiv::new(&GenericArray::from(key))
+            .encrypt(GenericArray::from_slice(&nonce), der.as_bytes())
+            .map_err(|_| CryptoError::Encryption)?;
+
+        let mut data = Vec::with_capacity(ENCRYPTED_HEADER_LEN + ciphertext.len());
+        data.push(ENCRYPTED_VERSION);
+        data.extend_from_slice(&salt);
+        data.extend_from_slice(&KDF_ITERATIONS.to_be_bytes());
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        let pem = pem::encode_string(ENCRYPTED_LABEL, LineEnding::LF, &data)
+            .map_err(|_| encode_error())?;
+        write_private(path.as_ref(), pem.as_bytes())
+    }
+
+    /// Loads the key pair from a file, generating and saving a new one if it doesn't exist
+    /// Keeps the server's public key stable across restarts, so clients keep trusting it
+    pub fn load_or_generate(path: impl AsRef<Path>, passphrase: Option<&str>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let res = match passphrase {
+            Some(passphrase) => Self::load_encrypted(path, passphrase),
+            None => Self::load(path),
+        };
+        match res {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            res => return res,
+        }
+
+        info!("Generating a new key pair in {}", path.display());
+        let keypair = Self::generate()?;
+        match passphrase {
+            Some(passphrase) => keypair.save_encrypted(path, passphrase)?,
+            None => keypair.save(path)?,
+        }
+        Ok(keypair)
+    }
+
+    fn from_private(private: RsaPrivateKey) -> Self {
+        Self {
+            public: RsaPublicKey::from(&private),
+            private,
+        }
+    }
+}
+
+/// Parses a PKCS#8 or PKCS#1 DER encoded private key
+fn parse_private_der(der: &[u8]) -> io::Result<RsaPrivateKey> {
+    RsaPrivateKey::from_pkcs8_der(der)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_der(der))
+        .map_err(|_| invalid_key())
+}
+
+/// Derives a key encryption key from a passphrase with PBKDF2-HMAC-SHA256
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mac = <Hmac<Sha256> as Mac>::new_from_slice(passphrase.as_bytes())
+        .expect("HMAC accepts keys of any length");
+
+    // Single block, as the output is as long as the hash
+    let mut block = mac.clone();
+    block.update(salt);
+    block.update(&1u32.to_be_bytes());
+    let mut u: [u8; 32] = block.finalize().into_bytes().into();
+    let mut key = u;
+    for _ in 1..iterations {
+        let mut block = mac.clone();
+        block.update(&u);
+        u = block.finalize().into_bytes().into();
+        key.iter_mut().zip(&u).for_each(|(k, u)| *k ^= u);
+    }
+    key
+}
+
+/// Writes a private key file, only readable by its owner on Unix
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    std::io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+fn invalid_key() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid private key")
+}
+
+fn encode_error() -> io::Error {
+    io::Error::other("private key serialization error")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::comm::testutil::test_keypair;
+
+    #[test]
+    fn keyfile_roundtrip() {
+        let dir = env::temp_dir().join(format!("pomegranate-keyfile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let keypair = test_keypair();
+
+        for name in ["key.pem", "key.der"] {
+            let path = dir.join(name);
+            keypair.save(&path).unwrap();
+            assert_eq!(RsaKeyPair::load(&path).unwrap().public, keypair.public);
+        }
+
+        // PKCS#1 keys, as loaded by ClientIdentity, are accepted too
+        let path = dir.join("pkcs1.pem");
+        let pem = rsa::pkcs1::EncodeRsaPrivateKey::to_pkcs1_pem(&keypair.private, LineEnding::LF)
+            .unwrap();
+        fs::write(&path, pem.as_bytes()).unwrap();
+        assert_eq!(RsaKeyPair::load(&path).unwrap().public, keypair.public);
+
+        let path = dir.join("encrypted.pem");
+        keypair.save_encrypted(&path, "secret").unwrap();
+        let loaded = RsaKeyPair::load_encrypted(&path, "secret").unwrap();
+        assert_eq!(loaded.public, keypair.public);
+        let err = RsaKeyPair::load_encrypted(&path, "guess").err().unwrap();
+        assert_eq!(CryptoError::from_io(&err), Some(&CryptoError::Decryption));
+        assert_eq!(
+            RsaKeyPair::load(&path).err().unwrap().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keyfile_load_or_generate() {
+        let path = env::temp_dir().join(format!("pomegranate-key-{}.pem", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let generated = RsaKeyPair::load_or_generate(&path, None).unwrap();
+        let loaded = RsaKeyPair::load_or_generate(&path, None).unwrap();
+        assert_eq!(loaded.public, generated.public);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pbkdf2_vector() {
+        // RFC 7914 section 11
+        let key = derive_key("passwd", b"salt", 1);
+        assert_eq!(
+            key[..16],
+            [
+                0x55, 0xac, 0x04, 0x6e, 0x56, 0xe3, 0x08, 0x9f, 0xec, 0x16, 0x91, 0xc2, 0x25, 0x44,
+                0xb6, 0x05
+            ]
+        );
+    }
+}
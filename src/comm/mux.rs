@@ -0,0 +1,310 @@
+use std::{future::poll_fn, mem, task::Poll};
+
+use log::debug;
+use tokio::{io, sync::mpsc};
+
+use super::{
+    encaps::{AsyncMsgRecv, AsyncMsgSend, DEFAULT_MAX_MSG_LEN},
+    error::CommError,
+};
+
+/// Length of the header of a mux frame: channel ID (u16) and flags (u8)
+const FRAME_HEADER_LEN: usize = 3;
+
+/// Flag of a fragment followed by more fragments of the same message
+const FLAG_MORE: u8 = 0x01;
+
+/// Options of a multiplexed connection
+#[derive(Debug, Clone, Copy)]
+pub struct MuxOptions {
+    pub queue_len: usize, // Fragments (outgoing) or messages (incoming) queued per channel
+    pub max_fragment_len: usize, // Messages are split into fragments of at most this length
+    pub max_msg_len: u64, // Maximum length of a reassembled received message
+}
+
+impl Default for MuxOptions {
+    fn default() -> Self {
+        Self {
+            queue_len: 16,
+            max_fragment_len: 64 * 1024,
+            max_msg_len: DEFAULT_MAX_MSG_LEN,
+        }
+    }
+}
+
+impl MuxOptions {
+    pub fn queue_len(mut self, val: usize) -> Self {
+        self.queue_len = val;
+        self
+    }
+
+    pub fn max_fragment_len(mut self, val: usize) -> Self {
+        self.max_fragment_len = val;
+        self
+    }
+
+    pub fn max_msg_len(mut self, val: u64) -> Self {
+        self.max_msg_len = val;
+        self
+    }
+}
+
+/// Sending half of a multiplexed channel
+pub struct MuxSender {
+    id: u16,
+    tx: mpsc::Sender<Vec<u8>>,
+    max_fragment_len: usize,
+}
+
+impl AsyncMsgSend for MuxSender {
+    /// Queues a message, waiting while the channel's queue is full
+    async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
+        // Empty messages are sent as a single empty fragment
+        let mut start = 0;
+        loop {
+            let end = (start + self.max_fragment_len).min(msg.len());
+            let more = end < msg.len();
+
+            let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + end - start);
+            frame.extend_from_slice(&self.id.to_be_bytes());
+            frame.push(if more { FLAG_MORE } else { 0 });
+            frame.extend_from_slice(&msg[start..end]);
+            self.tx.send(frame).await.map_err(|_| mux_closed())?;
+
+            if !more {
+                return Ok(());
+            }
+            start = end;
+        }
+    }
+}
+
+/// Receiving half of a multiplexed channel
+pub struct MuxReceiver {
+    rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl AsyncMsgRecv for MuxReceiver {
+    async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+        self.rx.recv().await.ok_or_else(mux_closed)
+    }
+}
+
+/// Error of a channel whose multiplexed connection is gone
+fn mux_closed() -> CommError {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "multiplexed connection closed",
+    )
+    .into()
+}
+
+/// Incoming side of a channel
+struct Inbound {
+    tx: mpsc::Sender<Vec<u8>>,
+    partial: Vec<u8>, // Fragments of the message being received
+}
+
+/// Connection carrying several independent channels
+/// Messages are split into fragments, which are sent round-robin across the
+/// channels, so a large message on one channel doesn't hold up the others.
+/// Both peers must create the same number of channels
+pub struct Mux<S, R> {
+    sender: S,
+    receiver: R,
+    outbound: Vec<mpsc::Receiver<Vec<u8>>>,
+    inbound: Vec<Inbound>,
+    max_msg_len: u64,
+}
+
+/// Multiplexes channels over a message connection
+/// The channels carry messages while the returned Mux is running
+pub fn mux<S, R>(
+    sender: S,
+    receiver: R,
+    channels: u16,
+    options: &MuxOptions,
+) -> (Mux<S, R>, Vec<(MuxSender, MuxReceiver)>)
+where
+    S: AsyncMsgSend,
+    R: AsyncMsgRecv,
+{
+    let queue_len = options.queue_len.max(1);
+    let mut outbound = Vec::new();
+    let mut inbound = Vec::new();
+    let mut halves = Vec::new();
+
+    for id in 0..channels {
+        let (out_tx, out_rx) = mpsc::channel(queue_len);
+        let (in_tx, in_rx) = mpsc::channel(queue_len);
+        outbound.push(out_rx);
+        inbound.push(Inbound {
+            tx: in_tx,
+            partial: Vec::new(),
+        });
+        halves.push((
+            MuxSender {
+                id,
+                tx: out_tx,
+                max_fragment_len: options.max_fragment_len.max(1),
+            },
+            MuxReceiver { rx: in_rx },
+        ));
+    }
+
+    let mux = Mux {
+        sender,
+        receiver,
+        outbound,
+        inbound,
+        max_msg_len: options.max_msg_len,
+    };
+    (mux, halves)
+}
+
+impl<S, R> Mux<S, R>
+where
+    S: AsyncMsgSend,
+    R: AsyncMsgRecv,
+{
+    /// Sends and receives the channels' messages until the connection fails
+    /// A channel whose incoming queue is full holds up receiving on all channels
+    pub async fn run(self) -> CommError {
+        let Mux {
+            mut sender,
+            mut receiver,
+            mut outbound,
+            mut inbound,
+            max_msg_len,
+        } = self;
+
+        let send_loop = async {
+            let mut next = 0;
+            loop {
+                // Take a fragment from the next channel with one queued
+                let frame = poll_fn(|cx| {
+                    let len = outbound.len();
+                    for i in 0..len {
+                        let idx = (next + i) % len;
+                        if let Poll::Ready(Some(frame)) = outbound[idx].poll_recv(cx) {
+                            next = idx + 1;
+                            return Poll::Ready(frame);
+                        }
+                    }
+                    Poll::Pending
+                })
+                .await;
+
+                if let Err(e) = sender.send(&frame).await {
+                    return e;
+                }
+            }
+        };
+
+        let recv_loop = async {
+            loop {
+                let frame = match receiver.recv().await {
+                    Ok(frame) => frame,
+                    Err(e) => return e,
+                };
+                if let Err(e) = deliver(&mut inbound, &frame, max_msg_len).await {
+                    return e.into();
+                }
+            }
+        };
+
+        tokio::select! {
+            e = send_loop => e,
+            e = recv_loop => e,
+        }
+    }
+}
+
+/// Adds a received fragment to its channel, queueing the message once complete
+async fn deliver(inbound: &mut [Inbound], frame: &[u8], max_msg_len: u64) -> io::Result<()> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid mux frame");
+    let (header, fragment) = frame
+        .split_first_chunk::<FRAME_HEADER_LEN>()
+        .ok_or_else(invalid)?;
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    let channel = inbound.get_mut(id as usize).ok_or_else(invalid)?;
+
+    if (channel.partial.len() + fragment.len()) as u64 > max_msg_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "mux message too long",
+        ));
+    }
+    channel.partial.extend_from_slice(fragment);
+    if header[2] & FLAG_MORE == 0 {
+        let msg = mem::take(&mut channel.partial);
+        // Messages for channels nobody receives on are dropped
+        if channel.tx.send(msg).await.is_err() {
+            debug!("Dropping message for closed mux channel {}", id);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future;
+
+    use super::*;
+    use crate::comm::testutil::{VecMsgReceiver, VecMsgSender};
+
+    /// Sender passing frames to the test
+    struct ChanSender(mpsc::UnboundedSender<Vec<u8>>);
+
+    impl AsyncMsgSend for ChanSender {
+        async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
+            let _ = self.0.send(msg.to_vec());
+            Ok(())
+        }
+    }
+
+    /// Receiver which never receives anything
+    struct IdleReceiver;
+
+    impl AsyncMsgRecv for IdleReceiver {
+        async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+            future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn mux_interleaves_channels() {
+        let options = MuxOptions::default().queue_len(8).max_fragment_len(4);
+        let (frames_tx, mut frames_rx) = mpsc::unbounded_channel();
+        let (mux_a, mut channels) = mux(ChanSender(frames_tx), IdleReceiver, 3, &options);
+
+        let big: Vec<u8> = (0..12).collect();
+        channels[0].0.send(&big).await.unwrap();
+        channels[1].0.send(b"hi").await.unwrap();
+        channels[2].0.send(b"").await.unwrap();
+
+        // The small messages don't wait for all fragments of the big one
+        let mut frames = Vec::new();
+        tokio::select! {
+            _ = mux_a.run() => unreachable!(),
+            _ = async {
+                while frames.len() < 5 {
+                    frames.push(frames_rx.recv().await.unwrap());
+                }
+            } => {}
+        }
+        let ids: Vec<_> = frames.iter().map(|frame| frame[1]).collect();
+        assert_eq!(ids, [0, 1, 2, 0, 0]);
+
+        // Fragments are reassembled on the other side
+        let receiver = VecMsgReceiver::new(frames);
+        let (mux_b, mut channels) = mux(VecMsgSender(Vec::new()), receiver, 3, &options);
+        let err = mux_b.run().await;
+        assert!(matches!(err, CommError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof));
+        assert_eq!(channels[0].1.recv().await.unwrap(), big);
+        assert_eq!(channels[1].1.recv().await.unwrap(), b"hi");
+        assert_eq!(channels[2].1.recv().await.unwrap(), b"");
+        let err = channels[1].1.recv().await.unwrap_err();
+        assert!(matches!(err, CommError::Io(e) if e.kind() == io::ErrorKind::ConnectionAborted));
+    }
+}
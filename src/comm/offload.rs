@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use tokio::{io, sync::Semaphore, task};
+
+/// Frames at least this long are processed off the connection task by default
+pub const DEFAULT_OFFLOAD_THRESHOLD: usize = 64 * 1024;
+
+/// Runs the decryption and decoding of large frames on the blocking thread pool
+/// Small frames are cheaper to process inline than to hand over to another thread.
+/// Clones share the parallelism limit, so one Offload can serve all connections
+#[derive(Debug, Clone)]
+pub struct Offload {
+    threshold: usize,        // Minimum frame length to offload
+    permits: Arc<Semaphore>, // Frames processed in parallel
+}
+
+impl Offload {
+    /// Offloads frames of at least threshold bytes, processing up to max_parallel at once
+    pub fn new(threshold: usize, max_parallel: usize) -> Self {
+        Self {
+            threshold,
+            permits: Arc::new(Semaphore::new(max_parallel.max(1))),
+        }
+    }
+
+    /// Runs f on the blocking pool if len reaches the threshold, inline otherwise
+    /// Waits for a free slot if max_parallel frames are already being processed
+    pub async fn run<T, F>(&self, len: usize, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        if len < self.threshold {
+            return f();
+        }
+
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("offload semaphore is never closed");
+        task::spawn_blocking(f).await.map_err(io::Error::other)?
+    }
+}
+
+impl Default for Offload {
+    fn default() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::new(DEFAULT_OFFLOAD_THRESHOLD, cpus)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use tokio::task::JoinSet;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn offload_bounded() {
+        let offload = Offload::new(10, 2);
+        let caller = thread::current().id();
+
+        // Small frames stay on the calling thread
+        let small = offload.run(9, move || Ok(thread::current().id())).await;
+        assert_eq!(small.unwrap(), caller);
+        let large = offload.run(10, move || Ok(thread::current().id())).await;
+        assert_ne!(large.unwrap(), caller);
+
+        // No more than max_parallel frames at once
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let mut jobs = JoinSet::new();
+        for _ in 0..8 {
+            let (offload, running, peak) = (offload.clone(), running.clone(), peak.clone());
+            jobs.spawn(async move {
+                offload
+                    .run(100, move || {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(20));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        Ok(())
+                    })
+                    .await
+            });
+        }
+        while let Some(res) = jobs.join_next().await {
+            res.unwrap().unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+
+        let err = offload
+            .run(100, || Err::<(), _>(io::Error::other("bad frame")))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "bad frame");
+    }
+}
@@ -0,0 +1,460 @@
+pub mod spec;
+
+use std::{error::Error, fmt, marker::PhantomData, mem};
+
+use bytes::BytesMut;
+use log::debug;
+use rkyv::{
+    de::deserializers::SharedDeserializeMap, ser::serializers::AllocSerializer,
+    validation::validators::DefaultValidator, AlignedVec, Archive, CheckBytes, Deserialize,
+    Serialize,
+};
+use sha2::{Digest, Sha256};
+use tokio::io;
+
+use super::{
+    encaps::{AsyncMsgRecv, AsyncMsgSend},
+    offload::Offload,
+};
+
+/// Scratch space size used when serializing messages
+const SCRATCH_SPACE: usize = 256;
+
+/// Version of the message protocol, checked during onboarding
+/// Bump it with every change to the layout of any message
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Kind of frame, first byte of every protocol frame
+/// Kinds with the high bit set are critical: a receiver that doesn't know one
+/// can't safely continue and closes the connection. Unknown kinds without it
+/// are skipped, so newer peers can send optional frames
+pub const FRAME_CRITICAL: u8 = 0x80;
+
+/// Frame carrying a protocol message
+pub const FRAME_MESSAGE: u8 = FRAME_CRITICAL;
+
+/// Frame opening every client connection, followed by the client's
+/// PROTOCOL_VERSION as a u32 in big endian. Its layout never changes, so
+/// peers of other versions are told apart before decoding any message
+pub const FRAME_VERSION: u8 = FRAME_CRITICAL | 0x01;
+
+/// Length of a FRAME_VERSION frame
+pub const FRAME_VERSION_LEN: usize = 1 + 4;
+
+/// Introduction sent by a worker right after the encrypted channel is set up
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub struct WorkerHello {
+    pub worker_id: String, // Stable worker name, defaults to the hostname
+    pub hostname: String,
+    pub cpus: u32,
+    pub tags: Vec<String>,       // Free-form capability labels
+    pub perf_score: u32,         // Single core benchmark score, 0 if not measured
+    pub cluster: Option<String>, // Name of the cluster the worker is configured to join
+    pub zone: Option<String>,    // Site or availability zone of the worker, for placement
+}
+
+/// Assignment given by the coordinator to an accepted worker
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub struct WorkerAssignment {
+    pub id: u64,                 // Connection ID assigned by the coordinator
+    pub cluster: Option<String>, // Name of the coordinator's cluster
+}
+
+/// Reason for the coordinator rejecting a worker during onboarding
+/// Carried inside an io::Error of kind InvalidData on the client
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub enum OnboardingReject {
+    VersionMismatch { supported: u32 }, // Worker speaks a different protocol version
+    Malformed,                          // Worker didn't introduce itself
+    PendingApproval,                    // Worker identity waits for an operator's approval
+    Unauthorized,                       // Worker identity rejected by an operator
+    ClusterMismatch,                    // Worker configured for another cluster
+}
+
+impl OnboardingReject {
+    /// Extracts an OnboardingReject from an io::Error, if it carries one
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for OnboardingReject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnboardingReject::VersionMismatch { supported } => write!(
+                f,
+                "protocol version mismatch, coordinator supports version {}",
+                supported
+            ),
+            OnboardingReject::Malformed => write!(f, "malformed worker introduction"),
+            OnboardingReject::PendingApproval => write!(f, "worker waiting for operator approval"),
+            OnboardingReject::Unauthorized => write!(f, "worker not authorized"),
+            OnboardingReject::ClusterMismatch => write!(f, "worker belongs to another cluster"),
+        }
+    }
+}
+
+impl Error for OnboardingReject {}
+
+impl From<OnboardingReject> for io::Error {
+    fn from(err: OnboardingReject) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Task sent to a worker for execution
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub struct TaskAssignment {
+    pub task_id: u64,
+    pub kind: String, // Task type, selects the handler on the worker
+    pub payload: Vec<u8>,
+}
+
+/// Reference to a task result stored as an artifact by the coordinator
+/// Artifacts are content addressed, named after the hash of their data
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[archive(check_bytes)]
+pub struct ArtifactRef {
+    pub len: u64,
+    pub digest: [u8; 32], // SHA-256 of the data
+}
+
+impl ArtifactRef {
+    /// Returns the reference of an artifact with some data
+    pub fn of(data: &[u8]) -> Self {
+        Self {
+            len: data.len() as u64,
+            digest: Sha256::digest(data).into(),
+        }
+    }
+
+    /// Returns the name of the artifact, its hex encoded digest
+    pub fn name(&self) -> String {
+        self.digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Outcome of a task executed by a worker
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub enum TaskOutcome {
+    Success(Vec<u8>),      // Task result
+    Failure(String),       // Error description
+    Artifact(ArtifactRef), // Result too large to send inline, sent before in ResultChunk messages
+}
+
+/// State of a connected worker, as reported to admin clients
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub struct WorkerStatus {
+    pub id: u64,
+    pub addr: String,
+    pub hello: WorkerHello, // Introduction sent by the worker, with the latest benchmark score
+    pub last_seen_ms: u64,  // Time since the last message from the worker
+    pub running_tasks: Vec<u64>, // IDs of the tasks assigned to the worker
+}
+
+/// Unknown worker waiting for an operator to approve its identity
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub struct PendingWorker {
+    pub fingerprint: String, // Fingerprint of the identity proven by the worker
+    pub addr: String,
+    pub hello: WorkerHello, // Latest introduction sent by the worker
+    pub waiting_ms: u64,    // Time since the worker first tried to join
+}
+
+/// Snapshot of the cluster state, as reported to admin clients
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub struct ClusterStatus {
+    pub workers: Vec<WorkerStatus>, // Connected workers, ordered by ID
+    pub pending_tasks: u64,         // Tasks waiting for a free worker
+    pub pending_approval: Vec<PendingWorker>, // Unknown workers, ordered by fingerprint
+}
+
+/// Messages sent by the client to the coordinator
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub enum ClientMessage {
+    Hello(WorkerHello), // First message of every connection
+    Data(Vec<u8>),      // Application payload
+    Heartbeat,          // Keeps the connection alive when idle
+    TaskResult { task_id: u64, outcome: TaskOutcome },
+    BenchmarkResult { score: u32 }, // Reply to a benchmark request
+    StatusRequest, // Ask for the cluster status, instead of Hello for admin clients
+    Approval { fingerprint: String, approve: bool }, // Decide on a pending worker (admin clients)
+    ResultChunk { task_id: u64, data: Vec<u8> }, // Part of a result too large to send inline
+}
+
+/// Messages sent by the coordinator to the client
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub enum CoordinatorMessage {
+    Welcome(WorkerAssignment),  // Worker accepted
+    Rejected(OnboardingReject), // Worker rejected, the connection is closed
+    Data(Vec<u8>),              // Application payload
+    Heartbeat,                  // Keeps the connection alive when idle
+    Task(TaskAssignment),       // Task to execute
+    Benchmark,                  // Rerun the benchmark and report the score
+    Status(ClusterStatus),      // Reply to a status or approval request
+}
+
+/// Error produced when a received message can't be decoded
+/// Carried inside an io::Error of kind InvalidData
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    Malformed,        // Message failed validation
+    Serialization,    // Message couldn't be serialized
+    UnknownFrame(u8), // Critical frame of a kind unknown to this version
+}
+
+impl ProtocolError {
+    /// Extracts a ProtocolError from an io::Error, if it carries one
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Malformed => write!(f, "malformed protocol message"),
+            ProtocolError::Serialization => write!(f, "message serialization error"),
+            ProtocolError::UnknownFrame(kind) => {
+                write!(f, "unknown critical frame kind {:#04x}", kind)
+            }
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+impl From<ProtocolError> for io::Error {
+    fn from(err: ProtocolError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Serializes a protocol message
+pub fn encode_message<T>(msg: &T) -> io::Result<AlignedVec>
+where
+    T: Serialize<AllocSerializer<SCRATCH_SPACE>>,
+{
+    rkyv::to_bytes::<_, SCRATCH_SPACE>(msg).map_err(|_| ProtocolError::Serialization.into())
+}
+
+/// Validates and deserializes a protocol message
+pub fn decode_message<T>(bytes: &[u8]) -> io::Result<T>
+where
+    T: Archive,
+    for<'a> T::Archived: CheckBytes<DefaultValidator<'a>> + Deserialize<T, SharedDeserializeMap>,
+{
+    // Received buffers carry no alignment guarantee
+    let mut aligned = AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(bytes);
+
+    rkyv::from_bytes::<T>(&aligned).map_err(|_| ProtocolError::Malformed.into())
+}
+
+/// Validates and deserializes a protocol frame
+/// Returns None for unknown frames which can be skipped
+pub fn decode_frame<T>(bytes: &[u8]) -> io::Result<Option<T>>
+where
+    T: Archive,
+    for<'a> T::Archived: CheckBytes<DefaultValidator<'a>> + Deserialize<T, SharedDeserializeMap>,
+{
+    match bytes.split_first() {
+        Some((&FRAME_MESSAGE, msg)) => decode_message(msg).map(Some),
+        Some((&kind, _)) if kind & FRAME_CRITICAL != 0 => {
+            Err(ProtocolError::UnknownFrame(kind).into())
+        }
+        Some(_) => Ok(None),
+        None => Err(ProtocolError::Malformed.into()),
+    }
+}
+
+/// Wrapper for an AsyncMsgSend object that sends typed protocol messages
+pub struct TypedMsgSender<T, S> {
+    sender: S,
+    _msg: PhantomData<fn(&T)>,
+}
+
+impl<T, S> TypedMsgSender<T, S>
+where
+    T: Serialize<AllocSerializer<SCRATCH_SPACE>>,
+    S: AsyncMsgSend,
+{
+    /// Constructs a new TypedMsgSender
+    pub fn new(sender: S) -> Self {
+        Self {
+            sender,
+            _msg: PhantomData,
+        }
+    }
+
+    /// Serializes and sends a message
+    pub async fn send(&mut self, msg: &T) -> io::Result<()> {
+        let bytes = encode_message(msg)?;
+        let mut frame = Vec::with_capacity(bytes.len() + 1);
+        frame.push(FRAME_MESSAGE);
+        frame.extend_from_slice(&bytes);
+        Ok(self.sender.send(&frame).await?)
+    }
+
+    /// Sends the version frame, which must precede the first message of a client
+    pub async fn send_version(&mut self, version: u32) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(FRAME_VERSION_LEN);
+        frame.push(FRAME_VERSION);
+        frame.extend_from_slice(&version.to_be_bytes());
+        Ok(self.sender.send(&frame).await?)
+    }
+}
+
+/// Wrapper for an AsyncMsgRecv object that receives typed protocol messages
+pub struct TypedMsgReceiver<T, R> {
+    receiver: R,
+    buf: BytesMut, // Received frame, reused across messages
+    offload: Option<Offload>,
+    skipped: u64, // Unknown non-critical frames skipped
+    _msg: PhantomData<fn() -> T>,
+}
+
+impl<T, R> TypedMsgReceiver<T, R>
+where
+    T: Archive + Send + 'static,
+    for<'a> T::Archived: CheckBytes<DefaultValidator<'a>> + Deserialize<T, SharedDeserializeMap>,
+    R: AsyncMsgRecv,
+{
+    /// Constructs a new TypedMsgReceiver
+    pub fn new(receiver: R) -> Self {
+        Self {
+            receiver,
+            buf: BytesMut::new(),
+            offload: None,
+            skipped: 0,
+            _msg: PhantomData,
+        }
+    }
+
+    /// Validates and deserializes large messages on the blocking thread pool
+    pub fn offload(mut self, val: Option<Offload>) -> Self {
+        self.offload = val;
+        self
+    }
+
+    /// Returns the number of unknown non-critical frames skipped so far
+    pub fn skipped_frames(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Receives the version frame opening a client connection
+    /// Returns None if the peer sent anything else, as peers predating it do
+    pub async fn recv_version(&mut self) -> io::Result<Option<u32>> {
+        self.receiver.recv_into(&mut self.buf).await?;
+        Ok(match self.buf[..] {
+            [FRAME_VERSION, a, b, c, d] => Some(u32::from_be_bytes([a, b, c, d])),
+            _ => None,
+        })
+    }
+
+    /// Receives and deserializes a message, skipping unknown non-critical frames
+    pub async fn recv(&mut self) -> io::Result<T> {
+        loop {
+            self.receiver.recv_into(&mut self.buf).await?;
+            let kind = self.buf.first().copied();
+            let msg = match &self.offload {
+                Some(offload) => {
+                    let bytes = mem::take(&mut self.buf);
+                    let (msg, bytes) = offload
+                        .run(bytes.len(), move || Ok((decode_frame(&bytes), bytes)))
+                        .await?;
+                    self.buf = bytes;
+                    msg?
+                }
+                None => decode_frame(&self.buf)?,
+            };
+
+            match msg {
+                Some(msg) => return Ok(msg),
+                None => {
+                    self.skipped += 1;
+                    debug!("Skipping unknown frame kind {:#04x}", kind.unwrap_or(0));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::testutil::{VecMsgReceiver, VecMsgSender};
+
+    #[tokio::test]
+    async fn typed_roundtrip() {
+        let msg = ClientMessage::Data(b"hello".to_vec());
+
+        let mut sender = TypedMsgSender::new(VecMsgSender(Vec::new()));
+        sender.send(&msg).await.unwrap();
+
+        let mut receiver =
+            TypedMsgReceiver::<ClientMessage, _>::new(VecMsgReceiver::new(sender.sender.0));
+        assert_eq!(receiver.recv().await.unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn typed_version() {
+        let mut sender = TypedMsgSender::<ClientMessage, _>::new(VecMsgSender(Vec::new()));
+        sender.send_version(7).await.unwrap();
+        sender.send(&ClientMessage::Heartbeat).await.unwrap();
+        let mut frames = sender.sender.0;
+        assert_eq!(frames[0], [FRAME_VERSION, 0, 0, 0, 7]);
+
+        // Anything but a version frame, such as a message, has no version
+        frames.swap(0, 1);
+        let mut receiver = TypedMsgReceiver::<ClientMessage, _>::new(VecMsgReceiver::new(frames));
+        assert_eq!(receiver.recv_version().await.unwrap(), None);
+        assert_eq!(receiver.recv_version().await.unwrap(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn typed_malformed() {
+        let garbage = vec![vec![FRAME_MESSAGE, 0xff, 0xff, 0xff]];
+        let mut receiver =
+            TypedMsgReceiver::<CoordinatorMessage, _>::new(VecMsgReceiver::new(garbage));
+
+        let err = receiver.recv().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            ProtocolError::from_io(&err),
+            Some(&ProtocolError::Malformed)
+        );
+    }
+
+    #[tokio::test]
+    async fn typed_unknown_frames() {
+        let msg = CoordinatorMessage::Heartbeat;
+        let mut sender = TypedMsgSender::new(VecMsgSender(Vec::new()));
+        sender.send(&msg).await.unwrap();
+
+        // Non-critical frames are skipped, critical ones are an error
+        let mut frames = vec![vec![0x01, 0xaa], vec![0x7f]];
+        frames.append(&mut sender.sender.0);
+        frames.push(vec![0x81, 0xaa]);
+        let mut receiver =
+            TypedMsgReceiver::<CoordinatorMessage, _>::new(VecMsgReceiver::new(frames));
+
+        assert_eq!(receiver.recv().await.unwrap(), msg);
+        assert_eq!(receiver.skipped_frames(), 2);
+        let err = receiver.recv().await.unwrap_err();
+        assert_eq!(
+            ProtocolError::from_io(&err),
+            Some(&ProtocolError::UnknownFrame(0x81))
+        );
+    }
+}
@@ -0,0 +1,300 @@
+//! Wire format of the cluster protocol
+//!
+//! Everything a peer sends is described here, so other implementations and
+//! later versions of this crate can check their bytes against the tests below.
+//! A connection stacks the following layers, outermost first:
+//!
+//! 1. Length framing: each message is preceded by its length, a u64 in big endian
+//! 2. Handshake, in plaintext frames:
+//!    - the server sends its RSA public key, PKCS#1 DER encoded
+//!    - the client sends either an [`AES256GCMInitializerPair`] encrypted with
+//!      the server key (RSA PKCS#1 v1.5), or an X25519 hello:
+//!      [`KEY_EXCHANGE_X25519`] or [`KEY_EXCHANGE_X25519_AUTH`] followed by its
+//!      ephemeral public key
+//!    - with [`KEY_EXCHANGE_X25519_AUTH`], an identity frame follows: the
+//!      length of the client's DER public key as a u16 in big endian, the key,
+//!      then its signature of [`client_identity_digest`]
+//!    - the server replies [`HANDSHAKE_ACCEPT`], followed for X25519 by its
+//!      ephemeral public key and its signature of the SHA-256 of
+//!      [`x25519_transcript`], or [`HANDSHAKE_REJECT`] and a reason code
+//! 3. Records: every later message starts with its sequence number, a u64 in
+//!    big endian counting from 0 in each direction, see [`SEQ_HEADER_LEN`].
+//!    The rest is the message encrypted with AES-256-GCM-SIV, followed by its
+//!    [`TAG_LEN`] bytes tag. The nonce starts from the initializer and is
+//!    incremented as a big endian integer after each message.
+//!    Peers may switch to the [`rekey`] derived key at any message
+//! 4. Protocol frames: a frame kind byte followed by the message, serialized
+//!    with rkyv 0.7 in the native endianness of the sender (little endian on
+//!    every supported platform), see [`FRAME_CRITICAL`]. A client first sends
+//!    its protocol version in a fixed layout frame, see [`FRAME_VERSION`]
+//!
+//! Results too large for a message are sent as transfer frames, see [`FRAME_START`]
+
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+pub use super::{
+    ArtifactRef, ClientMessage, ClusterStatus, CoordinatorMessage, OnboardingReject, PendingWorker,
+    TaskAssignment, TaskOutcome, WorkerAssignment, WorkerHello, WorkerStatus, FRAME_CRITICAL,
+    FRAME_MESSAGE, FRAME_VERSION, FRAME_VERSION_LEN, PROTOCOL_VERSION,
+};
+pub use crate::comm::crypto::{AES256GCMInitializer, AES256GCMInitializerPair};
+
+/// Length of the header of a length framed message
+pub const LEN_HEADER_LEN: u64 = 8;
+
+/// Status frames sent by the server at the end of the handshake
+/// Sent in plaintext, as the client can't decrypt anything if the handshake failed
+pub const HANDSHAKE_ACCEPT: u8 = 0x00;
+pub const HANDSHAKE_REJECT: u8 = 0x01;
+
+/// Reason codes following HANDSHAKE_REJECT
+/// Unknown codes are reported as such, so new ones can be added
+pub const REJECT_MALFORMED: u8 = 0x01;
+pub const REJECT_TIMEOUT: u8 = 0x02;
+pub const REJECT_UNAUTHORIZED: u8 = 0x03;
+
+/// First byte of a client's X25519 key exchange frame
+/// Never confused with an RSA encrypted initializer, which is as long as the RSA modulus
+pub const KEY_EXCHANGE_X25519: u8 = 0x02;
+
+/// First byte of a client's X25519 key exchange frame followed by an identity frame
+pub const KEY_EXCHANGE_X25519_AUTH: u8 = 0x03;
+
+/// Length of a client's X25519 key exchange frame
+pub const X25519_HELLO_LEN: usize = 33;
+
+/// Length of the sequence number preceding the ciphertext of a record
+/// Repeated, missing or reordered records are refused before decrypting them
+pub const SEQ_HEADER_LEN: usize = 8;
+
+/// Length of the authentication tag following the ciphertext of a record
+pub const TAG_LEN: usize = 16;
+
+/// Transfer frame opening a stream, followed by its ID, length and the offset
+/// it resumes from, each a u64 in big endian
+pub const FRAME_START: u8 = 0x01;
+
+/// Transfer frame carrying the next bytes of a stream
+pub const FRAME_CHUNK: u8 = 0x02;
+
+/// Transfer frame closing a stream, followed by the SHA-256 of the whole stream
+pub const FRAME_END: u8 = 0x03;
+
+/// Length of a FRAME_START frame
+pub const FRAME_START_LEN: usize = 1 + 3 * 8;
+
+/// Length of a FRAME_END frame
+pub const FRAME_END_LEN: usize = 1 + 32;
+
+/// Builds the handshake transcript signed by the server and bound into the derived keys
+/// The client frames are the X25519 hello, and the identity frame if any
+pub fn x25519_transcript(
+    server_key_der: &[u8],
+    client_frames: &[&[u8]],
+    server_public: &[u8; 32],
+) -> Vec<u8> {
+    let mut transcript = b"pomegranate-x25519".to_vec();
+    transcript.extend_from_slice(server_key_der);
+    for frame in client_frames {
+        transcript.extend_from_slice(frame);
+    }
+    transcript.extend_from_slice(server_public);
+    transcript
+}
+
+/// Digest signed by a client to prove its identity for a key exchange
+pub fn client_identity_digest(server_key_der: &[u8], client_public: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"pomegranate-client-identity");
+    hasher.update(server_key_der);
+    hasher.update(client_public);
+    hasher.finalize().into()
+}
+
+/// Derives the channel keys from an X25519 shared secret and the handshake transcript
+/// Returns the client-to-server key and nonce, then the server-to-client ones
+pub fn channel_keys(shared_secret: &[u8; 32], transcript: &[u8]) -> [u8; 88] {
+    let salt = Sha256::digest(transcript);
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+
+    let mut okm = [0u8; 88];
+    hkdf.expand(b"pomegranate channel keys", &mut okm)
+        .expect("valid HKDF output length");
+    okm
+}
+
+/// Derives the key and starting nonce following a rekey from the current key
+pub fn rekey(key: &[u8; 32]) -> [u8; 44] {
+    let hkdf = Hkdf::<Sha256>::new(None, key);
+
+    let mut okm = [0u8; 44];
+    hkdf.expand(b"pomegranate rekey", &mut okm)
+        .expect("valid HKDF output length");
+    okm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::{
+        crypto::{AES256GCMMsgReceiver, AES256GCMMsgSender},
+        encaps::{AsyncMsgRecv, AsyncMsgSend, LenU64EncapsMsgSender},
+        protocol::{decode_frame, encode_message},
+        testutil::{VecMsgReceiver, VecMsgSender},
+        transfer::send_stream,
+    };
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Decodes a hex string, ignoring whitespace
+    fn unhex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn spec_length_framing() {
+        let mut out = Vec::new();
+        LenU64EncapsMsgSender::new(&mut out)
+            .send(b"hello")
+            .await
+            .unwrap();
+        assert_eq!(out, unhex("0000000000000005 68656c6c6f"));
+        assert_eq!(out.len() as u64, LEN_HEADER_LEN + 5);
+    }
+
+    #[test]
+    fn spec_handshake() {
+        assert_eq!((HANDSHAKE_ACCEPT, HANDSHAKE_REJECT), (0x00, 0x01));
+        assert_eq!(
+            (REJECT_MALFORMED, REJECT_TIMEOUT, REJECT_UNAUTHORIZED),
+            (0x01, 0x02, 0x03)
+        );
+        assert_eq!(
+            (KEY_EXCHANGE_X25519, KEY_EXCHANGE_X25519_AUTH),
+            (0x02, 0x03)
+        );
+        assert_eq!(X25519_HELLO_LEN, 1 + 32);
+
+        let mut hello = vec![KEY_EXCHANGE_X25519];
+        hello.extend_from_slice(&[2; 32]);
+        let transcript = x25519_transcript(b"der", &[&hello], &[7; 32]);
+        let mut expected = b"pomegranate-x25519der".to_vec();
+        expected.extend_from_slice(&hello);
+        expected.extend_from_slice(&[7; 32]);
+        assert_eq!(transcript, expected);
+
+        assert_eq!(
+            hex(&client_identity_digest(b"der", &[5; 32])),
+            "8fd903bb8959670faba3fd3bf86fd007b77022762a409f5e1c164f5d4bb50e60"
+        );
+        assert_eq!(
+            channel_keys(&[1; 32], &transcript).to_vec(),
+            unhex(
+                "bcf33bd143b99a01a57efd117778b7d984c5cbc97ae962c1f5bfcac4cded36d7 ec6fdbeb3861d626a9ec79fb
+                 98365a10d26c51c016e276b508da5b7b30820b181c4eb231f149e01404e44813c188be87 ea96d4adf9047732"
+            )
+        );
+        assert_eq!(
+            rekey(&[0; 32]).to_vec(),
+            unhex(
+                "a7bc18d176027cb47f7b7d377bc6015116aeb83c74989bce8ef30e7dfd44b2ec 4c596354d6b329182bb5cb1d"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn spec_records() {
+        let init = AES256GCMInitializer::new([1; 32], [0; 12]);
+        let mut sender = AES256GCMMsgSender::new(VecMsgSender(Vec::new()), &init);
+        sender.send(b"hello").await.unwrap();
+        sender.send(b"hello").await.unwrap();
+        let records = sender.get_mut().0.clone();
+        assert_eq!(records[0].len(), 5 + TAG_LEN);
+        assert_eq!(
+            hex(&records[0]),
+            "9b6284c9cf2bfcca22be025748704157c68beab24c"
+        );
+        // The nonce changes with every message
+        assert_eq!(
+            hex(&records[1]),
+            "5fc5536861e376c0edc866dc6da7b36856106ca2b8"
+        );
+
+        let mut receiver = AES256GCMMsgReceiver::new(VecMsgReceiver::new(records), &init);
+        assert_eq!(receiver.recv().await.unwrap(), b"hello");
+        assert_eq!(receiver.recv().await.unwrap(), b"hello");
+    }
+
+    // rkyv lays messages out in the native endianness
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn spec_protocol_frames() {
+        assert_eq!(
+            (PROTOCOL_VERSION, FRAME_MESSAGE, FRAME_CRITICAL),
+            (3, 0x80, 0x80)
+        );
+        assert_eq!((FRAME_VERSION, FRAME_VERSION_LEN), (0x81, 1 + 4));
+
+        let msg = ClientMessage::Hello(WorkerHello {
+            worker_id: "w1".to_string(),
+            hostname: "host".to_string(),
+            cpus: 4,
+            tags: vec!["gpu".to_string()],
+            perf_score: 1000,
+            cluster: None,
+            zone: Some("eu".to_string()),
+        });
+        let frame = unhex(
+            "80 67707500000000 03000000000000000000000000000000 00010000006575000000000002
+             77310000000000 02686f7374000000 04040000 00c8ffff ff010000 00e80300 00000000 00",
+        );
+        let mut encoded = vec![FRAME_MESSAGE];
+        encoded.extend_from_slice(&encode_message(&msg).unwrap());
+        assert_eq!(hex(&encoded), hex(&frame));
+        assert_eq!(decode_frame::<ClientMessage>(&frame).unwrap(), Some(msg));
+
+        let msg = CoordinatorMessage::Task(TaskAssignment {
+            task_id: 7,
+            kind: "echo".to_string(),
+            payload: b"hi".to_vec(),
+        });
+        let frame = unhex(
+            "80 68690000000000000400000000000000 07000000000000006563686f00000004 e0ffffff02000000",
+        );
+        let mut encoded = vec![FRAME_MESSAGE];
+        encoded.extend_from_slice(&encode_message(&msg).unwrap());
+        assert_eq!(hex(&encoded), hex(&frame));
+        assert_eq!(
+            decode_frame::<CoordinatorMessage>(&frame).unwrap(),
+            Some(msg)
+        );
+    }
+
+    #[tokio::test]
+    async fn spec_transfer_frames() {
+        let mut sender = VecMsgSender(Vec::new());
+        send_stream(&mut sender, 9, &b"abc"[..], 3, 2)
+            .await
+            .unwrap();
+        let frames: Vec<_> = sender.0.iter().map(|frame| hex(frame)).collect();
+        assert_eq!(
+            frames,
+            [
+                "01 0000000000000009 0000000000000003 0000000000000000".replace(' ', ""),
+                "026162".to_string(),
+                "0263".to_string(),
+                // SHA-256 of "abc"
+                "03ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string(),
+            ]
+        );
+        assert_eq!(sender.0[0].len(), FRAME_START_LEN);
+        assert_eq!(sender.0[3].len(), FRAME_END_LEN);
+    }
+}
@@ -0,0 +1,233 @@
+use std::{error::Error, fmt};
+
+use bytes::{Buf, BytesMut};
+use tokio::io;
+
+use super::{
+    encaps::{AsyncMsgRecv, AsyncMsgSend},
+    error::CommError,
+    protocol::spec::SEQ_HEADER_LEN,
+};
+
+/// Error produced when a message is received out of sequence
+/// Carried inside an io::Error of kind InvalidData
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceError {
+    Missing,                                    // Message too short to contain a sequence number
+    Duplicate { expected: u64, received: u64 }, // Message repeated or from the past
+    Gap { expected: u64, received: u64 },       // Messages skipped or reordered
+}
+
+impl SequenceError {
+    /// Extracts a SequenceError from an io::Error, if it carries one
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SequenceError::Missing => write!(f, "message has no sequence number"),
+            SequenceError::Duplicate { expected, received } => write!(
+                f,
+                "duplicate message: expected #{}, received #{}",
+                expected, received
+            ),
+            SequenceError::Gap { expected, received } => write!(
+                f,
+                "sequence gap: expected #{}, received #{}",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl Error for SequenceError {}
+
+impl From<SequenceError> for io::Error {
+    fn from(err: SequenceError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Wrapper for an AsyncMsgSend object that prefixes every message with a
+/// monotonically increasing sequence number
+pub struct SeqMsgSender<S> {
+    sender: S,
+    next: u64,
+}
+
+impl<S> SeqMsgSender<S>
+where
+    S: AsyncMsgSend,
+{
+    /// Constructs a new SeqMsgSender
+    pub fn new(sender: S) -> Self {
+        Self { sender, next: 0 }
+    }
+}
+
+impl<S> AsyncMsgSend for SeqMsgSender<S>
+where
+    S: AsyncMsgSend,
+{
+    async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
+        let mut frame = Vec::with_capacity(SEQ_HEADER_LEN + msg.len());
+        frame.extend_from_slice(&self.next.to_be_bytes());
+        frame.extend_from_slice(msg);
+
+        self.sender.send(&frame).await?;
+        self.next += 1;
+        Ok(())
+    }
+}
+
+/// Wrapper for an AsyncMsgRecv object that strictly checks message sequence
+/// numbers, failing on any duplicated, skipped or reordered message
+pub struct SeqMsgReceiver<R> {
+    receiver: R,
+    next: u64,
+}
+
+impl<R> SeqMsgReceiver<R>
+where
+    R: AsyncMsgRecv,
+{
+    /// Constructs a new SeqMsgReceiver
+    pub fn new(receiver: R) -> Self {
+        Self { receiver, next: 0 }
+    }
+
+    /// Checks the sequence number a frame starts with, expecting the next one
+    fn check(&mut self, frame: &[u8]) -> Result<(), SequenceError> {
+        // Split sequence number from message
+        if frame.len() < SEQ_HEADER_LEN {
+            return Err(SequenceError::Missing);
+        }
+        let mut seq = [0u8; SEQ_HEADER_LEN];
+        seq.copy_from_slice(&frame[..SEQ_HEADER_LEN]);
+        let seq = u64::from_be_bytes(seq);
+
+        // Check sequence number
+        let expected = self.next;
+        if seq < expected {
+            return Err(SequenceError::Duplicate {
+                expected,
+                received: seq,
+            });
+        } else if seq > expected {
+            return Err(SequenceError::Gap {
+                expected,
+                received: seq,
+            });
+        }
+        self.next += 1;
+        Ok(())
+    }
+}
+
+impl<R> AsyncMsgRecv for SeqMsgReceiver<R>
+where
+    R: AsyncMsgRecv,
+{
+    async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+        let mut frame = self.receiver.recv().await?;
+        self.check(&frame)?;
+        frame.drain(..SEQ_HEADER_LEN);
+        Ok(frame)
+    }
+
+    /// Receives a message into a buffer, skipping the header without copying
+    async fn recv_into(&mut self, buf: &mut BytesMut) -> Result<(), CommError> {
+        self.receiver.recv_into(buf).await?;
+        self.check(buf)?;
+        buf.advance(SEQ_HEADER_LEN);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::testutil::{VecMsgReceiver, VecMsgSender};
+
+    /// Produces sequenced frames for the given messages
+    async fn frames(msgs: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut sender = SeqMsgSender::new(VecMsgSender(Vec::new()));
+        for msg in msgs {
+            sender.send(msg).await.unwrap();
+        }
+        sender.sender.0
+    }
+
+    #[tokio::test]
+    async fn seq_in_order() {
+        let frames = frames(&[b"a", b"b", b"c"]).await;
+        let mut receiver = SeqMsgReceiver::new(VecMsgReceiver::new(frames));
+
+        assert_eq!(receiver.recv().await.unwrap(), b"a");
+        assert_eq!(receiver.recv().await.unwrap(), b"b");
+        assert_eq!(receiver.recv().await.unwrap(), b"c");
+    }
+
+    #[tokio::test]
+    async fn seq_recv_into() {
+        let frames = frames(&[b"a", b"bc"]).await;
+        let frames = vec![frames[0].clone(), frames[1].clone(), frames[1].clone()];
+        let mut receiver = SeqMsgReceiver::new(VecMsgReceiver::new(frames));
+        let mut buf = BytesMut::new();
+
+        receiver.recv_into(&mut buf).await.unwrap();
+        assert_eq!(&buf[..], b"a");
+        receiver.recv_into(&mut buf).await.unwrap();
+        assert_eq!(&buf[..], b"bc");
+        let err = receiver.recv_into(&mut buf).await.unwrap_err();
+        assert!(matches!(
+            err,
+            CommError::Sequence(SequenceError::Duplicate { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn seq_duplicate() {
+        let frames = frames(&[b"a", b"b"]).await;
+        let frames = vec![frames[0].clone(), frames[0].clone()];
+        let mut receiver = SeqMsgReceiver::new(VecMsgReceiver::new(frames));
+
+        receiver.recv().await.unwrap();
+        let err = receiver.recv().await.unwrap_err();
+        assert!(matches!(
+            err,
+            CommError::Sequence(SequenceError::Duplicate {
+                expected: 1,
+                received: 0
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn seq_reordered() {
+        let mut frames = frames(&[b"a", b"b"]).await;
+        frames.swap(0, 1);
+        let mut receiver = SeqMsgReceiver::new(VecMsgReceiver::new(frames));
+
+        let err = receiver.recv().await.unwrap_err();
+        assert!(matches!(
+            err,
+            CommError::Sequence(SequenceError::Gap {
+                expected: 0,
+                received: 1
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn seq_missing() {
+        let frames = vec![vec![0x00, 0x01]];
+        let mut receiver = SeqMsgReceiver::new(VecMsgReceiver::new(frames));
+
+        let err = receiver.recv().await.unwrap_err();
+        assert!(matches!(err, CommError::Sequence(SequenceError::Missing)));
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use super::{
+    encaps::{AsyncMsgRecv, AsyncMsgSend},
+    error::CommError,
+};
+
+/// Number of histogram buckets, one per power of two
+const BUCKETS: usize = 65;
+
+/// Lock-free histogram with power-of-two buckets
+/// Bucket 0 counts zeros, bucket i counts values in [2^(i-1), 2^i)
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    /// Records a value
+    pub fn record(&self, val: u64) {
+        let bucket = (u64::BITS - val.leading_zeros()) as usize;
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(val, Ordering::Relaxed);
+    }
+
+    /// Takes a consistent-enough copy of the histogram for reporting
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of a Histogram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: u64,
+}
+
+impl HistogramSnapshot {
+    /// Returns the exclusive upper bound of bucket i
+    pub fn bucket_bound(i: usize) -> u64 {
+        1u64.checked_shl(i as u32).unwrap_or(u64::MAX)
+    }
+
+    /// Returns the mean of the recorded values
+    pub fn mean(&self) -> Option<f64> {
+        (self.count != 0).then(|| self.sum as f64 / self.count as f64)
+    }
+
+    /// Returns an upper bound for the q-th quantile (0 <= q <= 1)
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let target = ((self.count as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Some(Self::bucket_bound(i));
+            }
+        }
+
+        None
+    }
+}
+
+/// Message size and latency statistics of a connection
+/// Latencies are recorded in microseconds
+#[derive(Default)]
+pub struct ConnStats {
+    pub sent_size: Histogram,
+    pub recv_size: Histogram,
+    pub send_latency: Histogram, // Time for a send() call to complete
+    pub recv_wait: Histogram,    // Time spent waiting in recv(), including idle time
+}
+
+/// Wrapper for an AsyncMsgSend object that records message statistics
+pub struct StatsMsgSender<S> {
+    sender: S,
+    stats: Arc<ConnStats>,
+}
+
+impl<S> StatsMsgSender<S>
+where
+    S: AsyncMsgSend,
+{
+    /// Constructs a new StatsMsgSender
+    pub fn new(sender: S, stats: Arc<ConnStats>) -> Self {
+        Self { sender, stats }
+    }
+}
+
+impl<S> AsyncMsgSend for StatsMsgSender<S>
+where
+    S: AsyncMsgSend,
+{
+    async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
+        let start = Instant::now();
+        self.sender.send(msg).await?;
+
+        self.stats
+            .send_latency
+            .record(start.elapsed().as_micros() as u64);
+        self.stats.sent_size.record(msg.len() as u64);
+        Ok(())
+    }
+}
+
+/// Wrapper for an AsyncMsgRecv object that records message statistics
+pub struct StatsMsgReceiver<R> {
+    receiver: R,
+    stats: Arc<ConnStats>,
+}
+
+impl<R> StatsMsgReceiver<R>
+where
+    R: AsyncMsgRecv,
+{
+    /// Constructs a new StatsMsgReceiver
+    pub fn new(receiver: R, stats: Arc<ConnStats>) -> Self {
+        Self { receiver, stats }
+    }
+}
+
+impl<R> AsyncMsgRecv for StatsMsgReceiver<R>
+where
+    R: AsyncMsgRecv,
+{
+    async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+        let start = Instant::now();
+        let msg = self.receiver.recv().await?;
+
+        self.stats
+            .recv_wait
+            .record(start.elapsed().as_micros() as u64);
+        self.stats.recv_size.record(msg.len() as u64);
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let hist = Histogram::default();
+        hist.record(0);
+        hist.record(1);
+        hist.record(5);
+        hist.record(7);
+        hist.record(u64::MAX);
+
+        let snap = hist.snapshot();
+        assert_eq!(snap.count, 5);
+        assert_eq!(snap.buckets[0], 1);
+        assert_eq!(snap.buckets[1], 1);
+        assert_eq!(snap.buckets[3], 2);
+        assert_eq!(snap.buckets[64], 1);
+    }
+
+    #[test]
+    fn histogram_quantile() {
+        let hist = Histogram::default();
+        for val in 1..=100 {
+            hist.record(val);
+        }
+
+        let snap = hist.snapshot();
+        assert_eq!(snap.mean(), Some(50.5));
+        assert_eq!(snap.quantile(0.5), Some(64));
+        assert_eq!(snap.quantile(1.0), Some(128));
+        assert_eq!(Histogram::default().snapshot().quantile(0.5), None);
+    }
+}
@@ -0,0 +1,46 @@
+use aes_gcm_siv::aead::OsRng;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use tokio::io;
+
+use super::{
+    crypto::RsaKeyPair,
+    encaps::{AsyncMsgRecv, AsyncMsgSend},
+    error::CommError,
+};
+
+/// Returns a small key pair, to keep the tests fast
+/// Still large enough for the channel initializers
+pub fn test_keypair() -> RsaKeyPair {
+    let private = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+    RsaKeyPair {
+        public: RsaPublicKey::from(&private),
+        private,
+    }
+}
+
+/// Sender which records the messages sent through it
+pub struct VecMsgSender(pub Vec<Vec<u8>>);
+
+impl AsyncMsgSend for VecMsgSender {
+    async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
+        self.0.push(msg.to_vec());
+        Ok(())
+    }
+}
+
+/// Receiver which yields a fixed list of messages
+pub struct VecMsgReceiver(pub std::vec::IntoIter<Vec<u8>>);
+
+impl VecMsgReceiver {
+    pub fn new(msgs: Vec<Vec<u8>>) -> Self {
+        Self(msgs.into_iter())
+    }
+}
+
+impl AsyncMsgRecv for VecMsgReceiver {
+    async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+        self.0
+            .next()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+}
@@ -0,0 +1,133 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+/// Failure tracking state of a single address
+#[derive(Debug)]
+struct ThrottleEntry {
+    failures: u32,
+    window_start: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Tracks handshake failures per IP address and temporarily bans addresses
+/// which fail too often
+#[derive(Debug)]
+pub struct HandshakeThrottle {
+    max_failures: u32, // Failures within the window before banning
+    window: Duration,
+    ban: Duration,
+    entries: Mutex<HashMap<IpAddr, ThrottleEntry>>,
+}
+
+impl HandshakeThrottle {
+    /// Constructs a new HandshakeThrottle
+    pub fn new(max_failures: u32, window: Duration, ban: Duration) -> Self {
+        Self {
+            max_failures,
+            window,
+            ban,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if the address is currently banned
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .get(&ip)
+            .and_then(|entry| entry.banned_until)
+            .is_some_and(|until| until > now)
+    }
+
+    /// Records a failed handshake. Returns true if the address got banned
+    pub fn record_failure(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        // Forget stale entries so the map doesn't grow forever
+        entries.retain(|_, entry| {
+            entry.banned_until.is_some_and(|until| until > now)
+                || now - entry.window_start < self.window
+        });
+
+        let entry = entries.entry(ip).or_insert(ThrottleEntry {
+            failures: 0,
+            window_start: now,
+            banned_until: None,
+        });
+
+        // Start a new window if the old one expired
+        if now - entry.window_start >= self.window {
+            entry.failures = 0;
+            entry.window_start = now;
+        }
+
+        entry.failures += 1;
+        if entry.failures >= self.max_failures {
+            entry.failures = 0;
+            entry.window_start = now;
+            entry.banned_until = Some(now + self.ban);
+            return true;
+        }
+
+        false
+    }
+
+    /// Records a successful handshake, clearing the address' failures
+    pub fn record_success(&self, ip: IpAddr) {
+        self.entries.lock().unwrap().remove(&ip);
+    }
+}
+
+impl Default for HandshakeThrottle {
+    /// Bans addresses failing 5 handshakes within a minute for 10 minutes
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(60), Duration::from_secs(600))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::time;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_ban() {
+        let throttle = HandshakeThrottle::new(3, Duration::from_secs(60), Duration::from_secs(600));
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        assert!(!throttle.record_failure(ip));
+        assert!(!throttle.record_failure(ip));
+        assert!(!throttle.is_banned(ip));
+        assert!(throttle.record_failure(ip));
+        assert!(throttle.is_banned(ip));
+        assert!(!throttle.is_banned(other));
+
+        // Ban expires
+        time::advance(Duration::from_secs(601)).await;
+        assert!(!throttle.is_banned(ip));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_window() {
+        let throttle = HandshakeThrottle::new(2, Duration::from_secs(60), Duration::from_secs(600));
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        // Failures in different windows don't add up
+        assert!(!throttle.record_failure(ip));
+        time::advance(Duration::from_secs(61)).await;
+        assert!(!throttle.record_failure(ip));
+
+        // Success clears failures
+        throttle.record_success(ip);
+        assert!(!throttle.record_failure(ip));
+        assert!(throttle.record_failure(ip));
+    }
+}
@@ -1,4 +1,61 @@
-use std::time::Duration;
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use aes_gcm_siv::aead::{rand_core::RngCore, OsRng};
+
+/// Randomization of the durations returned by a DoublingTimer
+/// Spreads out the reconnection attempts of clients which lost the server at once
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Jitter {
+    /// Exact durations
+    #[default]
+    None,
+    /// Uniformly random between zero and the duration
+    Full,
+    /// Half the duration plus a uniformly random part of the other half
+    Equal,
+}
+
+impl Jitter {
+    fn apply(self, dur: Duration) -> Duration {
+        // Random fraction in [0, 1]
+        let random = || OsRng.next_u32() as f64 / u32::MAX as f64;
+        match self {
+            Jitter::None => dur,
+            Jitter::Full => dur.mul_f64(random()),
+            Jitter::Equal => dur / 2 + (dur / 2).mul_f64(random()),
+        }
+    }
+}
+
+impl FromStr for Jitter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Jitter::None),
+            "full" => Ok(Jitter::Full),
+            "equal" => Ok(Jitter::Equal),
+            _ => Err(format!(
+                "unknown jitter {:?}, expected none, full or equal",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Jitter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Jitter::None => write!(f, "none"),
+            Jitter::Full => write!(f, "full"),
+            Jitter::Equal => write!(f, "equal"),
+        }
+    }
+}
 
 /// Keeps track of time before next reconnection attempt
 pub struct DoublingTimer {
@@ -6,10 +63,14 @@ pub struct DoublingTimer {
     flat: u32, // Number of attempts before doubling duration
     init_dur: Duration,
     max_dur: Duration,
+    multiplier: f64, // Factor the duration grows by, 2 unless configured
+    jitter: Jitter,
+    deadline: Option<Duration>, // Time after the first attempt at which the timer is exhausted
 
     // State
     cur_dur: Duration,
-    rem: u32, // Remaining attempts before doubling
+    rem: u32,                 // Remaining attempts before doubling
+    started: Option<Instant>, // First attempt since the last reset
 }
 
 impl DoublingTimer {
@@ -21,12 +82,24 @@ impl DoublingTimer {
             init_dur,
             cur_dur: init_dur,
             max_dur,
+            multiplier: 2.0,
+            jitter: Jitter::None,
+            deadline: None,
             rem: flat,
+            started: None,
         }
     }
 
+    /// Constructs a new DoublingTimerBuilder with default values
+    pub fn builder() -> DoublingTimerBuilder {
+        DoublingTimerBuilder::default()
+    }
+
     /// Returns the next reconnection attempt delay
+    /// The delay never exceeds the maximum duration, jitter only shortens it
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Duration {
+        self.started.get_or_insert_with(Instant::now);
         let res = self.cur_dur;
 
         // Update for next attempt
@@ -35,7 +108,12 @@ impl DoublingTimer {
             self.rem -= 1;
             if self.rem == 0 {
                 self.rem = self.flat;
-                self.cur_dur *= 2; // Double duration
+                // Grow duration, anything not representable (overflow, 0 * inf) hits the cap
+                let grown = self.cur_dur.as_secs_f64() * self.multiplier;
+                self.cur_dur = Duration::try_from_secs_f64(grown)
+                    .ok()
+                    .filter(|dur| *dur < self.max_dur)
+                    .unwrap_or(self.max_dur);
             }
         }
 
@@ -44,13 +122,101 @@ impl DoublingTimer {
             self.cur_dur = self.max_dur;
         }
 
-        res
+        self.jitter.apply(res.min(self.max_dur))
+    }
+
+    /// Returns the next reconnection attempt delay, or None once the deadline passed
+    pub fn try_next(&mut self) -> Option<Duration> {
+        if self.is_exhausted() {
+            return None;
+        }
+        Some(self.next())
+    }
+
+    /// Returns true if the deadline passed since the first attempt after the last reset
+    pub fn is_exhausted(&self) -> bool {
+        match (self.deadline, self.started) {
+            (Some(deadline), Some(started)) => started.elapsed() >= deadline,
+            _ => false,
+        }
     }
 
     /// Resets the timer to the initial duration
     pub fn reset(&mut self) {
         self.cur_dur = self.init_dur;
         self.rem = self.flat;
+        self.started = None;
+    }
+}
+
+/// Builder for DoublingTimer
+#[derive(Debug, Clone)]
+pub struct DoublingTimerBuilder {
+    pub flat: u32,
+    pub init_dur: Duration,
+    pub max_dur: Duration,
+    pub multiplier: f64,
+    pub jitter: Jitter,
+    pub deadline: Option<Duration>,
+}
+
+impl Default for DoublingTimerBuilder {
+    fn default() -> Self {
+        Self {
+            flat: 5,
+            init_dur: Duration::from_secs(1),
+            max_dur: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: Jitter::None,
+            deadline: None,
+        }
+    }
+}
+
+impl DoublingTimerBuilder {
+    /// Number of attempts before growing the duration. 0 -> never grow
+    pub fn flat(mut self, val: u32) -> Self {
+        self.flat = val;
+        self
+    }
+
+    pub fn init_dur(mut self, val: Duration) -> Self {
+        self.init_dur = val;
+        self
+    }
+
+    /// Maximum duration ever returned by the timer
+    pub fn max_dur(mut self, val: Duration) -> Self {
+        self.max_dur = val;
+        self
+    }
+
+    /// Factor the duration grows by. Values below 1 and NaN are treated as 1,
+    /// growth past the maximum duration (including infinity) stops at the maximum
+    pub fn multiplier(mut self, val: f64) -> Self {
+        self.multiplier = val;
+        self
+    }
+
+    pub fn jitter(mut self, val: Jitter) -> Self {
+        self.jitter = val;
+        self
+    }
+
+    /// Time after the first attempt at which the timer reports exhaustion
+    pub fn deadline(mut self, val: Duration) -> Self {
+        self.deadline = Some(val);
+        self
+    }
+
+    /// Constructs the DoublingTimer
+    pub fn build(self) -> DoublingTimer {
+        let mut timer = DoublingTimer::new(self.flat, self.init_dur, self.max_dur);
+        // f64::max ignores NaN, so a NaN multiplier becomes 1
+        timer.multiplier = self.multiplier.max(1.0);
+        timer.jitter = self.jitter;
+        timer.deadline = self.deadline;
+        timer
     }
 }
 
@@ -99,4 +265,106 @@ mod tests {
 
         assert_eq!(timer.next(), Duration::from_millis(2500));
     }
+
+    #[test]
+    fn doubling_timer_multiplier() {
+        let mut timer = DoublingTimer::builder()
+            .flat(1)
+            .init_dur(Duration::from_millis(1000))
+            .max_dur(Duration::from_secs(10))
+            .multiplier(1.5)
+            .build();
+
+        assert_eq!(timer.next(), Duration::from_millis(1000));
+        assert_eq!(timer.next(), Duration::from_millis(1500));
+        assert_eq!(timer.next(), Duration::from_millis(2250));
+        assert_eq!(timer.next(), Duration::from_millis(3375));
+
+        // Multipliers too large for a Duration clamp to the cap instead of panicking
+        for val in [1e300, f64::INFINITY] {
+            let mut timer = DoublingTimer::builder()
+                .flat(1)
+                .max_dur(Duration::from_secs(10))
+                .multiplier(val)
+                .build();
+            assert_eq!(timer.next(), Duration::from_secs(1));
+            assert_eq!(timer.next(), Duration::from_secs(10));
+        }
+
+        // Zero times infinity is NaN, which also hits the cap
+        let mut timer = DoublingTimer::builder()
+            .flat(1)
+            .init_dur(Duration::ZERO)
+            .max_dur(Duration::from_secs(10))
+            .multiplier(f64::INFINITY)
+            .build();
+        assert_eq!(timer.next(), Duration::ZERO);
+        assert_eq!(timer.next(), Duration::from_secs(10));
+
+        // NaN never grows the duration
+        let mut timer = DoublingTimer::builder()
+            .flat(1)
+            .multiplier(f64::NAN)
+            .build();
+        assert_eq!(timer.next(), Duration::from_secs(1));
+        assert_eq!(timer.next(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn doubling_timer_cap() {
+        // Initial duration above the cap
+        let mut timer = DoublingTimer::builder()
+            .init_dur(Duration::from_secs(60))
+            .max_dur(Duration::from_secs(10))
+            .build();
+
+        assert_eq!(timer.next(), Duration::from_secs(10));
+        assert_eq!(timer.next(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn doubling_timer_jitter() {
+        let builder = DoublingTimer::builder()
+            .flat(1)
+            .init_dur(Duration::from_secs(8))
+            .max_dur(Duration::from_secs(16));
+
+        let mut full = builder.clone().jitter(Jitter::Full).build();
+        let mut equal = builder.jitter(Jitter::Equal).build();
+        let mut delays = Vec::new();
+        for _ in 0..20 {
+            full.reset();
+            equal.reset();
+            let delay = full.next();
+            assert!(delay <= Duration::from_secs(8));
+            delays.push(delay);
+            let delay = equal.next();
+            assert!(delay >= Duration::from_secs(4) && delay <= Duration::from_secs(8));
+        }
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+
+        // Jitter never exceeds the cap
+        for _ in 0..4 {
+            assert!(full.next() <= Duration::from_secs(16));
+        }
+        assert_eq!("equal".parse(), Ok(Jitter::Equal));
+        assert!("some".parse::<Jitter>().is_err());
+    }
+
+    #[test]
+    fn doubling_timer_deadline() {
+        let mut timer = DoublingTimer::builder()
+            .init_dur(Duration::from_millis(1))
+            .deadline(Duration::from_millis(20))
+            .build();
+
+        assert!(!timer.is_exhausted());
+        assert_eq!(timer.try_next(), Some(Duration::from_millis(1)));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(timer.is_exhausted());
+        assert_eq!(timer.try_next(), None);
+
+        timer.reset();
+        assert_eq!(timer.try_next(), Some(Duration::from_millis(1)));
+    }
 }
@@ -0,0 +1,199 @@
+use std::{net::IpAddr, sync::Arc};
+
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, Join, ReadHalf, WriteHalf},
+    time,
+};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    ClientConfig, RootCertStore, ServerConfig,
+};
+pub use tokio_rustls::rustls;
+
+use super::{
+    channel::ChannelOptions,
+    encaps::{AsyncMsgRecv, AsyncMsgSend, LenU64EncapsMsgReceiver, LenU64EncapsMsgSender},
+    error::CommError,
+};
+
+/// Certificate chain and private key presented by a TLS client
+#[derive(Debug)]
+pub struct ClientCert {
+    pub chain: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl Clone for ClientCert {
+    fn clone(&self) -> Self {
+        Self {
+            chain: self.chain.clone(),
+            key: self.key.clone_key(),
+        }
+    }
+}
+
+/// Sending half of a TLS channel
+/// Flushes after every message, as TLS records are buffered until then
+pub struct TlsMsgSender<T> {
+    sender: LenU64EncapsMsgSender<WriteHalf<T>>,
+}
+
+impl<T> AsyncMsgSend for TlsMsgSender<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
+        self.sender.send(msg).await?;
+        Ok(self.sender.get_mut().flush().await?)
+    }
+}
+
+/// Receiving half of a TLS channel
+pub struct TlsMsgReceiver<T> {
+    receiver: LenU64EncapsMsgReceiver<ReadHalf<T>>,
+}
+
+impl<T> AsyncMsgRecv for TlsMsgReceiver<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+        self.receiver.recv().await
+    }
+}
+
+/// TLS channel over the two halves of a byte stream
+pub type TlsChannel<T> = (TlsMsgSender<T>, TlsMsgReceiver<T>);
+
+/// Builds a client configuration trusting the given roots
+pub fn client_config(
+    root_store: Arc<RootCertStore>,
+    client_cert: Option<ClientCert>,
+) -> io::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_root_certificates(root_store);
+
+    let config = match client_cert {
+        Some(cert) => builder
+            .with_client_auth_cert(cert.chain, cert.key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Builds a server configuration presenting the given certificate, without client authentication
+pub fn server_config(
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> io::Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(Arc::new(config))
+}
+
+/// Returns the name a server at an IP address is verified against, if none is configured
+pub fn ip_server_name(ip: IpAddr) -> ServerName<'static> {
+    ServerName::IpAddress(ip.into())
+}
+
+/// Sets up a TLS channel over a byte stream on the client side
+pub async fn client_tls_channel<R, W>(
+    reader: R,
+    writer: W,
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    options: &ChannelOptions,
+) -> io::Result<TlsChannel<client::TlsStream<Join<R, W>>>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let connector = TlsConnector::from(config);
+    let stream = time::timeout(
+        options.handshake_timeout,
+        connector.connect(server_name, io::join(reader, writer)),
+    )
+    .await??;
+
+    Ok(split(stream, options))
+}
+
+/// Sets up a TLS channel over a byte stream on the server side
+pub async fn server_tls_channel<R, W>(
+    reader: R,
+    writer: W,
+    config: Arc<ServerConfig>,
+    options: &ChannelOptions,
+) -> io::Result<TlsChannel<server::TlsStream<Join<R, W>>>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let acceptor = TlsAcceptor::from(config);
+    let stream = time::timeout(
+        options.handshake_timeout,
+        acceptor.accept(io::join(reader, writer)),
+    )
+    .await??;
+
+    Ok(split(stream, options))
+}
+
+/// Splits a TLS stream into message sender and receiver
+fn split<T: AsyncRead + AsyncWrite>(stream: T, options: &ChannelOptions) -> TlsChannel<T> {
+    let (reader, writer) = io::split(stream);
+    (
+        TlsMsgSender {
+            sender: LenU64EncapsMsgSender::new(writer),
+        },
+        TlsMsgReceiver {
+            receiver: LenU64EncapsMsgReceiver::new(reader).max_len(options.max_msg_len),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn tls_roundtrip() {
+        let cert = rcgen::generate_simple_self_signed(["coordinator".to_string()]).unwrap();
+        let chain = vec![cert.cert.der().clone()];
+        let key = PrivateKeyDer::try_from(cert.key_pair.serialize_der()).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(chain[0].clone()).unwrap();
+        let client = client_config(Arc::new(roots), None).unwrap();
+        let server = server_config(chain, key).unwrap();
+
+        let options = ChannelOptions::default();
+        let (a, b) = duplex(4096);
+        let (a_reader, a_writer) = io::split(a);
+        let (b_reader, b_writer) = io::split(b);
+        let name = ServerName::try_from("coordinator").unwrap();
+        let (client, server) = tokio::join!(
+            client_tls_channel(a_reader, a_writer, client.clone(), name, &options),
+            server_tls_channel(b_reader, b_writer, server, &options),
+        );
+        let (mut client_sender, mut client_receiver) = client.unwrap();
+        let (mut server_sender, mut server_receiver) = server.unwrap();
+
+        client_sender.send(b"hello").await.unwrap();
+        assert_eq!(server_receiver.recv().await.unwrap(), b"hello");
+        server_sender.send(b"world").await.unwrap();
+        assert_eq!(client_receiver.recv().await.unwrap(), b"world");
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::File,
+    io::{self, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    sync::Mutex,
+    time,
+};
+
+use super::{
+    encaps::{AsyncMsgRecv, AsyncMsgSend},
+    error::CommError,
+};
+
+/// Direction of a traced message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(Direction::Sent),
+            1 => Ok(Direction::Received),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid trace record direction",
+            )),
+        }
+    }
+}
+
+/// Single message recorded in a trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub timestamp: Duration, // Time since the UNIX epoch
+    pub direction: Direction,
+    pub msg: Vec<u8>,
+}
+
+/// Handle to a trace file shared by a traced sender and receiver
+/// Records are stored as direction (u8), timestamp in microseconds (u64)
+/// and length-and-message encapsulated data, all big endian
+#[derive(Clone)]
+pub struct Tracer {
+    writer: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl Tracer {
+    /// Creates a new trace file, truncating it if it exists
+    pub async fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::create(path).await?;
+        Ok(Self {
+            writer: Arc::new(Mutex::new(BufWriter::new(file))),
+        })
+    }
+
+    /// Appends a message to the trace
+    pub async fn record(&self, direction: Direction, msg: &[u8]) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut writer = self.writer.lock().await;
+        writer.write_u8(direction.to_byte()).await?;
+        writer.write_u64(timestamp).await?;
+        writer.write_u64(msg.len() as u64).await?;
+        writer.write_all(msg).await?;
+        writer.flush().await
+    }
+}
+
+/// Wrapper for an AsyncMsgSend object that records sent messages to a trace
+pub struct TracingMsgSender<S> {
+    sender: S,
+    tracer: Option<Tracer>,
+}
+
+impl<S> TracingMsgSender<S>
+where
+    S: AsyncMsgSend,
+{
+    /// Constructs a new TracingMsgSender. Messages are passed through untraced if
+    /// there is no tracer
+    pub fn new(sender: S, tracer: Option<Tracer>) -> Self {
+        Self { sender, tracer }
+    }
+}
+
+impl<S> AsyncMsgSend for TracingMsgSender<S>
+where
+    S: AsyncMsgSend,
+{
+    async fn send(&mut self, msg: &[u8]) -> Result<(), CommError> {
+        if let Some(tracer) = &self.tracer {
+            tracer.record(Direction::Sent, msg).await?;
+        }
+
+        self.sender.send(msg).await
+    }
+}
+
+/// Wrapper for an AsyncMsgRecv object that records received messages to a trace
+pub struct TracingMsgReceiver<R> {
+    receiver: R,
+    tracer: Option<Tracer>,
+}
+
+impl<R> TracingMsgReceiver<R>
+where
+    R: AsyncMsgRecv,
+{
+    /// Constructs a new TracingMsgReceiver. Messages are passed through untraced if
+    /// there is no tracer
+    pub fn new(receiver: R, tracer: Option<Tracer>) -> Self {
+        Self { receiver, tracer }
+    }
+}
+
+impl<R> AsyncMsgRecv for TracingMsgReceiver<R>
+where
+    R: AsyncMsgRecv,
+{
+    async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+        let msg = self.receiver.recv().await?;
+
+        if let Some(tracer) = &self.tracer {
+            tracer.record(Direction::Received, &msg).await?;
+        }
+
+        Ok(msg)
+    }
+}
+
+/// Reads records from a trace file
+pub struct TraceReader {
+    reader: BufReader<File>,
+}
+
+impl TraceReader {
+    /// Opens a trace file
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(File::open(path).await?),
+        })
+    }
+
+    /// Reads the next record from the trace. Returns None at the end of the trace
+    pub async fn next_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let direction = match self.reader.read_u8().await {
+            Ok(byte) => Direction::from_byte(byte)?,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let timestamp = Duration::from_micros(self.reader.read_u64().await?);
+
+        let len = self.reader.read_u64().await?;
+        let mut msg = Vec::with_capacity(len.min(64 * 1024) as usize);
+        (&mut self.reader).take(len).read_to_end(&mut msg).await?;
+        if msg.len() as u64 != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        Ok(Some(TraceRecord {
+            timestamp,
+            direction,
+            msg,
+        }))
+    }
+}
+
+/// AsyncMsgRecv implementation which replays the received messages of a trace
+pub struct ReplayMsgReceiver {
+    reader: TraceReader,
+    realtime: bool,
+    last: Option<Duration>,
+}
+
+impl ReplayMsgReceiver {
+    /// Constructs a new ReplayMsgReceiver
+    /// If realtime is set, messages are delivered with their original spacing
+    pub fn new(reader: TraceReader, realtime: bool) -> Self {
+        Self {
+            reader,
+            realtime,
+            last: None,
+        }
+    }
+}
+
+impl AsyncMsgRecv for ReplayMsgReceiver {
+    async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+        loop {
+            let record = self
+                .reader
+                .next_record()
+                .await?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "end of trace"))?;
+
+            if record.direction != Direction::Received {
+                continue;
+            }
+
+            // Reproduce original timing
+            if let (true, Some(last)) = (self.realtime, self.last) {
+                time::sleep(record.timestamp.saturating_sub(last)).await;
+            }
+            self.last = Some(record.timestamp);
+
+            return Ok(record.msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// Sender which discards every message
+    struct NullMsgSender;
+
+    impl AsyncMsgSend for NullMsgSender {
+        async fn send(&mut self, _msg: &[u8]) -> Result<(), CommError> {
+            Ok(())
+        }
+    }
+
+    /// Receiver which yields the same message forever
+    struct RepeatMsgReceiver(Vec<u8>);
+
+    impl AsyncMsgRecv for RepeatMsgReceiver {
+        async fn recv(&mut self) -> Result<Vec<u8>, CommError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn trace_record_replay() {
+        let path = env::temp_dir().join(format!("pomegranate-trace-{}", std::process::id()));
+        let tracer = Tracer::create(&path).await.unwrap();
+
+        let mut sender = TracingMsgSender::new(NullMsgSender, Some(tracer.clone()));
+        let mut receiver =
+            TracingMsgReceiver::new(RepeatMsgReceiver(b"pong".to_vec()), Some(tracer));
+
+        sender.send(b"ping").await.unwrap();
+        receiver.recv().await.unwrap();
+        sender.send(b"ping").await.unwrap();
+        receiver.recv().await.unwrap();
+
+        // Check raw records
+        let mut reader = TraceReader::open(&path).await.unwrap();
+        let first = reader.next_record().await.unwrap().unwrap();
+        assert_eq!(first.direction, Direction::Sent);
+        assert_eq!(first.msg, b"ping");
+        let second = reader.next_record().await.unwrap().unwrap();
+        assert_eq!(second.direction, Direction::Received);
+        assert_eq!(second.msg, b"pong");
+
+        // Replay received messages only
+        let mut replay = ReplayMsgReceiver::new(TraceReader::open(&path).await.unwrap(), false);
+        assert_eq!(replay.recv().await.unwrap(), b"pong");
+        assert_eq!(replay.recv().await.unwrap(), b"pong");
+        replay.recv().await.unwrap_err();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
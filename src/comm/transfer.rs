@@ -0,0 +1,377 @@
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{self, AsyncRead, AsyncReadExt, ReadBuf};
+
+use super::{
+    encaps::{AsyncMsgRecv, AsyncMsgSend},
+    protocol::spec::{FRAME_CHUNK, FRAME_END, FRAME_END_LEN, FRAME_START, FRAME_START_LEN},
+};
+
+/// Default maximum length of the data carried by a chunk
+pub const DEFAULT_CHUNK_LEN: usize = 256 * 1024;
+
+/// Typed stream transfer failures
+/// Carried inside an io::Error of kind InvalidData
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferError {
+    UnexpectedFrame,  // Frame is malformed or not valid at this point of the transfer
+    BadResume,        // Resumed transfer doesn't continue the partial one
+    ChecksumMismatch, // Received payload differs from the sent one
+}
+
+impl TransferError {
+    /// Extracts a TransferError from an io::Error, if it carries one
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::UnexpectedFrame => write!(f, "unexpected transfer frame"),
+            TransferError::BadResume => write!(f, "resumed transfer doesn't match"),
+            TransferError::ChecksumMismatch => write!(f, "transfer checksum mismatch"),
+        }
+    }
+}
+
+impl Error for TransferError {}
+
+impl From<TransferError> for io::Error {
+    fn from(err: TransferError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Sends the len bytes read from reader as a stream of chunks
+/// Only one message is held in memory at a time. The peer must be expecting
+/// the stream, as its frames aren't distinguishable from other messages
+pub async fn send_stream(
+    sender: &mut impl AsyncMsgSend,
+    id: u64,
+    reader: impl AsyncRead + Unpin,
+    len: u64,
+    chunk_len: usize,
+) -> io::Result<()> {
+    resume_stream(sender, id, reader, len, 0, chunk_len).await
+}
+
+/// Sends a stream, skipping the first offset bytes the peer already received
+/// The reader must still start from the beginning of the payload, skipped
+/// bytes are read to compute the checksum of the whole payload
+pub async fn resume_stream(
+    sender: &mut impl AsyncMsgSend,
+    id: u64,
+    mut reader: impl AsyncRead + Unpin,
+    len: u64,
+    offset: u64,
+    chunk_len: usize,
+) -> io::Result<()> {
+    if offset > len || chunk_len == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid stream offset or chunk length",
+        ));
+    }
+
+    let mut start = vec![FRAME_START];
+    start.extend_from_slice(&id.to_be_bytes());
+    start.extend_from_slice(&len.to_be_bytes());
+    start.extend_from_slice(&offset.to_be_bytes());
+    sender.send(&start).await?;
+
+    let mut hasher = Sha256::new();
+    let mut pos = 0;
+    let mut buf = vec![0; chunk_len + 1];
+    while pos < len {
+        let n = (len - pos).min(chunk_len as u64) as usize;
+        buf[0] = FRAME_CHUNK;
+        reader.read_exact(&mut buf[1..=n]).await?;
+        hasher.update(&buf[1..=n]);
+
+        // Chunks the peer already has are only hashed, a chunk may straddle the offset
+        let skip = offset.saturating_sub(pos).min(n as u64) as usize;
+        if skip < n {
+            buf[skip] = FRAME_CHUNK;
+            sender.send(&buf[skip..=n]).await?;
+        }
+        pos += n as u64;
+    }
+
+    let mut end = vec![FRAME_END];
+    end.extend_from_slice(&hasher.finalize());
+    Ok(sender.send(&end).await?)
+}
+
+/// State of an interrupted stream, used to resume it on a new connection
+#[derive(Clone)]
+pub struct PartialStream {
+    id: u64,
+    len: u64,
+    received: u64,
+    hasher: Sha256,
+}
+
+impl PartialStream {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the number of bytes received so far, the offset to resume from
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+}
+
+impl fmt::Debug for PartialStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartialStream")
+            .field("id", &self.id)
+            .field("len", &self.len)
+            .field("received", &self.received)
+            .finish()
+    }
+}
+
+/// Waits for the start of a stream and returns a reader of its data
+/// With a partial stream, the sender must resume it from where it stopped.
+/// The reader fails with TransferError::ChecksumMismatch instead of reaching
+/// the end if the payload is corrupted
+pub async fn recv_stream<R: AsyncMsgRecv>(
+    receiver: &mut R,
+    partial: Option<PartialStream>,
+) -> io::Result<StreamReader<'_, R>> {
+    let frame = receiver.recv().await?;
+    let header = match frame.split_first() {
+        Some((&FRAME_START, header)) if header.len() == FRAME_START_LEN - 1 => header,
+        _ => return Err(TransferError::UnexpectedFrame.into()),
+    };
+    let field = |i: usize| u64::from_be_bytes(header[i * 8..(i + 1) * 8].try_into().unwrap());
+    let (id, len, offset) = (field(0), field(1), field(2));
+
+    let state = match partial {
+        Some(partial) if partial.id == id && partial.len == len && partial.received == offset => {
+            partial
+        }
+        None if offset == 0 => PartialStream {
+            id,
+            len,
+            received: 0,
+            hasher: Sha256::new(),
+        },
+        _ => return Err(TransferError::BadResume.into()),
+    };
+
+    Ok(StreamReader {
+        state,
+        pending: Pending::Idle(receiver),
+        chunk: Vec::new(),
+        chunk_pos: 0,
+        done: false,
+    })
+}
+
+/// Message being received by a StreamReader, owning the receiver until it arrives
+type RecvFuture<'a, R> = Pin<Box<dyn Future<Output = (&'a mut R, io::Result<Vec<u8>>)> + 'a>>;
+
+enum Pending<'a, R> {
+    Idle(&'a mut R),
+    Receiving(RecvFuture<'a, R>),
+    Empty,
+}
+
+/// Reader of the data of a stream sent with send_stream
+pub struct StreamReader<'a, R> {
+    state: PartialStream, // Data read so far
+    pending: Pending<'a, R>,
+    chunk: Vec<u8>,   // Chunk being read, with its tag
+    chunk_pos: usize, // Position of the first unread byte in chunk
+    done: bool,
+}
+
+impl<R> StreamReader<'_, R> {
+    /// Returns the total length of the stream
+    pub fn len(&self) -> u64 {
+        self.state.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.len == 0
+    }
+
+    /// Returns the state needed to resume the stream after a failure
+    /// The resumed stream continues right after the data already read,
+    /// buffered data which wasn't read is sent again
+    pub fn partial(&self) -> PartialStream {
+        self.state.clone()
+    }
+
+    /// Handles a chunk or end frame
+    fn handle_frame(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        match frame.first() {
+            Some(&FRAME_CHUNK) => {
+                // Frames are only received once the previous chunk was read
+                let data_len = (frame.len() - 1) as u64;
+                if data_len == 0 || data_len > self.state.len - self.state.received {
+                    return Err(TransferError::UnexpectedFrame.into());
+                }
+                self.chunk = frame;
+                self.chunk_pos = 1;
+                Ok(())
+            }
+            Some(&FRAME_END) if frame.len() == FRAME_END_LEN => {
+                if self.state.received != self.state.len {
+                    return Err(TransferError::UnexpectedFrame.into());
+                }
+                if self.state.hasher.clone().finalize()[..] != frame[1..] {
+                    return Err(TransferError::ChecksumMismatch.into());
+                }
+                self.done = true;
+                Ok(())
+            }
+            _ => Err(TransferError::UnexpectedFrame.into()),
+        }
+    }
+}
+
+impl<'a, R: AsyncMsgRecv + 'a> AsyncRead for StreamReader<'a, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            // Serve buffered data first
+            if this.chunk_pos < this.chunk.len() {
+                let n = buf.remaining().min(this.chunk.len() - this.chunk_pos);
+                let data = &this.chunk[this.chunk_pos..this.chunk_pos + n];
+                buf.put_slice(data);
+                this.state.hasher.update(data);
+                this.state.received += n as u64;
+                this.chunk_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut fut = match std::mem::replace(&mut this.pending, Pending::Empty) {
+                Pending::Idle(receiver) => Box::pin(async move {
+                    let res = receiver.recv().await.map_err(io::Error::from);
+                    (receiver, res)
+                }),
+                Pending::Receiving(fut) => fut,
+                Pending::Empty => {
+                    return Poll::Ready(Err(io::Error::other("stream receiver failed")))
+                }
+            };
+            let (receiver, res) = match fut.as_mut().poll(cx) {
+                Poll::Ready(out) => out,
+                Poll::Pending => {
+                    this.pending = Pending::Receiving(fut);
+                    return Poll::Pending;
+                }
+            };
+            this.pending = Pending::Idle(receiver);
+            res.and_then(|frame| this.handle_frame(frame))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::testutil::{VecMsgReceiver, VecMsgSender};
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn stream_roundtrip() {
+        let data = payload(10_000);
+        let mut sender = VecMsgSender(Vec::new());
+        send_stream(&mut sender, 1, &data[..], data.len() as u64, 3000)
+            .await
+            .unwrap();
+        // Start, 4 chunks, end
+        assert_eq!(sender.0.len(), 6);
+        assert!(sender.0.iter().all(|frame| frame.len() <= 3001));
+
+        let mut receiver = VecMsgReceiver::new(sender.0);
+        let mut reader = recv_stream(&mut receiver, None).await.unwrap();
+        assert_eq!(reader.len(), 10_000);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[tokio::test]
+    async fn stream_corrupted() {
+        let data = payload(100);
+        let mut sender = VecMsgSender(Vec::new());
+        send_stream(&mut sender, 1, &data[..], 100, 30)
+            .await
+            .unwrap();
+        sender.0[2][5] ^= 0xff;
+
+        let mut receiver = VecMsgReceiver::new(sender.0);
+        let mut reader = recv_stream(&mut receiver, None).await.unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(
+            TransferError::from_io(&err),
+            Some(&TransferError::ChecksumMismatch)
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_resume() {
+        let data = payload(1000);
+        let mut sender = VecMsgSender(Vec::new());
+        send_stream(&mut sender, 7, &data[..], 1000, 300)
+            .await
+            .unwrap();
+
+        // Connection lost after the second chunk
+        sender.0.truncate(3);
+        let mut receiver = VecMsgReceiver::new(sender.0);
+        let mut reader = recv_stream(&mut receiver, None).await.unwrap();
+        let mut out = vec![0; 450];
+        reader.read_exact(&mut out).await.unwrap();
+        let partial = reader.partial();
+        assert_eq!(partial.received(), 450);
+
+        // Resuming from the wrong offset is refused
+        let mut sender = VecMsgSender(Vec::new());
+        resume_stream(&mut sender, 7, &data[..], 1000, 600, 300)
+            .await
+            .unwrap();
+        let mut receiver = VecMsgReceiver::new(sender.0);
+        let err = recv_stream(&mut receiver, Some(partial.clone()))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            TransferError::from_io(&err),
+            Some(&TransferError::BadResume)
+        );
+
+        let mut sender = VecMsgSender(Vec::new());
+        resume_stream(&mut sender, 7, &data[..], 1000, partial.received(), 300)
+            .await
+            .unwrap();
+        let mut receiver = VecMsgReceiver::new(sender.0);
+        let mut reader = recv_stream(&mut receiver, Some(partial)).await.unwrap();
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, data);
+    }
+}
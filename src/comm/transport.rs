@@ -0,0 +1,180 @@
+use std::{fmt, future::Future, net::SocketAddr};
+
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+};
+
+#[cfg(unix)]
+use tokio::net::{unix, UnixListener, UnixStream};
+
+/// Reading half of a connection over any transport
+pub type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Writing half of a connection over any transport
+pub type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Way of reaching a server, yielding the halves of a byte stream
+pub trait Transport {
+    type Reader: AsyncRead + Send + Unpin + 'static;
+    type Writer: AsyncWrite + Send + Unpin + 'static;
+
+    /// Connects to the server
+    fn connect(&self) -> impl Future<Output = io::Result<(Self::Reader, Self::Writer)>>;
+}
+
+/// Source of incoming connections, yielding the halves of a byte stream
+pub trait TransportListener {
+    type Reader: AsyncRead + Send + Unpin + 'static;
+    type Writer: AsyncWrite + Send + Unpin + 'static;
+
+    /// Accepts a connection, returning its halves and the address of the peer
+    fn accept(&self) -> impl Future<Output = io::Result<(Self::Reader, Self::Writer, Endpoint)>>;
+}
+
+/// Address of a server or peer on one of the supported transports
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf), // Unix domain socket, for workers on the coordinator's host
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Endpoint::Tcp(addr)
+    }
+}
+
+impl Transport for SocketAddr {
+    type Reader = OwnedReadHalf;
+    type Writer = OwnedWriteHalf;
+
+    async fn connect(&self) -> io::Result<(Self::Reader, Self::Writer)> {
+        Ok(TcpStream::connect(self).await?.into_split())
+    }
+}
+
+#[cfg(unix)]
+impl Transport for PathBuf {
+    type Reader = unix::OwnedReadHalf;
+    type Writer = unix::OwnedWriteHalf;
+
+    async fn connect(&self) -> io::Result<(Self::Reader, Self::Writer)> {
+        Ok(UnixStream::connect(self).await?.into_split())
+    }
+}
+
+impl Transport for Endpoint {
+    type Reader = BoxedReader;
+    type Writer = BoxedWriter;
+
+    async fn connect(&self) -> io::Result<(Self::Reader, Self::Writer)> {
+        match self {
+            Endpoint::Tcp(addr) => boxed(addr.connect().await?),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => boxed(path.connect().await?),
+        }
+    }
+}
+
+impl TransportListener for TcpListener {
+    type Reader = OwnedReadHalf;
+    type Writer = OwnedWriteHalf;
+
+    async fn accept(&self) -> io::Result<(Self::Reader, Self::Writer, Endpoint)> {
+        let (socket, addr) = TcpListener::accept(self).await?;
+        let (reader, writer) = socket.into_split();
+        Ok((reader, writer, addr.into()))
+    }
+}
+
+#[cfg(unix)]
+impl TransportListener for UnixListener {
+    type Reader = unix::OwnedReadHalf;
+    type Writer = unix::OwnedWriteHalf;
+
+    async fn accept(&self) -> io::Result<(Self::Reader, Self::Writer, Endpoint)> {
+        let (socket, addr) = UnixListener::accept(self).await?;
+
+        // Clients are usually unnamed, identify them by the listening socket instead
+        let path = match addr.as_pathname() {
+            Some(path) => path.to_path_buf(),
+            None => self
+                .local_addr()?
+                .as_pathname()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+        };
+
+        let (reader, writer) = socket.into_split();
+        Ok((reader, writer, Endpoint::Unix(path)))
+    }
+}
+
+/// Binds a Unix domain socket, replacing a stale socket file left by a previous run
+#[cfg(unix)]
+pub async fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() && UnixStream::connect(path).await.is_err() {
+        std::fs::remove_file(path)?;
+    }
+
+    UnixListener::bind(path)
+}
+
+/// Boxes the halves of a connection
+pub(crate) fn boxed<R, W>((reader, writer): (R, W)) -> io::Result<(BoxedReader, BoxedWriter)>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::env;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn transport_unix() {
+        let path =
+            env::temp_dir().join(format!("pomegranate-transport-{}.sock", std::process::id()));
+        // A stale socket file doesn't prevent binding
+        drop(bind_unix(&path).await.unwrap());
+        let listener = bind_unix(&path).await.unwrap();
+
+        let endpoint = Endpoint::Unix(path.clone());
+        let (client, server) =
+            tokio::join!(endpoint.connect(), TransportListener::accept(&listener));
+        let (_, mut client_writer) = client.unwrap();
+        let (mut server_reader, _, peer) = server.unwrap();
+        assert_eq!(peer, endpoint);
+
+        client_writer.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        server_reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,23 +1,796 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fmt, fs, io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::comm::{
+    channel::ChannelOptions, heartbeat::HeartbeatConfig, proxy::Proxy, timer::DoublingTimerBuilder,
+    transport::Endpoint,
+};
+
+#[cfg(feature = "tls")]
+use crate::comm::tls::{rustls::RootCertStore, ClientCert};
+use crate::{coordinator::notify::Notifier, metrics::Metrics};
+
+/// Security of the channel to the coordinator
+#[derive(Debug, Clone, Default)]
+pub enum ChannelSecurity {
+    /// RSA authenticated handshake with AES-256-GCM-SIV encryption, keys trusted on first use
+    #[default]
+    Custom,
+    /// Standard TLS with CA-issued certificates
+    #[cfg(feature = "tls")]
+    Tls {
+        root_store: Arc<RootCertStore>, // CAs trusted to sign the coordinator certificate
+        client_cert: Option<ClientCert>, // Certificate presented to the coordinator
+        server_name: Option<String>,    // Name to verify, defaults to the coordinator IP
+    },
+}
 
 /// Configuration of the cluster client
 #[derive(Debug)]
 pub struct ClusterClientConfig {
-    pub coord_addr: SocketAddr, // Cluster Coordinator adddress
-    pub bypass_pk_check: bool,  // Bypass Server public key check
+    pub coord_addr: Endpoint,                  // Cluster Coordinator adddress
+    pub fallback_addrs: Vec<Endpoint>, // Redundant coordinators, tried in order after coord_addr
+    pub bypass_pk_check: bool,         // Bypass Server public key check
+    pub security: ChannelSecurity,     // Encryption and authentication of the channel
+    pub known_hosts: Option<PathBuf>,  // Persist trusted Server public keys to this file
+    pub identity: Option<PathBuf>,     // Private key proving this worker's identity (PEM)
+    pub trace_path: Option<PathBuf>,   // Record decrypted messages to this file
+    pub hexdump_len: Option<usize>,    // Log hexdumps of messages (overrides environment)
+    pub reconnect_timer: DoublingTimerBuilder, // Delay between reconnection attempts
+    pub breaker_threshold: u32,        // Failed attempts before pausing reconnection, 0 = never
+    pub breaker_cooldown: Duration,    // Pause after too many failed attempts
+    pub heartbeat: HeartbeatConfig,    // Keepalive and dead coordinator detection
+    pub worker_id: Option<String>,     // Name sent during onboarding, defaults to the hostname
+    pub tags: Vec<String>,             // Capability labels sent during onboarding
+    pub benchmark_duration: Duration,  // Length of the startup benchmark, zero to skip it
+    pub event_queue_len: usize,        // Cluster events queued before they are dropped
+    pub metrics: Option<Arc<dyn Metrics>>, // Receiver of traffic and connection metrics
+    pub max_tasks: usize,              // Tasks executed at once, defaults to the CPU count
+    pub task_timeout: Duration,        // Maximum duration of a task, zero = unlimited
+    pub max_result_len: usize,         // Larger results are sent as artifacts, in parts
+    pub cluster: Option<String>,       // Only join coordinators of the cluster with this name
+    pub zone: Option<String>,          // Site or availability zone, for job placement
+    pub proxy: Option<Proxy>,          // Tunnel TCP connections to the coordinator through a proxy
 }
 
 impl ClusterClientConfig {
     /// Creates a new ClusterClientConfig instance with default values
+    /// Panics if the address doesn't resolve, see try_new
     pub fn new(coord_addr: impl ToSocketAddrs) -> Self {
+        Self::try_new(coord_addr).unwrap()
+    }
+
+    /// Creates a new ClusterClientConfig instance with default values
+    pub fn try_new(coord_addr: impl ToSocketAddrs) -> Result<Self, ConfigError> {
+        let addr = coord_addr
+            .to_socket_addrs()
+            .map_err(|e| ConfigError::invalid("coord_addr", e))?
+            .next()
+            .ok_or_else(|| ConfigError::invalid("coord_addr", "no addresses resolved"))?;
+
+        Ok(Self::with_endpoint(Endpoint::Tcp(addr)))
+    }
+
+    /// Checks the options which can't be used as set, ClusterClient::try_new calls this
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.event_queue_len == 0 {
+            return Err(ConfigError::invalid("event_queue_len", "must not be zero"));
+        }
+        Ok(())
+    }
+
+    /// Loads the configuration from a TOML file
+    /// Missing options keep their default values, only coord_addr is required
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        let raw: RawClientConfig = toml::from_str(&text).map_err(ConfigError::Parse)?;
+        raw.into_config()
+    }
+
+    /// Loads the configuration from POMEGRANATE_* environment variables
+    /// Variables are named after the file options, e.g. POMEGRANATE_COORD_ADDR.
+    /// Tags are comma separated, hexdumps are configured through POMEGRANATE_HEXDUMP
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let raw = RawClientConfig {
+            coord_addr: env_var("coord_addr")?,
+            fallback_addrs: env_var::<String>("fallback_addrs")?.map(|addrs| split_list(&addrs)),
+            bypass_pk_check: env_var("bypass_pk_check")?,
+            known_hosts: env_var("known_hosts")?,
+            identity: env_var("identity")?,
+            trace_path: env_var("trace_path")?,
+            hexdump_len: None,
+            reconnect_flat: env_var("reconnect_flat")?,
+            reconnect_init_ms: env_var("reconnect_init_ms")?,
+            reconnect_max_ms: env_var("reconnect_max_ms")?,
+            reconnect_multiplier: env_var("reconnect_multiplier")?,
+            reconnect_jitter: env_var("reconnect_jitter")?,
+            reconnect_deadline_ms: env_var("reconnect_deadline_ms")?,
+            breaker_threshold: env_var("breaker_threshold")?,
+            breaker_cooldown_ms: env_var("breaker_cooldown_ms")?,
+            heartbeat_interval_ms: env_var("heartbeat_interval_ms")?,
+            heartbeat_timeout_ms: env_var("heartbeat_timeout_ms")?,
+            worker_id: env_var("worker_id")?,
+            tags: env_var::<String>("tags")?.map(|tags| split_list(&tags)),
+            benchmark_ms: env_var("benchmark_ms")?,
+            max_tasks: env_var("max_tasks")?,
+            task_timeout_ms: env_var("task_timeout_ms")?,
+            max_result_len: env_var("max_result_len")?,
+            cluster: env_var("cluster")?,
+            zone: env_var("zone")?,
+            proxy: env_var("proxy")?,
+        };
+        raw.into_config()
+    }
+
+    fn with_endpoint(coord_addr: Endpoint) -> Self {
         Self {
-            coord_addr: coord_addr.to_socket_addrs().unwrap().next().unwrap(), // TODO: Add error handling
+            coord_addr,
+            fallback_addrs: Vec::new(),
             bypass_pk_check: false,
+            security: ChannelSecurity::default(),
+            known_hosts: None,
+            identity: None,
+            trace_path: None,
+            hexdump_len: None,
+            reconnect_timer: DoublingTimerBuilder::default(),
+            breaker_threshold: 20,
+            breaker_cooldown: Duration::from_secs(300),
+            heartbeat: HeartbeatConfig::default(),
+            worker_id: None,
+            tags: Vec::new(),
+            benchmark_duration: Duration::from_millis(200),
+            event_queue_len: 1024,
+            metrics: None,
+            max_tasks: thread::available_parallelism().map_or(1, |n| n.get()),
+            task_timeout: Duration::from_secs(60 * 60),
+            max_result_len: DEFAULT_MAX_RESULT_LEN,
+            cluster: None,
+            zone: None,
+            proxy: None,
         }
     }
 
+    /// Connects to the coordinator on another endpoint, such as a Unix domain socket
+    pub fn coord_addr(mut self, val: impl Into<Endpoint>) -> Self {
+        self.coord_addr = val.into();
+        self
+    }
+
+    /// Adds a redundant coordinator, tried when the previous ones can't be reached
+    pub fn fallback_addr(mut self, val: impl Into<Endpoint>) -> Self {
+        self.fallback_addrs.push(val.into());
+        self
+    }
+
+    /// Returns the coordinator addresses in the order they are tried
+    pub fn coord_addrs(&self) -> impl Iterator<Item = &Endpoint> {
+        std::iter::once(&self.coord_addr).chain(&self.fallback_addrs)
+    }
+
     pub fn bypass_pk_check(mut self, val: bool) -> Self {
         self.bypass_pk_check = val;
         self
     }
+
+    pub fn security(mut self, val: ChannelSecurity) -> Self {
+        self.security = val;
+        self
+    }
+
+    pub fn known_hosts(mut self, val: impl Into<PathBuf>) -> Self {
+        self.known_hosts = Some(val.into());
+        self
+    }
+
+    pub fn identity(mut self, val: impl Into<PathBuf>) -> Self {
+        self.identity = Some(val.into());
+        self
+    }
+
+    pub fn trace_path(mut self, val: impl Into<PathBuf>) -> Self {
+        self.trace_path = Some(val.into());
+        self
+    }
+
+    pub fn hexdump_len(mut self, val: usize) -> Self {
+        self.hexdump_len = Some(val);
+        self
+    }
+
+    pub fn reconnect_timer(mut self, val: DoublingTimerBuilder) -> Self {
+        self.reconnect_timer = val;
+        self
+    }
+
+    pub fn breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.breaker_threshold = threshold;
+        self.breaker_cooldown = cooldown;
+        self
+    }
+
+    pub fn heartbeat(mut self, val: HeartbeatConfig) -> Self {
+        self.heartbeat = val;
+        self
+    }
+
+    pub fn worker_id(mut self, val: impl Into<String>) -> Self {
+        self.worker_id = Some(val.into());
+        self
+    }
+
+    pub fn tag(mut self, val: impl Into<String>) -> Self {
+        self.tags.push(val.into());
+        self
+    }
+
+    pub fn benchmark_duration(mut self, val: Duration) -> Self {
+        self.benchmark_duration = val;
+        self
+    }
+
+    pub fn event_queue_len(mut self, val: usize) -> Self {
+        self.event_queue_len = val;
+        self
+    }
+
+    pub fn metrics(mut self, val: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(val);
+        self
+    }
+
+    pub fn max_tasks(mut self, val: usize) -> Self {
+        self.max_tasks = val;
+        self
+    }
+
+    pub fn task_timeout(mut self, val: Duration) -> Self {
+        self.task_timeout = val;
+        self
+    }
+
+    pub fn cluster(mut self, val: impl Into<String>) -> Self {
+        self.cluster = Some(val.into());
+        self
+    }
+
+    pub fn zone(mut self, val: impl Into<String>) -> Self {
+        self.zone = Some(val.into());
+        self
+    }
+
+    pub fn max_result_len(mut self, val: usize) -> Self {
+        self.max_result_len = val;
+        self
+    }
+
+    pub fn proxy(mut self, val: Proxy) -> Self {
+        self.proxy = Some(val);
+        self
+    }
+}
+
+/// Prefix of the environment variables read by ClusterClientConfig::from_env
+pub const ENV_PREFIX: &str = "POMEGRANATE_";
+
+/// Default maximum length of a task result sent inline
+pub const DEFAULT_MAX_RESULT_LEN: usize = 4 * 1024 * 1024;
+
+/// Default maximum length of a task result uploaded to the artifact store
+pub const DEFAULT_MAX_ARTIFACT_LEN: u64 = 1024 * 1024 * 1024;
+
+/// Error produced when loading a configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),                  // Configuration file can't be read
+    Parse(toml::de::Error), // Configuration file isn't valid TOML or has unknown options
+    Invalid { key: String, reason: String }, // Option has an invalid value
+}
+
+impl ConfigError {
+    pub(crate) fn invalid(key: &str, reason: impl fmt::Display) -> Self {
+        ConfigError::Invalid {
+            key: key.to_owned(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "can't read {}: {}", path.display(), err),
+            ConfigError::Parse(err) => write!(f, "invalid configuration file: {}", err),
+            ConfigError::Invalid { key, reason } => write!(f, "invalid {}: {}", key, reason),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io(_, err) => Some(err),
+            ConfigError::Parse(err) => Some(err),
+            ConfigError::Invalid { .. } => None,
+        }
+    }
+}
+
+/// Client options as written in a configuration file
+/// Durations are in milliseconds
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawClientConfig {
+    coord_addr: Option<String>,
+    fallback_addrs: Option<Vec<String>>,
+    bypass_pk_check: Option<bool>,
+    known_hosts: Option<PathBuf>,
+    identity: Option<PathBuf>,
+    trace_path: Option<PathBuf>,
+    hexdump_len: Option<usize>,
+    reconnect_flat: Option<u32>,
+    reconnect_init_ms: Option<u64>,
+    reconnect_max_ms: Option<u64>,
+    reconnect_multiplier: Option<f64>,
+    reconnect_jitter: Option<String>, // none, full or equal
+    reconnect_deadline_ms: Option<u64>,
+    breaker_threshold: Option<u32>,
+    breaker_cooldown_ms: Option<u64>,
+    heartbeat_interval_ms: Option<u64>,
+    heartbeat_timeout_ms: Option<u64>,
+    worker_id: Option<String>,
+    tags: Option<Vec<String>>,
+    benchmark_ms: Option<u64>,
+    max_tasks: Option<usize>,
+    task_timeout_ms: Option<u64>,
+    max_result_len: Option<usize>,
+    cluster: Option<String>,
+    zone: Option<String>,
+    proxy: Option<String>, // socks5://[user:password@]host:port or http://host:port
+}
+
+impl RawClientConfig {
+    /// Validates the options and applies them over the defaults
+    fn into_config(self) -> Result<ClusterClientConfig, ConfigError> {
+        let addr = self
+            .coord_addr
+            .ok_or_else(|| ConfigError::invalid("coord_addr", "missing"))?;
+        let mut config = ClusterClientConfig::with_endpoint(resolve_endpoint("coord_addr", &addr)?);
+        for addr in self.fallback_addrs.unwrap_or_default() {
+            config
+                .fallback_addrs
+                .push(resolve_endpoint("fallback_addrs", &addr)?);
+        }
+
+        if let Some(val) = self.bypass_pk_check {
+            config.bypass_pk_check = val;
+        }
+        config.known_hosts = self.known_hosts;
+        config.identity = self.identity;
+        config.trace_path = self.trace_path;
+        config.hexdump_len = self.hexdump_len;
+        config.cluster = self.cluster;
+        config.zone = self.zone;
+        if let Some(val) = self.proxy {
+            config.proxy = Some(val.parse().map_err(|e| ConfigError::invalid("proxy", e))?);
+        }
+
+        let timer = &mut config.reconnect_timer;
+        if let Some(val) = self.reconnect_flat {
+            timer.flat = val;
+        }
+        if let Some(val) = self.reconnect_init_ms {
+            timer.init_dur = Duration::from_millis(val);
+        }
+        if let Some(val) = self.reconnect_max_ms {
+            timer.max_dur = Duration::from_millis(val);
+        }
+        if let Some(val) = self.reconnect_multiplier {
+            if !(val.is_finite() && val >= 1.0) {
+                return Err(ConfigError::invalid(
+                    "reconnect_multiplier",
+                    "must be at least 1",
+                ));
+            }
+            timer.multiplier = val;
+        }
+        if let Some(val) = self.reconnect_jitter {
+            timer.jitter = val
+                .parse()
+                .map_err(|e| ConfigError::invalid("reconnect_jitter", e))?;
+        }
+        if let Some(val) = self.reconnect_deadline_ms {
+            timer.deadline = Some(Duration::from_millis(val));
+        }
+        if timer.init_dur > timer.max_dur {
+            return Err(ConfigError::invalid(
+                "reconnect_init_ms",
+                "greater than reconnect_max_ms",
+            ));
+        }
+
+        if let Some(val) = self.breaker_threshold {
+            config.breaker_threshold = val;
+        }
+        if let Some(val) = self.breaker_cooldown_ms {
+            config.breaker_cooldown = Duration::from_millis(val);
+        }
+
+        if let Some(val) = self.heartbeat_interval_ms {
+            config.heartbeat.interval = Duration::from_millis(val);
+        }
+        if let Some(val) = self.heartbeat_timeout_ms {
+            config.heartbeat.timeout = Duration::from_millis(val);
+        }
+        if config.heartbeat.interval.is_zero() {
+            return Err(ConfigError::invalid(
+                "heartbeat_interval_ms",
+                "must not be zero",
+            ));
+        }
+        if config.heartbeat.timeout <= config.heartbeat.interval {
+            return Err(ConfigError::invalid(
+                "heartbeat_timeout_ms",
+                "must be greater than heartbeat_interval_ms",
+            ));
+        }
+
+        config.worker_id = self.worker_id;
+        config.tags = self.tags.unwrap_or_default();
+        if let Some(val) = self.benchmark_ms {
+            config.benchmark_duration = Duration::from_millis(val);
+        }
+
+        if let Some(val) = self.max_tasks {
+            if val == 0 {
+                return Err(ConfigError::invalid("max_tasks", "must not be zero"));
+            }
+            config.max_tasks = val;
+        }
+        if let Some(val) = self.task_timeout_ms {
+            config.task_timeout = Duration::from_millis(val);
+        }
+        if let Some(val) = self.max_result_len {
+            if val == 0 {
+                return Err(ConfigError::invalid("max_result_len", "must not be zero"));
+            }
+            config.max_result_len = val;
+        }
+
+        Ok(config)
+    }
+}
+
+/// Resolves a coordinator address, either host:port or unix:path
+fn resolve_endpoint(key: &str, addr: &str) -> Result<Endpoint, ConfigError> {
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return Ok(Endpoint::Unix(path.into()));
+    }
+
+    addr.to_socket_addrs()
+        .map_err(|e| ConfigError::invalid(key, format!("{}: {}", addr, e)))?
+        .next()
+        .map(Endpoint::Tcp)
+        .ok_or_else(|| ConfigError::invalid(key, format!("{} didn't resolve", addr)))
+}
+
+/// Splits a comma separated list, skipping empty items
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Reads and parses the environment variable of an option, if set
+fn env_var<T>(key: &str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let name = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+    match env::var(&name) {
+        Ok(val) => val
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| ConfigError::invalid(&name, e)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(ConfigError::invalid(&name, e)),
+    }
+}
+
+/// Configuration of the cluster coordinator
+#[derive(Debug)]
+pub struct ClusterCoordinatorConfig {
+    pub bind_addr: SocketAddr, // Address to listen for workers on
+    #[cfg(unix)]
+    pub unix_socket: Option<PathBuf>, // Also listen on this Unix domain socket
+    pub channel: ChannelOptions, // Encrypted channel options
+    pub worker_queue_len: usize, // Outgoing messages queued per worker
+    pub event_queue_len: usize, // Worker events queued before they are dropped
+    pub heartbeat: HeartbeatConfig, // Keepalive and dead worker detection
+    pub notifiers: Vec<Arc<dyn Notifier>>, // Receivers of operator notifications
+    pub stall_timeout: Duration, // Pending tasks without progress before notifying, zero = never
+    pub allowlist: Option<PathBuf>, // Authorized clients file receiving approved workers
+    pub cluster: Option<String>, // Name of the cluster, workers configured for another are rejected
+    pub artifact_dir: Option<PathBuf>, // Store of results too large to be sent inline
+    pub max_result_len: usize, // Larger inline results are moved to the artifact store
+    pub max_artifact_len: u64, // Larger results uploaded in parts fail their task
+    pub max_queued_tasks: usize, // Unfinished tasks accepted across jobs, zero = unlimited
+    pub queue_limits: HashMap<String, usize>, // Unfinished tasks accepted per task kind
+    pub event_log: Option<PathBuf>, // Log of job and worker lifecycle events
+}
+
+impl ClusterCoordinatorConfig {
+    /// Creates a new ClusterCoordinatorConfig instance with default values
+    /// Panics if the address doesn't resolve, see try_new
+    pub fn new(bind_addr: impl ToSocketAddrs) -> Self {
+        Self::try_new(bind_addr).unwrap()
+    }
+
+    /// Creates a new ClusterCoordinatorConfig instance with default values
+    pub fn try_new(bind_addr: impl ToSocketAddrs) -> Result<Self, ConfigError> {
+        let bind_addr = bind_addr
+            .to_socket_addrs()
+            .map_err(|e| ConfigError::invalid("bind_addr", e))?
+            .next()
+            .ok_or_else(|| ConfigError::invalid("bind_addr", "no addresses resolved"))?;
+
+        Ok(Self {
+            bind_addr,
+            #[cfg(unix)]
+            unix_socket: None,
+            channel: ChannelOptions::default(),
+            worker_queue_len: 64,
+            event_queue_len: 1024,
+            heartbeat: HeartbeatConfig::default(),
+            notifiers: Vec::new(),
+            stall_timeout: Duration::from_secs(600),
+            allowlist: None,
+            cluster: None,
+            artifact_dir: None,
+            max_result_len: DEFAULT_MAX_RESULT_LEN,
+            max_artifact_len: DEFAULT_MAX_ARTIFACT_LEN,
+            max_queued_tasks: 0,
+            queue_limits: HashMap::new(),
+            event_log: None,
+        })
+    }
+
+    /// Checks the options which can't be used as set, ClusterCoordinator::bind calls this
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.worker_queue_len == 0 {
+            return Err(ConfigError::invalid("worker_queue_len", "must not be zero"));
+        }
+        if self.event_queue_len == 0 {
+            return Err(ConfigError::invalid("event_queue_len", "must not be zero"));
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    pub fn unix_socket(mut self, val: impl Into<PathBuf>) -> Self {
+        self.unix_socket = Some(val.into());
+        self
+    }
+
+    pub fn channel(mut self, val: ChannelOptions) -> Self {
+        self.channel = val;
+        self
+    }
+
+    pub fn worker_queue_len(mut self, val: usize) -> Self {
+        self.worker_queue_len = val;
+        self
+    }
+
+    pub fn event_queue_len(mut self, val: usize) -> Self {
+        self.event_queue_len = val;
+        self
+    }
+
+    pub fn heartbeat(mut self, val: HeartbeatConfig) -> Self {
+        self.heartbeat = val;
+        self
+    }
+
+    pub fn notifier(mut self, val: Arc<dyn Notifier>) -> Self {
+        self.notifiers.push(val);
+        self
+    }
+
+    pub fn stall_timeout(mut self, val: Duration) -> Self {
+        self.stall_timeout = val;
+        self
+    }
+
+    pub fn allowlist(mut self, val: impl Into<PathBuf>) -> Self {
+        self.allowlist = Some(val.into());
+        self
+    }
+
+    pub fn cluster(mut self, val: impl Into<String>) -> Self {
+        self.cluster = Some(val.into());
+        self
+    }
+
+    pub fn artifact_dir(mut self, val: impl Into<PathBuf>) -> Self {
+        self.artifact_dir = Some(val.into());
+        self
+    }
+
+    pub fn max_result_len(mut self, val: usize) -> Self {
+        self.max_result_len = val;
+        self
+    }
+
+    pub fn max_artifact_len(mut self, val: u64) -> Self {
+        self.max_artifact_len = val;
+        self
+    }
+
+    pub fn max_queued_tasks(mut self, val: usize) -> Self {
+        self.max_queued_tasks = val;
+        self
+    }
+
+    /// Limits the unfinished tasks of a kind, on top of max_queued_tasks
+    pub fn queue_limit(mut self, kind: impl Into<String>, val: usize) -> Self {
+        self.queue_limits.insert(kind.into(), val);
+        self
+    }
+
+    pub fn event_log(mut self, val: impl Into<PathBuf>) -> Self {
+        self.event_log = Some(val.into());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::timer::Jitter;
+
+    #[test]
+    fn coordinator_config_try_new() {
+        let config = ClusterCoordinatorConfig::try_new("127.0.0.1:5000").unwrap();
+        assert_eq!(
+            config.bind_addr,
+            "127.0.0.1:5000".parse::<SocketAddr>().unwrap()
+        );
+        assert!(matches!(
+            ClusterCoordinatorConfig::try_new("127.0.0.1"),
+            Err(ConfigError::Invalid { key, .. }) if key == "bind_addr"
+        ));
+    }
+
+    #[test]
+    fn config_zero_queue_len() {
+        let config = ClusterCoordinatorConfig::new("127.0.0.1:0");
+        assert!(config.validate().is_ok());
+        assert!(matches!(
+            config.worker_queue_len(0).validate(),
+            Err(ConfigError::Invalid { key, .. }) if key == "worker_queue_len"
+        ));
+        assert!(matches!(
+            ClusterCoordinatorConfig::new("127.0.0.1:0").event_queue_len(0).validate(),
+            Err(ConfigError::Invalid { key, .. }) if key == "event_queue_len"
+        ));
+        assert!(matches!(
+            ClusterClientConfig::new("127.0.0.1:5000").event_queue_len(0).validate(),
+            Err(ConfigError::Invalid { key, .. }) if key == "event_queue_len"
+        ));
+    }
+
+    #[test]
+    fn client_config_from_file() {
+        let path = env::temp_dir().join(format!("pomegranate-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+            coord_addr = "127.0.0.1:5000"
+            fallback_addrs = ["127.0.0.1:5001"]
+            known_hosts = "/var/lib/pomegranate/known_hosts"
+            reconnect_max_ms = 60000
+            reconnect_jitter = "equal"
+            heartbeat_interval_ms = 1000
+            heartbeat_timeout_ms = 4000
+            tags = ["gpu"]
+            cluster = "prod"
+            zone = "eu-west"
+            max_result_len = 65536
+            proxy = "http://10.0.0.1:3128"
+            "#,
+        )
+        .unwrap();
+        let config = ClusterClientConfig::from_file(&path).unwrap();
+        assert_eq!(
+            config.coord_addr,
+            Endpoint::Tcp("127.0.0.1:5000".parse().unwrap())
+        );
+        assert_eq!(
+            config
+                .coord_addrs()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>(),
+            ["127.0.0.1:5000", "127.0.0.1:5001"]
+        );
+        assert_eq!(config.reconnect_timer.max_dur, Duration::from_secs(60));
+        assert_eq!(config.reconnect_timer.jitter, Jitter::Equal);
+        assert_eq!(config.heartbeat.timeout, Duration::from_secs(4));
+        assert_eq!(config.breaker_threshold, 20);
+        assert_eq!(config.tags, ["gpu"]);
+        assert_eq!(config.cluster.as_deref(), Some("prod"));
+        assert_eq!(config.zone.as_deref(), Some("eu-west"));
+        assert_eq!(config.max_result_len, 65536);
+        assert_eq!(
+            config.proxy,
+            Some(Proxy::Http {
+                addr: "10.0.0.1:3128".to_string()
+            })
+        );
+
+        fs::write(&path, "coord_addr = \"127.0.0.1:5000\"\ntimeout = 3\n").unwrap();
+        assert!(matches!(
+            ClusterClientConfig::from_file(&path),
+            Err(ConfigError::Parse(_))
+        ));
+
+        fs::write(
+            &path,
+            "coord_addr = \"127.0.0.1:5000\"\nheartbeat_timeout_ms = 10\n",
+        )
+        .unwrap();
+        match ClusterClientConfig::from_file(&path) {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "heartbeat_timeout_ms"),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            ClusterClientConfig::from_file(&path),
+            Err(ConfigError::Io(..))
+        ));
+    }
+
+    #[test]
+    fn client_config_from_env() {
+        env::set_var("POMEGRANATE_COORD_ADDR", "127.0.0.1:5001");
+        env::set_var("POMEGRANATE_BYPASS_PK_CHECK", "true");
+        env::set_var("POMEGRANATE_TAGS", "gpu, avx2");
+        let config = ClusterClientConfig::from_env().unwrap();
+        assert_eq!(
+            config.coord_addr,
+            Endpoint::Tcp("127.0.0.1:5001".parse().unwrap())
+        );
+        assert!(config.bypass_pk_check);
+        assert_eq!(config.tags, ["gpu", "avx2"]);
+
+        env::set_var("POMEGRANATE_BREAKER_THRESHOLD", "many");
+        match ClusterClientConfig::from_env() {
+            Err(ConfigError::Invalid { key, .. }) => {
+                assert_eq!(key, "POMEGRANATE_BREAKER_THRESHOLD")
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        for key in ["COORD_ADDR", "BYPASS_PK_CHECK", "TAGS", "BREAKER_THRESHOLD"] {
+            env::remove_var(format!("{}{}", ENV_PREFIX, key));
+        }
+    }
 }
@@ -0,0 +1,1648 @@
+pub mod approval;
+pub mod artifacts;
+pub mod eventlog;
+pub mod jobs;
+pub mod notify;
+pub mod pipeline;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{self, mpsc, mpsc::error::TrySendError},
+    time::{self, Instant},
+};
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use crate::{
+    comm::{
+        channel::server_channel,
+        crypto::{AuthorizedClients, RsaKeyPair},
+        encaps::{AsyncMsgRecv, AsyncMsgSend},
+        heartbeat::recv_timeout,
+        protocol::{
+            ClientMessage, ClusterStatus, CoordinatorMessage, OnboardingReject, ProtocolError,
+            TaskOutcome, TypedMsgReceiver, TypedMsgSender, WorkerAssignment, WorkerHello,
+            WorkerStatus, PROTOCOL_VERSION,
+        },
+        transport::{Endpoint, TransportListener},
+    },
+    config::ClusterCoordinatorConfig,
+    coordinator::{
+        approval::ApprovalQueue,
+        artifacts::{ArtifactStore, Upload},
+        eventlog::{EventLog, LifecycleEvent},
+        jobs::{Explanation, JobHandle, JobSpec, QueueFull, Scheduler, TaskId},
+        notify::Notification,
+    },
+    systemd::{self, Watchdog},
+};
+
+/// Delay before accepting again after an accept error (e.g. out of file descriptors)
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Identifier assigned to a worker connection by the coordinator
+pub type WorkerId = u64;
+
+/// Information about a connected worker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerInfo {
+    pub id: WorkerId,
+    pub addr: Endpoint,
+    pub hello: WorkerHello, // Introduction sent by the worker during onboarding
+}
+
+/// Event concerning a connected worker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorkerEvent {
+    Connected(WorkerInfo),
+    Message { id: WorkerId, msg: Vec<u8> },
+    Disconnected { id: WorkerId },
+}
+
+/// Coordinator side of a worker connection
+struct WorkerHandle {
+    info: WorkerInfo,
+    tx: mpsc::Sender<CoordinatorMessage>, // Queue of the connection's writer task
+    last_seen: Instant,                   // Last message received from the worker
+}
+
+/// State shared between the coordinator and the connection tasks
+struct Shared {
+    config: ClusterCoordinatorConfig,
+    keypair: RsaKeyPair,
+    workers: Mutex<HashMap<WorkerId, WorkerHandle>>,
+    scheduler: Mutex<Scheduler>,
+    approvals: Mutex<ApprovalQueue>,
+    artifacts: Option<ArtifactStore>,
+    event_log: Option<EventLog>,
+    queue_room: sync::Notify, // Notified when tasks leave the queue
+    redispatch: AtomicBool, // A task didn't fit in a worker's queue, dispatch again once it drains
+    next_id: AtomicU64,
+    events_tx: mpsc::Sender<WorkerEvent>,
+    dropped_events: AtomicU64, // Events dropped because the queue was full
+    watchdog: Watchdog,
+}
+
+/// Pomegranate Cluster Coordinator
+pub struct ClusterCoordinator {
+    listener: TcpListener,
+    #[cfg(unix)]
+    unix_listener: Option<UnixListener>,
+    shared: Arc<Shared>,
+    events_rx: sync::Mutex<mpsc::Receiver<WorkerEvent>>,
+}
+
+impl ClusterCoordinator {
+    /// Creates new ClusterCoordinator listening on the configured address
+    /// Fails with InvalidInput if the configuration doesn't validate
+    pub async fn bind(config: ClusterCoordinatorConfig, keypair: RsaKeyPair) -> io::Result<Self> {
+        config
+            .validate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let listener = TcpListener::bind(config.bind_addr).await?;
+        #[cfg(unix)]
+        let unix_listener = match &config.unix_socket {
+            Some(path) => Some(crate::comm::transport::bind_unix(path).await?),
+            None => None,
+        };
+        let (events_tx, events_rx) = mpsc::channel(config.event_queue_len);
+        let mut scheduler = Scheduler::default();
+        scheduler.set_queue_limits(config.max_queued_tasks, config.queue_limits.clone());
+        let approvals = ApprovalQueue::new(config.allowlist.clone());
+        let artifacts = match &config.artifact_dir {
+            Some(dir) => Some(ArtifactStore::open(dir).await?),
+            None => None,
+        };
+        let event_log = match &config.event_log {
+            Some(path) => Some(EventLog::open(path)?),
+            None => None,
+        };
+
+        Ok(Self {
+            listener,
+            #[cfg(unix)]
+            unix_listener,
+            shared: Arc::new(Shared {
+                config,
+                keypair,
+                workers: Mutex::new(HashMap::new()),
+                scheduler: Mutex::new(scheduler),
+                approvals: Mutex::new(approvals),
+                artifacts,
+                event_log,
+                queue_room: sync::Notify::new(),
+                redispatch: AtomicBool::new(false),
+                next_id: AtomicU64::new(0),
+                events_tx,
+                dropped_events: AtomicU64::new(0),
+                watchdog: Watchdog::from_env(),
+            }),
+            events_rx: sync::Mutex::new(events_rx),
+        })
+    }
+
+    /// Returns the address the coordinator is listening on
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Returns the store of results too large to be sent inline, if configured
+    pub fn artifacts(&self) -> Option<&ArtifactStore> {
+        self.shared.artifacts.as_ref()
+    }
+
+    /// Returns the log of job and worker lifecycle events, if configured
+    pub fn event_log(&self) -> Option<&EventLog> {
+        self.shared.event_log.as_ref()
+    }
+
+    /// Run Coordinator
+    /// Accepts workers until the returned future is dropped
+    /// Under systemd, readiness is notified and the watchdog pinged while
+    /// the accept loop and the scheduler make progress
+    pub async fn run(&self) {
+        let addr = self.listener.local_addr().unwrap();
+        info!("Listening on {}", addr);
+        systemd::notify_status(&format!("Listening on {}", addr));
+        systemd::notify_ready();
+
+        let accept = async {
+            #[cfg(unix)]
+            if let Some(unix_listener) = &self.unix_listener {
+                if let Some(path) = &self.shared.config.unix_socket {
+                    info!("Listening on {}", path.display());
+                }
+                tokio::join!(
+                    accept_loop(&self.shared, &self.listener),
+                    accept_loop(&self.shared, unix_listener),
+                );
+                return;
+            }
+
+            accept_loop(&self.shared, &self.listener).await
+        };
+
+        tokio::join!(
+            accept,
+            self.shared.watch_stalls(),
+            self.shared.watch_scheduler()
+        );
+    }
+
+    /// Waits for the next worker event
+    /// Events are dropped if they aren't read, connections never wait for the reader
+    pub async fn next_event(&self) -> WorkerEvent {
+        self.events_rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .expect("event sender is owned by the coordinator")
+    }
+
+    /// Returns the number of worker events dropped because nobody read them
+    pub fn dropped_events(&self) -> u64 {
+        self.shared.dropped_events.load(Ordering::Relaxed)
+    }
+
+    /// Returns information about the connected workers, ordered by ID
+    pub fn workers(&self) -> Vec<WorkerInfo> {
+        let mut workers: Vec<_> = self
+            .shared
+            .workers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|worker| worker.info.clone())
+            .collect();
+        workers.sort_by_key(|worker| worker.id);
+        workers
+    }
+
+    /// Returns the state of the cluster: connected workers, their tasks and the queue
+    pub fn cluster_status(&self) -> ClusterStatus {
+        self.shared.status()
+    }
+
+    /// Approves or rejects a worker waiting for approval, by its identity fingerprint
+    /// Approved identities are appended to the configured allowlist file.
+    /// Returns false if no worker with that identity is waiting
+    pub fn decide_approval(&self, fingerprint: &str, approve: bool) -> io::Result<bool> {
+        self.shared
+            .approvals
+            .lock()
+            .unwrap()
+            .decide(fingerprint, approve)
+    }
+
+    /// Queues a message to a worker
+    pub async fn send(&self, id: WorkerId, msg: Vec<u8>) -> io::Result<()> {
+        self.shared.send_to(id, CoordinatorMessage::Data(msg)).await
+    }
+
+    /// Asks a worker to rerun its benchmark
+    /// The new score shows up in the worker's information once reported
+    pub async fn request_benchmark(&self, id: WorkerId) -> io::Result<()> {
+        self.shared.send_to(id, CoordinatorMessage::Benchmark).await
+    }
+
+    /// Queues a message to all connected workers
+    /// Returns the number of workers the message was queued to
+    pub async fn broadcast(&self, msg: &[u8]) -> usize {
+        let txs: Vec<_> = self
+            .shared
+            .workers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|worker| worker.tx.clone())
+            .collect();
+
+        let mut count = 0;
+        for tx in txs {
+            if tx
+                .send(CoordinatorMessage::Data(msg.to_vec()))
+                .await
+                .is_ok()
+            {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Explains how a job would be scheduled right now, without submitting it
+    /// Useful to find out why the tasks of a job stay pending
+    pub fn explain(&self, spec: &JobSpec) -> Explanation {
+        self.shared.scheduler.lock().unwrap().explain(spec)
+    }
+
+    /// Submits a job, distributing its tasks to the connected workers
+    /// Fails if the job doesn't fit within the configured queue limits
+    pub fn submit_job(&self, spec: JobSpec) -> Result<JobHandle, QueueFull> {
+        let mut scheduler = self.shared.scheduler.lock().unwrap();
+        scheduler.check_room(&spec)?;
+        let handle = scheduler.submit(spec);
+        drop(scheduler);
+
+        Ok(self.submitted(handle))
+    }
+
+    /// Submits a job, waiting up to timeout for its tasks to fit within the queue limits
+    /// Jobs larger than a limit are rejected right away
+    pub async fn submit_job_timeout(
+        &self,
+        spec: JobSpec,
+        timeout: Duration,
+    ) -> Result<JobHandle, QueueFull> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Created before checking, so no room is missed
+            let room = self.shared.queue_room.notified();
+            let full = {
+                let mut scheduler = self.shared.scheduler.lock().unwrap();
+                match scheduler.check_room(&spec) {
+                    Ok(()) => {
+                        let handle = scheduler.submit(spec);
+                        drop(scheduler);
+                        return Ok(self.submitted(handle));
+                    }
+                    Err(full) => full,
+                }
+            };
+
+            if spec.tasks.len() > full.limit || time::timeout_at(deadline, room).await.is_err() {
+                return Err(full);
+            }
+        }
+    }
+
+    fn submitted(&self, handle: JobHandle) -> JobHandle {
+        debug!(job = handle.id(); "Submitted job {} with {} tasks", handle.id(), handle.len());
+        self.shared.dispatch();
+        handle
+    }
+}
+
+impl Shared {
+    /// Queues a protocol message to a worker
+    async fn send_to(&self, id: WorkerId, msg: CoordinatorMessage) -> io::Result<()> {
+        let tx = match self.workers.lock().unwrap().get(&id) {
+            Some(worker) => worker.tx.clone(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no worker with id {}", id),
+                ))
+            }
+        };
+
+        tx.send(msg).await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("worker {} disconnected", id),
+            )
+        })
+    }
+
+    /// Queues a worker event, dropping it if nobody reads the events
+    fn emit(&self, event: WorkerEvent) {
+        if let Err(TrySendError::Full(event)) = self.events_tx.try_send(event) {
+            self.dropped_events.fetch_add(1, Ordering::Relaxed);
+            debug!("Event queue full, dropping {:?}", event);
+        }
+    }
+
+    /// Builds a snapshot of the cluster state
+    fn status(&self) -> ClusterStatus {
+        // Same lock order as dispatch
+        let scheduler = self.scheduler.lock().unwrap();
+        let workers = self.workers.lock().unwrap();
+
+        let mut statuses: Vec<_> = workers
+            .values()
+            .map(|worker| WorkerStatus {
+                id: worker.info.id,
+                addr: worker.info.addr.to_string(),
+                hello: worker.info.hello.clone(),
+                last_seen_ms: worker.last_seen.elapsed().as_millis() as u64,
+                running_tasks: scheduler.running(worker.info.id),
+            })
+            .collect();
+        statuses.sort_by_key(|worker| worker.id);
+
+        ClusterStatus {
+            workers: statuses,
+            pending_tasks: scheduler.pending_len() as u64,
+            pending_approval: self.approvals.lock().unwrap().pending(),
+        }
+    }
+
+    /// Returns true if a task is assigned to a worker, so the worker may send its result
+    fn is_running(&self, id: WorkerId, task_id: TaskId) -> bool {
+        self.scheduler.lock().unwrap().is_running(id, task_id)
+    }
+
+    /// Returns true if a peer proved an identity the coordinator trusts
+    /// Only such peers may see the cluster status or decide on pending workers
+    fn is_authorized(&self, fingerprint: Option<&str>) -> bool {
+        match (
+            self.config.channel.authorized_clients.as_deref(),
+            fingerprint,
+        ) {
+            (Some(authorized), Some(peer)) => self
+                .approvals
+                .lock()
+                .unwrap()
+                .is_authorized(authorized, peer),
+            _ => false,
+        }
+    }
+
+    /// Returns the authorized clients if unknown workers are held for approval
+    fn approval_mode(&self) -> Option<&AuthorizedClients> {
+        self.config
+            .channel
+            .authorized_clients
+            .as_deref()
+            .filter(|authorized| authorized.admits_unknown())
+    }
+
+    /// Sends pending tasks to workers with free slots
+    fn dispatch(&self) {
+        let mut scheduler = self.scheduler.lock().unwrap();
+        let workers = self.workers.lock().unwrap();
+
+        for (id, task) in scheduler.assign() {
+            let task_id = task.task_id;
+            let res = match workers.get(&id) {
+                Some(worker) => worker.tx.try_send(CoordinatorMessage::Task(task)),
+                None => Err(TrySendError::Closed(CoordinatorMessage::Task(task))),
+            };
+
+            // Worker queue full or gone, try again once a writer drains or the worker is removed
+            if let Err(e) = res {
+                if matches!(e, TrySendError::Full(_)) {
+                    self.redispatch.store(true, Ordering::Relaxed);
+                }
+                scheduler.unassign(id, task_id);
+            }
+        }
+
+        let notifications = scheduler.take_notifications();
+        let lifecycle = scheduler.take_lifecycle();
+        drop(workers);
+        drop(scheduler);
+        self.notify(notifications);
+        self.log(lifecycle);
+    }
+
+    /// Appends lifecycle events to the event log, if configured
+    fn log(&self, events: impl IntoIterator<Item = LifecycleEvent>) {
+        let Some(event_log) = &self.event_log else {
+            return;
+        };
+
+        for event in events {
+            if let Err(e) = event_log.append(event) {
+                warn!("Error writing to the event log: {}", e);
+            }
+        }
+    }
+
+    /// Passes notifications to the configured notifiers
+    fn notify(&self, notifications: impl IntoIterator<Item = Notification>) {
+        for notification in notifications {
+            debug!("Notification: {}", notification);
+            for notifier in &self.config.notifiers {
+                notifier.notify(&notification);
+            }
+        }
+    }
+
+    /// Pings the systemd watchdog while the scheduler stays responsive
+    async fn watch_scheduler(&self) {
+        let Some(period) = self.watchdog.period() else {
+            return;
+        };
+
+        let mut interval = time::interval(period);
+        loop {
+            interval.tick().await;
+            // Blocks if the scheduler is stuck, which stops the pings
+            drop(self.scheduler.lock().unwrap());
+            self.watchdog.progress();
+        }
+    }
+
+    /// Periodically checks for a stalled task queue
+    async fn watch_stalls(&self) {
+        let timeout = self.config.stall_timeout;
+        if timeout.is_zero() {
+            return;
+        }
+
+        let mut interval = time::interval(timeout / 4);
+        loop {
+            interval.tick().await;
+            let notifications = {
+                let mut scheduler = self.scheduler.lock().unwrap();
+                scheduler.check_stall(timeout);
+                scheduler.take_notifications()
+            };
+            self.notify(notifications);
+        }
+    }
+}
+
+/// Accepts workers from a listener until the returned future is dropped
+async fn accept_loop(shared: &Arc<Shared>, listener: &impl TransportListener) {
+    loop {
+        let res = listener.accept().await;
+        shared.watchdog.progress();
+        let (reader, writer, peer) = match res {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Error accepting connection: {}", e);
+                time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+
+        // Drop connections from addresses with too many failed handshakes
+        if let (Some(throttle), Some(ip)) = (&shared.config.channel.throttle, peer_ip(&peer)) {
+            if throttle.is_banned(ip) {
+                debug!("Dropping connection from banned address {}", peer);
+                continue;
+            }
+        }
+
+        tokio::spawn(handle_connection(shared.clone(), reader, writer, peer));
+    }
+}
+
+/// Returns the IP address of a peer, if it has one
+fn peer_ip(peer: &Endpoint) -> Option<IpAddr> {
+    match peer {
+        Endpoint::Tcp(addr) => Some(addr.ip()),
+        #[cfg(unix)]
+        Endpoint::Unix(_) => None,
+    }
+}
+
+/// Sets up the encrypted channel with a worker and relays its messages
+async fn handle_connection<R, W>(shared: Arc<Shared>, reader: R, writer: W, addr: Endpoint)
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let res = server_channel(reader, writer, &shared.keypair, &shared.config.channel).await;
+
+    // Keep track of handshake failures
+    if let (Some(throttle), Some(ip)) = (&shared.config.channel.throttle, peer_ip(&addr)) {
+        match &res {
+            Ok(_) => throttle.record_success(ip),
+            Err(_) => {
+                if throttle.record_failure(ip) {
+                    warn!("Too many failed handshakes from {}, banning", ip);
+                }
+            }
+        }
+    }
+
+    let (sender, receiver) = match res {
+        Ok(channel) => channel,
+        Err(e) => {
+            warn!("Handshake with {} failed: {}", addr, e);
+            return;
+        }
+    };
+
+    let identity = receiver.peer_identity().map(str::to_string);
+    let mut sender = TypedMsgSender::<CoordinatorMessage, _>::new(sender);
+    let mut receiver = TypedMsgReceiver::<ClientMessage, _>::new(receiver)
+        .offload(shared.config.channel.offload.clone());
+
+    // Wait for the worker to introduce itself
+    let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+    let identity = Identity {
+        addr: &addr,
+        fingerprint: identity.as_deref(),
+    };
+    let hello = match onboard(&shared, id, identity, &mut sender, &mut receiver).await {
+        Ok(Some(hello)) => hello,
+        Ok(None) => {
+            debug!("Served admin request from {}", addr);
+            return;
+        }
+        Err(e) => {
+            warn!("Onboarding of {} failed: {}", addr, e);
+            return;
+        }
+    };
+    let admin = shared.is_authorized(identity.fingerprint);
+
+    // Register worker
+    let info = WorkerInfo {
+        id,
+        addr: addr.clone(),
+        hello,
+    };
+    let slots = info.hello.cpus as usize;
+    let worker_id = info.hello.worker_id.clone();
+    let perf_score = info.hello.perf_score;
+    let zone = info.hello.zone.clone();
+    let (tx, mut rx) = mpsc::channel(shared.config.worker_queue_len);
+    shared.workers.lock().unwrap().insert(
+        id,
+        WorkerHandle {
+            info: info.clone(),
+            tx,
+            last_seen: Instant::now(),
+        },
+    );
+    info!(
+        worker = id, worker_id = info.hello.worker_id.as_str();
+        "Worker {} ({}) connected from {}",
+        id, info.hello.worker_id, addr
+    );
+    shared.log([LifecycleEvent::WorkerConnected {
+        id,
+        worker_id: worker_id.clone(),
+        addr: addr.to_string(),
+    }]);
+
+    // One task slot per CPU
+    shared
+        .scheduler
+        .lock()
+        .unwrap()
+        .add_worker(id, slots, perf_score, zone);
+    shared.dispatch();
+
+    // Writer task, ends when the worker is unregistered
+    let heartbeat = shared.config.heartbeat;
+    let writer_shared = shared.clone();
+    let writer = tokio::spawn(async move {
+        loop {
+            // Send a heartbeat if nothing was queued for an interval
+            let msg = match time::timeout(heartbeat.interval, rx.recv()).await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break,
+                Err(_) => CoordinatorMessage::Heartbeat,
+            };
+
+            if let Err(e) = sender.send(&msg).await {
+                debug!(worker = id; "Error sending to worker {}: {}", id, e);
+                break;
+            }
+
+            // Room was made in the queue, for tasks which didn't fit before
+            if writer_shared.redispatch.swap(false, Ordering::Relaxed) {
+                writer_shared.dispatch();
+            }
+        }
+    });
+
+    shared.emit(WorkerEvent::Connected(info));
+
+    // Oversized results being received, by task
+    let mut uploads = HashMap::new();
+    loop {
+        let res = recv_timeout(heartbeat.timeout, receiver.recv()).await;
+        if res.is_ok() {
+            if let Some(worker) = shared.workers.lock().unwrap().get_mut(&id) {
+                worker.last_seen = Instant::now();
+            }
+        }
+
+        let msg = match res {
+            Ok(ClientMessage::Data(msg)) => msg,
+            Ok(ClientMessage::Heartbeat) => continue,
+            Ok(ClientMessage::TaskResult { task_id, outcome }) => {
+                debug!(worker = id, task = task_id; "Worker {} finished task {}", id, task_id);
+                // Results of tasks assigned elsewhere (or already done) are never stored
+                if !shared.is_running(id, task_id) {
+                    warn!(worker = id, task = task_id; "Worker {} sent the result of task {} it isn't running, ignoring", id, task_id);
+                    uploads.remove(&task_id);
+                    continue;
+                }
+                let outcome = store_result(&shared, &mut uploads, task_id, outcome).await;
+                shared
+                    .scheduler
+                    .lock()
+                    .unwrap()
+                    .complete(id, task_id, outcome);
+                shared.queue_room.notify_waiters();
+                shared.dispatch();
+                continue;
+            }
+            Ok(ClientMessage::ResultChunk { task_id, data }) => {
+                if !shared.is_running(id, task_id) {
+                    warn!(worker = id, task = task_id; "Worker {} sent part of the result of task {} it isn't running, ignoring", id, task_id);
+                    continue;
+                }
+                receive_chunk(&shared, &mut uploads, task_id, &data).await;
+                continue;
+            }
+            Ok(ClientMessage::BenchmarkResult { score }) => {
+                debug!(worker = id; "Worker {} benchmark score: {}", id, score);
+                if let Some(worker) = shared.workers.lock().unwrap().get_mut(&id) {
+                    worker.info.hello.perf_score = score;
+                }
+                shared.scheduler.lock().unwrap().set_perf_score(id, score);
+                continue;
+            }
+            Ok(ClientMessage::StatusRequest) if admin => {
+                let _ = shared
+                    .send_to(id, CoordinatorMessage::Status(shared.status()))
+                    .await;
+                continue;
+            }
+            Ok(ClientMessage::StatusRequest) => {
+                warn!(worker = id; "Worker {} asked for the status without authorization, ignoring", id);
+                continue;
+            }
+            Ok(ClientMessage::Hello(_)) => {
+                warn!(worker = id; "Worker {} sent a second introduction, disconnecting", id);
+                break;
+            }
+            Ok(ClientMessage::Approval { .. }) => {
+                warn!(worker = id; "Worker {} sent an admin request, ignoring", id);
+                continue;
+            }
+            Err(e) => {
+                info!(worker = id; "Worker {} disconnected: {}", id, e);
+                break;
+            }
+        };
+
+        shared.emit(WorkerEvent::Message { id, msg });
+    }
+
+    // Unregister worker and reschedule its tasks
+    shared.workers.lock().unwrap().remove(&id);
+    shared.scheduler.lock().unwrap().remove_worker(id);
+    shared.queue_room.notify_waiters();
+    shared.dispatch();
+    shared.log([LifecycleEvent::WorkerDisconnected {
+        id,
+        worker_id: worker_id.clone(),
+    }]);
+    shared.notify([Notification::WorkerLost { id, worker_id }]);
+    writer.abort();
+    shared.emit(WorkerEvent::Disconnected { id });
+}
+
+/// Appends part of an oversized result to its upload
+/// A failed upload is kept as its error, which fails the task once its result arrives.
+/// Uploads growing past the maximum artifact length fail, discarding the data
+async fn receive_chunk(
+    shared: &Shared,
+    uploads: &mut HashMap<TaskId, io::Result<Upload>>,
+    task_id: TaskId,
+    data: &[u8],
+) {
+    // Without a store the result is rejected when it arrives
+    let Some(store) = &shared.artifacts else {
+        return;
+    };
+
+    let upload = match uploads.remove(&task_id) {
+        Some(upload) => upload,
+        None => store.upload().await,
+    };
+    let upload = match upload {
+        Ok(upload) if upload.len() + data.len() as u64 > shared.config.max_artifact_len => {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "result exceeds maximum artifact length",
+            ))
+        }
+        Ok(mut upload) => upload.write(data).await.map(|()| upload),
+        Err(e) => Err(e),
+    };
+    uploads.insert(task_id, upload);
+}
+
+/// Moves oversized task results to the artifact store
+/// Results which can't be stored fail the attempt
+async fn store_result(
+    shared: &Shared,
+    uploads: &mut HashMap<TaskId, io::Result<Upload>>,
+    task_id: TaskId,
+    outcome: TaskOutcome,
+) -> TaskOutcome {
+    let upload = uploads.remove(&task_id);
+    let Some(store) = &shared.artifacts else {
+        return match outcome {
+            TaskOutcome::Artifact(_) => TaskOutcome::Failure(
+                "result too large, the coordinator has no artifact store".into(),
+            ),
+            outcome => outcome,
+        };
+    };
+
+    let res = match outcome {
+        TaskOutcome::Success(result) if result.len() > shared.config.max_result_len => {
+            store.store(&result).await
+        }
+        TaskOutcome::Artifact(artifact) => match upload {
+            Some(Ok(upload)) => upload.finish(&artifact).await.map(|()| artifact),
+            Some(Err(e)) => Err(e),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no data received for the result",
+            )),
+        },
+        outcome => return outcome,
+    };
+    match res {
+        Ok(artifact) => TaskOutcome::Artifact(artifact),
+        Err(e) => {
+            warn!(task = task_id; "Can't store the result of task {}: {}", task_id, e);
+            TaskOutcome::Failure(format!("can't store result: {}", e))
+        }
+    }
+}
+
+/// Peer of a connection being onboarded
+#[derive(Clone, Copy)]
+struct Identity<'a> {
+    addr: &'a Endpoint,
+    fingerprint: Option<&'a str>, // Identity proven during the handshake
+}
+
+/// Receives the worker introduction and accepts or rejects it
+/// Admin clients send a request instead, they are answered and None is returned
+async fn onboard(
+    shared: &Shared,
+    id: WorkerId,
+    identity: Identity<'_>,
+    sender: &mut TypedMsgSender<CoordinatorMessage, impl AsyncMsgSend>,
+    receiver: &mut TypedMsgReceiver<ClientMessage, impl AsyncMsgRecv>,
+) -> io::Result<Option<WorkerHello>> {
+    let deadline = time::Instant::now() + shared.config.channel.handshake_timeout;
+
+    // Checked before decoding any message, whose layout may differ between versions
+    let version = time::timeout_at(deadline, receiver.recv_version()).await??;
+    if version != Some(PROTOCOL_VERSION) {
+        debug!(worker = id; "Worker {} speaks protocol version {:?}", id, version);
+        let reject = OnboardingReject::VersionMismatch {
+            supported: PROTOCOL_VERSION,
+        };
+        sender.send(&CoordinatorMessage::Rejected(reject)).await?;
+        return Err(reject.into());
+    }
+
+    let reject = match time::timeout_at(deadline, receiver.recv()).await? {
+        Ok(ClientMessage::Hello(hello)) => {
+            let cluster = shared.config.cluster.clone();
+            let res = if hello.cluster.is_some() && hello.cluster != cluster {
+                Err(OnboardingReject::ClusterMismatch)
+            } else {
+                admit(shared, identity, &hello)
+            };
+            match res {
+                Ok(()) => {
+                    let assignment = WorkerAssignment { id, cluster };
+                    sender
+                        .send(&CoordinatorMessage::Welcome(assignment))
+                        .await?;
+                    return Ok(Some(hello));
+                }
+                Err(reject) => reject,
+            }
+        }
+        Ok(ClientMessage::StatusRequest) if shared.is_authorized(identity.fingerprint) => {
+            sender
+                .send(&CoordinatorMessage::Status(shared.status()))
+                .await?;
+            return Ok(None);
+        }
+        Ok(ClientMessage::StatusRequest) => OnboardingReject::Unauthorized,
+        Ok(ClientMessage::Approval {
+            fingerprint,
+            approve,
+        }) => {
+            // Only identities the coordinator trusts may decide
+            if shared.approval_mode().is_some() && shared.is_authorized(identity.fingerprint) {
+                shared
+                    .approvals
+                    .lock()
+                    .unwrap()
+                    .decide(&fingerprint, approve)?;
+                sender
+                    .send(&CoordinatorMessage::Status(shared.status()))
+                    .await?;
+                return Ok(None);
+            }
+            OnboardingReject::Unauthorized
+        }
+        Ok(_) => OnboardingReject::Malformed,
+        Err(e) if ProtocolError::from_io(&e).is_some() => OnboardingReject::Malformed,
+        Err(e) => return Err(e),
+    };
+
+    sender.send(&CoordinatorMessage::Rejected(reject)).await?;
+    Err(reject.into())
+}
+
+/// Holds unknown workers for approval, if enabled
+fn admit(shared: &Shared, identity: Identity, hello: &WorkerHello) -> Result<(), OnboardingReject> {
+    let (Some(authorized), Some(fingerprint)) = (shared.approval_mode(), identity.fingerprint)
+    else {
+        return Ok(());
+    };
+
+    let mut approvals = shared.approvals.lock().unwrap();
+    let res = approvals.admit(authorized, fingerprint, &identity.addr.to_string(), hello);
+    let notifications = approvals.take_notifications();
+    drop(approvals);
+    shared.notify(notifications);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::admin::{cluster_status, decide_approval};
+    use crate::comm::{
+        channel::{ChannelOptions, ChannelReceiver, ChannelSender},
+        connect_encrypted,
+        crypto::{ClientIdentity, ServerPublicKeyValidator},
+        heartbeat::HeartbeatConfig,
+        protocol::{ArtifactRef, TaskOutcome, FRAME_MESSAGE},
+        testutil::test_keypair,
+    };
+    use crate::coordinator::{
+        jobs::TaskOutput,
+        notify::Notifier,
+        pipeline::{Pipeline, PipelineEvent},
+    };
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+    type TestSender = TypedMsgSender<ClientMessage, ChannelSender<OwnedWriteHalf>>;
+    type TestReceiver = TypedMsgReceiver<CoordinatorMessage, ChannelReceiver<OwnedReadHalf>>;
+
+    /// Notifier keeping all notifications
+    #[derive(Default)]
+    struct RecordingNotifier(Mutex<Vec<Notification>>);
+
+    impl Notifier for RecordingNotifier {
+        fn notify(&self, notification: &Notification) {
+            self.0.lock().unwrap().push(notification.clone());
+        }
+    }
+
+    /// Starts a coordinator on a random local port
+    async fn start(config: ClusterCoordinatorConfig) -> (Arc<ClusterCoordinator>, SocketAddr) {
+        let coord = Arc::new(
+            ClusterCoordinator::bind(config, test_keypair())
+                .await
+                .unwrap(),
+        );
+        let addr = coord.local_addr().unwrap();
+        tokio::spawn({
+            let coord = coord.clone();
+            async move { coord.run().await }
+        });
+
+        (coord, addr)
+    }
+
+    /// Connects to a coordinator and sends a worker introduction
+    async fn connect(addr: SocketAddr, protocol_version: u32) -> (TestSender, TestReceiver) {
+        connect_with(addr, protocol_version, &ChannelOptions::default()).await
+    }
+
+    async fn connect_with(
+        addr: SocketAddr,
+        protocol_version: u32,
+        options: &ChannelOptions,
+    ) -> (TestSender, TestReceiver) {
+        let mut key_validator = ServerPublicKeyValidator::new(false);
+        let (sender, receiver) = connect_encrypted(addr, &mut key_validator, options)
+            .await
+            .unwrap();
+        let mut sender = TypedMsgSender::new(sender);
+        let receiver = TypedMsgReceiver::new(receiver);
+
+        let hello = WorkerHello {
+            worker_id: "test".to_string(),
+            hostname: "localhost".to_string(),
+            cpus: 4,
+            tags: vec!["gpu".to_string()],
+            perf_score: 100,
+            cluster: None,
+            zone: None,
+        };
+        sender.send_version(protocol_version).await.unwrap();
+        sender.send(&ClientMessage::Hello(hello)).await.unwrap();
+
+        (sender, receiver)
+    }
+
+    #[tokio::test]
+    async fn coordinator_status() {
+        let admin = Arc::new(ClientIdentity::new(test_keypair()));
+        let worker = Arc::new(ClientIdentity::new(test_keypair()));
+        let mut authorized = AuthorizedClients::new();
+        authorized.insert(admin.fingerprint().unwrap());
+        authorized.insert(worker.fingerprint().unwrap());
+        let channel = ChannelOptions::default().authorized_clients(Arc::new(authorized));
+        let (coord, addr) =
+            start(ClusterCoordinatorConfig::new("127.0.0.1:0").channel(channel)).await;
+        let worker_options = ChannelOptions::default().identity(worker);
+        let (_sender, mut receiver) = connect_with(addr, PROTOCOL_VERSION, &worker_options).await;
+        let id = match receiver.recv().await.unwrap() {
+            CoordinatorMessage::Welcome(assignment) => assignment.id,
+            msg => panic!("unexpected message {:?}", msg),
+        };
+        let _job = coord
+            .submit_job(JobSpec::new("render").task(b"frame".to_vec()))
+            .unwrap();
+        let task_id = match receiver.recv().await.unwrap() {
+            CoordinatorMessage::Task(task) => task.task_id,
+            msg => panic!("unexpected message {:?}", msg),
+        };
+
+        let status = coord.cluster_status();
+        assert_eq!(status.pending_tasks, 0);
+        assert_eq!(status.workers.len(), 1);
+        assert_eq!(status.workers[0].id, id);
+        assert_eq!(status.workers[0].hello.worker_id, "test");
+        assert_eq!(status.workers[0].running_tasks, [task_id]);
+
+        // Admin clients get the same status over the protocol
+        let mut key_validator = ServerPublicKeyValidator::new(false);
+        let admin_options = ChannelOptions::default().identity(admin);
+        let remote = cluster_status(addr, &mut key_validator, &admin_options)
+            .await
+            .unwrap();
+        assert_eq!(remote.workers.len(), 1);
+        assert_eq!(remote.workers[0].running_tasks, [task_id]);
+        assert_eq!(coord.workers().len(), 1);
+
+        // Peers without an authorized identity don't
+        let (_open, addr) = start(ClusterCoordinatorConfig::new("127.0.0.1:0")).await;
+        let mut key_validator = ServerPublicKeyValidator::new(false);
+        let err = cluster_status(addr, &mut key_validator, &ChannelOptions::default())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            OnboardingReject::from_io(&err),
+            Some(&OnboardingReject::Unauthorized)
+        );
+    }
+
+    #[tokio::test]
+    async fn coordinator_approval() {
+        let path = std::env::temp_dir().join(format!(
+            "pomegranate-coordinator-allowlist-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let admin = Arc::new(ClientIdentity::new(test_keypair()));
+        let worker = Arc::new(ClientIdentity::new(test_keypair()));
+        let mut authorized = AuthorizedClients::new();
+        authorized.insert(admin.fingerprint().unwrap());
+
+        let notifier = Arc::new(RecordingNotifier::default());
+        let channel =
+            ChannelOptions::default().authorized_clients(Arc::new(authorized.admit_unknown(true)));
+        let config = ClusterCoordinatorConfig::new("127.0.0.1:0")
+            .channel(channel)
+            .notifier(notifier.clone())
+            .allowlist(&path);
+        let (coord, addr) = start(config).await;
+
+        // Unknown workers wait for approval
+        let worker_options = ChannelOptions::default().identity(worker.clone());
+        let (_sender, mut receiver) = connect_with(addr, PROTOCOL_VERSION, &worker_options).await;
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Rejected(OnboardingReject::PendingApproval)
+        );
+        let status = coord.cluster_status();
+        assert_eq!(status.pending_approval.len(), 1);
+        assert_eq!(
+            status.pending_approval[0].fingerprint,
+            worker.fingerprint().unwrap()
+        );
+        assert_eq!(status.pending_approval[0].hello.worker_id, "test");
+        assert!(matches!(
+            notifier.0.lock().unwrap()[..],
+            [Notification::WorkerPendingApproval { .. }]
+        ));
+
+        // Pending workers can't see the cluster or approve themselves
+        let mut key_validator = ServerPublicKeyValidator::new(false);
+        let err = cluster_status(addr, &mut key_validator, &worker_options)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            OnboardingReject::from_io(&err),
+            Some(&OnboardingReject::Unauthorized)
+        );
+        let err = decide_approval(
+            addr,
+            &mut key_validator,
+            &worker_options,
+            &worker.fingerprint().unwrap(),
+            true,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            OnboardingReject::from_io(&err),
+            Some(&OnboardingReject::Unauthorized)
+        );
+
+        // Authorized admins can
+        let admin_options = ChannelOptions::default().identity(admin);
+        let status = decide_approval(
+            addr,
+            &mut key_validator,
+            &admin_options,
+            &worker.fingerprint().unwrap(),
+            true,
+        )
+        .await
+        .unwrap();
+        assert!(status.pending_approval.is_empty());
+        assert!(AuthorizedClients::from_file(&path)
+            .unwrap()
+            .contains(&worker.fingerprint().unwrap()));
+
+        let (_sender, mut receiver) = connect_with(addr, PROTOCOL_VERSION, &worker_options).await;
+        assert!(matches!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Welcome(_)
+        ));
+
+        // Rejected workers stay out
+        let other = Arc::new(ClientIdentity::new(test_keypair()));
+        let other_options = ChannelOptions::default().identity(other.clone());
+        let (_sender, mut receiver) = connect_with(addr, PROTOCOL_VERSION, &other_options).await;
+        receiver.recv().await.unwrap();
+        assert!(coord
+            .decide_approval(&other.fingerprint().unwrap(), false)
+            .unwrap());
+        let (_sender, mut receiver) = connect_with(addr, PROTOCOL_VERSION, &other_options).await;
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Rejected(OnboardingReject::Unauthorized)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn coordinator_workers() {
+        let notifier = Arc::new(RecordingNotifier::default());
+        let config = ClusterCoordinatorConfig::new("127.0.0.1:0").notifier(notifier.clone());
+        let (coord, addr) = start(config).await;
+        let (mut sender, mut receiver) = connect(addr, PROTOCOL_VERSION).await;
+
+        let info = match coord.next_event().await {
+            WorkerEvent::Connected(info) => info,
+            ev => panic!("unexpected event {:?}", ev),
+        };
+        let id = info.id;
+        assert_eq!(info.hello.worker_id, "test");
+        assert_eq!(info.hello.tags, ["gpu"]);
+        assert_eq!(coord.workers(), [info]);
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Welcome(WorkerAssignment { id, cluster: None })
+        );
+
+        // Worker to coordinator
+        sender
+            .send(&ClientMessage::Data(b"hello".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(
+            coord.next_event().await,
+            WorkerEvent::Message {
+                id,
+                msg: b"hello".to_vec()
+            }
+        );
+
+        // Coordinator to worker
+        coord.send(id, b"world".to_vec()).await.unwrap();
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Data(b"world".to_vec())
+        );
+        assert_eq!(coord.broadcast(b"all").await, 1);
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Data(b"all".to_vec())
+        );
+
+        // Benchmark on demand, the result arrives before the next message
+        coord.request_benchmark(id).await.unwrap();
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Benchmark
+        );
+        for msg in [
+            ClientMessage::BenchmarkResult { score: 250 },
+            ClientMessage::Data(b"done".to_vec()),
+        ] {
+            sender.send(&msg).await.unwrap();
+        }
+        coord.next_event().await;
+        assert_eq!(coord.workers()[0].hello.perf_score, 250);
+
+        // Disconnection
+        drop((sender, receiver));
+        assert_eq!(coord.next_event().await, WorkerEvent::Disconnected { id });
+        assert!(coord.workers().is_empty());
+        assert_eq!(
+            *notifier.0.lock().unwrap(),
+            [Notification::WorkerLost {
+                id,
+                worker_id: "test".to_string()
+            }]
+        );
+        assert_eq!(
+            coord.send(id, b"gone".to_vec()).await.unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn coordinator_unix_socket() {
+        use crate::comm::{channel::client_channel, transport::Transport};
+
+        let path =
+            std::env::temp_dir().join(format!("pomegranate-coord-{}.sock", std::process::id()));
+        let (coord, _) =
+            start(ClusterCoordinatorConfig::new("127.0.0.1:0").unix_socket(&path)).await;
+
+        let endpoint = Endpoint::Unix(path.clone());
+        let (reader, writer) = endpoint.connect().await.unwrap();
+        let mut key_validator = ServerPublicKeyValidator::new(false);
+        let (sender, _receiver) = client_channel(
+            reader,
+            writer,
+            &mut key_validator,
+            &ChannelOptions::default(),
+        )
+        .await
+        .unwrap();
+        let hello = WorkerHello {
+            worker_id: "local".to_string(),
+            hostname: "localhost".to_string(),
+            cpus: 1,
+            tags: Vec::new(),
+            perf_score: 0,
+            cluster: None,
+            zone: None,
+        };
+        let mut sender = TypedMsgSender::new(sender);
+        sender.send_version(PROTOCOL_VERSION).await.unwrap();
+        sender.send(&ClientMessage::Hello(hello)).await.unwrap();
+
+        match coord.next_event().await {
+            WorkerEvent::Connected(info) => assert_eq!(info.addr, endpoint),
+            ev => panic!("unexpected event {:?}", ev),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn coordinator_version_mismatch() {
+        let (coord, addr) = start(ClusterCoordinatorConfig::new("127.0.0.1:0")).await;
+        let (_sender, mut receiver) = connect(addr, PROTOCOL_VERSION + 1).await;
+
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Rejected(OnboardingReject::VersionMismatch {
+                supported: PROTOCOL_VERSION
+            })
+        );
+        assert!(receiver.recv().await.is_err());
+
+        // Peers predating version frames introduce themselves in a layout that
+        // may not decode, they are told about the version all the same
+        let mut key_validator = ServerPublicKeyValidator::new(false);
+        let (mut sender, receiver) =
+            connect_encrypted(addr, &mut key_validator, &ChannelOptions::default())
+                .await
+                .unwrap();
+        let mut receiver = TestReceiver::new(receiver);
+        sender.send(&[FRAME_MESSAGE, 0xff, 0xff]).await.unwrap();
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Rejected(OnboardingReject::VersionMismatch {
+                supported: PROTOCOL_VERSION
+            })
+        );
+        assert!(coord.workers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn coordinator_heartbeat() {
+        let heartbeat = HeartbeatConfig::default()
+            .interval(Duration::from_millis(50))
+            .timeout(Duration::from_millis(300));
+        let (coord, addr) =
+            start(ClusterCoordinatorConfig::new("127.0.0.1:0").heartbeat(heartbeat)).await;
+        let (_sender, mut receiver) = connect(addr, PROTOCOL_VERSION).await;
+
+        let id = match coord.next_event().await {
+            WorkerEvent::Connected(info) => info.id,
+            ev => panic!("unexpected event {:?}", ev),
+        };
+
+        // Idle coordinator sends heartbeats
+        assert!(matches!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Welcome(_)
+        ));
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Heartbeat
+        );
+
+        // Silent worker is dropped
+        assert_eq!(coord.next_event().await, WorkerEvent::Disconnected { id });
+    }
+
+    #[tokio::test]
+    async fn coordinator_queue_limits() {
+        let config = ClusterCoordinatorConfig::new("127.0.0.1:0").max_queued_tasks(2);
+        let (coord, addr) = start(config).await;
+        let _queued = coord
+            .submit_job(JobSpec::new("echo").task(*b"a").task(*b"b"))
+            .unwrap();
+
+        let full = coord.submit_job(JobSpec::new("echo").task(*b"c")).err();
+        assert_eq!(full.map(|full| full.queued), Some(2));
+        let timeout = Duration::from_millis(50);
+        let too_big = JobSpec::new("echo").task(*b"c").task(*b"d").task(*b"e");
+        assert!(coord.submit_job_timeout(too_big, timeout).await.is_err());
+        let job = JobSpec::new("echo").task(*b"c");
+        assert!(coord
+            .submit_job_timeout(job.clone(), timeout)
+            .await
+            .is_err());
+
+        // A waiting job is admitted once a task finishes
+        let (mut sender, mut receiver) = connect(addr, PROTOCOL_VERSION).await;
+        let worker = async {
+            loop {
+                if let CoordinatorMessage::Task(task) = receiver.recv().await.unwrap() {
+                    sender
+                        .send(&ClientMessage::TaskResult {
+                            task_id: task.task_id,
+                            outcome: TaskOutcome::Success(Vec::new()),
+                        })
+                        .await
+                        .unwrap();
+                    break;
+                }
+            }
+        };
+        let (handle, ()) = tokio::join!(
+            coord.submit_job_timeout(job, Duration::from_secs(5)),
+            worker
+        );
+        assert_eq!(handle.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn coordinator_pipeline() {
+        let (coord, addr) = start(ClusterCoordinatorConfig::new("127.0.0.1:0")).await;
+        let (mut sender, mut receiver) = connect(addr, PROTOCOL_VERSION).await;
+        let pipeline = Pipeline::parse(
+            r#"
+            # Listed before the stages they depend on
+            [[stage]]
+            name = "notify"
+            kind = "echo"
+            tasks = ["e"]
+            depends_on = ["publish"]
+
+            [[stage]]
+            name = "publish"
+            kind = "echo"
+            tasks = ["c"]
+            depends_on = ["check"]
+
+            [[stage]]
+            name = "fetch"
+            kind = "echo"
+            tasks = ["a", "b"]
+
+            [[stage]]
+            name = "check"
+            kind = "echo"
+            tasks = ["bad"]
+            depends_on = ["fetch"]
+            max_attempts = 1
+
+            [[stage]]
+            name = "index"
+            kind = "echo"
+            tasks = ["d"]
+            depends_on = ["fetch"]
+            "#,
+        )
+        .unwrap();
+
+        // Echoes payloads, failing "bad"
+        let worker = async {
+            loop {
+                if let CoordinatorMessage::Task(task) = receiver.recv().await.unwrap() {
+                    let outcome = match &task.payload[..] {
+                        b"bad" => TaskOutcome::Failure("bad input".to_string()),
+                        payload => TaskOutcome::Success(payload.to_vec()),
+                    };
+                    let result = ClientMessage::TaskResult {
+                        task_id: task.task_id,
+                        outcome,
+                    };
+                    sender.send(&result).await.unwrap();
+                }
+            }
+        };
+
+        let mut events = Vec::new();
+        let results = tokio::select! {
+            results = coord.run_pipeline(&pipeline, |event| events.push(event.clone())) => results,
+            () = worker => unreachable!(),
+        }
+        .unwrap();
+
+        assert_eq!(results["index"], [Ok(TaskOutput::Inline(b"d".to_vec()))]);
+        assert!(results["check"][0].is_err());
+        assert!(!results.contains_key("publish"));
+        assert_eq!(events.len(), 8);
+        for stage in ["publish", "notify"] {
+            assert!(events.contains(&PipelineEvent::StageSkipped {
+                stage: stage.to_string()
+            }));
+        }
+        assert!(events.contains(&PipelineEvent::StageFinished {
+            stage: "check".to_string(),
+            failed: 1
+        }));
+    }
+
+    #[tokio::test]
+    async fn coordinator_jobs() {
+        let (coord, addr) = start(ClusterCoordinatorConfig::new("127.0.0.1:0")).await;
+        let handle = coord
+            .submit_job(JobSpec::new("echo").task(*b"job").max_attempts(2))
+            .unwrap();
+
+        let (mut sender, mut receiver) = connect(addr, PROTOCOL_VERSION).await;
+        assert!(matches!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Welcome(_)
+        ));
+
+        // Fail the first attempt, with no other worker the retry comes back here
+        for outcome in [
+            TaskOutcome::Failure("busy".to_string()),
+            TaskOutcome::Success(b"done".to_vec()),
+        ] {
+            let task = match receiver.recv().await.unwrap() {
+                CoordinatorMessage::Task(task) => task,
+                msg => panic!("unexpected message {:?}", msg),
+            };
+            assert_eq!(task.kind, "echo");
+            assert_eq!(task.payload, b"job");
+
+            sender
+                .send(&ClientMessage::TaskResult {
+                    task_id: task.task_id,
+                    outcome,
+                })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            handle.results().await,
+            [Ok(TaskOutput::Inline(b"done".to_vec()))]
+        );
+    }
+
+    #[tokio::test]
+    async fn coordinator_result_uploads() {
+        let dir = std::env::temp_dir().join(format!("pomegranate-uploads-{}", std::process::id()));
+        let config = ClusterCoordinatorConfig::new("127.0.0.1:0")
+            .artifact_dir(&dir)
+            .max_artifact_len(16);
+        let (coord, addr) = start(config).await;
+        let handle = coord
+            .submit_job(JobSpec::new("echo").task(*b"job").max_attempts(1))
+            .unwrap();
+
+        let (mut sender, mut receiver) = connect(addr, PROTOCOL_VERSION).await;
+        assert!(matches!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Welcome(_)
+        ));
+        let task = match receiver.recv().await.unwrap() {
+            CoordinatorMessage::Task(task) => task,
+            msg => panic!("unexpected message {:?}", msg),
+        };
+
+        // Results of a task the worker isn't running are never written
+        let data = vec![1; 8];
+        let foreign = task.task_id + 1;
+        for msg in [
+            ClientMessage::ResultChunk {
+                task_id: foreign,
+                data: data.clone(),
+            },
+            ClientMessage::TaskResult {
+                task_id: foreign,
+                outcome: TaskOutcome::Artifact(ArtifactRef::of(&data)),
+            },
+        ] {
+            sender.send(&msg).await.unwrap();
+        }
+
+        // Streams past the maximum artifact length fail the task
+        let data = vec![2; 32];
+        for part in data.chunks(8) {
+            sender
+                .send(&ClientMessage::ResultChunk {
+                    task_id: task.task_id,
+                    data: part.to_vec(),
+                })
+                .await
+                .unwrap();
+        }
+        sender
+            .send(&ClientMessage::TaskResult {
+                task_id: task.task_id,
+                outcome: TaskOutcome::Artifact(ArtifactRef::of(&data)),
+            })
+            .await
+            .unwrap();
+
+        let results = handle.results().await;
+        assert!(results[0]
+            .as_ref()
+            .unwrap_err()
+            .error
+            .contains("maximum artifact length"));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn coordinator_invalid_config() {
+        let config = ClusterCoordinatorConfig::new("127.0.0.1:0").worker_queue_len(0);
+        let err = ClusterCoordinator::bind(config, test_keypair())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn coordinator_unread_events() {
+        // Nobody reads the events, connections keep serving jobs anyway
+        let config = ClusterCoordinatorConfig::new("127.0.0.1:0").event_queue_len(1);
+        let (coord, addr) = start(config).await;
+        let (mut sender, mut receiver) = connect(addr, PROTOCOL_VERSION).await;
+        assert!(matches!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Welcome(_)
+        ));
+        for _ in 0..4 {
+            sender
+                .send(&ClientMessage::Data(b"hi".to_vec()))
+                .await
+                .unwrap();
+        }
+
+        let handle = coord
+            .submit_job(JobSpec::new("echo").task(*b"job"))
+            .unwrap();
+        let task = match receiver.recv().await.unwrap() {
+            CoordinatorMessage::Task(task) => task,
+            msg => panic!("unexpected message {:?}", msg),
+        };
+        sender
+            .send(&ClientMessage::TaskResult {
+                task_id: task.task_id,
+                outcome: TaskOutcome::Success(b"done".to_vec()),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            handle.results().await,
+            [Ok(TaskOutput::Inline(b"done".to_vec()))]
+        );
+        assert_eq!(coord.dropped_events(), 4);
+    }
+
+    #[tokio::test]
+    async fn coordinator_full_worker_queue() {
+        let config = ClusterCoordinatorConfig::new("127.0.0.1:0").worker_queue_len(1);
+        let (coord, addr) = start(config).await;
+        let (mut sender, mut receiver) = connect(addr, PROTOCOL_VERSION).await;
+        assert!(matches!(
+            receiver.recv().await.unwrap(),
+            CoordinatorMessage::Welcome(_)
+        ));
+
+        // Large messages the worker doesn't read yet fill the connection and its queue
+        let burst = tokio::spawn({
+            let coord = coord.clone();
+            async move {
+                for _ in 0..12 {
+                    coord.broadcast(&vec![0; 1024 * 1024]).await;
+                }
+            }
+        });
+        time::sleep(Duration::from_millis(200)).await;
+        let handle = coord
+            .submit_job(JobSpec::new("echo").task(*b"job"))
+            .unwrap();
+
+        // The task is sent once the queue drains
+        let task = loop {
+            match receiver.recv().await.unwrap() {
+                CoordinatorMessage::Task(task) => break task,
+                CoordinatorMessage::Data(_) => {}
+                msg => panic!("unexpected message {:?}", msg),
+            }
+        };
+        sender
+            .send(&ClientMessage::TaskResult {
+                task_id: task.task_id,
+                outcome: TaskOutcome::Success(b"done".to_vec()),
+            })
+            .await
+            .unwrap();
+        assert_eq!(handle.results().await.len(), 1);
+        burst.await.unwrap();
+    }
+}
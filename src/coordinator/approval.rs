@@ -0,0 +1,198 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io, mem,
+    path::PathBuf,
+};
+
+use log::info;
+use tokio::time::Instant;
+
+use super::notify::Notification;
+use crate::comm::{
+    crypto::AuthorizedClients,
+    protocol::{OnboardingReject, PendingWorker, WorkerHello},
+};
+
+/// Unknown worker waiting for a decision
+struct Pending {
+    addr: String,
+    hello: WorkerHello,
+    since: Instant, // First attempt to join
+}
+
+/// Worker identities held for an operator's approval
+/// Unknown workers are rejected until approved, and rejoin through their
+/// reconnection backoff. Approved identities are appended to the allowlist
+/// file, so they stay authorized when the coordinator restarts
+#[derive(Default)]
+pub(crate) struct ApprovalQueue {
+    allowlist: Option<PathBuf>, // Authorized clients file receiving approved identities
+    approved: HashSet<String>,
+    denied: HashSet<String>,
+    pending: BTreeMap<String, Pending>,
+    notifications: Vec<Notification>,
+}
+
+impl ApprovalQueue {
+    pub fn new(allowlist: Option<PathBuf>) -> Self {
+        Self {
+            allowlist,
+            ..Self::default()
+        }
+    }
+
+    /// Returns true if an identity may act on the cluster
+    pub fn is_authorized(&self, authorized: &AuthorizedClients, fingerprint: &str) -> bool {
+        authorized.contains(fingerprint) || self.approved.contains(fingerprint)
+    }
+
+    /// Decides whether a worker may join, queueing unknown identities
+    pub fn admit(
+        &mut self,
+        authorized: &AuthorizedClients,
+        fingerprint: &str,
+        addr: &str,
+        hello: &WorkerHello,
+    ) -> Result<(), OnboardingReject> {
+        if self.is_authorized(authorized, fingerprint) {
+            return Ok(());
+        }
+        if self.denied.contains(fingerprint) {
+            return Err(OnboardingReject::Unauthorized);
+        }
+
+        match self.pending.get_mut(fingerprint) {
+            Some(pending) => {
+                pending.addr = addr.to_string();
+                pending.hello = hello.clone();
+            }
+            None => {
+                info!(
+                    "Worker {} ({}) from {} is waiting for approval",
+                    hello.worker_id, fingerprint, addr
+                );
+                self.pending.insert(
+                    fingerprint.to_string(),
+                    Pending {
+                        addr: addr.to_string(),
+                        hello: hello.clone(),
+                        since: Instant::now(),
+                    },
+                );
+                self.notifications
+                    .push(Notification::WorkerPendingApproval {
+                        fingerprint: fingerprint.to_string(),
+                        worker_id: hello.worker_id.clone(),
+                    });
+            }
+        }
+        Err(OnboardingReject::PendingApproval)
+    }
+
+    /// Approves or rejects a pending identity
+    /// Returns false if the identity isn't pending
+    pub fn decide(&mut self, fingerprint: &str, approve: bool) -> io::Result<bool> {
+        let Some(pending) = self.pending.get(fingerprint) else {
+            return Ok(false);
+        };
+
+        if approve {
+            if let Some(path) = &self.allowlist {
+                AuthorizedClients::append_to_file(path, fingerprint, &pending.hello.worker_id)?;
+            }
+            info!(
+                "Approved worker {} ({})",
+                pending.hello.worker_id, fingerprint
+            );
+            self.approved.insert(fingerprint.to_string());
+        } else {
+            info!(
+                "Rejected worker {} ({})",
+                pending.hello.worker_id, fingerprint
+            );
+            self.denied.insert(fingerprint.to_string());
+        }
+        self.pending.remove(fingerprint);
+        Ok(true)
+    }
+
+    /// Returns the workers waiting for approval, ordered by fingerprint
+    pub fn pending(&self) -> Vec<PendingWorker> {
+        self.pending
+            .iter()
+            .map(|(fingerprint, pending)| PendingWorker {
+                fingerprint: fingerprint.clone(),
+                addr: pending.addr.clone(),
+                hello: pending.hello.clone(),
+                waiting_ms: pending.since.elapsed().as_millis() as u64,
+            })
+            .collect()
+    }
+
+    /// Takes the notifications raised since the last call
+    pub fn take_notifications(&mut self) -> Vec<Notification> {
+        mem::take(&mut self.notifications)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    fn hello(worker_id: &str) -> WorkerHello {
+        WorkerHello {
+            worker_id: worker_id.to_string(),
+            hostname: "localhost".to_string(),
+            cpus: 1,
+            tags: Vec::new(),
+            perf_score: 0,
+            cluster: None,
+            zone: None,
+        }
+    }
+
+    #[test]
+    fn approval_decisions() {
+        let path = env::temp_dir().join(format!("pomegranate-allowlist-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut authorized = AuthorizedClients::new();
+        authorized.insert("known");
+        let mut queue = ApprovalQueue::new(Some(path.clone()));
+
+        assert_eq!(queue.admit(&authorized, "known", "a", &hello("k")), Ok(()));
+        for _ in 0..2 {
+            assert_eq!(
+                queue.admit(&authorized, "new", "b", &hello("n")),
+                Err(OnboardingReject::PendingApproval)
+            );
+        }
+        assert_eq!(
+            queue.admit(&authorized, "bad", "c", &hello("x")),
+            Err(OnboardingReject::PendingApproval)
+        );
+
+        // Each identity is notified once
+        assert_eq!(queue.take_notifications().len(), 2);
+        let pending: Vec<_> = queue.pending().into_iter().map(|p| p.fingerprint).collect();
+        assert_eq!(pending, ["bad", "new"]);
+
+        assert!(queue.decide("new", true).unwrap());
+        assert!(queue.decide("bad", false).unwrap());
+        assert!(!queue.decide("other", true).unwrap());
+        assert!(queue.pending().is_empty());
+
+        assert_eq!(queue.admit(&authorized, "new", "b", &hello("n")), Ok(()));
+        assert_eq!(
+            queue.admit(&authorized, "bad", "c", &hello("x")),
+            Err(OnboardingReject::Unauthorized)
+        );
+
+        // Approved identities are persisted to the allowlist
+        let allowlist = AuthorizedClients::from_file(&path).unwrap();
+        assert!(allowlist.contains("new"));
+        assert!(!allowlist.contains("bad"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::comm::{
+    encaps::AsyncMsgSend,
+    protocol::ArtifactRef,
+    transfer::{self, DEFAULT_CHUNK_LEN},
+};
+
+/// Counter making upload file names unique within the process
+static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Directory of task results too large to be sent inline
+/// Each artifact is a file named after the digest of its data, so a result
+/// received twice, e.g. from a retried task, is only stored once
+#[derive(Debug, Clone)]
+pub struct ArtifactStore {
+    dir: PathBuf,
+}
+
+impl ArtifactStore {
+    /// Opens the store in a directory, creating it if needed
+    pub async fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).await?;
+        Ok(Self { dir })
+    }
+
+    /// Returns the path of an artifact
+    pub fn path(&self, artifact: &ArtifactRef) -> PathBuf {
+        self.dir.join(artifact.name())
+    }
+
+    /// Opens an artifact for reading
+    pub async fn open_artifact(&self, artifact: &ArtifactRef) -> io::Result<fs::File> {
+        fs::File::open(self.path(artifact)).await
+    }
+
+    /// Sends an artifact as a transfer stream, to be received with recv_stream
+    /// The stream ID is taken from the start of the digest
+    pub async fn send(
+        &self,
+        sender: &mut impl AsyncMsgSend,
+        artifact: &ArtifactRef,
+    ) -> io::Result<()> {
+        let id = u64::from_be_bytes(artifact.digest[..8].try_into().unwrap());
+        let file = self.open_artifact(artifact).await?;
+        transfer::send_stream(sender, id, file, artifact.len, DEFAULT_CHUNK_LEN).await
+    }
+
+    /// Removes an artifact the submitter is done with
+    pub async fn remove(&self, artifact: &ArtifactRef) -> io::Result<()> {
+        fs::remove_file(self.path(artifact)).await
+    }
+
+    /// Stores data held in memory
+    pub(crate) async fn store(&self, data: &[u8]) -> io::Result<ArtifactRef> {
+        let artifact = ArtifactRef::of(data);
+        let mut upload = self.upload().await?;
+        upload.write(data).await?;
+        upload.finish(&artifact).await?;
+        Ok(artifact)
+    }
+
+    /// Starts receiving an artifact in parts
+    pub(crate) async fn upload(&self) -> io::Result<Upload> {
+        let n = UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let tmp_path = self
+            .dir
+            .join(format!(".upload-{}-{}", std::process::id(), n));
+        let file = fs::File::create(&tmp_path).await?;
+
+        Ok(Upload {
+            dir: self.dir.clone(),
+            tmp_path,
+            file,
+            hasher: Sha256::new(),
+            len: 0,
+        })
+    }
+}
+
+/// Artifact being received, removed unless finished
+pub(crate) struct Upload {
+    dir: PathBuf,
+    tmp_path: PathBuf,
+    file: fs::File,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl Upload {
+    /// Returns the length of the data written so far
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data).await?;
+        self.hasher.update(data);
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    /// Checks the data against its reference and moves it into the store
+    pub async fn finish(self, artifact: &ArtifactRef) -> io::Result<()> {
+        let digest: [u8; 32] = self.hasher.clone().finalize().into();
+        if self.len != artifact.len || digest != artifact.digest {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "artifact doesn't match its reference",
+            ));
+        }
+
+        self.file.sync_all().await?;
+        fs::rename(&self.tmp_path, self.dir.join(artifact.name())).await
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // Nothing left to remove once the upload was moved into the store
+        let _ = std::fs::remove_file(&self.tmp_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn artifact_store() {
+        let dir = env::temp_dir().join(format!("pomegranate-artifacts-{}", std::process::id()));
+        let store = ArtifactStore::open(&dir).await.unwrap();
+
+        let data = vec![7; 1000];
+        let artifact = store.store(&data).await.unwrap();
+        assert_eq!(artifact, ArtifactRef::of(&data));
+        let mut read = Vec::new();
+        let mut file = store.open_artifact(&artifact).await.unwrap();
+        file.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data);
+
+        // Uploads not matching their reference are discarded
+        let mut upload = store.upload().await.unwrap();
+        upload.write(&data[1..]).await.unwrap();
+        let err = upload.finish(&ArtifactRef::of(b"other")).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut entries = fs::read_dir(&dir).await.unwrap();
+        assert!(entries.next_entry().await.unwrap().is_some());
+        assert!(entries.next_entry().await.unwrap().is_none());
+
+        store.remove(&artifact).await.unwrap();
+        fs::remove_dir(&dir).await.unwrap();
+    }
+}
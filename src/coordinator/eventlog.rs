@@ -0,0 +1,343 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rkyv::{Archive, Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use super::{jobs::JobId, WorkerId};
+use crate::comm::protocol::{decode_message, encode_message};
+
+/// Records delivered to subscribers before the slowest ones re-read the file
+const LIVE_QUEUE_LEN: usize = 1024;
+
+/// Length of the header of a record, its length as a u32
+const HEADER_LEN: u64 = 4;
+
+/// Job or worker lifecycle event
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub enum LifecycleEvent {
+    JobSubmitted {
+        job: JobId,
+        kind: String,
+        tasks: u64,
+    },
+    /// A task exhausted its attempts
+    TaskFailed {
+        job: JobId,
+        index: u64, // Position of the task in the job
+        error: String,
+    },
+    /// Every task of the job has a final result
+    JobFinished {
+        job: JobId,
+        failed: u64,
+    },
+    WorkerConnected {
+        id: WorkerId,
+        worker_id: String,
+        addr: String,
+    },
+    WorkerDisconnected {
+        id: WorkerId,
+        worker_id: String,
+    },
+}
+
+/// Event recorded in an EventLog
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub struct LogRecord {
+    pub offset: u64,       // Position of the record in the log, to resume after it
+    pub timestamp_ms: u64, // Time of the event, since the Unix epoch
+    pub event: LifecycleEvent,
+}
+
+/// Append-only file of lifecycle events
+/// Records are addressed by their byte offset in the file, so subscribers can
+/// save the offset of the last record they processed and resume right after it
+pub struct EventLog {
+    path: PathBuf,
+    file: Mutex<(File, u64)>, // Log opened for appending, and its length
+    live_tx: broadcast::Sender<(LogRecord, u64)>, // Appended records, with the next offset
+}
+
+impl EventLog {
+    /// Opens a log, creating it if needed
+    /// A record left incomplete by a crash is truncated
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let mut end = 0;
+        while let Some((record, next)) = read_record(&mut file, end)? {
+            if record.offset != end {
+                return Err(invalid_offset());
+            }
+            end = next;
+        }
+        file.set_len(end)?;
+
+        let (live_tx, _) = broadcast::channel(LIVE_QUEUE_LEN);
+        Ok(Self {
+            path,
+            file: Mutex::new((file, end)),
+            live_tx,
+        })
+    }
+
+    /// Returns the offset the next record will be written at
+    pub fn end_offset(&self) -> u64 {
+        self.file.lock().unwrap().1
+    }
+
+    /// Appends an event, returning the offset of its record
+    pub fn append(&self, event: LifecycleEvent) -> io::Result<u64> {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+
+        let mut file = self.file.lock().unwrap();
+        let record = LogRecord {
+            offset: file.1,
+            timestamp_ms,
+            event,
+        };
+        let bytes = encode_message(&record)?;
+        let len = u32::try_from(bytes.len()).map_err(|_| io::Error::other("event too large"))?;
+
+        let mut buf = Vec::with_capacity(HEADER_LEN as usize + bytes.len());
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(&bytes);
+        file.0.write_all(&buf)?;
+        file.1 += buf.len() as u64;
+
+        // Sent with the file locked, so subscribers see records in order
+        let offset = record.offset;
+        let _ = self.live_tx.send((record, file.1));
+        Ok(offset)
+    }
+
+    /// Reads the records from an offset to the end of the log
+    /// The offset must be the start of a record, or the end of the log
+    pub fn read_from(&self, offset: u64) -> io::Result<Vec<LogRecord>> {
+        let entries = self.read_complete(offset)?;
+        Ok(entries.into_iter().map(|(record, _)| record).collect())
+    }
+
+    /// Returns a subscription to the records from an offset on, both those
+    /// already in the log and the ones appended later
+    pub fn subscribe(&self, offset: u64) -> io::Result<Subscription> {
+        // Subscribed before reading, so no record falls in between
+        // Records read both ways are skipped by offset
+        let live_rx = self.live_tx.subscribe();
+        let backlog = self.read_complete(offset)?;
+
+        Ok(Subscription {
+            path: self.path.clone(),
+            next: offset,
+            backlog: backlog.into(),
+            live_rx,
+        })
+    }
+
+    /// Reads the records from an offset, checking they end where the log does
+    /// An offset in the middle of a record may otherwise look like a torn tail
+    fn read_complete(&self, offset: u64) -> io::Result<Vec<(LogRecord, u64)>> {
+        let file = self.file.lock().unwrap();
+        let entries = read_entries(&self.path, offset)?;
+        if entries.last().map_or(offset, |(_, next)| *next) != file.1 {
+            return Err(invalid_offset());
+        }
+        Ok(entries)
+    }
+}
+
+/// Records of an EventLog, replayed from an offset then followed as they are appended
+pub struct Subscription {
+    path: PathBuf,
+    next: u64, // Offset of the next record to return
+    backlog: VecDeque<(LogRecord, u64)>,
+    live_rx: broadcast::Receiver<(LogRecord, u64)>,
+}
+
+impl Subscription {
+    /// Waits for the next record
+    /// Fails if the log was dropped, or the file can't be read to catch up
+    pub async fn next(&mut self) -> io::Result<LogRecord> {
+        loop {
+            let (record, next) = match self.backlog.pop_front() {
+                Some(entry) => entry,
+                None => match self.live_rx.recv().await {
+                    Ok(entry) => entry,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        // Catch up from the file
+                        self.backlog = read_entries(&self.path, self.next)?.into();
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::BrokenPipe,
+                            "event log closed",
+                        ))
+                    }
+                },
+            };
+
+            // Live records may already have been read from the file
+            if record.offset >= self.next {
+                self.next = next;
+                return Ok(record);
+            }
+        }
+    }
+
+    /// Returns the offset to resume the subscription from
+    pub fn offset(&self) -> u64 {
+        self.next
+    }
+}
+
+/// Reads the records of a log file from an offset to its end, with the offset
+/// of the record following each
+fn read_entries(path: &Path, offset: u64) -> io::Result<Vec<(LogRecord, u64)>> {
+    let mut file = File::open(path)?;
+    if offset > file.metadata()?.len() {
+        return Err(invalid_offset());
+    }
+
+    let mut entries = Vec::new();
+    let mut pos = offset;
+    while let Some((record, next)) = read_record(&mut file, pos)? {
+        if record.offset != pos {
+            return Err(invalid_offset());
+        }
+        entries.push((record, next));
+        pos = next;
+    }
+    Ok(entries)
+}
+
+/// Reads the record at an offset, with the offset of the following one
+/// Returns None at the end of the file, or if the record is incomplete
+fn read_record(file: &mut File, offset: u64) -> io::Result<Option<(LogRecord, u64)>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut len = [0; HEADER_LEN as usize];
+    if !read_full(file, &mut len)? {
+        return Ok(None);
+    }
+
+    let len = u32::from_be_bytes(len);
+    let mut bytes = vec![0; len as usize];
+    if !read_full(file, &mut bytes)? {
+        return Ok(None);
+    }
+
+    let record = decode_message(&bytes).map_err(|_| invalid_offset())?;
+    Ok(Some((record, offset + HEADER_LEN + len as u64)))
+}
+
+/// Fills buf, returns false if the file ends first
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<bool> {
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn invalid_offset() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "event log offset isn't the start of a record",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    fn submitted(job: JobId) -> LifecycleEvent {
+        LifecycleEvent::JobSubmitted {
+            job,
+            kind: "square".to_string(),
+            tasks: 1,
+        }
+    }
+
+    #[test]
+    fn eventlog_reopen() {
+        let path = env::temp_dir().join(format!("pomegranate-events-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let log = EventLog::open(&path).unwrap();
+        let first = log.append(submitted(0)).unwrap();
+        let second = log.append(submitted(1)).unwrap();
+        assert_eq!(first, 0);
+        assert_eq!(log.read_from(second).unwrap()[0].event, submitted(1));
+        assert!(log.read_from(second + 1).is_err());
+        let end = log.end_offset();
+        drop(log);
+
+        // A record torn by a crash is dropped, and offsets stay valid
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 100, 1, 2]).unwrap();
+        let log = EventLog::open(&path).unwrap();
+        assert_eq!(log.end_offset(), end);
+        let records = log.read_from(0).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].offset, second);
+        assert_eq!(log.append(submitted(2)).unwrap(), end);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn eventlog_subscribe_resume() {
+        let path = env::temp_dir().join(format!("pomegranate-subscribe-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let log = EventLog::open(&path).unwrap();
+        log.append(submitted(0)).unwrap();
+        let mut sub = log.subscribe(0).unwrap();
+        log.append(submitted(1)).unwrap();
+        assert_eq!(sub.next().await.unwrap().event, submitted(0));
+        assert_eq!(sub.next().await.unwrap().event, submitted(1));
+
+        // Events appended while disconnected are replayed from the saved offset
+        let offset = sub.offset();
+        drop(sub);
+        log.append(submitted(2)).unwrap();
+        let mut sub = log.subscribe(offset).unwrap();
+        log.append(submitted(3)).unwrap();
+        assert_eq!(sub.next().await.unwrap().event, submitted(2));
+        assert_eq!(sub.next().await.unwrap().event, submitted(3));
+        assert_eq!(sub.offset(), log.end_offset());
+
+        // Lagging subscribers catch up from the file
+        let mut sub = log.subscribe(0).unwrap();
+        for job in 4..LIVE_QUEUE_LEN as u64 + 10 {
+            log.append(submitted(job)).unwrap();
+        }
+        for job in 0..LIVE_QUEUE_LEN as u64 + 10 {
+            assert_eq!(sub.next().await.unwrap().event, submitted(job));
+        }
+
+        drop(log);
+        let err = sub.next().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        fs::remove_file(&path).unwrap();
+    }
+}
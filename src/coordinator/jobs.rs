@@ -0,0 +1,1094 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt, mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{sync::mpsc, time::Instant};
+
+use super::{eventlog::LifecycleEvent, notify::Notification, WorkerId};
+use crate::comm::protocol::{ArtifactRef, TaskAssignment, TaskOutcome};
+
+/// Identifier of a submitted job
+pub type JobId = u64;
+
+/// Identifier of a task, unique across jobs
+pub type TaskId = u64;
+
+/// Where the tasks of a job may run, by the zone workers declare
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Placement {
+    /// Any worker
+    #[default]
+    Any,
+    /// Only workers of this zone, tasks wait if none is connected
+    Zone(String),
+    /// Spread tasks evenly across zones, workers without a zone count as one
+    Spread,
+}
+
+/// Description of a job submitted to the coordinator
+#[derive(Debug, Clone)]
+pub struct JobSpec {
+    pub kind: String,         // Task type, selects the handler on the worker
+    pub tasks: Vec<Vec<u8>>,  // Payload of each task
+    pub max_attempts: u32,    // Attempts per task before giving up
+    pub placement: Placement, // Zones the tasks may run in
+}
+
+impl JobSpec {
+    /// Creates a new JobSpec without tasks
+    pub fn new(kind: impl Into<String>) -> Self {
+        Self {
+            kind: kind.into(),
+            tasks: Vec::new(),
+            max_attempts: 3,
+            placement: Placement::Any,
+        }
+    }
+
+    pub fn task(mut self, payload: impl Into<Vec<u8>>) -> Self {
+        self.tasks.push(payload.into());
+        self
+    }
+
+    pub fn max_attempts(mut self, val: u32) -> Self {
+        self.max_attempts = val;
+        self
+    }
+
+    pub fn placement(mut self, val: Placement) -> Self {
+        self.placement = val;
+        self
+    }
+}
+
+/// Failure of a task which exhausted its attempts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskError {
+    pub attempts: u32,
+    pub error: String, // Error of the last attempt
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "task failed after {} attempts: {}",
+            self.attempts, self.error
+        )
+    }
+}
+
+impl Error for TaskError {}
+
+/// Rejection of a job which doesn't fit in the task queue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueFull {
+    pub kind: Option<String>, // Kind whose queue is full, None for the cluster-wide limit
+    pub limit: usize,
+    pub queued: usize, // Unfinished tasks when the job was rejected
+}
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            Some(kind) => write!(
+                f,
+                "queue of {} tasks full ({} of {})",
+                kind, self.queued, self.limit
+            ),
+            None => write!(f, "task queue full ({} of {})", self.queued, self.limit),
+        }
+    }
+}
+
+impl Error for QueueFull {}
+
+/// Output of a successful task
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskOutput {
+    Inline(Vec<u8>),
+    /// Result too large to be sent inline, kept in the coordinator's artifact store
+    Artifact(ArtifactRef),
+}
+
+/// Result of a single task
+pub type TaskResult = Result<TaskOutput, TaskError>;
+
+/// Whether a worker could take tasks of a job, and why not
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorkerFit {
+    Available {
+        free_slots: usize,
+    },
+    /// Every slot is running a task
+    Busy {
+        slots: usize,
+    },
+    /// Placement requires another zone
+    ZoneMismatch {
+        zone: Option<String>,
+    },
+}
+
+/// Outcome of scheduling a job, computed without submitting it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub rejected: Option<QueueFull>, // Queue limit the job would exceed
+    pub queued_ahead: usize,         // Pending tasks assigned before the job's
+    pub workers: Vec<(WorkerId, WorkerFit)>,
+    pub startable: usize, // Tasks which would start right away, at worst
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(full) = &self.rejected {
+            return write!(f, "job would be rejected: {}", full);
+        }
+
+        write!(
+            f,
+            "{} tasks would start right away, {} tasks queued ahead",
+            self.startable, self.queued_ahead
+        )?;
+        if self.workers.is_empty() {
+            write!(f, "\nno workers connected")?;
+        }
+        for (id, fit) in &self.workers {
+            match fit {
+                WorkerFit::Available { free_slots } => {
+                    write!(f, "\nworker {}: {} free slots", id, free_slots)?
+                }
+                WorkerFit::Busy { slots } => {
+                    write!(f, "\nworker {}: all {} slots busy", id, slots)?
+                }
+                WorkerFit::ZoneMismatch { zone } => write!(
+                    f,
+                    "\nworker {}: zone {} not allowed by placement",
+                    id,
+                    zone.as_deref().unwrap_or("(none)")
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Estimated time left for a job, and when it was estimated
+type SharedEta = Arc<Mutex<Option<(Duration, Instant)>>>;
+
+/// Handle to a submitted job, yields task results as they complete
+pub struct JobHandle {
+    id: JobId,
+    len: usize,
+    received: usize,
+    results_rx: mpsc::UnboundedReceiver<(usize, TaskResult)>,
+    eta: SharedEta,
+}
+
+impl JobHandle {
+    /// Returns the job ID
+    pub fn id(&self) -> JobId {
+        self.id
+    }
+
+    /// Returns the number of tasks in the job
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the estimated time until all tasks are finished
+    /// Updated as tasks complete, None until a task of this kind ever completed
+    pub fn eta(&self) -> Option<Duration> {
+        let eta = *self.eta.lock().unwrap();
+        eta.map(|(eta, at)| eta.saturating_sub(at.elapsed()))
+    }
+
+    /// Waits for the next task to finish and returns its index and result
+    /// Returns None once all results were received, or if the coordinator was dropped
+    pub async fn next(&mut self) -> Option<(usize, TaskResult)> {
+        if self.received == self.len {
+            return None;
+        }
+
+        let res = self.results_rx.recv().await?;
+        self.received += 1;
+        Some(res)
+    }
+
+    /// Waits for all tasks and returns their results in submission order
+    pub async fn results(mut self) -> Vec<TaskResult> {
+        let mut results = vec![None; self.len];
+        while let Some((index, res)) = self.next().await {
+            results[index] = Some(res);
+        }
+
+        results
+            .into_iter()
+            .map(|res| {
+                res.unwrap_or(Err(TaskError {
+                    attempts: 0,
+                    error: "coordinator stopped".to_string(),
+                }))
+            })
+            .collect()
+    }
+}
+
+/// Scheduling state of a task
+struct TaskState {
+    job: JobId,
+    index: usize, // Position in the job
+    kind: Arc<str>,
+    payload: Vec<u8>,
+    attempts: u32,
+    max_attempts: u32,
+    failed_on: HashSet<WorkerId>, // Workers on which an attempt failed
+    started: Option<Instant>,     // Start of the running attempt
+    results_tx: mpsc::UnboundedSender<(usize, TaskResult)>,
+}
+
+/// Scheduling state of a job
+struct JobState {
+    kind: Arc<str>,
+    remaining: usize, // Tasks without a final result
+    failed: usize,    // Tasks which exhausted their attempts
+    eta: SharedEta,
+    placement: Placement,
+}
+
+/// Scheduling state of a worker
+struct WorkerSlots {
+    slots: usize, // Maximum concurrent tasks
+    running: HashSet<TaskId>,
+    perf_score: u32,
+    zone: Option<String>,
+}
+
+impl WorkerSlots {
+    /// Relative speed of the worker, unmeasured workers count as the slowest possible
+    fn speed(&self) -> f64 {
+        self.perf_score.max(1) as f64
+    }
+}
+
+/// Work done by successful tasks of a kind, in seconds times worker speed
+#[derive(Default)]
+struct TaskHistory {
+    work: f64,
+    count: u32,
+}
+
+/// Queue of tasks and their assignment to workers
+#[derive(Default)]
+pub(crate) struct Scheduler {
+    next_job: JobId,
+    next_task: TaskId,
+    pending: VecDeque<TaskId>,
+    tasks: HashMap<TaskId, TaskState>,
+    jobs: HashMap<JobId, JobState>,
+    workers: BTreeMap<WorkerId, WorkerSlots>,
+    history: HashMap<Arc<str>, TaskHistory>,
+    notifications: Vec<Notification>,
+    lifecycle: Vec<LifecycleEvent>,
+    last_progress: Option<Instant>, // Last assignment or result, or when tasks started pending
+    stall_reported: bool,
+    max_queued: usize, // Unfinished tasks across kinds, zero = unlimited
+    kind_limits: HashMap<String, usize>, // Unfinished tasks per kind
+    queued_by_kind: HashMap<Arc<str>, usize>,
+}
+
+impl Scheduler {
+    /// Limits the unfinished tasks accepted, in total and per kind
+    pub fn set_queue_limits(&mut self, max_queued: usize, kind_limits: HashMap<String, usize>) {
+        self.max_queued = max_queued;
+        self.kind_limits = kind_limits;
+    }
+
+    /// Checks that the tasks of a job fit within the queue limits
+    /// Tasks count against the limits until their final result
+    pub fn check_room(&self, spec: &JobSpec) -> Result<(), QueueFull> {
+        let len = spec.tasks.len();
+        let queued = self.tasks.len();
+        if self.max_queued > 0 && queued + len > self.max_queued {
+            return Err(QueueFull {
+                kind: None,
+                limit: self.max_queued,
+                queued,
+            });
+        }
+
+        if let Some(&limit) = self.kind_limits.get(&spec.kind) {
+            let queued = self
+                .queued_by_kind
+                .get(spec.kind.as_str())
+                .copied()
+                .unwrap_or(0);
+            if queued + len > limit {
+                return Err(QueueFull {
+                    kind: Some(spec.kind.clone()),
+                    limit,
+                    queued,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Explains how a job would be scheduled if submitted now
+    /// Free slots may go to the tasks queued ahead, so startable is a lower bound
+    pub fn explain(&self, spec: &JobSpec) -> Explanation {
+        let workers: Vec<_> = self
+            .workers
+            .iter()
+            .map(|(&id, slots)| {
+                let fit = match &spec.placement {
+                    Placement::Zone(zone) if slots.zone.as_ref() != Some(zone) => {
+                        WorkerFit::ZoneMismatch {
+                            zone: slots.zone.clone(),
+                        }
+                    }
+                    _ if slots.running.len() >= slots.slots => {
+                        WorkerFit::Busy { slots: slots.slots }
+                    }
+                    _ => WorkerFit::Available {
+                        free_slots: slots.slots - slots.running.len(),
+                    },
+                };
+                (id, fit)
+            })
+            .collect();
+
+        let free_slots: usize = workers
+            .iter()
+            .map(|(_, fit)| match fit {
+                WorkerFit::Available { free_slots } => *free_slots,
+                _ => 0,
+            })
+            .sum();
+        let queued_ahead = self.pending.len();
+        let rejected = self.check_room(spec).err();
+        let startable = match rejected {
+            Some(_) => 0,
+            None => free_slots
+                .saturating_sub(queued_ahead)
+                .min(spec.tasks.len()),
+        };
+
+        Explanation {
+            rejected,
+            queued_ahead,
+            workers,
+            startable,
+        }
+    }
+
+    /// Queues the tasks of a job, regardless of the queue limits
+    pub fn submit(&mut self, spec: JobSpec) -> JobHandle {
+        let id = self.next_job;
+        self.next_job += 1;
+
+        let (results_tx, results_rx) = mpsc::unbounded_channel();
+        let kind: Arc<str> = spec.kind.into();
+        let len = spec.tasks.len();
+        let eta = SharedEta::default();
+        if self.pending.is_empty() {
+            self.progress();
+        }
+        if len > 0 {
+            *self.queued_by_kind.entry(kind.clone()).or_default() += len;
+        }
+        self.lifecycle.push(LifecycleEvent::JobSubmitted {
+            job: id,
+            kind: kind.to_string(),
+            tasks: len as u64,
+        });
+
+        for (index, payload) in spec.tasks.into_iter().enumerate() {
+            let task_id = self.next_task;
+            self.next_task += 1;
+
+            self.tasks.insert(
+                task_id,
+                TaskState {
+                    job: id,
+                    index,
+                    kind: kind.clone(),
+                    payload,
+                    attempts: 0,
+                    max_attempts: spec.max_attempts.max(1),
+                    failed_on: HashSet::new(),
+                    started: None,
+                    results_tx: results_tx.clone(),
+                },
+            );
+            self.pending.push_back(task_id);
+        }
+
+        if len == 0 {
+            *eta.lock().unwrap() = Some((Duration::ZERO, Instant::now()));
+            self.lifecycle
+                .push(LifecycleEvent::JobFinished { job: id, failed: 0 });
+        } else {
+            self.jobs.insert(
+                id,
+                JobState {
+                    kind,
+                    remaining: len,
+                    failed: 0,
+                    eta: eta.clone(),
+                    placement: spec.placement,
+                },
+            );
+            self.update_eta(id);
+        }
+
+        JobHandle {
+            id,
+            len,
+            received: 0,
+            results_rx,
+            eta,
+        }
+    }
+
+    /// Makes a worker available for tasks
+    pub fn add_worker(
+        &mut self,
+        id: WorkerId,
+        slots: usize,
+        perf_score: u32,
+        zone: Option<String>,
+    ) {
+        self.workers.insert(
+            id,
+            WorkerSlots {
+                slots: slots.max(1),
+                running: HashSet::new(),
+                perf_score,
+                zone,
+            },
+        );
+    }
+
+    /// Returns the tasks running on a worker, ordered by ID
+    pub fn running(&self, id: WorkerId) -> Vec<TaskId> {
+        let mut running: Vec<_> = self
+            .workers
+            .get(&id)
+            .map(|worker| worker.running.iter().copied().collect())
+            .unwrap_or_default();
+        running.sort();
+        running
+    }
+
+    /// Returns true if a task is currently assigned to a worker
+    pub fn is_running(&self, id: WorkerId, task_id: TaskId) -> bool {
+        self.workers
+            .get(&id)
+            .is_some_and(|worker| worker.running.contains(&task_id))
+    }
+
+    /// Returns the number of tasks waiting for a free worker
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Updates the benchmark score of a worker
+    /// Zero isn't a measurement, the worker keeps its previous score
+    pub fn set_perf_score(&mut self, id: WorkerId, perf_score: u32) {
+        if perf_score == 0 {
+            return;
+        }
+        if let Some(worker) = self.workers.get_mut(&id) {
+            worker.perf_score = perf_score;
+        }
+    }
+
+    /// Removes a worker, failing the attempts running on it
+    pub fn remove_worker(&mut self, id: WorkerId) {
+        if let Some(worker) = self.workers.remove(&id) {
+            for task_id in worker.running {
+                self.fail_attempt(task_id, id, "worker disconnected".to_string());
+            }
+        }
+    }
+
+    /// Records the outcome of a task attempt
+    /// Results for tasks not running on the worker are ignored
+    pub fn complete(&mut self, worker: WorkerId, task_id: TaskId, outcome: TaskOutcome) {
+        let Some(slots) = self.workers.get_mut(&worker) else {
+            return;
+        };
+        if !slots.running.remove(&task_id) {
+            return;
+        }
+        let speed = slots.speed();
+        self.progress();
+
+        let output = match outcome {
+            TaskOutcome::Success(result) => TaskOutput::Inline(result),
+            TaskOutcome::Artifact(artifact) => TaskOutput::Artifact(artifact),
+            TaskOutcome::Failure(error) => return self.fail_attempt(task_id, worker, error),
+        };
+        if let Some(task) = self.remove_task(task_id) {
+            // Remember how much work tasks of this kind take
+            if let Some(started) = task.started {
+                let history = self.history.entry(task.kind.clone()).or_default();
+                history.work += started.elapsed().as_secs_f64() * speed;
+                history.count += 1;
+            }
+
+            let _ = task.results_tx.send((task.index, Ok(output)));
+            self.finish_task(task.job);
+        }
+    }
+
+    /// Returns a task whose assignment couldn't be delivered to the queue,
+    /// without counting the attempt
+    pub fn unassign(&mut self, worker: WorkerId, task_id: TaskId) {
+        let slots = self.workers.get_mut(&worker);
+        if !slots.is_some_and(|slots| slots.running.remove(&task_id)) {
+            return;
+        }
+
+        if let Some(task) = self.tasks.get_mut(&task_id) {
+            task.attempts -= 1;
+            self.pending.push_front(task_id);
+        }
+    }
+
+    /// Assigns pending tasks to workers with free slots
+    /// Tasks avoid workers they already failed on, unless no other eligible worker is connected
+    pub fn assign(&mut self) -> Vec<(WorkerId, TaskAssignment)> {
+        let mut assignments = Vec::new();
+        let mut deferred = VecDeque::new();
+
+        while let Some(task_id) = self.pending.pop_front() {
+            let job = self.tasks[&task_id].job;
+            let placement = &self.jobs[&job].placement;
+            let eligible = |slots: &WorkerSlots| match placement {
+                Placement::Zone(zone) => slots.zone.as_ref() == Some(zone),
+                Placement::Any | Placement::Spread => true,
+            };
+
+            // Tasks of the job already running in each zone
+            let mut zone_load: HashMap<Option<&str>, usize> = HashMap::new();
+            if *placement == Placement::Spread {
+                for slots in self.workers.values() {
+                    let running = slots
+                        .running
+                        .iter()
+                        .filter(|id| self.tasks.get(id).is_some_and(|task| task.job == job))
+                        .count();
+                    *zone_load.entry(slots.zone.as_deref()).or_default() += running;
+                }
+            }
+
+            // Every eligible worker failed this task, so allow retrying on any
+            let task = &self.tasks[&task_id];
+            let avoid = !self
+                .workers
+                .iter()
+                .filter(|(_, slots)| eligible(slots))
+                .all(|(worker, _)| task.failed_on.contains(worker));
+
+            // Least loaded worker with a free slot, in the least loaded zone if spreading
+            let worker = self
+                .workers
+                .iter()
+                .filter(|(id, slots)| {
+                    slots.running.len() < slots.slots
+                        && eligible(slots)
+                        && !(avoid && task.failed_on.contains(id))
+                })
+                .min_by_key(|(_, slots)| {
+                    let zone_load = zone_load.get(&slots.zone.as_deref()).copied();
+                    (zone_load.unwrap_or(0), slots.running.len())
+                })
+                .map(|(id, _)| *id);
+
+            let task = self.tasks.get_mut(&task_id).unwrap();
+            match worker.map(|id| (id, self.workers.get_mut(&id).unwrap())) {
+                Some((worker_id, slots)) => {
+                    slots.running.insert(task_id);
+                    task.attempts += 1;
+                    task.started = Some(Instant::now());
+                    assignments.push((
+                        worker_id,
+                        TaskAssignment {
+                            task_id,
+                            kind: task.kind.to_string(),
+                            payload: task.payload.clone(),
+                        },
+                    ));
+                }
+                None => deferred.push_back(task_id),
+            }
+
+            // No free slots left anywhere
+            if self
+                .workers
+                .values()
+                .all(|slots| slots.running.len() >= slots.slots)
+            {
+                break;
+            }
+        }
+
+        deferred.append(&mut self.pending);
+        self.pending = deferred;
+        if !assignments.is_empty() {
+            self.progress();
+        }
+        assignments
+    }
+
+    /// Queues a QueueStalled notification if tasks are pending without progress for the timeout
+    /// Reported once per stall
+    pub fn check_stall(&mut self, timeout: Duration) {
+        let Some(last_progress) = self.last_progress else {
+            return;
+        };
+
+        let since = last_progress.elapsed();
+        if !self.pending.is_empty() && !self.stall_reported && since >= timeout {
+            self.stall_reported = true;
+            self.notifications.push(Notification::QueueStalled {
+                pending: self.pending.len(),
+                since,
+            });
+        }
+    }
+
+    /// Returns the notifications queued since the last call
+    pub fn take_notifications(&mut self) -> Vec<Notification> {
+        mem::take(&mut self.notifications)
+    }
+
+    /// Returns the job lifecycle events since the last call
+    pub fn take_lifecycle(&mut self) -> Vec<LifecycleEvent> {
+        mem::take(&mut self.lifecycle)
+    }
+
+    /// Records that the queue moved forward
+    fn progress(&mut self) {
+        self.last_progress = Some(Instant::now());
+        self.stall_reported = false;
+    }
+
+    /// Records a failed attempt, retrying the task or reporting the failure
+    fn fail_attempt(&mut self, task_id: TaskId, worker: WorkerId, error: String) {
+        let Some(task) = self.tasks.get_mut(&task_id) else {
+            return;
+        };
+
+        if task.attempts >= task.max_attempts {
+            let task = self.remove_task(task_id).unwrap();
+            self.notifications.push(Notification::JobFailed {
+                job: task.job,
+                index: task.index,
+                error: error.clone(),
+            });
+            self.lifecycle.push(LifecycleEvent::TaskFailed {
+                job: task.job,
+                index: task.index as u64,
+                error: error.clone(),
+            });
+            if let Some(job) = self.jobs.get_mut(&task.job) {
+                job.failed += 1;
+            }
+            let _ = task.results_tx.send((
+                task.index,
+                Err(TaskError {
+                    attempts: task.attempts,
+                    error,
+                }),
+            ));
+            self.finish_task(task.job);
+        } else {
+            task.failed_on.insert(worker);
+            self.pending.push_back(task_id);
+        }
+    }
+
+    /// Removes a task with its final result, freeing its place in the queue
+    fn remove_task(&mut self, task_id: TaskId) -> Option<TaskState> {
+        let task = self.tasks.remove(&task_id)?;
+        if let Some(queued) = self.queued_by_kind.get_mut(&task.kind) {
+            *queued -= 1;
+            if *queued == 0 {
+                self.queued_by_kind.remove(&task.kind);
+            }
+        }
+        Some(task)
+    }
+
+    /// Records the final result of a task of a job
+    fn finish_task(&mut self, job_id: JobId) {
+        let Some(job) = self.jobs.get_mut(&job_id) else {
+            return;
+        };
+
+        job.remaining -= 1;
+        if job.remaining == 0 {
+            *job.eta.lock().unwrap() = Some((Duration::ZERO, Instant::now()));
+            self.lifecycle.push(LifecycleEvent::JobFinished {
+                job: job_id,
+                failed: job.failed as u64,
+            });
+            self.jobs.remove(&job_id);
+        } else {
+            self.update_eta(job_id);
+        }
+    }
+
+    /// Estimates the time left for a job from the work its kind of task took in the past
+    /// and the speed of the connected workers, assuming the job gets all of them
+    fn update_eta(&self, job_id: JobId) {
+        let Some(job) = self.jobs.get(&job_id) else {
+            return;
+        };
+        let history = self.history.get(&job.kind).filter(|h| h.count > 0);
+        let capacity: f64 = self
+            .workers
+            .values()
+            .map(|worker| worker.slots as f64 * worker.speed())
+            .sum();
+
+        let eta = match history {
+            Some(history) if capacity > 0.0 => {
+                // Scores are reported by the workers, an estimate too large for a
+                // Duration (or not finite) is no estimate
+                let work = job.remaining as f64 * history.work / history.count as f64;
+                Duration::try_from_secs_f64(work / capacity)
+                    .ok()
+                    .map(|eta| (eta, Instant::now()))
+            }
+            _ => None,
+        };
+        *job.eta.lock().unwrap() = eta;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn scheduler_assign_complete() {
+        let mut sched = Scheduler::default();
+        let handle = sched.submit(JobSpec::new("square").task([2]).task([3]).task([4]));
+        assert_eq!(handle.len(), 3);
+
+        // Nothing to assign without workers
+        assert!(sched.assign().is_empty());
+
+        sched.add_worker(0, 1, 0, None);
+        sched.add_worker(1, 1, 0, None);
+        let assignments = sched.assign();
+        assert_eq!(assignments.len(), 2);
+        assert_ne!(assignments[0].0, assignments[1].0);
+        assert!(sched.assign().is_empty());
+
+        // Results go back to the handle, freeing slots
+        for (worker, task) in assignments {
+            let result = vec![task.payload[0] * task.payload[0]];
+            sched.complete(worker, task.task_id, TaskOutcome::Success(result));
+        }
+        let (worker, task) = sched.assign().pop().unwrap();
+        sched.complete(worker, task.task_id, TaskOutcome::Success(vec![16]));
+
+        assert_eq!(
+            handle.results().await,
+            [4, 9, 16].map(|n| Ok(TaskOutput::Inline(vec![n])))
+        );
+    }
+
+    #[test]
+    fn scheduler_queue_limits() {
+        let mut sched = Scheduler::default();
+        sched.set_queue_limits(3, HashMap::from([("render".to_string(), 1)]));
+        let render = || JobSpec::new("render").task([0]);
+
+        sched.check_room(&render()).unwrap();
+        let _render = sched.submit(render());
+        assert_eq!(
+            sched.check_room(&render()),
+            Err(QueueFull {
+                kind: Some("render".into()),
+                limit: 1,
+                queued: 1
+            })
+        );
+
+        let _encode = sched.submit(JobSpec::new("encode").task([0]));
+        let big = JobSpec::new("encode").task([0]).task([1]);
+        assert_eq!(
+            sched.check_room(&big),
+            Err(QueueFull {
+                kind: None,
+                limit: 3,
+                queued: 2
+            })
+        );
+
+        // Running tasks still count, finished ones free their place
+        sched.add_worker(0, 2, 0, None);
+        let assignments = sched.assign();
+        assert!(sched.check_room(&render()).is_err());
+        for (worker, task) in assignments {
+            sched.complete(worker, task.task_id, TaskOutcome::Success(vec![]));
+        }
+        sched.check_room(&render()).unwrap();
+        sched.check_room(&big).unwrap();
+    }
+
+    #[tokio::test]
+    async fn scheduler_retry_other_worker() {
+        let mut sched = Scheduler::default();
+        let handle = sched.submit(JobSpec::new("flaky").task([0]).max_attempts(2));
+        sched.add_worker(0, 1, 0, None);
+        sched.add_worker(1, 1, 0, None);
+
+        let (first, task) = sched.assign().pop().unwrap();
+        sched.complete(first, task.task_id, TaskOutcome::Failure("oops".into()));
+
+        // Retried on the other worker, then gives up
+        let (second, task) = sched.assign().pop().unwrap();
+        assert_ne!(first, second);
+        sched.complete(second, task.task_id, TaskOutcome::Failure("again".into()));
+        assert!(sched.assign().is_empty());
+
+        assert_eq!(
+            sched.take_notifications(),
+            [Notification::JobFailed {
+                job: handle.id(),
+                index: 0,
+                error: "again".into()
+            }]
+        );
+        assert_eq!(
+            handle.results().await,
+            [Err(TaskError {
+                attempts: 2,
+                error: "again".into()
+            })]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scheduler_eta() {
+        let mut sched = Scheduler::default();
+        sched.add_worker(0, 1, 100, None);
+        let first = sched.submit(JobSpec::new("render").task([0]).task([1]).task([2]));
+        assert_eq!(first.eta(), None);
+
+        let (worker, task) = sched.assign().pop().unwrap();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        sched.complete(worker, task.task_id, TaskOutcome::Success(vec![]));
+        assert_eq!(first.eta(), Some(Duration::from_secs(20)));
+
+        // Later jobs of the same kind know the history, and faster workers shorten it
+        sched.add_worker(1, 1, 300, None);
+        let second = sched.submit(JobSpec::new("render").task([0]).task([1]));
+        assert_eq!(second.eta(), Some(Duration::from_secs(5)));
+
+        // Counts down between updates
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(second.eta(), Some(Duration::from_secs(3)));
+    }
+
+    #[tokio::test]
+    async fn scheduler_eta_overflow() {
+        let mut sched = Scheduler::default();
+        sched.add_worker(0, 1, 100, None);
+
+        // Zero scores are ignored
+        sched.set_perf_score(0, 0);
+        assert_eq!(sched.workers[&0].perf_score, 100);
+
+        // History of a worker reporting an absurd score, the estimate overflows
+        sched.history.insert(
+            "render".into(),
+            TaskHistory {
+                work: f64::MAX,
+                count: 1,
+            },
+        );
+        let job = sched.submit(JobSpec::new("render").task([0]).task([1]));
+        assert_eq!(job.eta(), None);
+
+        sched.history.get_mut("render").unwrap().work = f64::INFINITY;
+        let job = sched.submit(JobSpec::new("render").task([0]));
+        assert_eq!(job.eta(), None);
+    }
+
+    #[tokio::test]
+    async fn scheduler_placement() {
+        let mut sched = Scheduler::default();
+        sched.add_worker(0, 4, 0, Some("eu".to_string()));
+        sched.add_worker(1, 4, 0, Some("eu".to_string()));
+        sched.add_worker(2, 4, 0, Some("us".to_string()));
+
+        // Pinned tasks only run in their zone, and wait if it has no free slots
+        let mut pinned = JobSpec::new("render").placement(Placement::Zone("us".to_string()));
+        for i in 0..5 {
+            pinned = pinned.task([i]);
+        }
+        let _pinned = sched.submit(pinned);
+        let assigned = sched.assign();
+        assert_eq!(assigned.len(), 4);
+        assert!(assigned.iter().all(|(worker, _)| *worker == 2));
+        assert_eq!(sched.pending_len(), 1);
+
+        // Spread tasks alternate between zones, not workers
+        let mut spread = JobSpec::new("render").placement(Placement::Spread);
+        for i in 0..4 {
+            spread = spread.task([i]);
+        }
+        let _spread = sched.submit(spread);
+        sched.add_worker(3, 4, 0, Some("us".to_string()));
+        let zones: Vec<_> = sched
+            .assign()
+            .into_iter()
+            .filter(|(_, task)| task.task_id >= 5)
+            .map(|(worker, _)| if worker < 2 { "eu" } else { "us" })
+            .collect();
+        assert_eq!(zones, ["eu", "us", "eu", "us"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scheduler_stall() {
+        let timeout = Duration::from_secs(60);
+        let mut sched = Scheduler::default();
+        let _handle = sched.submit(JobSpec::new("idle").task([0]).task([1]));
+
+        // No workers to take the tasks
+        tokio::time::advance(timeout).await;
+        sched.check_stall(timeout);
+        sched.check_stall(timeout);
+        assert_eq!(
+            sched.take_notifications(),
+            [Notification::QueueStalled {
+                pending: 2,
+                since: timeout
+            }]
+        );
+
+        // Progress resets the stall
+        sched.add_worker(0, 1, 0, None);
+        sched.assign();
+        sched.check_stall(timeout);
+        assert!(sched.take_notifications().is_empty());
+    }
+
+    #[tokio::test]
+    async fn scheduler_worker_lost() {
+        let mut sched = Scheduler::default();
+        let mut handle = sched.submit(JobSpec::new("long").task([0]));
+        sched.add_worker(0, 4, 0, None);
+
+        let (worker, _) = sched.assign().pop().unwrap();
+        sched.remove_worker(worker);
+
+        // Requeued and assigned to the next worker
+        sched.add_worker(1, 4, 0, None);
+        let (worker, task) = sched.assign().pop().unwrap();
+        assert_eq!(worker, 1);
+
+        // Stale results from other workers are ignored
+        sched.complete(0, task.task_id, TaskOutcome::Success(vec![0]));
+        sched.complete(1, task.task_id, TaskOutcome::Success(vec![1]));
+        assert_eq!(
+            handle.next().await,
+            Some((0, Ok(TaskOutput::Inline(vec![1]))))
+        );
+        assert_eq!(handle.next().await, None);
+    }
+
+    #[test]
+    fn scheduler_lifecycle() {
+        let mut sched = Scheduler::default();
+        sched.submit(JobSpec::new("empty"));
+        sched.submit(JobSpec::new("square").task([2]).task([3]).max_attempts(1));
+        sched.add_worker(0, 2, 0, None);
+        let assignments = sched.assign();
+        sched.complete(0, assignments[0].1.task_id, TaskOutcome::Success(vec![4]));
+        sched.complete(
+            0,
+            assignments[1].1.task_id,
+            TaskOutcome::Failure("oops".into()),
+        );
+
+        let submitted = |job, kind: &str, tasks| LifecycleEvent::JobSubmitted {
+            job,
+            kind: kind.to_string(),
+            tasks,
+        };
+        assert_eq!(
+            sched.take_lifecycle(),
+            [
+                submitted(0, "empty", 0),
+                LifecycleEvent::JobFinished { job: 0, failed: 0 },
+                submitted(1, "square", 2),
+                LifecycleEvent::TaskFailed {
+                    job: 1,
+                    index: 1,
+                    error: "oops".to_string()
+                },
+                LifecycleEvent::JobFinished { job: 1, failed: 1 },
+            ]
+        );
+        assert!(sched.take_lifecycle().is_empty());
+    }
+
+    #[test]
+    fn scheduler_explain() {
+        let mut sched = Scheduler::default();
+        let spec = JobSpec::new("square").task([2]).task([3]);
+        let explanation = sched.explain(&spec);
+        assert_eq!(explanation.startable, 0);
+        assert!(explanation.workers.is_empty());
+
+        sched.add_worker(0, 1, 0, Some("eu".to_string()));
+        sched.add_worker(1, 2, 0, Some("us".to_string()));
+        sched.submit(JobSpec::new("long").task([0]));
+        sched.assign();
+        let explanation = sched.explain(&spec);
+        assert_eq!(explanation.startable, 2);
+        assert_eq!(
+            explanation.workers,
+            [
+                (0, WorkerFit::Busy { slots: 1 }),
+                (1, WorkerFit::Available { free_slots: 2 })
+            ]
+        );
+
+        // Only the busy worker is in the zone
+        let spec = spec.placement(Placement::Zone("eu".to_string()));
+        let explanation = sched.explain(&spec);
+        assert_eq!(explanation.startable, 0);
+        assert_eq!(
+            explanation.workers[1],
+            (
+                1,
+                WorkerFit::ZoneMismatch {
+                    zone: Some("us".to_string())
+                }
+            )
+        );
+
+        // Nothing was submitted
+        assert_eq!(sched.pending_len(), 0);
+        sched.set_queue_limits(2, HashMap::new());
+        assert!(sched.explain(&spec).rejected.is_some());
+    }
+}
@@ -0,0 +1,62 @@
+use std::{fmt, time::Duration};
+
+use super::{jobs::JobId, WorkerId};
+
+/// Event worth alerting an operator about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notification {
+    /// A task of a job exhausted its attempts
+    JobFailed {
+        job: JobId,
+        index: usize, // Position of the task in the job
+        error: String,
+    },
+    /// A worker disconnected, its running tasks are rescheduled
+    WorkerLost { id: WorkerId, worker_id: String },
+    /// Tasks are pending but none was assigned or finished for a while
+    QueueStalled { pending: usize, since: Duration },
+    /// An unknown worker is waiting for an operator to approve its identity
+    WorkerPendingApproval {
+        fingerprint: String,
+        worker_id: String,
+    },
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Notification::JobFailed { job, index, error } => {
+                write!(f, "task {} of job {} failed: {}", index, job, error)
+            }
+            Notification::WorkerLost { id, worker_id } => {
+                write!(f, "worker {} ({}) lost", id, worker_id)
+            }
+            Notification::QueueStalled { pending, since } => write!(
+                f,
+                "{} tasks pending without progress for {}s",
+                pending,
+                since.as_secs()
+            ),
+            Notification::WorkerPendingApproval {
+                fingerprint,
+                worker_id,
+            } => write!(
+                f,
+                "worker {} ({}) waiting for approval",
+                worker_id, fingerprint
+            ),
+        }
+    }
+}
+
+/// Receiver of coordinator notifications, e.g. to send alerts
+/// Called from the coordinator's tasks, so slow deliveries should be spawned
+pub trait Notifier: Send + Sync {
+    fn notify(&self, notification: &Notification);
+}
+
+impl fmt::Debug for dyn Notifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Notifier")
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use serde::Deserialize;
+use tokio::task::JoinSet;
+
+use super::{
+    jobs::{JobId, JobSpec, Placement, QueueFull, TaskResult},
+    ClusterCoordinator,
+};
+use crate::config::ConfigError;
+
+/// Jobs to run in dependency order, as described in a TOML file
+///
+/// ```toml
+/// [[stage]]
+/// name = "render"
+/// kind = "render"
+/// tasks = ["frame-1", "frame-2"]
+///
+/// [[stage]]
+/// name = "encode"
+/// kind = "encode"
+/// tasks = ["video"]
+/// depends_on = ["render"]
+/// zone = "gpu"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pipeline {
+    #[serde(rename = "stage")]
+    pub stages: Vec<Stage>,
+}
+
+/// Job of a pipeline, submitted once the stages it depends on succeeded
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stage {
+    pub name: String,
+    pub kind: String,       // Task type, selects the handler on the worker
+    pub tasks: Vec<String>, // Payload of each task
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    pub max_attempts: Option<u32>,
+    pub zone: Option<String>, // Only run on workers of this zone
+}
+
+impl Stage {
+    fn spec(&self) -> JobSpec {
+        let mut spec = JobSpec::new(&self.kind);
+        for payload in &self.tasks {
+            spec = spec.task(payload.as_bytes());
+        }
+        if let Some(val) = self.max_attempts {
+            spec = spec.max_attempts(val);
+        }
+        if let Some(zone) = &self.zone {
+            spec = spec.placement(Placement::Zone(zone.clone()));
+        }
+        spec
+    }
+}
+
+/// Progress of a running pipeline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipelineEvent {
+    StageStarted {
+        stage: String,
+        job: JobId,
+    },
+    StageFinished {
+        stage: String,
+        failed: usize, // Failed tasks, the stage failed if not zero
+    },
+    /// A stage it depends on failed
+    StageSkipped {
+        stage: String,
+    },
+}
+
+impl Pipeline {
+    /// Loads a pipeline from a TOML file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        Self::parse(&text)
+    }
+
+    /// Parses and validates a pipeline
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let pipeline: Pipeline = toml::from_str(text).map_err(ConfigError::Parse)?;
+        pipeline.validate()?;
+        Ok(pipeline)
+    }
+
+    /// Checks that stage names are unique and dependencies exist without cycles
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut names = HashSet::new();
+        for stage in &self.stages {
+            if !names.insert(stage.name.as_str()) {
+                return Err(ConfigError::invalid(
+                    "stage",
+                    format!("duplicate {}", stage.name),
+                ));
+            }
+        }
+        for stage in &self.stages {
+            if let Some(dep) = stage
+                .depends_on
+                .iter()
+                .find(|dep| !names.contains(dep.as_str()))
+            {
+                return Err(ConfigError::invalid(
+                    "depends_on",
+                    format!("{} depends on unknown stage {}", stage.name, dep),
+                ));
+            }
+        }
+
+        // Remove stages whose dependencies were all removed, until none is left
+        let mut left: Vec<_> = self.stages.iter().collect();
+        let mut done = HashSet::new();
+        while !left.is_empty() {
+            let (ready, blocked): (Vec<_>, Vec<_>) = left
+                .into_iter()
+                .partition(|stage| stage.depends_on.iter().all(|dep| done.contains(dep)));
+            if ready.is_empty() {
+                return Err(ConfigError::invalid(
+                    "depends_on",
+                    format!("dependency cycle through {}", blocked[0].name),
+                ));
+            }
+            done.extend(ready.into_iter().map(|stage| &stage.name));
+            left = blocked;
+        }
+        Ok(())
+    }
+}
+
+impl ClusterCoordinator {
+    /// Runs the stages of a pipeline, each as soon as its dependencies succeeded
+    /// Stages depending on a failed one are skipped. Returns the results of the
+    /// stages which ran, by name, or the error of a stage rejected by the queue
+    pub async fn run_pipeline(
+        &self,
+        pipeline: &Pipeline,
+        mut progress: impl FnMut(&PipelineEvent),
+    ) -> Result<HashMap<String, Vec<TaskResult>>, QueueFull> {
+        let mut results = HashMap::new();
+        let mut failed = HashSet::new();
+        let mut started = HashSet::new();
+        let mut running = JoinSet::new();
+
+        loop {
+            // Skipping a stage may skip stages listed before it, so scan until nothing changes
+            let mut scanned = None;
+            while scanned != Some(started.len()) {
+                scanned = Some(started.len());
+                for stage in &pipeline.stages {
+                    if started.contains(&stage.name) {
+                        continue;
+                    }
+
+                    let deps_failed = stage.depends_on.iter().any(|dep| failed.contains(dep));
+                    if deps_failed {
+                        started.insert(stage.name.clone());
+                        failed.insert(stage.name.clone());
+                        progress(&PipelineEvent::StageSkipped {
+                            stage: stage.name.clone(),
+                        });
+                    } else if stage.depends_on.iter().all(|dep| results.contains_key(dep)) {
+                        let handle = self.submit_job(stage.spec())?;
+                        started.insert(stage.name.clone());
+                        progress(&PipelineEvent::StageStarted {
+                            stage: stage.name.clone(),
+                            job: handle.id(),
+                        });
+                        let name = stage.name.clone();
+                        running.spawn(async move { (name, handle.results().await) });
+                    }
+                }
+            }
+
+            let Some(res) = running.join_next().await else {
+                break;
+            };
+            let (stage, stage_results) = res.expect("stage tasks don't panic");
+            let stage_failed = stage_results.iter().filter(|res| res.is_err()).count();
+            if stage_failed > 0 {
+                failed.insert(stage.clone());
+            }
+            progress(&PipelineEvent::StageFinished {
+                stage: stage.clone(),
+                failed: stage_failed,
+            });
+            results.insert(stage, stage_results);
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipeline_parse() {
+        let pipeline = Pipeline::parse(
+            r#"
+            [[stage]]
+            name = "split"
+            kind = "echo"
+            tasks = ["a", "b"]
+
+            [[stage]]
+            name = "join"
+            kind = "echo"
+            tasks = ["ab"]
+            depends_on = ["split"]
+            max_attempts = 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(pipeline.stages[1].depends_on, ["split"]);
+        assert_eq!(pipeline.stages[1].spec().max_attempts, 1);
+
+        let cycle = r#"
+            [[stage]]
+            name = "a"
+            kind = "echo"
+            tasks = []
+            depends_on = ["b"]
+
+            [[stage]]
+            name = "b"
+            kind = "echo"
+            tasks = []
+            depends_on = ["a"]
+            "#;
+        assert!(matches!(
+            Pipeline::parse(cycle),
+            Err(ConfigError::Invalid { key, .. }) if key == "depends_on"
+        ));
+        let unknown =
+            "[[stage]]\nname = \"a\"\nkind = \"echo\"\ntasks = []\ndepends_on = [\"b\"]\n";
+        assert!(Pipeline::parse(unknown).is_err());
+    }
+}
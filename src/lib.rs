@@ -1,3 +1,7 @@
 pub mod client;
 pub mod comm;
 pub mod config;
+pub mod coordinator;
+pub mod logging;
+pub mod metrics;
+pub mod systemd;
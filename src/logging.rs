@@ -0,0 +1,309 @@
+use std::{
+    env,
+    fmt::{self, Write as _},
+    io::{self, Write},
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    Level, LevelFilter, Log, Metadata, Record, SetLoggerError,
+};
+
+use rotate::RotatingFile;
+
+pub mod rotate;
+
+/// Environment variable selecting the log format, "text" or "json"
+pub const LOG_FORMAT_ENV: &str = "POMEGRANATE_LOG_FORMAT";
+
+/// Format of the log records written to stderr
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors such as ELK or Loki.
+    /// Context such as worker and task IDs becomes separate fields
+    Json,
+}
+
+impl LogFormat {
+    /// Reads the format from the environment, defaults to text if unset or invalid
+    pub fn from_env() -> Self {
+        env::var(LOG_FORMAT_ENV)
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Installs the global logger, writing records of at least the given level to stderr
+pub fn init(format: LogFormat, level: LevelFilter) -> Result<(), SetLoggerError> {
+    match format {
+        LogFormat::Text => stderrlog::new()
+            .verbosity(level)
+            .timestamp(stderrlog::Timestamp::Millisecond)
+            .init(),
+        LogFormat::Json => {
+            log::set_boxed_logger(Box::new(JsonLogger::new(level)))?;
+            log::set_max_level(level);
+            Ok(())
+        }
+    }
+}
+
+/// Installs the global logger, writing records of at least the given level to a file
+/// The file is rotated between records according to its policy
+pub fn init_file(
+    format: LogFormat,
+    level: LevelFilter,
+    file: RotatingFile,
+) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(FileLogger {
+        format,
+        level,
+        file: Mutex::new(file),
+    }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Logger writing JSON records to stderr
+#[derive(Debug)]
+pub struct JsonLogger {
+    level: LevelFilter,
+}
+
+impl JsonLogger {
+    pub fn new(level: LevelFilter) -> Self {
+        Self { level }
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut line = json_record(record, SystemTime::now());
+        line.push('\n');
+        // Whole lines in a single write, so records of different threads don't interleave
+        let _ = io::stderr().lock().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// Logger writing records to a rotated file
+#[derive(Debug)]
+struct FileLogger {
+    format: LogFormat,
+    level: LevelFilter,
+    file: Mutex<RotatingFile>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let now = SystemTime::now();
+        let mut line = match self.format {
+            LogFormat::Text => text_record(record, now),
+            LogFormat::Json => json_record(record, now),
+        };
+        line.push('\n');
+        // Logging failures have nowhere to be reported
+        let _ = self.file.lock().unwrap().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().flush();
+    }
+}
+
+/// Formats a record as a single human readable line
+fn text_record(record: &Record, time: SystemTime) -> String {
+    format!(
+        "{} {:<5} {}: {}",
+        format_timestamp(time),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+/// Formats a record as a single line JSON object
+/// Key-values attached to the record become fields after the fixed ones
+fn json_record(record: &Record, time: SystemTime) -> String {
+    let mut out = String::from("{\"ts\":");
+    write_json_str(&mut out, &format_timestamp(time));
+    out.push_str(",\"level\":");
+    write_json_str(&mut out, level_name(record.level()));
+    out.push_str(",\"module\":");
+    write_json_str(&mut out, record.target());
+
+    let _ = record.key_values().visit(&mut JsonFields(&mut out));
+
+    out.push_str(",\"msg\":");
+    write_json_str(&mut out, &record.args().to_string());
+    out.push('}');
+    out
+}
+
+/// Appends key-values to a JSON object, numbers and booleans unquoted
+struct JsonFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push(',');
+        write_json_str(self.0, key.as_str());
+        self.0.push(':');
+        if let Some(val) = value.to_u64() {
+            let _ = write!(self.0, "{}", val);
+        } else if let Some(val) = value.to_i64() {
+            let _ = write!(self.0, "{}", val);
+        } else if let Some(val) = value.to_bool() {
+            let _ = write!(self.0, "{}", val);
+        } else {
+            write_json_str(self.0, &value.to_string());
+        }
+        Ok(())
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+/// Appends a JSON string literal
+fn write_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Formats a time as an RFC 3339 UTC timestamp with milliseconds
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    );
+    out
+}
+
+/// Converts days since 1970-01-01 into a (year, month, day) date
+/// Algorithm from Howard Hinnant's chrono-compatible date algorithms
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn json_log_record() {
+        let kvs: [(&str, Value); 3] = [
+            ("worker", Value::from(7u64)),
+            ("task", Value::from(42u64)),
+            ("worker_id", Value::from("node \"a\"")),
+        ];
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target("pomegranate::coordinator")
+            .args(format_args!("Worker 7 failed\ttask"))
+            .key_values(&kvs)
+            .build();
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_251_199_123);
+
+        assert_eq!(
+            json_record(&record, time),
+            r#"{"ts":"2024-02-29T23:59:59.123Z","level":"warn","module":"pomegranate::coordinator","worker":7,"task":42,"worker_id":"node \"a\"","msg":"Worker 7 failed\ttask"}"#
+        );
+        assert_eq!(
+            text_record(&record, time),
+            "2024-02-29T23:59:59.123Z WARN  pomegranate::coordinator: Worker 7 failed\ttask"
+        );
+        assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// When log files are rotated and how many old ones are kept
+#[derive(Debug, Clone, Copy)]
+pub struct RotationPolicy {
+    pub max_size: u64, // Rotate before the file grows past this size in bytes, 0 = never
+    pub max_age: Duration, // Rotate files older than this, zero = never
+    pub keep: usize,   // Rotated files kept as path.1 (newest) to path.N
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        Self {
+            max_size: 10 * 1024 * 1024,
+            max_age: Duration::from_secs(24 * 60 * 60),
+            keep: 7,
+        }
+    }
+}
+
+impl RotationPolicy {
+    pub fn max_size(mut self, val: u64) -> Self {
+        self.max_size = val;
+        self
+    }
+
+    pub fn max_age(mut self, val: Duration) -> Self {
+        self.max_age = val;
+        self
+    }
+
+    pub fn keep(mut self, val: usize) -> Self {
+        self.keep = val;
+        self
+    }
+}
+
+/// Append-only file which is rotated according to a RotationPolicy
+/// Rotation only happens between writes, so writing whole records keeps them intact
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    policy: RotationPolicy,
+    file: File,
+    size: u64,
+    created: SystemTime,
+}
+
+impl RotatingFile {
+    /// Opens the file, appending to it if it exists
+    pub fn open(path: impl Into<PathBuf>, policy: RotationPolicy) -> io::Result<Self> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let metadata = file.metadata()?;
+        let created = metadata.created().unwrap_or_else(|_| SystemTime::now());
+
+        Ok(Self {
+            path,
+            policy,
+            file,
+            size: metadata.len(),
+            created,
+        })
+    }
+
+    /// Returns the path of the current file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns true if writing len bytes should go to a new file
+    fn needs_rotation(&self, len: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+
+        let too_big =
+            self.policy.max_size > 0 && self.size.saturating_add(len as u64) > self.policy.max_size;
+        let too_old = !self.policy.max_age.is_zero()
+            && self.created.elapsed().unwrap_or_default() >= self.policy.max_age;
+        too_big || too_old
+    }
+
+    /// Shifts path.N to path.N+1, dropping the oldest, and starts a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.policy.keep == 0 {
+            remove_if_exists(&self.path)?;
+        } else {
+            remove_if_exists(&rotated_path(&self.path, self.policy.keep))?;
+            for n in (1..self.policy.keep).rev() {
+                rename_if_exists(
+                    &rotated_path(&self.path, n),
+                    &rotated_path(&self.path, n + 1),
+                )?;
+            }
+            rename_if_exists(&self.path, &rotated_path(&self.path, 1))?;
+        }
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.created = SystemTime::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Returns the path of the n-th rotated file
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = env::temp_dir().join(format!("pomegranate-rotate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("worker.log");
+
+        let policy = RotationPolicy::default()
+            .max_size(7)
+            .max_age(Duration::ZERO)
+            .keep(2);
+        let mut file = RotatingFile::open(&path, policy).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        // Lines are never split, the oldest files were dropped
+        assert_eq!(fs::read_to_string(&path).unwrap(), "five\n");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            "four\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 2)).unwrap(),
+            "three\n"
+        );
+        assert!(!rotated_path(&path, 3).exists());
+
+        // Reopening appends and accounts for the existing size
+        let mut file = RotatingFile::open(&path, policy).unwrap();
+        file.write_all(b"6\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "five\n6\n");
+        file.write_all(b"seven\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "seven\n");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            "five\n6\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::client::breaker::FailureClass;
+
+/// Receiver of instrumentation events from channels and the client
+/// Methods are called inline on the connection tasks, so they should only
+/// update counters or hand the values to an exporter. All methods default
+/// to doing nothing, implementors pick the events they care about
+pub trait Metrics: Send + Sync {
+    /// A message was written to the connection, including its length header
+    fn bytes_sent(&self, _bytes: u64) {}
+
+    /// A message was read from the connection, including its length header
+    fn bytes_received(&self, _bytes: u64) {}
+
+    /// A received message failed authentication and was rejected
+    fn decryption_failed(&self) {}
+
+    /// An encrypted channel handshake completed
+    fn handshake_completed(&self, _latency: Duration) {}
+
+    /// An encrypted channel handshake failed
+    fn handshake_failed(&self) {}
+
+    /// The client failed to connect to a coordinator
+    fn connect_failed(&self, _class: FailureClass) {}
+
+    /// The client connected to a coordinator
+    fn connected(&self) {}
+
+    /// The client lost its connection to a coordinator
+    fn disconnected(&self) {}
+}
+
+impl fmt::Debug for dyn Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Metrics")
+    }
+}
+
+/// Metrics implementation keeping running totals, for exporters that poll
+#[derive(Debug, Default)]
+pub struct Counters {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    decryption_failures: AtomicU64,
+    handshakes: AtomicU64,
+    handshake_failures: AtomicU64,
+    handshake_micros: AtomicU64,
+    connect_failures: AtomicU64,
+    connects: AtomicU64,
+    disconnects: AtomicU64,
+}
+
+/// Point-in-time copy of Counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CountersSnapshot {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub decryption_failures: u64,
+    pub handshakes: u64,
+    pub handshake_failures: u64,
+    pub handshake_micros: u64, // Total duration of the completed handshakes
+    pub connect_failures: u64,
+    pub connects: u64,
+    pub disconnects: u64,
+}
+
+impl Counters {
+    pub fn snapshot(&self) -> CountersSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        CountersSnapshot {
+            bytes_sent: load(&self.bytes_sent),
+            bytes_received: load(&self.bytes_received),
+            decryption_failures: load(&self.decryption_failures),
+            handshakes: load(&self.handshakes),
+            handshake_failures: load(&self.handshake_failures),
+            handshake_micros: load(&self.handshake_micros),
+            connect_failures: load(&self.connect_failures),
+            connects: load(&self.connects),
+            disconnects: load(&self.disconnects),
+        }
+    }
+}
+
+impl Metrics for Counters {
+    fn bytes_sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn bytes_received(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn decryption_failed(&self) {
+        self.decryption_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handshake_completed(&self, latency: Duration) {
+        self.handshakes.fetch_add(1, Ordering::Relaxed);
+        self.handshake_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connect_failed(&self, _class: FailureClass) {
+        self.connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connected(&self) {
+        self.connects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn disconnected(&self) {
+        self.disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+}
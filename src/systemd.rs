@@ -0,0 +1,170 @@
+use std::{env, future::Future, io, sync::Mutex, time::Duration};
+
+use log::warn;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+/// Sends a state notification to the systemd service manager
+/// Returns Ok(false) if the process is not running under systemd (NOTIFY_SOCKET unset)
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => {
+            send_notification(&path.to_string_lossy(), state)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Notifies systemd that service startup is finished
+pub fn notify_ready() {
+    if let Err(e) = notify("READY=1") {
+        warn!("Unable to notify systemd readiness: {}", e);
+    }
+}
+
+/// Updates the service status string shown by systemd
+pub fn notify_status(status: &str) {
+    if let Err(e) = notify(&format!("STATUS={}", status)) {
+        warn!("Unable to notify systemd status: {}", e);
+    }
+}
+
+/// Returns the watchdog timeout configured by systemd for this process, if any
+pub fn watchdog_timeout() -> Option<Duration> {
+    // WATCHDOG_PID, if set, must refer to this process
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        if pid.to_string_lossy().parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+
+    Some(Duration::from_micros(usec))
+}
+
+#[cfg(unix)]
+fn send_notification(path: &str, state: &str) -> io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+
+    // Socket paths starting with '@' live in the abstract namespace
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let addr = SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_notification(_path: &str, _state: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "systemd notifications are only supported on unix",
+    ))
+}
+
+/// Sends keep-alive notifications to the systemd watchdog as its owner makes progress
+/// Loops report each completed iteration, so a hung loop stops pinging and
+/// gets restarted by systemd
+pub struct Watchdog {
+    period: Option<Duration>, // Minimum time between pings, None = disabled
+    last_ping: Mutex<Option<Instant>>, // When the last ping was sent
+}
+
+impl Watchdog {
+    /// Constructs a new Watchdog configured from the environment set up by systemd
+    pub fn from_env() -> Self {
+        Self::new(watchdog_timeout())
+    }
+
+    /// Constructs a new Watchdog for a given timeout. None disables the watchdog
+    /// Pings are sent at most every quarter of the timeout, so loops completing
+    /// an iteration at least every half timeout are never restarted
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            period: timeout.map(|timeout| timeout / 4),
+            last_ping: Mutex::new(None),
+        }
+    }
+
+    /// Returns true if the watchdog is enabled
+    pub fn enabled(&self) -> bool {
+        self.period.is_some()
+    }
+
+    /// Returns the minimum time between pings, if the watchdog is enabled
+    pub fn period(&self) -> Option<Duration> {
+        self.period
+    }
+
+    /// Reports progress of the owning loop, pinging the watchdog if one is due
+    pub fn progress(&self) {
+        let Some(period) = self.period else {
+            return;
+        };
+
+        let mut last_ping = self.last_ping.lock().unwrap();
+        if last_ping.is_some_and(|last| last.elapsed() < period) {
+            return;
+        }
+        *last_ping = Some(Instant::now());
+        drop(last_ping);
+
+        if let Err(e) = notify("WATCHDOG=1") {
+            warn!("Unable to ping systemd watchdog: {}", e);
+        }
+    }
+
+    /// Waits for a future which can't report progress itself, like a sleep or
+    /// a connection attempt with its own timeouts, pinging the watchdog meanwhile
+    pub async fn guard<F: Future>(&self, fut: F) -> F::Output {
+        let Some(period) = self.period else {
+            return fut.await;
+        };
+
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                res = &mut fut => return res,
+                _ = interval.tick() => self.progress(),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    use super::*;
+
+    #[test]
+    fn notification_datagram() {
+        let dir = env::temp_dir().join(format!("pomegranate-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        send_notification(path.to_str().unwrap(), "READY=1").unwrap();
+
+        let mut buf = [0u8; 64];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}